@@ -1,5 +1,7 @@
-use crate::config::env::REDIS_URL;
+use crate::config::env::{DB_AUTH, DB_URL, REDIS_URL};
+use crate::repositories::Surreal;
 use crate::routes;
+use crate::services::{deletions, tenants};
 use crate::structs::AppState;
 use crate::utils::redis::RedisClient;
 use axum::Router;
@@ -12,11 +14,27 @@ pub async fn create_app() -> Router {
         RedisClient::new(REDIS_URL.to_owned()).await.unwrap(),
     ));
     let http = Client::new();
-    let state = AppState { redis, http };
+    let db = Surreal {
+        client: http.clone(),
+        url: DB_URL.to_owned(),
+        auth: DB_AUTH.to_owned(),
+    };
+    if let Err(e) = tenants::load_suspensions(&db, &mut *redis.lock().await).await {
+        // Checks load them again until they can be read
+        log::error!("Failed to load suspended tenants: {}", e.detail);
+    }
+    tokio::spawn(deletions::run_sweeper(db.clone(), redis.clone()));
+    let state = AppState { redis, http, db };
 
     Router::new()
+        .nest("/clients", routes::clients::create_route())
+        .nest("/groups", routes::groups::create_route())
         .nest("/otps", routes::otps::create_route())
         .nest("/jwts", routes::jwts::create_route())
+        .nest("/profile", routes::profile::create_route())
+        .nest("/roles", routes::roles::create_route())
+        .nest("/sessions", routes::sessions::create_route())
+        .nest("/settings", routes::settings::create_route())
         .nest("/tenants", routes::tenants::create_route())
         .nest("/users", routes::users::create_route())
         .with_state(state)
@@ -1 +1,30 @@
 pub const BEARER: &str = "Bearer";
+pub const DPOP: &str = "DPoP";
+
+/// The namespace and database holding platform-wide records such as the tenant registry.
+pub const PLATFORM_NS: &str = "haltion";
+pub const PLATFORM_DB: &str = "haltion";
+
+/// Names no tenant can be created or deleted under: the platform's own namespace, and names
+/// operators are likely to want for themselves.
+pub const RESERVED_TENANT_NAMES: [&str; 5] =
+    [PLATFORM_NS, PLATFORM_DB, "platform", "admin", "system"];
+
+/// The scope a token must carry to use the platform admin API.
+pub const PLATFORM_ADMIN_SCOPE: &str = "platform:admin";
+
+/// The prefix of scopes that only the platform grants. Tenant roles cannot carry them.
+pub const PLATFORM_SCOPE_PREFIX: &str = "platform:";
+
+/// Lifetimes, in seconds, applied to access tokens and one-time codes unless a tenant or client
+/// sets its own.
+pub const DEFAULT_ACCESS_TOKEN_TTL: i64 = 86_400;
+pub const DEFAULT_OTP_TTL: i64 = 300;
+
+/// How long, in seconds, users wait between verification codes unless their tenant sets its own.
+pub const DEFAULT_RESEND_INTERVAL: i64 = 60;
+
+/// The bounds, in seconds, that token and one-time code lifetimes must fall within. Access
+/// tokens cannot outlive their session.
+pub const MIN_TTL: i64 = 60;
+pub const MAX_OTP_TTL: i64 = 3_600;
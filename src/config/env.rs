@@ -17,6 +17,11 @@ lazy_static! {
     pub static ref SMTP_USERNAME: String = env_or_default("SMTP_USERNAME");
     pub static ref SMTP_PASSWORD: String = env_or_default("SMTP_PASSWORD");
     pub static ref SMTP_FROM: String = env_or_default("SMTP_FROM");
+    pub static ref ARGON2_MEMORY_KIB: String = env_or("ARGON2_MEMORY_KIB", "19456");
+    pub static ref ARGON2_ITERATIONS: String = env_or("ARGON2_ITERATIONS", "2");
+    pub static ref ARGON2_PARALLELISM: String = env_or("ARGON2_PARALLELISM", "1");
+    pub static ref BREACHED_PASSWORDS_DIR: String = env_or("BREACHED_PASSWORDS_DIR", "");
+    pub static ref TRUSTED_PROXIES: String = env_or("TRUSTED_PROXIES", "");
 }
 
 fn env_or_default(key: &str) -> String {
@@ -28,3 +33,10 @@ fn env_or_default(key: &str) -> String {
         },
     }
 }
+
+fn env_or(key: &str, default: &str) -> String {
+    match dotenvy::var(key) {
+        Ok(val) => val,
+        Err(_) => env::var(key).unwrap_or_else(|_| default.to_owned()),
+    }
+}
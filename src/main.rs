@@ -1,5 +1,6 @@
 mod app;
 pub mod config;
+pub mod repositories;
 pub mod routes;
 pub mod services;
 pub mod structs;
@@ -21,7 +22,7 @@ async fn main() {
     println!("listening on http://{addr}");
 
     axum::Server::bind(&addr)
-        .serve(app.into_make_service())
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
         .await
         .unwrap();
 }
@@ -0,0 +1,98 @@
+use super::{take, Ident, RepoError, Surreal};
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+
+/// Reads and writes the raw records of a tenant's namespace, so that they can be moved between
+/// deployments with their record ids intact.
+pub struct ArchiveRepository<'a> {
+    db: &'a Surreal,
+    tenant: Ident,
+}
+
+impl<'a> ArchiveRepository<'a> {
+    pub fn new(db: &'a Surreal, tenant: Ident) -> Self {
+        Self { db, tenant }
+    }
+
+    /// Returns every record of a table, ordered by id.
+    pub async fn export(&self, table: &Ident) -> Result<Vec<Value>, RepoError> {
+        let results = self
+            .query(&format!("SELECT * FROM {table} ORDER BY id"), &[])
+            .await?;
+
+        take(&results, 0)
+    }
+
+    /// Returns the ids of every record of a table, without the table name.
+    pub async fn ids(&self, table: &Ident) -> Result<HashSet<String>, RepoError> {
+        let results = self
+            .query(&format!("SELECT VALUE id FROM {table}"), &[])
+            .await?;
+
+        Ok(take::<String>(&results, 0)?
+            .iter()
+            .filter_map(|id| record_key(id))
+            .collect())
+    }
+
+    /// Returns the id of every user, keyed by username.
+    pub async fn usernames(&self) -> Result<HashMap<String, String>, RepoError> {
+        let results = self.query("SELECT id, username FROM user", &[]).await?;
+
+        Ok(take::<Value>(&results, 0)?
+            .iter()
+            .filter_map(|user| {
+                let id = record_key(user.get("id")?.as_str()?)?;
+                let username = user.get("username")?.as_str()?;
+                Some((username.to_owned(), id))
+            })
+            .collect())
+    }
+
+    /// Writes a record under the given id. An existing record is replaced only when `overwrite`
+    /// is set, otherwise writing it fails.
+    pub async fn write(
+        &self,
+        table: &Ident,
+        id: &str,
+        record: &Value,
+        overwrite: bool,
+    ) -> Result<(), RepoError> {
+        let mut content = record.clone();
+        if let Some(content) = content.as_object_mut() {
+            content.remove("id");
+        }
+        let sql = match overwrite {
+            true => format!(
+                "UPDATE type::thing('{}', $id) CONTENT $record",
+                table.as_str()
+            ),
+            false => format!(
+                "CREATE type::thing('{}', $id) CONTENT $record",
+                table.as_str()
+            ),
+        };
+
+        self.query(&sql, &[("id", json!(id)), ("record", content)])
+            .await
+            .map(|_| ())
+    }
+
+    async fn query(&self, sql: &str, vars: &[(&str, Value)]) -> Result<Vec<Value>, RepoError> {
+        self.db.query(&self.tenant, &self.tenant, sql, vars).await
+    }
+}
+
+/// Returns the key of a record id such as `user:abc` or `user:⟨a-b⟩`.
+pub fn record_key(id: &str) -> Option<String> {
+    let (_, key) = id.split_once(':')?;
+    let key = key
+        .strip_prefix('⟨')
+        .and_then(|k| k.strip_suffix('⟩'))
+        .unwrap_or(key);
+
+    match key.is_empty() {
+        true => None,
+        false => Some(key.to_owned()),
+    }
+}
@@ -0,0 +1,136 @@
+use super::{take, take_one, Ident, RepoError, Surreal};
+use crate::config::constants::{PLATFORM_DB, PLATFORM_NS};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+/// A channel through which one-time codes can be delivered.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Channel {
+    Sms,
+    Email,
+}
+
+/// A client application registered by a tenant.
+///
+/// Clients are kept in the platform namespace so that a `client_id` can be resolved to its
+/// tenant without knowing the tenant up front.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ClientRecord {
+    pub client_id: String,
+    pub tenant: String,
+    pub name: String,
+    #[serde(default)]
+    pub allowed_origins: Vec<String>,
+    #[serde(default)]
+    pub redirect_uris: Vec<String>,
+    /// How long, in seconds, access tokens issued to the client are valid.
+    pub access_token_ttl: i64,
+    /// How long, in seconds, one-time codes sent for the client are valid.
+    pub otp_ttl: i64,
+    pub allowed_channels: Vec<Channel>,
+    pub created_at: String,
+    pub updated_at: String,
+}
+
+impl ClientRecord {
+    pub fn allows_channel(&self, channel: Channel) -> bool {
+        self.allowed_channels.contains(&channel)
+    }
+
+    /// Checks a request's `Origin` against the client's allowed origins. Requests without an
+    /// origin, and clients without allowed origins, are not restricted.
+    pub fn allows_origin(&self, origin: Option<&str>) -> bool {
+        match origin {
+            Some(origin) if !self.allowed_origins.is_empty() => {
+                self.allowed_origins.iter().any(|o| o == origin)
+            }
+            _ => true,
+        }
+    }
+}
+
+/// Stores the client registry in the platform namespace.
+pub struct ClientRepository<'a> {
+    db: &'a Surreal,
+    ns: Ident,
+    database: Ident,
+}
+
+impl<'a> ClientRepository<'a> {
+    pub fn new(db: &'a Surreal) -> Self {
+        Self {
+            db,
+            ns: Ident::parse(PLATFORM_NS).unwrap(),
+            database: Ident::parse(PLATFORM_DB).unwrap(),
+        }
+    }
+
+    pub async fn create(&self, client: &ClientRecord) -> Result<ClientRecord, RepoError> {
+        let results = self
+            .query(
+                "CREATE type::thing('client', $id) CONTENT $client",
+                &[("id", json!(client.client_id)), ("client", json!(client))],
+            )
+            .await?;
+
+        Ok(take_one(&results, 0)?.unwrap_or_else(|| client.clone()))
+    }
+
+    pub async fn find(&self, client_id: &str) -> Result<Option<ClientRecord>, RepoError> {
+        let results = self
+            .query(
+                "SELECT * FROM type::thing('client', $id)",
+                &[("id", json!(client_id))],
+            )
+            .await?;
+
+        take_one(&results, 0)
+    }
+
+    pub async fn list(&self, tenant: &str) -> Result<Vec<ClientRecord>, RepoError> {
+        let results = self
+            .query(
+                "SELECT * FROM client WHERE tenant = $tenant ORDER BY created_at",
+                &[("tenant", json!(tenant))],
+            )
+            .await?;
+
+        take(&results, 0)
+    }
+
+    /// Replaces a client record and returns it.
+    pub async fn update(&self, client: &ClientRecord) -> Result<Option<ClientRecord>, RepoError> {
+        let results = self
+            .query(
+                "UPDATE type::thing('client', $id) CONTENT $client RETURN AFTER",
+                &[("id", json!(client.client_id)), ("client", json!(client))],
+            )
+            .await?;
+
+        take_one(&results, 0)
+    }
+
+    pub async fn delete(&self, client_id: &str) -> Result<(), RepoError> {
+        self.query(
+            "DELETE type::thing('client', $id)",
+            &[("id", json!(client_id))],
+        )
+        .await
+        .map(|_| ())
+    }
+
+    /// Deletes every client of a tenant.
+    pub async fn delete_all(&self, tenant: &str) -> Result<(), RepoError> {
+        self.query(
+            "DELETE client WHERE tenant = $tenant",
+            &[("tenant", json!(tenant))],
+        )
+        .await
+        .map(|_| ())
+    }
+
+    async fn query(&self, sql: &str, vars: &[(&str, Value)]) -> Result<Vec<Value>, RepoError> {
+        self.db.query(&self.ns, &self.database, sql, vars).await
+    }
+}
@@ -0,0 +1,107 @@
+use super::{take_one, Ident, RepoError, Surreal};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+/// Who asked for a user to be deleted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Requester {
+    User,
+    Admin,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeletionStatus {
+    /// The user is disabled and waits out the grace period.
+    Scheduled,
+    /// The user was erased.
+    Completed,
+    /// The deletion was called off during the grace period.
+    Cancelled,
+}
+
+/// The record that a user was deleted. It names the user only by a pseudonym, so that it can be
+/// kept after the user is erased.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DeletionReceipt {
+    /// The record id, such as `deletion_receipt:abc`. Set by the database.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    pub receipt_id: String,
+    /// A keyed hash of the user's username, which the same username always maps to.
+    pub subject: String,
+    pub requested_by: Requester,
+    pub status: DeletionStatus,
+    pub requested_at: String,
+    /// When the user is erased, as an RFC 3339 timestamp.
+    pub delete_after: String,
+    #[serde(default)]
+    pub completed_at: Option<String>,
+    /// What was erased from the user's data.
+    #[serde(default)]
+    pub erased: Vec<String>,
+    /// What was kept under the pseudonym instead of the username.
+    #[serde(default)]
+    pub pseudonymized: Vec<String>,
+}
+
+/// Stores deletion receipts in the namespace of a single tenant.
+pub struct DeletionRepository<'a> {
+    db: &'a Surreal,
+    tenant: Ident,
+}
+
+impl<'a> DeletionRepository<'a> {
+    pub fn new(db: &'a Surreal, tenant: Ident) -> Self {
+        Self { db, tenant }
+    }
+
+    pub async fn create(&self, receipt: &DeletionReceipt) -> Result<DeletionReceipt, RepoError> {
+        let results = self
+            .query(
+                "CREATE type::thing('deletion_receipt', $id) CONTENT $receipt",
+                &[
+                    ("id", json!(receipt.receipt_id)),
+                    ("receipt", json!(receipt)),
+                ],
+            )
+            .await?;
+
+        Ok(take_one(&results, 0)?.unwrap_or_else(|| receipt.clone()))
+    }
+
+    pub async fn find(&self, receipt_id: &str) -> Result<Option<DeletionReceipt>, RepoError> {
+        let results = self
+            .query(
+                "SELECT * FROM type::thing('deletion_receipt', $id)",
+                &[("id", json!(receipt_id))],
+            )
+            .await?;
+
+        take_one(&results, 0)
+    }
+
+    /// Replaces a receipt with a newer state of it.
+    pub async fn update(
+        &self,
+        receipt: &DeletionReceipt,
+    ) -> Result<Option<DeletionReceipt>, RepoError> {
+        let mut content = json!(receipt);
+        if let Some(content) = content.as_object_mut() {
+            content.remove("id");
+        }
+        let results = self
+            .query(
+                "UPDATE type::thing('deletion_receipt', $id) CONTENT $receipt RETURN AFTER",
+                &[("id", json!(receipt.receipt_id)), ("receipt", content)],
+            )
+            .await?;
+
+        take_one(&results, 0)
+    }
+
+    async fn query(&self, sql: &str, vars: &[(&str, Value)]) -> Result<Vec<Value>, RepoError> {
+        self.db.query(&self.tenant, &self.tenant, sql, vars).await
+    }
+}
@@ -0,0 +1,54 @@
+use super::{take_one, Ident, RepoError, Surreal};
+use crate::repositories::clients::Channel;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+/// A second factor a user has enrolled. Logins with a password must be confirmed with a code
+/// sent to its recipient.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct FactorRecord {
+    pub username: String,
+    pub channel: Channel,
+    /// The phone number or email address codes are sent to.
+    pub recipient: String,
+}
+
+/// Stores second factors in the namespace of a single tenant.
+pub struct FactorRepository<'a> {
+    db: &'a Surreal,
+    tenant: Ident,
+}
+
+impl<'a> FactorRepository<'a> {
+    pub fn new(db: &'a Surreal, tenant: Ident) -> Self {
+        Self { db, tenant }
+    }
+
+    pub async fn find_by_username(
+        &self,
+        username: &str,
+    ) -> Result<Option<FactorRecord>, RepoError> {
+        let results = self
+            .query(
+                "SELECT * FROM factor WHERE username = $username LIMIT 1",
+                &[("username", json!(username))],
+            )
+            .await?;
+
+        take_one(&results, 0)
+    }
+
+    /// Deletes every factor a user has enrolled.
+    pub async fn delete_by_username(&self, username: &str) -> Result<(), RepoError> {
+        self.query(
+            "DELETE factor WHERE username = $username",
+            &[("username", json!(username))],
+        )
+        .await
+        .map(|_| ())
+    }
+
+    async fn query(&self, sql: &str, vars: &[(&str, Value)]) -> Result<Vec<Value>, RepoError> {
+        self.db.query(&self.tenant, &self.tenant, sql, vars).await
+    }
+}
@@ -0,0 +1,129 @@
+use super::{take, take_one, Ident, RepoError, Surreal};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+/// A set of users that are granted the same roles. Members are recorded on the users.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct GroupRecord {
+    /// The record id, such as `group:engineering`. Set by the database.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    /// The name of the group, which is also the key of its record.
+    pub name: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    /// The roles every member of the group is granted.
+    #[serde(default)]
+    pub roles: Vec<String>,
+    #[serde(default)]
+    pub created_at: Option<String>,
+    #[serde(default)]
+    pub updated_at: Option<String>,
+}
+
+/// Stores groups in the namespace of a single tenant.
+pub struct GroupRepository<'a> {
+    db: &'a Surreal,
+    tenant: Ident,
+}
+
+impl<'a> GroupRepository<'a> {
+    pub fn new(db: &'a Surreal, tenant: Ident) -> Self {
+        Self { db, tenant }
+    }
+
+    pub async fn create(&self, group: &GroupRecord) -> Result<GroupRecord, RepoError> {
+        let results = self
+            .query(
+                "CREATE type::thing('group', $name) CONTENT $group",
+                &[("name", json!(group.name)), ("group", json!(group))],
+            )
+            .await?;
+
+        Ok(take_one(&results, 0)?.unwrap_or_else(|| group.clone()))
+    }
+
+    pub async fn list(&self) -> Result<Vec<GroupRecord>, RepoError> {
+        let results = self
+            .query("SELECT * FROM `group` ORDER BY name", &[])
+            .await?;
+
+        take(&results, 0)
+    }
+
+    pub async fn find(&self, name: &str) -> Result<Option<GroupRecord>, RepoError> {
+        let results = self
+            .query(
+                "SELECT * FROM type::thing('group', $name)",
+                &[("name", json!(name))],
+            )
+            .await?;
+
+        take_one(&results, 0)
+    }
+
+    /// Returns the groups with the given names. Names without a group are left out.
+    pub async fn find_many(&self, names: &[String]) -> Result<Vec<GroupRecord>, RepoError> {
+        if names.is_empty() {
+            return Ok(Vec::new());
+        }
+        let results = self
+            .query(
+                "SELECT * FROM `group` WHERE name INSIDE $names",
+                &[("names", json!(names))],
+            )
+            .await?;
+
+        take(&results, 0)
+    }
+
+    /// Replaces a group with a newer state of it.
+    pub async fn update(&self, group: &GroupRecord) -> Result<Option<GroupRecord>, RepoError> {
+        let mut content = json!(group);
+        if let Some(content) = content.as_object_mut() {
+            content.remove("id");
+        }
+        let results = self
+            .query(
+                "UPDATE type::thing('group', $name) CONTENT $group RETURN AFTER",
+                &[("name", json!(group.name)), ("group", content)],
+            )
+            .await?;
+
+        take_one(&results, 0)
+    }
+
+    /// Takes a role away from every group that grants it.
+    pub async fn remove_role(&self, role: &str) -> Result<(), RepoError> {
+        self.query(
+            "UPDATE `group` SET roles -= $role, updated_at = $now WHERE roles CONTAINS $role",
+            &[
+                ("role", json!(role)),
+                ("now", json!(Utc::now().to_rfc3339())),
+            ],
+        )
+        .await
+        .map(|_| ())
+    }
+
+    /// Deletes a group and returns the deleted record, or `None` if it does not exist.
+    pub async fn delete(&self, name: &str) -> Result<Option<GroupRecord>, RepoError> {
+        let group = match self.find(name).await? {
+            Some(group) => group,
+            None => return Ok(None),
+        };
+
+        self.query(
+            "DELETE type::thing('group', $name)",
+            &[("name", json!(name))],
+        )
+        .await?;
+
+        Ok(Some(group))
+    }
+
+    async fn query(&self, sql: &str, vars: &[(&str, Value)]) -> Result<Vec<Value>, RepoError> {
+        self.db.query(&self.tenant, &self.tenant, sql, vars).await
+    }
+}
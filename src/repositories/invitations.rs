@@ -0,0 +1,224 @@
+use super::{take, take_one, Ident, RepoError, Surreal};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InvitationStatus {
+    /// The invitation waits to be accepted.
+    Pending,
+    /// The invited user was created.
+    Accepted,
+    /// A tenant admin called the invitation off, or its code was guessed at too often.
+    Revoked,
+    /// The invitation was not accepted in time. Never stored, since it follows from the expiry
+    /// of a pending invitation.
+    Expired,
+}
+
+/// An invitation for someone to join a tenant.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct InvitationRecord {
+    /// The record id, such as `invitation:abc`. Set by the database.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    pub invitation_id: String,
+    /// The normalized phone number or email address the invitation was sent to. It becomes the
+    /// username of the invited user.
+    pub identifier: String,
+    /// The registered client the invitation is accepted through.
+    pub client_id: String,
+    /// The roles the invited user is granted.
+    #[serde(default)]
+    pub roles: Vec<String>,
+    /// The attributes the invited user is created with.
+    #[serde(default)]
+    pub attributes: Map<String, Value>,
+    /// Where the invite link points, if a link was sent rather than a bare code.
+    #[serde(default)]
+    pub redirect_uri: Option<String>,
+    pub status: InvitationStatus,
+    /// A SHA-256 hash of the code sent, as hex.
+    pub code_hash: String,
+    /// How many wrong codes were tried.
+    #[serde(default)]
+    pub attempts: u32,
+    /// How long, in seconds, the invitation lasts once sent.
+    pub expires_in: i64,
+    pub expires_at: String,
+    pub sent_at: String,
+    pub created_at: String,
+    #[serde(default)]
+    pub accepted_at: Option<String>,
+    #[serde(default)]
+    pub revoked_at: Option<String>,
+}
+
+impl InvitationRecord {
+    /// Returns the status of the invitation, which is expired once a pending invitation outlives
+    /// its expiry.
+    pub fn current_status(&self) -> InvitationStatus {
+        match self.status {
+            InvitationStatus::Pending if self.expires_at <= Utc::now().to_rfc3339() => {
+                InvitationStatus::Expired
+            }
+            status => status,
+        }
+    }
+}
+
+/// Stores invitations in the namespace of a single tenant.
+pub struct InvitationRepository<'a> {
+    db: &'a Surreal,
+    tenant: Ident,
+}
+
+impl<'a> InvitationRepository<'a> {
+    pub fn new(db: &'a Surreal, tenant: Ident) -> Self {
+        Self { db, tenant }
+    }
+
+    pub async fn create(
+        &self,
+        invitation: &InvitationRecord,
+    ) -> Result<InvitationRecord, RepoError> {
+        let results = self
+            .query(
+                "CREATE type::thing('invitation', $id) CONTENT $invitation",
+                &[
+                    ("id", json!(invitation.invitation_id)),
+                    ("invitation", json!(invitation)),
+                ],
+            )
+            .await?;
+
+        Ok(take_one(&results, 0)?.unwrap_or_else(|| invitation.clone()))
+    }
+
+    pub async fn find(&self, invitation_id: &str) -> Result<Option<InvitationRecord>, RepoError> {
+        let results = self
+            .query(
+                "SELECT * FROM type::thing('invitation', $id)",
+                &[("id", json!(invitation_id))],
+            )
+            .await?;
+
+        take_one(&results, 0)
+    }
+
+    /// Finds the latest pending invitation sent to an identifier, whether or not it expired.
+    pub async fn find_pending(
+        &self,
+        identifier: &str,
+    ) -> Result<Option<InvitationRecord>, RepoError> {
+        let results = self
+            .query(
+                "SELECT * FROM invitation WHERE identifier = $identifier AND status = 'pending' \
+                 ORDER BY created_at DESC LIMIT 1",
+                &[("identifier", json!(identifier))],
+            )
+            .await?;
+
+        take_one(&results, 0)
+    }
+
+    /// Returns a page of invitations, newest first, along with the number of invitations in the
+    /// listing. Without a status, every invitation is listed.
+    pub async fn list(
+        &self,
+        status: Option<InvitationStatus>,
+        limit: u32,
+        start: u32,
+    ) -> Result<(Vec<InvitationRecord>, u64), RepoError> {
+        let conditions = match status {
+            None => "true",
+            Some(InvitationStatus::Pending) => "status = 'pending' AND expires_at > $now",
+            Some(InvitationStatus::Expired) => "status = 'pending' AND expires_at <= $now",
+            Some(_) => "status = $status",
+        };
+
+        let results = self
+            .query(
+                &format!(
+                    "SELECT * FROM invitation WHERE {conditions} ORDER BY created_at DESC \
+                     LIMIT $limit START $start; \
+                     SELECT count() FROM invitation WHERE {conditions} GROUP ALL;"
+                ),
+                &[
+                    ("status", json!(status)),
+                    ("now", json!(Utc::now().to_rfc3339())),
+                    ("limit", json!(limit)),
+                    ("start", json!(start)),
+                ],
+            )
+            .await?;
+
+        let total = take_one::<Value>(&results, 1)?
+            .and_then(|r| r.get("count").and_then(|c| c.as_u64()))
+            .unwrap_or(0);
+
+        Ok((take(&results, 0)?, total))
+    }
+
+    /// Replaces an invitation with a newer state of it.
+    pub async fn update(
+        &self,
+        invitation: &InvitationRecord,
+    ) -> Result<Option<InvitationRecord>, RepoError> {
+        let mut content = json!(invitation);
+        if let Some(content) = content.as_object_mut() {
+            content.remove("id");
+        }
+        let results = self
+            .query(
+                "UPDATE type::thing('invitation', $id) CONTENT $invitation RETURN AFTER",
+                &[
+                    ("id", json!(invitation.invitation_id)),
+                    ("invitation", content),
+                ],
+            )
+            .await?;
+
+        take_one(&results, 0)
+    }
+
+    /// Counts a wrong code against a pending invitation, revoking it once `max` wrong codes were
+    /// tried. The count is raised in the database, so that concurrent guesses are all counted.
+    /// Returns the invitation as it is afterwards, or `None` if it is no longer pending.
+    pub async fn record_wrong_code(
+        &self,
+        invitation_id: &str,
+        max: u32,
+    ) -> Result<Option<InvitationRecord>, RepoError> {
+        let results = self
+            .query(
+                "UPDATE type::thing('invitation', $id) SET attempts += 1, \
+                 revoked_at = IF attempts >= $max THEN $now ELSE revoked_at END, \
+                 status = IF attempts >= $max THEN 'revoked' ELSE status END \
+                 WHERE status = 'pending' RETURN AFTER",
+                &[
+                    ("id", json!(invitation_id)),
+                    ("max", json!(max)),
+                    ("now", json!(Utc::now().to_rfc3339())),
+                ],
+            )
+            .await?;
+
+        take_one(&results, 0)
+    }
+
+    /// Deletes every invitation sent to any of the given identifiers, whatever its status.
+    pub async fn delete_by_identifiers(&self, identifiers: &[String]) -> Result<(), RepoError> {
+        self.query(
+            "DELETE invitation WHERE identifier INSIDE $identifiers",
+            &[("identifiers", json!(identifiers))],
+        )
+        .await
+        .map(|_| ())
+    }
+
+    async fn query(&self, sql: &str, vars: &[(&str, Value)]) -> Result<Vec<Value>, RepoError> {
+        self.db.query(&self.tenant, &self.tenant, sql, vars).await
+    }
+}
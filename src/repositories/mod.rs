@@ -0,0 +1,239 @@
+pub mod archive;
+pub mod clients;
+pub mod deletions;
+pub mod factors;
+pub mod groups;
+pub mod invitations;
+pub mod roles;
+pub mod tenants;
+pub mod users;
+
+use axum::http::StatusCode;
+use reqwest::Client;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::fmt;
+use thiserror::Error;
+use url::Url;
+
+const MAX_IDENT_LEN: usize = 64;
+
+#[derive(Debug, Error)]
+pub enum RepoError {
+    #[error("Invalid identifier: {0}")]
+    InvalidIdent(String),
+    #[error("Database request failed: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("{detail}")]
+    Query { status: StatusCode, detail: String },
+    #[error("Unexpected database response: {0}")]
+    Decode(#[from] serde_json::Error),
+}
+
+impl RepoError {
+    /// The status code to respond with when the error reaches a route.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            RepoError::InvalidIdent(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            RepoError::Http(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            RepoError::Query { status, .. } => *status,
+            RepoError::Decode(_) => StatusCode::BAD_GATEWAY,
+        }
+    }
+}
+
+/// A namespace, database or table name that is safe to place in a SurrealQL statement.
+///
+/// Identifiers cannot be bound as parameters, so they are restricted to ASCII letters, digits and
+/// underscores, must start with a letter and are always written escaped.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Ident(String);
+
+impl Ident {
+    pub fn parse(s: &str) -> Result<Self, RepoError> {
+        let valid = !s.is_empty()
+            && s.len() <= MAX_IDENT_LEN
+            && s.starts_with(|c: char| c.is_ascii_alphabetic())
+            && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+
+        match valid {
+            true => Ok(Self(s.to_owned())),
+            false => Err(RepoError::InvalidIdent(s.to_owned())),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for Ident {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "`{}`", self.0)
+    }
+}
+
+/// A client for SurrealDB's HTTP API.
+#[derive(Clone, Debug)]
+pub struct Surreal {
+    /// The HTTP client used to send queries.
+    pub client: Client,
+
+    /// The URL of the database.
+    pub url: String,
+
+    /// The base64 encoded `user:pass` credentials of the database.
+    pub auth: String,
+}
+
+impl Surreal {
+    /// Runs SurrealQL statements and returns the result of each statement.
+    ///
+    /// Values must never be formatted into `sql`. Reference them as `$name` and pass them in
+    /// `vars` instead. Variables are JSON encoded so that SurrealDB binds them with their
+    /// proper types, and are sent as `LET` statements in the request body rather than in the
+    /// URL, which proxies and access logs record. Their results are left out of the returned
+    /// ones, so that results are still indexed by the statements of `sql`.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `RepoError` if the request fails or any of the statements fails.
+    pub async fn query(
+        &self,
+        ns: &Ident,
+        db: &Ident,
+        sql: &str,
+        vars: &[(&str, Value)],
+    ) -> Result<Vec<Value>, RepoError> {
+        let url = Url::parse(&self.url)
+            .and_then(|url| url.join("sql"))
+            .map_err(|e| RepoError::Query {
+                status: StatusCode::INTERNAL_SERVER_ERROR,
+                detail: format!("Invalid database URL: {e}"),
+            })?;
+        let body = bind(sql, vars)?;
+
+        let resp = self
+            .client
+            .post(url)
+            .header("Accept", "application/json")
+            .header("Authorization", format!("Basic {}", self.auth))
+            .header("NS", ns.as_str())
+            .header("DB", db.as_str())
+            .body(body)
+            .send()
+            .await?;
+
+        let status = resp.status();
+        let body = resp.json::<Value>().await?;
+        if status != StatusCode::OK {
+            return Err(RepoError::Query {
+                status,
+                detail: detail_of(&body),
+            });
+        }
+
+        let mut results = Vec::new();
+        for (i, statement) in body
+            .as_array()
+            .cloned()
+            .unwrap_or_default()
+            .into_iter()
+            .enumerate()
+        {
+            if statement.get("status").and_then(|s| s.as_str()) != Some("OK") {
+                let detail = detail_of(&statement);
+                // Unique indexes and existing record ids refuse duplicates
+                let status = match detail.contains("already contains")
+                    || detail.contains("already exists")
+                {
+                    true => StatusCode::CONFLICT,
+                    false => StatusCode::BAD_REQUEST,
+                };
+                return Err(RepoError::Query { status, detail });
+            }
+            if i >= vars.len() {
+                results.push(statement.get("result").cloned().unwrap_or(Value::Null));
+            }
+        }
+
+        Ok(results)
+    }
+}
+
+/// Deserializes the records returned by the statement at `index`.
+pub fn take<T: DeserializeOwned>(results: &[Value], index: usize) -> Result<Vec<T>, RepoError> {
+    match results.get(index) {
+        Some(Value::Array(records)) => records
+            .iter()
+            .map(|r| serde_json::from_value(r.clone()).map_err(RepoError::from))
+            .collect(),
+        Some(Value::Null) | None => Ok(Vec::new()),
+        Some(record) => Ok(vec![serde_json::from_value(record.clone())?]),
+    }
+}
+
+/// Deserializes the first record returned by the statement at `index`, if any.
+pub fn take_one<T: DeserializeOwned>(
+    results: &[Value],
+    index: usize,
+) -> Result<Option<T>, RepoError> {
+    Ok(take(results, index)?.into_iter().next())
+}
+
+/// Prefixes `sql` with a `LET` statement for each variable.
+fn bind(sql: &str, vars: &[(&str, Value)]) -> Result<String, RepoError> {
+    let mut body = String::new();
+    for (name, value) in vars {
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(RepoError::InvalidIdent((*name).to_owned()));
+        }
+        body.push_str(&format!("LET ${name} = {value};\n"));
+    }
+    body.push_str(sql);
+
+    Ok(body)
+}
+
+fn detail_of(body: &Value) -> String {
+    body.get("detail")
+        .or_else(|| body.get("information"))
+        .and_then(|d| d.as_str())
+        .map(str::to_owned)
+        .unwrap_or_else(|| body.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn bind_sends_vars_as_let_statements() {
+        let body = bind(
+            "SELECT * FROM user WHERE username = $username LIMIT $limit",
+            &[
+                ("username", json!("ann\"; DELETE user; --")),
+                ("limit", json!(1)),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(
+            body,
+            "LET $username = \"ann\\\"; DELETE user; --\";\n\
+             LET $limit = 1;\n\
+             SELECT * FROM user WHERE username = $username LIMIT $limit"
+        );
+    }
+
+    #[test]
+    fn bind_refuses_names_that_are_not_identifiers() {
+        for name in ["", "a = 1; DELETE user; LET $b", "a-b"] {
+            assert!(matches!(
+                bind("RETURN 1", &[(name, json!(1))]),
+                Err(RepoError::InvalidIdent(_))
+            ));
+        }
+    }
+}
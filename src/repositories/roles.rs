@@ -0,0 +1,113 @@
+use super::{take, take_one, Ident, RepoError, Surreal};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+/// A named set of permissions that can be granted to users and groups.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RoleRecord {
+    /// The record id, such as `role:editor`. Set by the database.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    /// The name of the role, which is also the key of its record.
+    pub name: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    /// The scopes the role grants, such as `documents:write`.
+    #[serde(default)]
+    pub permissions: Vec<String>,
+    #[serde(default)]
+    pub created_at: Option<String>,
+    #[serde(default)]
+    pub updated_at: Option<String>,
+}
+
+/// Stores roles in the namespace of a single tenant.
+pub struct RoleRepository<'a> {
+    db: &'a Surreal,
+    tenant: Ident,
+}
+
+impl<'a> RoleRepository<'a> {
+    pub fn new(db: &'a Surreal, tenant: Ident) -> Self {
+        Self { db, tenant }
+    }
+
+    pub async fn create(&self, role: &RoleRecord) -> Result<RoleRecord, RepoError> {
+        let results = self
+            .query(
+                "CREATE type::thing('role', $name) CONTENT $role",
+                &[("name", json!(role.name)), ("role", json!(role))],
+            )
+            .await?;
+
+        Ok(take_one(&results, 0)?.unwrap_or_else(|| role.clone()))
+    }
+
+    pub async fn list(&self) -> Result<Vec<RoleRecord>, RepoError> {
+        let results = self.query("SELECT * FROM role ORDER BY name", &[]).await?;
+
+        take(&results, 0)
+    }
+
+    pub async fn find(&self, name: &str) -> Result<Option<RoleRecord>, RepoError> {
+        let results = self
+            .query(
+                "SELECT * FROM type::thing('role', $name)",
+                &[("name", json!(name))],
+            )
+            .await?;
+
+        take_one(&results, 0)
+    }
+
+    /// Returns the roles with the given names. Names without a role are left out.
+    pub async fn find_many(&self, names: &[String]) -> Result<Vec<RoleRecord>, RepoError> {
+        if names.is_empty() {
+            return Ok(Vec::new());
+        }
+        let results = self
+            .query(
+                "SELECT * FROM role WHERE name INSIDE $names",
+                &[("names", json!(names))],
+            )
+            .await?;
+
+        take(&results, 0)
+    }
+
+    /// Replaces a role with a newer state of it.
+    pub async fn update(&self, role: &RoleRecord) -> Result<Option<RoleRecord>, RepoError> {
+        let mut content = json!(role);
+        if let Some(content) = content.as_object_mut() {
+            content.remove("id");
+        }
+        let results = self
+            .query(
+                "UPDATE type::thing('role', $name) CONTENT $role RETURN AFTER",
+                &[("name", json!(role.name)), ("role", content)],
+            )
+            .await?;
+
+        take_one(&results, 0)
+    }
+
+    /// Deletes a role and returns the deleted record, or `None` if it does not exist.
+    pub async fn delete(&self, name: &str) -> Result<Option<RoleRecord>, RepoError> {
+        let role = match self.find(name).await? {
+            Some(role) => role,
+            None => return Ok(None),
+        };
+
+        self.query(
+            "DELETE type::thing('role', $name)",
+            &[("name", json!(name))],
+        )
+        .await?;
+
+        Ok(Some(role))
+    }
+
+    async fn query(&self, sql: &str, vars: &[(&str, Value)]) -> Result<Vec<Value>, RepoError> {
+        self.db.query(&self.tenant, &self.tenant, sql, vars).await
+    }
+}
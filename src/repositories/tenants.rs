@@ -0,0 +1,517 @@
+use super::{clients::Channel, take, take_one, Ident, RepoError, Surreal};
+use crate::config::constants::{
+    DEFAULT_ACCESS_TOKEN_TTL, DEFAULT_OTP_TTL, DEFAULT_RESEND_INTERVAL, PLATFORM_DB, PLATFORM_NS,
+};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+/// A usage counter kept for every tenant.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Metric {
+    OtpSms,
+    OtpEmail,
+    Verifications,
+    ActiveUsers,
+    TokensIssued,
+}
+
+impl Metric {
+    pub const ALL: [Metric; 5] = [
+        Metric::OtpSms,
+        Metric::OtpEmail,
+        Metric::Verifications,
+        Metric::ActiveUsers,
+        Metric::TokensIssued,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Metric::OtpSms => "otp_sms",
+            Metric::OtpEmail => "otp_email",
+            Metric::Verifications => "verifications",
+            Metric::ActiveUsers => "active_users",
+            Metric::TokensIssued => "tokens_issued",
+        }
+    }
+}
+
+/// The period over which usage is aggregated.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Period {
+    Day,
+    Month,
+}
+
+impl Period {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Period::Day => "day",
+            Period::Month => "month",
+        }
+    }
+}
+
+/// A limit on a metric over a period. Exceeding the soft limit is only reported, exceeding the
+/// hard limit rejects the request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Quota {
+    pub metric: Metric,
+    pub period: Period,
+    pub soft: Option<u64>,
+    pub hard: Option<u64>,
+}
+
+/// How users can be added to a tenant.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SignupMode {
+    #[default]
+    Open,
+    InviteOnly,
+    Closed,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Branding {
+    pub logo_url: Option<String>,
+    pub primary_color: Option<String>,
+    pub support_email: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OtpPolicy {
+    /// The longest, in seconds, any of the tenant's clients may keep a one-time code valid.
+    pub ttl: i64,
+}
+
+impl Default for OtpPolicy {
+    fn default() -> Self {
+        Self {
+            ttl: DEFAULT_OTP_TTL,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TokenLifetimes {
+    /// The longest, in seconds, any of the tenant's clients may keep an access token valid.
+    pub access_token_ttl: i64,
+}
+
+impl Default for TokenLifetimes {
+    fn default() -> Self {
+        Self {
+            access_token_ttl: DEFAULT_ACCESS_TOKEN_TTL,
+        }
+    }
+}
+
+/// The type of a profile attribute.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AttributeType {
+    String,
+    Integer,
+    Number,
+    Boolean,
+    /// A calendar date such as `2024-01-31`.
+    Date,
+    Email,
+    Phone,
+}
+
+/// A profile attribute tenants keep on their users, in addition to the username.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AttributeDefinition {
+    /// The key the attribute is stored under. A letter followed by letters, digits or
+    /// underscores.
+    pub name: String,
+    #[serde(rename = "type")]
+    pub kind: AttributeType,
+    /// Whether every user must have the attribute.
+    #[serde(default)]
+    pub required: bool,
+    /// Whether no two users may share a value of the attribute.
+    #[serde(default)]
+    pub unique: bool,
+    /// A regular expression string values must match.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pattern: Option<String>,
+    /// The smallest value of numbers, or the shortest length of strings.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min: Option<f64>,
+    /// The largest value of numbers, or the longest length of strings.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max: Option<f64>,
+    /// The only values allowed, when given.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub options: Vec<Value>,
+    /// The access token claim the attribute is copied into, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub claim: Option<String>,
+    /// Whether users may change the attribute themselves. Otherwise only tenant admins can.
+    #[serde(default)]
+    pub editable: bool,
+}
+
+/// The rules new passwords must follow.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PasswordPolicy {
+    pub min_length: usize,
+    pub max_length: usize,
+    pub require_lowercase: bool,
+    pub require_uppercase: bool,
+    pub require_digit: bool,
+    pub require_symbol: bool,
+    /// How many of the user's previous passwords cannot be used again. `0` allows reuse.
+    pub history: usize,
+    /// Whether passwords found in the breached-password corpus are refused.
+    pub reject_breached: bool,
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        Self {
+            min_length: 8,
+            max_length: 128,
+            require_lowercase: false,
+            require_uppercase: false,
+            require_digit: false,
+            require_symbol: false,
+            history: 0,
+            reject_breached: true,
+        }
+    }
+}
+
+/// How failed password logins are throttled. Failures are counted per account and per IP address.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LockoutPolicy {
+    /// How many failures lock an account. `0` never locks accounts.
+    pub max_attempts: u32,
+    /// How many failures, across accounts, lock an IP address. `0` never locks IP addresses.
+    pub max_ip_attempts: u32,
+    /// How long, in seconds, a lockout lasts.
+    pub lockout_duration: i64,
+    /// How long, in seconds, failures are remembered after the last one.
+    pub window: i64,
+    /// How long, in seconds, to wait after the first failure. The wait doubles with each further
+    /// failure. `0` turns off the backoff.
+    pub backoff_base: i64,
+    /// The longest wait, in seconds, between attempts.
+    pub backoff_max: i64,
+    /// Whether users are told when their account is locked.
+    pub notify: bool,
+}
+
+impl Default for LockoutPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            max_ip_attempts: 20,
+            lockout_duration: 900,
+            window: 900,
+            backoff_base: 1,
+            backoff_max: 60,
+            notify: true,
+        }
+    }
+}
+
+/// How users are deleted.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DeletionPolicy {
+    /// How long, in seconds, a deleted user is kept disabled before they are erased. `0` erases
+    /// them right away.
+    pub grace_period: i64,
+}
+
+impl Default for DeletionPolicy {
+    fn default() -> Self {
+        Self {
+            grace_period: 30 * 86_400,
+        }
+    }
+}
+
+/// What happens when a username that was registered but never verified is registered again.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReregisterPolicy {
+    /// The new registration takes over the account: its password replaces the old one and a
+    /// new verification code is sent. Whoever registered first loses the account, so tenants
+    /// must opt into this.
+    Replace,
+    /// The registration is refused like that of a verified username.
+    #[default]
+    Reject,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct VerificationPolicy {
+    /// Whether users must confirm their username before they can sign in with a password.
+    pub required_for_login: bool,
+    /// How long, in seconds, a user must wait before another verification code is sent.
+    pub resend_interval: i64,
+    pub reregister_unverified: ReregisterPolicy,
+}
+
+impl Default for VerificationPolicy {
+    fn default() -> Self {
+        Self {
+            required_for_login: true,
+            resend_interval: DEFAULT_RESEND_INTERVAL,
+            reregister_unverified: ReregisterPolicy::default(),
+        }
+    }
+}
+
+/// The settings a tenant controls. `version` is incremented by every update.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TenantSettings {
+    pub version: u64,
+    pub display_name: String,
+    pub branding: Branding,
+    pub otp: OtpPolicy,
+    pub tokens: TokenLifetimes,
+    pub allowed_channels: Vec<Channel>,
+    pub signup: SignupMode,
+    pub verification: VerificationPolicy,
+    pub password: PasswordPolicy,
+    pub lockout: LockoutPolicy,
+    pub deletion: DeletionPolicy,
+    /// The profile attributes users have.
+    pub attributes: Vec<AttributeDefinition>,
+}
+
+impl Default for TenantSettings {
+    fn default() -> Self {
+        Self {
+            version: 0,
+            display_name: String::new(),
+            branding: Branding::default(),
+            otp: OtpPolicy::default(),
+            tokens: TokenLifetimes::default(),
+            allowed_channels: vec![Channel::Sms, Channel::Email],
+            signup: SignupMode::default(),
+            verification: VerificationPolicy::default(),
+            password: PasswordPolicy::default(),
+            lockout: LockoutPolicy::default(),
+            deletion: DeletionPolicy::default(),
+            attributes: Vec::new(),
+        }
+    }
+}
+
+/// A tenant as registered on the platform.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TenantRecord {
+    pub name: String,
+    #[serde(default)]
+    pub settings: TenantSettings,
+    #[serde(default)]
+    pub suspended: bool,
+    #[serde(default)]
+    pub quotas: Vec<Quota>,
+    pub created_at: String,
+    pub updated_at: String,
+}
+
+/// Changes to a tenant record. Fields left as `None` are kept as they are.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct TenantPatch {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub suspended: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quotas: Option<Vec<Quota>>,
+    pub updated_at: String,
+}
+
+impl TenantPatch {
+    pub fn new() -> Self {
+        Self {
+            updated_at: Utc::now().to_rfc3339(),
+            ..Default::default()
+        }
+    }
+}
+
+/// Stores the tenant registry in the platform namespace and provisions tenant namespaces.
+pub struct TenantRepository<'a> {
+    db: &'a Surreal,
+    ns: Ident,
+    database: Ident,
+}
+
+impl<'a> TenantRepository<'a> {
+    pub fn new(db: &'a Surreal) -> Self {
+        Self {
+            db,
+            ns: Ident::parse(PLATFORM_NS).unwrap(),
+            database: Ident::parse(PLATFORM_DB).unwrap(),
+        }
+    }
+
+    /// Creates the namespace holding a tenant's data.
+    pub async fn define_namespace(&self, tenant: &Ident) -> Result<(), RepoError> {
+        self.db
+            .query(tenant, tenant, &format!("DEFINE NAMESPACE {tenant}"), &[])
+            .await
+            .map(|_| ())
+    }
+
+    /// Removes the namespace holding a tenant's data along with everything in it.
+    pub async fn remove_namespace(&self, tenant: &Ident) -> Result<(), RepoError> {
+        self.db
+            .query(tenant, tenant, &format!("REMOVE NAMESPACE {tenant}"), &[])
+            .await
+            .map(|_| ())
+    }
+
+    pub async fn create(&self, tenant: &Ident) -> Result<TenantRecord, RepoError> {
+        let now = Utc::now().to_rfc3339();
+        let record = TenantRecord {
+            name: tenant.as_str().to_owned(),
+            settings: TenantSettings {
+                version: 1,
+                display_name: tenant.as_str().to_owned(),
+                ..Default::default()
+            },
+            suspended: false,
+            quotas: Vec::new(),
+            created_at: now.clone(),
+            updated_at: now,
+        };
+
+        let results = self
+            .query(
+                "CREATE type::thing('tenant', $id) CONTENT $record",
+                &[("id", json!(tenant.as_str())), ("record", json!(record))],
+            )
+            .await?;
+
+        Ok(take_one(&results, 0)?.unwrap_or(record))
+    }
+
+    /// Returns a page of tenants ordered by creation date, along with the total number of tenants.
+    pub async fn list(
+        &self,
+        limit: u32,
+        start: u32,
+    ) -> Result<(Vec<TenantRecord>, u64), RepoError> {
+        let results = self
+            .query(
+                "SELECT * FROM tenant ORDER BY created_at LIMIT $limit START $start; \
+                 SELECT count() FROM tenant GROUP ALL;",
+                &[("limit", json!(limit)), ("start", json!(start))],
+            )
+            .await?;
+
+        let total = take_one::<Value>(&results, 1)?
+            .and_then(|r| r.get("count").and_then(|c| c.as_u64()))
+            .unwrap_or(0);
+
+        Ok((take(&results, 0)?, total))
+    }
+
+    /// Returns the names of the suspended tenants.
+    pub async fn list_suspended(&self) -> Result<Vec<String>, RepoError> {
+        let results = self
+            .query("SELECT VALUE name FROM tenant WHERE suspended = true", &[])
+            .await?;
+
+        take(&results, 0)
+    }
+
+    pub async fn find(&self, tenant: &str) -> Result<Option<TenantRecord>, RepoError> {
+        let results = self
+            .query(
+                "SELECT * FROM type::thing('tenant', $id)",
+                &[("id", json!(tenant))],
+            )
+            .await?;
+
+        take_one(&results, 0)
+    }
+
+    /// Applies a patch to a tenant and returns the updated record, or `None` if it does not exist.
+    pub async fn update(
+        &self,
+        tenant: &str,
+        patch: &TenantPatch,
+    ) -> Result<Option<TenantRecord>, RepoError> {
+        if self.find(tenant).await?.is_none() {
+            return Ok(None);
+        }
+
+        let results = self
+            .query(
+                "UPDATE type::thing('tenant', $id) MERGE $patch RETURN AFTER",
+                &[("id", json!(tenant)), ("patch", json!(patch))],
+            )
+            .await?;
+
+        take_one(&results, 0)
+    }
+
+    /// Replaces a tenant's settings if they are still at `expected_version`. Returns the updated
+    /// record, or `None` if the tenant does not exist or its settings have changed since.
+    pub async fn update_settings(
+        &self,
+        tenant: &str,
+        settings: &TenantSettings,
+        expected_version: u64,
+    ) -> Result<Option<TenantRecord>, RepoError> {
+        let results = self
+            .query(
+                "UPDATE type::thing('tenant', $id) \
+                 SET settings = $settings, updated_at = $now \
+                 WHERE (settings.version ?? 0) = $expected RETURN AFTER",
+                &[
+                    ("id", json!(tenant)),
+                    ("settings", json!(settings)),
+                    ("now", json!(Utc::now().to_rfc3339())),
+                    ("expected", json!(expected_version)),
+                ],
+            )
+            .await?;
+
+        take_one(&results, 0)
+    }
+
+    pub async fn delete(&self, tenant: &str) -> Result<(), RepoError> {
+        self.query(
+            "DELETE type::thing('tenant', $id)",
+            &[("id", json!(tenant))],
+        )
+        .await
+        .map(|_| ())
+    }
+
+    async fn query(&self, sql: &str, vars: &[(&str, Value)]) -> Result<Vec<Value>, RepoError> {
+        self.db.query(&self.ns, &self.database, sql, vars).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unverified_usernames_cannot_be_taken_over_by_default() {
+        let policy: VerificationPolicy = serde_json::from_value(json!({})).unwrap();
+
+        assert_eq!(policy.reregister_unverified, ReregisterPolicy::Reject);
+    }
+}
@@ -0,0 +1,567 @@
+use super::{archive::record_key, take, take_one, Ident, RepoError, Surreal};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+
+/// A user as stored in a tenant's namespace.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct UserRecord {
+    /// The record id, such as `user:abc`. Set by the database.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    /// The user's primary phone number or email address, normalized so that each can only be
+    /// registered once. Tokens are issued with it as their subject.
+    pub username: String,
+    /// The password as a PHC string, which records the algorithm and parameters of the hash.
+    pub password: String,
+    /// The hashes of the passwords the user had before, newest first.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub password_history: Vec<String>,
+    /// Every phone number and email address the user can sign in with, including the username.
+    #[serde(default)]
+    pub identifiers: Vec<Identifier>,
+    /// Whether the user confirmed their username.
+    #[serde(default)]
+    pub verified: bool,
+    /// When the user confirmed their username, as an RFC 3339 timestamp.
+    #[serde(default)]
+    pub verified_at: Option<String>,
+    /// Disabled users cannot sign in.
+    #[serde(default)]
+    pub disabled: bool,
+    /// Attributes tenant admins keep on the user.
+    #[serde(default)]
+    pub attributes: Map<String, Value>,
+    /// The roles the user was granted in their tenant.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub roles: Vec<String>,
+    /// The groups the user is a member of.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub groups: Vec<String>,
+    /// When the user is erased, as an RFC 3339 timestamp, if their deletion was requested.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub delete_after: Option<String>,
+    /// The receipt of the user's pending deletion.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deletion_receipt: Option<String>,
+    #[serde(default)]
+    pub created_at: Option<String>,
+    #[serde(default)]
+    pub updated_at: Option<String>,
+}
+
+/// A phone number or email address a user can sign in with.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Identifier {
+    /// The normalized phone number or email address.
+    pub value: String,
+    /// Whether this is the user's username.
+    #[serde(default)]
+    pub primary: bool,
+    #[serde(default)]
+    pub verified: bool,
+    #[serde(default)]
+    pub verified_at: Option<String>,
+}
+
+impl UserRecord {
+    /// Returns the user's identifier with the given value. Users stored before they could have
+    /// several identifiers only have their username.
+    pub fn identifier(&self, value: &str) -> Option<Identifier> {
+        self.identifiers
+            .iter()
+            .find(|i| i.value == value)
+            .cloned()
+            .or_else(|| (value == self.username).then(|| self.primary_identifier()))
+    }
+
+    /// Marks one of the user's identifiers as verified. Verifying the username verifies the user.
+    pub fn verify_identifier(&mut self, value: &str) {
+        let now = Utc::now().to_rfc3339();
+        self.backfill_identifiers();
+        for identifier in self.identifiers.iter_mut().filter(|i| i.value == value) {
+            identifier.verified = true;
+            identifier.verified_at = Some(now.clone());
+        }
+        if value == self.username {
+            self.verified = true;
+            self.verified_at = Some(now);
+        }
+    }
+
+    /// Makes one of the user's verified identifiers their username.
+    pub fn set_primary(&mut self, value: &str) {
+        self.backfill_identifiers();
+        for identifier in self.identifiers.iter_mut() {
+            identifier.primary = identifier.value == value;
+            if identifier.primary {
+                self.username = identifier.value.clone();
+                self.verified = identifier.verified;
+                self.verified_at = identifier.verified_at.clone();
+            }
+        }
+    }
+
+    /// Adds the username to the identifiers of users stored before they could have several.
+    fn backfill_identifiers(&mut self) {
+        if !self.identifiers.iter().any(|i| i.value == self.username) {
+            let primary = self.primary_identifier();
+            self.identifiers.insert(0, primary);
+        }
+    }
+
+    fn primary_identifier(&self) -> Identifier {
+        Identifier {
+            value: self.username.clone(),
+            primary: true,
+            verified: self.verified,
+            verified_at: self.verified_at.clone(),
+        }
+    }
+}
+
+/// Narrows a listing of users. Filters left out match every user.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+pub struct UserFilter {
+    /// Part of the username.
+    pub username: Option<String>,
+    pub verified: Option<bool>,
+    /// Only users created at or after this RFC 3339 timestamp.
+    pub created_after: Option<String>,
+    /// Only users created before this RFC 3339 timestamp.
+    pub created_before: Option<String>,
+    /// Only members of this group.
+    pub group: Option<String>,
+    /// Only users granted this role directly.
+    pub role: Option<String>,
+}
+
+/// Changes to a user. Fields left out are kept as they are.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct UserPatch {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub attributes: Option<Map<String, Value>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub verified: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub disabled: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub roles: Option<Vec<String>>,
+    pub updated_at: String,
+}
+
+impl UserPatch {
+    pub fn new() -> Self {
+        Self {
+            updated_at: Utc::now().to_rfc3339(),
+            ..Default::default()
+        }
+    }
+}
+
+/// Stores users in the namespace of a single tenant.
+pub struct UserRepository<'a> {
+    db: &'a Surreal,
+    tenant: Ident,
+}
+
+impl<'a> UserRepository<'a> {
+    pub fn new(db: &'a Surreal, tenant: Ident) -> Self {
+        Self { db, tenant }
+    }
+
+    /// Defines the indexes of the user table. Usernames and identifiers are unique within a
+    /// tenant.
+    pub async fn define_indexes(&self) -> Result<(), RepoError> {
+        self.query(
+            "DEFINE INDEX user_username ON TABLE user COLUMNS username UNIQUE; \
+             DEFINE INDEX user_identifier ON TABLE user COLUMNS identifiers.*.value UNIQUE;",
+            &[],
+        )
+        .await
+        .map(|_| ())
+    }
+
+    /// Defines a unique index on a profile attribute, so that no two users share a value of it.
+    pub async fn define_attribute_index(&self, name: &Ident) -> Result<(), RepoError> {
+        self.query(
+            &format!(
+                "DEFINE INDEX user_attr_{} ON TABLE user COLUMNS attributes.{name} UNIQUE",
+                name.as_str()
+            ),
+            &[],
+        )
+        .await
+        .map(|_| ())
+    }
+
+    pub async fn remove_attribute_index(&self, name: &Ident) -> Result<(), RepoError> {
+        self.query(
+            &format!("REMOVE INDEX user_attr_{} ON TABLE user", name.as_str()),
+            &[],
+        )
+        .await
+        .map(|_| ())
+    }
+
+    pub async fn create(&self, user: &UserRecord) -> Result<UserRecord, RepoError> {
+        let results = self
+            .query("CREATE user CONTENT $user", &[("user", json!(user))])
+            .await?;
+
+        Ok(take_one(&results, 0)?.unwrap_or_else(|| user.clone()))
+    }
+
+    /// Returns a page of users matching a filter, ordered by creation date, along with the
+    /// number of users matching it.
+    pub async fn list(
+        &self,
+        filter: &UserFilter,
+        limit: u32,
+        start: u32,
+    ) -> Result<(Vec<UserRecord>, u64), RepoError> {
+        let mut conditions = vec!["true"];
+        if filter.username.is_some() {
+            conditions.push("string::contains(username, $username)");
+        }
+        if filter.verified.is_some() {
+            conditions.push("verified = $verified");
+        }
+        if filter.created_after.is_some() {
+            conditions.push("created_at >= $created_after");
+        }
+        if filter.created_before.is_some() {
+            conditions.push("created_at < $created_before");
+        }
+        if filter.group.is_some() {
+            conditions.push("groups CONTAINS $group");
+        }
+        if filter.role.is_some() {
+            conditions.push("roles CONTAINS $role");
+        }
+        let conditions = conditions.join(" AND ");
+
+        let results = self
+            .query(
+                &format!(
+                    "SELECT * FROM user WHERE {conditions} ORDER BY created_at \
+                     LIMIT $limit START $start; \
+                     SELECT count() FROM user WHERE {conditions} GROUP ALL;"
+                ),
+                &[
+                    ("username", json!(filter.username)),
+                    ("verified", json!(filter.verified)),
+                    ("created_after", json!(filter.created_after)),
+                    ("created_before", json!(filter.created_before)),
+                    ("group", json!(filter.group)),
+                    ("role", json!(filter.role)),
+                    ("limit", json!(limit)),
+                    ("start", json!(start)),
+                ],
+            )
+            .await?;
+
+        let total = take_one::<Value>(&results, 1)?
+            .and_then(|r| r.get("count").and_then(|c| c.as_u64()))
+            .unwrap_or(0);
+
+        Ok((take(&results, 0)?, total))
+    }
+
+    pub async fn find(&self, id: &str) -> Result<Option<UserRecord>, RepoError> {
+        let results = self
+            .query(
+                "SELECT * FROM type::thing('user', $id)",
+                &[("id", json!(id))],
+            )
+            .await?;
+
+        take_one(&results, 0)
+    }
+
+    pub async fn find_by_username(&self, username: &str) -> Result<Option<UserRecord>, RepoError> {
+        let results = self
+            .query(
+                "SELECT * FROM user WHERE username = $username LIMIT 1",
+                &[("username", json!(username))],
+            )
+            .await?;
+
+        take_one(&results, 0)
+    }
+
+    /// Finds the user who has the given phone number or email address as their username or as
+    /// one of their identifiers.
+    pub async fn find_by_identifier(&self, value: &str) -> Result<Option<UserRecord>, RepoError> {
+        let results = self
+            .query(
+                "SELECT * FROM user WHERE username = $value OR identifiers.*.value CONTAINS $value \
+                 LIMIT 1",
+                &[("value", json!(value))],
+            )
+            .await?;
+
+        take_one(&results, 0)
+    }
+
+    /// Replaces the password and attributes of a user who has not been verified, for when their
+    /// username is registered again.
+    pub async fn replace_unverified(
+        &self,
+        username: &str,
+        hash: &str,
+        attributes: &Map<String, Value>,
+    ) -> Result<Option<UserRecord>, RepoError> {
+        let results = self
+            .query(
+                "UPDATE user SET password = $hash, attributes = $attributes, updated_at = $now \
+                 WHERE username = $username AND verified = false RETURN AFTER",
+                &[
+                    ("username", json!(username)),
+                    ("hash", json!(hash)),
+                    ("attributes", json!(attributes)),
+                    ("now", json!(Utc::now().to_rfc3339())),
+                ],
+            )
+            .await?;
+
+        take_one(&results, 0)
+    }
+
+    /// Sets a user's new password hash, keeping the hashes of earlier passwords in its history.
+    pub async fn change_password(
+        &self,
+        username: &str,
+        hash: &str,
+        history: &[String],
+    ) -> Result<(), RepoError> {
+        self.query(
+            "UPDATE user SET password = $hash, password_history = $history, updated_at = $now \
+             WHERE username = $username",
+            &[
+                ("username", json!(username)),
+                ("hash", json!(hash)),
+                ("history", json!(history)),
+                ("now", json!(Utc::now().to_rfc3339())),
+            ],
+        )
+        .await
+        .map(|_| ())
+    }
+
+    /// Replaces the stored password hash of a user, as when rehashing it with new parameters.
+    pub async fn set_password(&self, username: &str, hash: &str) -> Result<(), RepoError> {
+        self.query(
+            "UPDATE user SET password = $hash WHERE username = $username",
+            &[("username", json!(username)), ("hash", json!(hash))],
+        )
+        .await
+        .map(|_| ())
+    }
+
+    /// Stores a user's identifiers, along with the username and verified state that follow the
+    /// primary one. Returns `None` if there is no such user.
+    pub async fn save_identifiers(
+        &self,
+        user: &UserRecord,
+    ) -> Result<Option<UserRecord>, RepoError> {
+        let id = user.id.as_deref().and_then(record_key).unwrap_or_default();
+        if self.find(&id).await?.is_none() {
+            return Ok(None);
+        }
+
+        let results = self
+            .query(
+                "UPDATE type::thing('user', $id) SET username = $username, \
+                 identifiers = $identifiers, verified = $verified, verified_at = $verified_at, \
+                 updated_at = $now RETURN AFTER",
+                &[
+                    ("id", json!(id)),
+                    ("username", json!(user.username)),
+                    ("identifiers", json!(user.identifiers)),
+                    ("verified", json!(user.verified)),
+                    ("verified_at", json!(user.verified_at)),
+                    ("now", json!(Utc::now().to_rfc3339())),
+                ],
+            )
+            .await?;
+
+        take_one(&results, 0)
+    }
+
+    /// Applies a patch to a user and returns the updated record, or `None` if it does not exist.
+    pub async fn update(
+        &self,
+        id: &str,
+        patch: &UserPatch,
+    ) -> Result<Option<UserRecord>, RepoError> {
+        if self.find(id).await?.is_none() {
+            return Ok(None);
+        }
+
+        let results = self
+            .query(
+                "UPDATE type::thing('user', $id) MERGE $patch RETURN AFTER",
+                &[("id", json!(id)), ("patch", json!(patch))],
+            )
+            .await?;
+
+        take_one(&results, 0)
+    }
+
+    /// Adds a user to a group. Returns `None` if there is no such user.
+    pub async fn add_to_group(
+        &self,
+        id: &str,
+        group: &str,
+    ) -> Result<Option<UserRecord>, RepoError> {
+        if self.find(id).await?.is_none() {
+            return Ok(None);
+        }
+
+        let results = self
+            .query(
+                "UPDATE type::thing('user', $id) SET groups = array::union(groups OR [], [$group]), \
+                 updated_at = $now RETURN AFTER",
+                &[
+                    ("id", json!(id)),
+                    ("group", json!(group)),
+                    ("now", json!(Utc::now().to_rfc3339())),
+                ],
+            )
+            .await?;
+
+        take_one(&results, 0)
+    }
+
+    /// Removes a user from a group. Returns `None` if there is no such user.
+    pub async fn remove_from_group(
+        &self,
+        id: &str,
+        group: &str,
+    ) -> Result<Option<UserRecord>, RepoError> {
+        if self.find(id).await?.is_none() {
+            return Ok(None);
+        }
+
+        let results = self
+            .query(
+                "UPDATE type::thing('user', $id) SET groups -= $group, updated_at = $now \
+                 RETURN AFTER",
+                &[
+                    ("id", json!(id)),
+                    ("group", json!(group)),
+                    ("now", json!(Utc::now().to_rfc3339())),
+                ],
+            )
+            .await?;
+
+        take_one(&results, 0)
+    }
+
+    /// Removes every member of a group from it.
+    pub async fn remove_group(&self, group: &str) -> Result<(), RepoError> {
+        self.query(
+            "UPDATE user SET groups -= $group, updated_at = $now WHERE groups CONTAINS $group",
+            &[
+                ("group", json!(group)),
+                ("now", json!(Utc::now().to_rfc3339())),
+            ],
+        )
+        .await
+        .map(|_| ())
+    }
+
+    /// Takes a role away from every user granted it directly.
+    pub async fn remove_role(&self, role: &str) -> Result<(), RepoError> {
+        self.query(
+            "UPDATE user SET roles -= $role, updated_at = $now WHERE roles CONTAINS $role",
+            &[
+                ("role", json!(role)),
+                ("now", json!(Utc::now().to_rfc3339())),
+            ],
+        )
+        .await
+        .map(|_| ())
+    }
+
+    /// Disables a user and schedules them to be erased. Returns `None` if there is no such user.
+    pub async fn schedule_deletion(
+        &self,
+        id: &str,
+        delete_after: &str,
+        receipt: &str,
+    ) -> Result<Option<UserRecord>, RepoError> {
+        if self.find(id).await?.is_none() {
+            return Ok(None);
+        }
+
+        let results = self
+            .query(
+                "UPDATE type::thing('user', $id) SET disabled = true, \
+                 delete_after = $delete_after, deletion_receipt = $receipt, updated_at = $now \
+                 RETURN AFTER",
+                &[
+                    ("id", json!(id)),
+                    ("delete_after", json!(delete_after)),
+                    ("receipt", json!(receipt)),
+                    ("now", json!(Utc::now().to_rfc3339())),
+                ],
+            )
+            .await?;
+
+        take_one(&results, 0)
+    }
+
+    /// Cancels a user's pending deletion and enables them again. Returns `None` if there is no
+    /// such user.
+    pub async fn cancel_deletion(&self, id: &str) -> Result<Option<UserRecord>, RepoError> {
+        if self.find(id).await?.is_none() {
+            return Ok(None);
+        }
+
+        let results = self
+            .query(
+                "UPDATE type::thing('user', $id) SET disabled = false, delete_after = NONE, \
+                 deletion_receipt = NONE, updated_at = $now RETURN AFTER",
+                &[("id", json!(id)), ("now", json!(Utc::now().to_rfc3339()))],
+            )
+            .await?;
+
+        take_one(&results, 0)
+    }
+
+    /// Returns users whose grace period ended before `now`, at most `limit` of them.
+    pub async fn due_for_deletion(
+        &self,
+        now: &str,
+        limit: u32,
+    ) -> Result<Vec<UserRecord>, RepoError> {
+        let results = self
+            .query(
+                "SELECT * FROM user WHERE delete_after != NONE AND delete_after <= $now \
+                 LIMIT $limit",
+                &[("now", json!(now)), ("limit", json!(limit))],
+            )
+            .await?;
+
+        take(&results, 0)
+    }
+
+    /// Deletes a user and returns the deleted record, or `None` if it does not exist.
+    pub async fn delete(&self, id: &str) -> Result<Option<UserRecord>, RepoError> {
+        let user = match self.find(id).await? {
+            Some(user) => user,
+            None => return Ok(None),
+        };
+
+        self.query("DELETE type::thing('user', $id)", &[("id", json!(id))])
+            .await?;
+
+        Ok(Some(user))
+    }
+
+    async fn query(&self, sql: &str, vars: &[(&str, Value)]) -> Result<Vec<Value>, RepoError> {
+        self.db.query(&self.tenant, &self.tenant, sql, vars).await
+    }
+}
@@ -0,0 +1,114 @@
+use crate::config::env::APP_SECRET;
+use crate::services::{clients, users};
+use crate::structs::AppState;
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+    routing::get,
+    Json, Router,
+};
+use serde_json::{json, Value};
+
+pub fn create_route() -> Router<AppState> {
+    Router::new()
+        .route("/", get(list_clients).post(create_client))
+        .route(
+            "/:client_id",
+            get(get_client).patch(update_client).delete(delete_client),
+        )
+}
+
+async fn create_client(
+    headers: HeaderMap,
+    State(state): State<AppState>,
+    payload: Json<clients::ClientPayload>,
+) -> impl IntoResponse {
+    let tenant = match verify_tenant(&headers, &state).await {
+        Ok(tenant) => tenant,
+        Err(e) => return e,
+    };
+
+    let result = clients::create_client(&state.db, &tenant, &payload).await;
+    respond(result.status, result.detail)
+}
+
+async fn list_clients(headers: HeaderMap, State(state): State<AppState>) -> impl IntoResponse {
+    let tenant = match verify_tenant(&headers, &state).await {
+        Ok(tenant) => tenant,
+        Err(e) => return e,
+    };
+
+    let result = clients::list_clients(&state.db, &tenant).await;
+    respond(result.status, result.detail)
+}
+
+async fn get_client(
+    headers: HeaderMap,
+    Path(client_id): Path<String>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let tenant = match verify_tenant(&headers, &state).await {
+        Ok(tenant) => tenant,
+        Err(e) => return e,
+    };
+
+    let result = clients::get_client(&state.db, &tenant, &client_id).await;
+    respond(result.status, result.detail)
+}
+
+async fn update_client(
+    headers: HeaderMap,
+    Path(client_id): Path<String>,
+    State(state): State<AppState>,
+    payload: Json<clients::ClientPayload>,
+) -> impl IntoResponse {
+    let tenant = match verify_tenant(&headers, &state).await {
+        Ok(tenant) => tenant,
+        Err(e) => return e,
+    };
+
+    let result = clients::update_client(&state.db, &tenant, &client_id, &payload).await;
+    respond(result.status, result.detail)
+}
+
+async fn delete_client(
+    headers: HeaderMap,
+    Path(client_id): Path<String>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let tenant = match verify_tenant(&headers, &state).await {
+        Ok(tenant) => tenant,
+        Err(e) => return e,
+    };
+
+    let result = clients::delete_client(&state.db, &tenant, &client_id).await;
+    respond(result.status, result.detail)
+}
+
+async fn verify_tenant(
+    headers: &HeaderMap,
+    state: &AppState,
+) -> Result<String, (StatusCode, [(&'static str, &'static str); 1], String)> {
+    let mut redis = state.redis.lock().await;
+    let v_result =
+        users::verify_tenant_jwt(&state.db, &mut redis, headers, APP_SECRET.as_str()).await;
+    match v_result.0 {
+        StatusCode::OK => Ok(v_result.1),
+        status => Err(respond(status, json!(v_result.1))),
+    }
+}
+
+fn respond(
+    status: StatusCode,
+    detail: Value,
+) -> (StatusCode, [(&'static str, &'static str); 1], String) {
+    (
+        status,
+        [("content-type", "application/json")],
+        json!({
+            "detail": detail,
+        })
+        .to_string(),
+    )
+}
@@ -0,0 +1,177 @@
+use crate::config::env::APP_SECRET;
+use crate::services::{access, users};
+use crate::structs::AppState;
+use axum::{
+    extract::{Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+    routing::{delete, get},
+    Json, Router,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+pub fn create_route() -> Router<AppState> {
+    Router::new()
+        .route("/", get(list_groups).post(create_group))
+        .route(
+            "/:name",
+            get(get_group).patch(update_group).delete(delete_group),
+        )
+        .route("/:name/members", get(list_members).post(add_member))
+        .route("/:name/members/:user_id", delete(remove_member))
+}
+
+async fn create_group(
+    headers: HeaderMap,
+    State(state): State<AppState>,
+    Json(payload): Json<access::GroupRequest>,
+) -> impl IntoResponse {
+    let tenant = match verify_tenant(&headers, &state).await {
+        Ok(tenant) => tenant,
+        Err(e) => return e,
+    };
+
+    let result = access::create_group(&state.db, &tenant, &payload).await;
+    respond(result.status, result.detail)
+}
+
+async fn list_groups(headers: HeaderMap, State(state): State<AppState>) -> impl IntoResponse {
+    let tenant = match verify_tenant(&headers, &state).await {
+        Ok(tenant) => tenant,
+        Err(e) => return e,
+    };
+
+    let result = access::list_groups(&state.db, &tenant).await;
+    respond(result.status, result.detail)
+}
+
+async fn get_group(
+    headers: HeaderMap,
+    Path(name): Path<String>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let tenant = match verify_tenant(&headers, &state).await {
+        Ok(tenant) => tenant,
+        Err(e) => return e,
+    };
+
+    let result = access::get_group(&state.db, &tenant, &name).await;
+    respond(result.status, result.detail)
+}
+
+async fn update_group(
+    headers: HeaderMap,
+    Path(name): Path<String>,
+    State(state): State<AppState>,
+    Json(payload): Json<access::GroupUpdate>,
+) -> impl IntoResponse {
+    let tenant = match verify_tenant(&headers, &state).await {
+        Ok(tenant) => tenant,
+        Err(e) => return e,
+    };
+
+    let result = access::update_group(&state.db, &tenant, &name, &payload).await;
+    respond(result.status, result.detail)
+}
+
+async fn delete_group(
+    headers: HeaderMap,
+    Path(name): Path<String>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let tenant = match verify_tenant(&headers, &state).await {
+        Ok(tenant) => tenant,
+        Err(e) => return e,
+    };
+
+    let result = access::delete_group(&state.db, &tenant, &name).await;
+    respond(result.status, result.detail)
+}
+
+async fn list_members(
+    headers: HeaderMap,
+    Path(name): Path<String>,
+    Query(query): Query<MemberQuery>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let tenant = match verify_tenant(&headers, &state).await {
+        Ok(tenant) => tenant,
+        Err(e) => return e,
+    };
+
+    let result = access::list_members(
+        &state.db,
+        &tenant,
+        &name,
+        query.page.unwrap_or(1),
+        query.per_page.unwrap_or(20),
+    )
+    .await;
+    respond(result.status, result.detail)
+}
+
+async fn add_member(
+    headers: HeaderMap,
+    Path(name): Path<String>,
+    State(state): State<AppState>,
+    Json(payload): Json<MemberPayload>,
+) -> impl IntoResponse {
+    let tenant = match verify_tenant(&headers, &state).await {
+        Ok(tenant) => tenant,
+        Err(e) => return e,
+    };
+
+    let result = access::add_member(&state.db, &tenant, &name, &payload.user_id).await;
+    respond(result.status, result.detail)
+}
+
+async fn remove_member(
+    headers: HeaderMap,
+    Path((name, user_id)): Path<(String, String)>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let tenant = match verify_tenant(&headers, &state).await {
+        Ok(tenant) => tenant,
+        Err(e) => return e,
+    };
+
+    let result = access::remove_member(&state.db, &tenant, &name, &user_id).await;
+    respond(result.status, result.detail)
+}
+
+async fn verify_tenant(
+    headers: &HeaderMap,
+    state: &AppState,
+) -> Result<String, (StatusCode, [(&'static str, &'static str); 1], String)> {
+    let mut redis = state.redis.lock().await;
+    let v_result =
+        users::verify_tenant_jwt(&state.db, &mut redis, headers, APP_SECRET.as_str()).await;
+    match v_result.0 {
+        StatusCode::OK => Ok(v_result.1),
+        status => Err(respond(status, json!(v_result.1))),
+    }
+}
+
+fn respond(
+    status: StatusCode,
+    detail: Value,
+) -> (StatusCode, [(&'static str, &'static str); 1], String) {
+    (
+        status,
+        [("content-type", "application/json")],
+        json!({ "detail": detail }).to_string(),
+    )
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct MemberQuery {
+    pub page: Option<u32>,
+    pub per_page: Option<u32>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct MemberPayload {
+    /// The id of the user to add to the group.
+    pub user_id: String,
+}
@@ -1,16 +1,44 @@
+use crate::config::constants::DPOP;
 use crate::config::env::APP_SECRET;
-use crate::services::jwts;
+use crate::services::{clients, jwts};
 use crate::structs::AppState;
-use axum::{body::Body, http::Request, response::IntoResponse, routing::get, Router};
+use crate::utils::dpop;
+use axum::{
+    extract::{ConnectInfo, OriginalUri, State},
+    http::{HeaderMap, Method, StatusCode},
+    response::IntoResponse,
+    routing::{get, post},
+    Form, Router,
+};
+use serde::Deserialize;
 use serde_json::json;
+use std::net::SocketAddr;
 
 pub fn create_route() -> Router<AppState> {
-    Router::new().route("/", get(verify_jwt))
+    Router::new()
+        .route("/", get(verify_jwt))
+        .route("/exchange", post(exchange_token))
 }
 
-async fn verify_jwt(req: Request<Body>) -> impl IntoResponse {
-    let headers = req.headers();
-    let result = jwts::verify_jwt(headers, APP_SECRET.as_str()).await;
+async fn verify_jwt(
+    method: Method,
+    OriginalUri(uri): OriginalUri,
+    headers: HeaderMap,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let mut redis = state.redis.lock().await;
+    let result = jwts::verify_jwt(
+        &state.db,
+        &mut redis,
+        &jwts::JwtVerification {
+            headers: &headers,
+            htm: dpop::request_htm(&headers, &method, &addr),
+            htu: &dpop::request_htu(&headers, &uri, &addr),
+            secret: APP_SECRET.as_str(),
+        },
+    )
+    .await;
 
     (
         result.0,
@@ -21,3 +49,117 @@ async fn verify_jwt(req: Request<Body>) -> impl IntoResponse {
         .to_string(),
     )
 }
+
+async fn exchange_token(
+    method: Method,
+    OriginalUri(uri): OriginalUri,
+    headers: HeaderMap,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    State(state): State<AppState>,
+    Form(payload): Form<TokenExchangePayload>,
+) -> impl IntoResponse {
+    let mut redis = state.redis.lock().await;
+    let origin = headers.get("Origin").and_then(|o| o.to_str().ok());
+    let client =
+        match clients::resolve_client(&state.db, &mut redis, &payload.client_id, origin).await {
+            Ok(client) => client,
+            Err(e) => {
+                return (
+                    e.status,
+                    [
+                        ("content-type", "application/json"),
+                        ("cache-control", "no-store"),
+                    ],
+                    json!({
+                        "error": "invalid_client",
+                        "error_description": e.detail,
+                    })
+                    .to_string(),
+                )
+            }
+        };
+    let dpop_jkt = match headers.get(DPOP).and_then(|p| p.to_str().ok()) {
+        Some(proof) => {
+            let htu = dpop::request_htu(&headers, &uri, &addr);
+            match jwts::check_dpop_proof(
+                &mut redis,
+                &client.tenant,
+                proof,
+                method.as_str(),
+                &htu,
+                None,
+            )
+            .await
+            {
+                Ok(proof) => Some(proof.jkt),
+                Err(e) => {
+                    return (
+                        StatusCode::BAD_REQUEST,
+                        [
+                            ("content-type", "application/json"),
+                            ("cache-control", "no-store"),
+                        ],
+                        json!({
+                            "error": "invalid_dpop_proof",
+                            "error_description": e.to_string(),
+                        })
+                        .to_string(),
+                    )
+                }
+            }
+        }
+        None => None,
+    };
+
+    let result = jwts::exchange_token(
+        &state.db,
+        &mut redis,
+        &jwts::TokenExchange {
+            client: &client,
+            grant_type: &payload.grant_type,
+            subject_token: &payload.subject_token,
+            subject_token_type: &payload.subject_token_type,
+            actor_token: payload.actor_token.as_deref(),
+            actor_token_type: payload.actor_token_type.as_deref(),
+            audience: payload.audience.as_deref(),
+            scope: payload.scope.as_deref(),
+            dpop_jkt: dpop_jkt.as_deref(),
+        },
+    )
+    .await;
+    let resp = match (result.status, result.token) {
+        (StatusCode::OK, Some(token)) => json!({
+            "access_token": token.access_token,
+            "issued_token_type": jwts::ACCESS_TOKEN_TYPE,
+            "token_type": token.token_type,
+            "expires_in": token.expires_in,
+            "scope": token.scope,
+        }),
+        _ => json!({
+            "error": result.error,
+            "error_description": result.detail,
+        }),
+    };
+
+    (
+        result.status,
+        [
+            ("content-type", "application/json"),
+            ("cache-control", "no-store"),
+        ],
+        resp.to_string(),
+    )
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct TokenExchangePayload {
+    /// The registered client making the request.
+    pub client_id: String,
+    pub grant_type: String,
+    pub subject_token: String,
+    pub subject_token_type: String,
+    pub actor_token: Option<String>,
+    pub actor_token_type: Option<String>,
+    pub audience: Option<String>,
+    pub scope: Option<String>,
+}
@@ -1,4 +1,10 @@
+pub mod clients;
+pub mod groups;
 pub mod jwts;
 pub mod otps;
+pub mod profile;
+pub mod roles;
+pub mod sessions;
+pub mod settings;
 pub mod tenants;
 pub mod users;
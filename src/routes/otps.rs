@@ -1,32 +1,104 @@
-use crate::config::constants::BEARER;
-use crate::config::env::{APP_SECRET, SMS_HOST};
-use crate::services::otps;
+use crate::config::constants::{BEARER, DPOP};
+use crate::config::env::{self, SMS_HOST, SMTP_HOST};
+use crate::services::{clients, jwts, messages::VerificationHost, otps, sessions::SessionInfo};
 use crate::structs::AppState;
+use crate::utils::{dpop, request};
 use axum::{
-    extract::{Path, State},
-    http::StatusCode,
+    extract::{ConnectInfo, OriginalUri, Path, Query, State},
+    http::{HeaderMap, Method, StatusCode},
     response::IntoResponse,
     routing::{get, post},
     Json, Router,
 };
-use reqwest::Client;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
+use std::net::SocketAddr;
 
 pub fn create_route() -> Router<AppState> {
     Router::new()
-        .route("/:otp", get(verify_otp))
+        .route("/:challenge", get(verify_otp))
         .route("/", post(authorize_user))
 }
 
-async fn verify_otp(Path(otp): Path<String>, State(state): State<AppState>) -> impl IntoResponse {
+async fn verify_otp(
+    method: Method,
+    OriginalUri(uri): OriginalUri,
+    headers: HeaderMap,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(challenge): Path<String>,
+    Query(query): Query<VerifyOtpQuery>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
     let mut redis = state.redis.lock().await;
-    let result = otps::verify_otp(&mut redis, &otp).await;
+    let origin = headers.get("Origin").and_then(|o| o.to_str().ok());
+    let client =
+        match clients::resolve_client(&state.db, &mut redis, &query.client_id, origin).await {
+            Ok(client) => client,
+            Err(e) => {
+                return (
+                    e.status,
+                    [("content-type", "application/json")],
+                    json!({
+                        "verified": false,
+                        "detail": e.detail,
+                    })
+                    .to_string(),
+                )
+            }
+        };
+
+    // Bind the token to the client's key when it sends a DPoP proof
+    let jkt = match headers.get(DPOP).and_then(|p| p.to_str().ok()) {
+        Some(proof) => {
+            let htu = dpop::request_htu(&headers, &uri, &addr);
+            match jwts::check_dpop_proof(
+                &mut redis,
+                &client.tenant,
+                proof,
+                method.as_str(),
+                &htu,
+                None,
+            )
+            .await
+            {
+                Ok(proof) => Some(proof.jkt),
+                Err(e) => {
+                    return (
+                        StatusCode::BAD_REQUEST,
+                        [("content-type", "application/json")],
+                        json!({
+                            "verified": false,
+                            "detail": e.to_string(),
+                        })
+                        .to_string(),
+                    )
+                }
+            }
+        }
+        None => None,
+    };
+    let token_type = if jkt.is_some() { DPOP } else { BEARER };
+
+    let info = SessionInfo {
+        device: query.device.unwrap_or_default(),
+        ip: request::client_ip(&headers, &addr),
+        user_agent: request::user_agent(&headers),
+    };
+    let result = otps::verify_otp(
+        &state.db,
+        &mut redis,
+        &challenge,
+        &query.code,
+        &client,
+        jkt,
+        &info,
+    )
+    .await;
     let response = match result.status {
         StatusCode::OK => json!({
             "verified": true,
             "access_token": result.detail,
-            "token_type": BEARER.to_string(),
+            "token_type": token_type.to_string(),
         }),
         _ => json!({
             "verified": false,
@@ -44,21 +116,39 @@ async fn verify_otp(Path(otp): Path<String>, State(state): State<AppState>) -> i
 // TODO: Rate limit this route
 #[axum_macros::debug_handler]
 async fn authorize_user(
+    headers: HeaderMap,
     State(state): State<AppState>,
     payload: Json<OtpPayload>,
 ) -> impl IntoResponse {
     let mut redis = state.redis.lock().await;
+    let origin = headers.get("Origin").and_then(|o| o.to_str().ok());
+    let client =
+        match clients::resolve_client(&state.db, &mut redis, &payload.client_id, origin).await {
+            Ok(client) => client,
+            Err(e) => {
+                return (
+                    e.status,
+                    [("content-type", "application/json")],
+                    json!({ "sms_sent": false, "detail": e.detail }).to_string(),
+                )
+            }
+        };
     let result = otps::authorize_user(
         &mut redis,
         &payload.phone_number,
-        &payload.domain,
-        &SMS_HOST,
-        Client::new(),
-        &APP_SECRET,
+        &client,
+        &state.http,
+        &VerificationHost {
+            sms: &SMS_HOST,
+            smtp: &SMTP_HOST,
+            smtp_port: &env::SMTP_PORT.as_str().parse::<u16>().unwrap(),
+            smtp_pass: &env::SMTP_PASSWORD,
+            smtp_user: &env::SMTP_USERNAME,
+        },
     )
     .await;
     let resp = match result.status {
-        StatusCode::OK => json!({ "sms_sent": true }),
+        StatusCode::OK => json!({ "sms_sent": true, "challenge": result.detail }),
         _ => json!({ "sms_sent": false }),
     };
 
@@ -69,10 +159,20 @@ async fn authorize_user(
     )
 }
 
+#[derive(Clone, Debug, Deserialize)]
+pub struct VerifyOtpQuery {
+    /// The OTP sent for the challenge.
+    pub code: String,
+    /// The registered client the OTP was requested for.
+    pub client_id: String,
+    /// A name for the device, shown when listing sessions.
+    pub device: Option<String>,
+}
+
 #[derive(Clone, Debug, Deserialize)]
 pub struct OtpPayload {
     pub phone_number: String,
-    pub domain: String,
+    pub client_id: String,
 }
 
 #[derive(Debug, Serialize)]
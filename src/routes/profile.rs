@@ -0,0 +1,247 @@
+use crate::config::env::{self, APP_SECRET, SMS_HOST, SMTP_HOST};
+use crate::repositories::Surreal;
+use crate::services::{
+    clients, deletions, jwts,
+    messages::VerificationHost,
+    profiles::{self, IdentifierChange, IdentifierChangeParams, PasswordChange},
+};
+use crate::structs::AppState;
+use crate::utils::{dpop, jwt::UserClaims, redis::RedisClient, request};
+use axum::{
+    extract::{ConnectInfo, OriginalUri, State},
+    http::{HeaderMap, Method, StatusCode, Uri},
+    response::IntoResponse,
+    routing::{get, post},
+    Json, Router,
+};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::net::SocketAddr;
+
+pub fn create_route() -> Router<AppState> {
+    Router::new()
+        .route(
+            "/",
+            get(get_profile)
+                .patch(update_profile)
+                .delete(delete_account),
+        )
+        .route("/password", post(change_password))
+        .route("/identifiers", post(request_identifier_change))
+        .route("/identifiers/confirm", post(confirm_identifier_change))
+}
+
+async fn get_profile(
+    method: Method,
+    OriginalUri(uri): OriginalUri,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let mut redis = state.redis.lock().await;
+    let (claims, tenant) =
+        match authenticate(&state.db, &mut redis, &method, &uri, &headers, &addr).await {
+            Ok(authenticated) => authenticated,
+            Err(e) => return respond(e.0, json!(e.1)),
+        };
+
+    let result = profiles::get_profile(&state.db, &tenant, &claims.sub).await;
+    respond(result.status, result.detail)
+}
+
+async fn update_profile(
+    method: Method,
+    OriginalUri(uri): OriginalUri,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    State(state): State<AppState>,
+    Json(payload): Json<ProfilePayload>,
+) -> impl IntoResponse {
+    let mut redis = state.redis.lock().await;
+    let (claims, tenant) =
+        match authenticate(&state.db, &mut redis, &method, &uri, &headers, &addr).await {
+            Ok(authenticated) => authenticated,
+            Err(e) => return respond(e.0, json!(e.1)),
+        };
+
+    let result = profiles::update_profile(
+        &state.db,
+        &mut redis,
+        &tenant,
+        &claims.sub,
+        &payload.attributes,
+    )
+    .await;
+    respond(result.status, result.detail)
+}
+
+async fn delete_account(
+    method: Method,
+    OriginalUri(uri): OriginalUri,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let mut redis = state.redis.lock().await;
+    let (claims, tenant) =
+        match authenticate(&state.db, &mut redis, &method, &uri, &headers, &addr).await {
+            Ok(authenticated) => authenticated,
+            Err(e) => return respond(e.0, json!(e.1)),
+        };
+
+    let result = deletions::delete_own_account(&state.db, &mut redis, &tenant, &claims.sub).await;
+    respond(result.status, result.detail)
+}
+
+async fn change_password(
+    method: Method,
+    OriginalUri(uri): OriginalUri,
+    headers: HeaderMap,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    State(state): State<AppState>,
+    Json(payload): Json<PasswordChange>,
+) -> impl IntoResponse {
+    let mut redis = state.redis.lock().await;
+    let (claims, tenant) =
+        match authenticate(&state.db, &mut redis, &method, &uri, &headers, &addr).await {
+            Ok(authenticated) => authenticated,
+            Err(e) => return respond(e.0, json!(e.1)),
+        };
+
+    let result = profiles::change_password(
+        &state.db,
+        &mut redis,
+        &tenant,
+        &claims.sub,
+        claims.sid.as_deref(),
+        &request::client_ip(&headers, &addr),
+        &payload,
+    )
+    .await;
+    respond(result.status, result.detail)
+}
+
+async fn request_identifier_change(
+    method: Method,
+    OriginalUri(uri): OriginalUri,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    State(state): State<AppState>,
+    Json(payload): Json<IdentifierChange>,
+) -> impl IntoResponse {
+    let mut redis = state.redis.lock().await;
+    let (claims, tenant) =
+        match authenticate(&state.db, &mut redis, &method, &uri, &headers, &addr).await {
+            Ok(authenticated) => authenticated,
+            Err(e) => return respond(e.0, json!(e.1)),
+        };
+    // The code is sent on behalf of the client the token was issued to
+    let origin = headers.get("Origin").and_then(|o| o.to_str().ok());
+    let client = match clients::resolve_client(&state.db, &mut redis, &claims.aud, origin).await {
+        Ok(client) if client.tenant == tenant => client,
+        Ok(_) => {
+            return respond(
+                StatusCode::FORBIDDEN,
+                json!("Token does not belong to the client's tenant"),
+            )
+        }
+        Err(e) => return respond(e.status, e.detail),
+    };
+
+    let result = profiles::request_identifier_change(IdentifierChangeParams {
+        db: &state.db,
+        redis: &mut redis,
+        req: &state.http,
+        host: &VerificationHost {
+            sms: &SMS_HOST,
+            smtp: &SMTP_HOST,
+            smtp_port: &env::SMTP_PORT.as_str().parse::<u16>().unwrap(),
+            smtp_pass: &env::SMTP_PASSWORD,
+            smtp_user: &env::SMTP_USERNAME,
+        },
+        client: &client,
+        sub: &claims.sub,
+        change: &payload,
+    })
+    .await;
+    respond(result.status, result.detail)
+}
+
+async fn confirm_identifier_change(
+    method: Method,
+    OriginalUri(uri): OriginalUri,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    State(state): State<AppState>,
+    Json(payload): Json<ConfirmationPayload>,
+) -> impl IntoResponse {
+    let mut redis = state.redis.lock().await;
+    let (claims, tenant) =
+        match authenticate(&state.db, &mut redis, &method, &uri, &headers, &addr).await {
+            Ok(authenticated) => authenticated,
+            Err(e) => return respond(e.0, json!(e.1)),
+        };
+
+    let result = profiles::confirm_identifier_change(
+        &state.db,
+        &mut redis,
+        &tenant,
+        &claims.sub,
+        &payload.code,
+    )
+    .await;
+    respond(result.status, result.detail)
+}
+
+/// Authenticates the user and returns their claims along with the tenant they belong to.
+async fn authenticate(
+    db: &Surreal,
+    redis: &mut RedisClient,
+    method: &Method,
+    uri: &Uri,
+    headers: &HeaderMap,
+    addr: &SocketAddr,
+) -> Result<(UserClaims, String), (StatusCode, String)> {
+    let claims = jwts::authenticate(
+        db,
+        redis,
+        &jwts::JwtVerification {
+            headers,
+            htm: method.as_str(),
+            htu: &dpop::request_htu(headers, uri, addr),
+            secret: APP_SECRET.as_str(),
+        },
+    )
+    .await?;
+
+    match claims.tid.clone() {
+        Some(tenant) => Ok((claims, tenant)),
+        None => Err((
+            StatusCode::FORBIDDEN,
+            "Token does not belong to a tenant".to_string(),
+        )),
+    }
+}
+
+fn respond(
+    status: StatusCode,
+    detail: Value,
+) -> (StatusCode, [(&'static str, &'static str); 1], String) {
+    (
+        status,
+        [("content-type", "application/json")],
+        json!({ "detail": detail }).to_string(),
+    )
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ProfilePayload {
+    /// The attributes to change. Attributes set to `null` are removed.
+    pub attributes: Map<String, Value>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ConfirmationPayload {
+    /// The code sent to the new identifier.
+    pub code: String,
+}
@@ -0,0 +1,111 @@
+use crate::config::env::APP_SECRET;
+use crate::services::{access, users};
+use crate::structs::AppState;
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+    routing::get,
+    Json, Router,
+};
+use serde_json::{json, Value};
+
+pub fn create_route() -> Router<AppState> {
+    Router::new()
+        .route("/", get(list_roles).post(create_role))
+        .route(
+            "/:name",
+            get(get_role).patch(update_role).delete(delete_role),
+        )
+}
+
+async fn create_role(
+    headers: HeaderMap,
+    State(state): State<AppState>,
+    Json(payload): Json<access::RoleRequest>,
+) -> impl IntoResponse {
+    let tenant = match verify_tenant(&headers, &state).await {
+        Ok(tenant) => tenant,
+        Err(e) => return e,
+    };
+
+    let result = access::create_role(&state.db, &tenant, &payload).await;
+    respond(result.status, result.detail)
+}
+
+async fn list_roles(headers: HeaderMap, State(state): State<AppState>) -> impl IntoResponse {
+    let tenant = match verify_tenant(&headers, &state).await {
+        Ok(tenant) => tenant,
+        Err(e) => return e,
+    };
+
+    let result = access::list_roles(&state.db, &tenant).await;
+    respond(result.status, result.detail)
+}
+
+async fn get_role(
+    headers: HeaderMap,
+    Path(name): Path<String>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let tenant = match verify_tenant(&headers, &state).await {
+        Ok(tenant) => tenant,
+        Err(e) => return e,
+    };
+
+    let result = access::get_role(&state.db, &tenant, &name).await;
+    respond(result.status, result.detail)
+}
+
+async fn update_role(
+    headers: HeaderMap,
+    Path(name): Path<String>,
+    State(state): State<AppState>,
+    Json(payload): Json<access::RoleUpdate>,
+) -> impl IntoResponse {
+    let tenant = match verify_tenant(&headers, &state).await {
+        Ok(tenant) => tenant,
+        Err(e) => return e,
+    };
+
+    let result = access::update_role(&state.db, &tenant, &name, &payload).await;
+    respond(result.status, result.detail)
+}
+
+async fn delete_role(
+    headers: HeaderMap,
+    Path(name): Path<String>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let tenant = match verify_tenant(&headers, &state).await {
+        Ok(tenant) => tenant,
+        Err(e) => return e,
+    };
+
+    let result = access::delete_role(&state.db, &tenant, &name).await;
+    respond(result.status, result.detail)
+}
+
+async fn verify_tenant(
+    headers: &HeaderMap,
+    state: &AppState,
+) -> Result<String, (StatusCode, [(&'static str, &'static str); 1], String)> {
+    let mut redis = state.redis.lock().await;
+    let v_result =
+        users::verify_tenant_jwt(&state.db, &mut redis, headers, APP_SECRET.as_str()).await;
+    match v_result.0 {
+        StatusCode::OK => Ok(v_result.1),
+        status => Err(respond(status, json!(v_result.1))),
+    }
+}
+
+fn respond(
+    status: StatusCode,
+    detail: Value,
+) -> (StatusCode, [(&'static str, &'static str); 1], String) {
+    (
+        status,
+        [("content-type", "application/json")],
+        json!({ "detail": detail }).to_string(),
+    )
+}
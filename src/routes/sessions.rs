@@ -0,0 +1,353 @@
+use crate::config::constants::{BEARER, DPOP};
+use crate::config::env::{self, APP_SECRET, SMS_HOST, SMTP_HOST};
+use crate::repositories::Surreal;
+use crate::services::{
+    clients, jwts,
+    logins::{self, Login, LoginParams},
+    messages::VerificationHost,
+    sessions::{self, SessionInfo},
+    users,
+};
+use crate::structs::AppState;
+use crate::utils::{dpop, jwt::UserClaims, redis::RedisClient, request};
+use axum::{
+    extract::{ConnectInfo, OriginalUri, Path, State},
+    http::{HeaderMap, Method, StatusCode, Uri},
+    response::IntoResponse,
+    routing::{delete, get, post},
+    Json, Router,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::net::SocketAddr;
+
+pub fn create_route() -> Router<AppState> {
+    Router::new()
+        .route("/", get(list_sessions).post(login).delete(revoke_sessions))
+        .route("/challenges/:challenge", post(confirm_login))
+        .route("/:sid", delete(revoke_session))
+        .route(
+            "/users/:sub",
+            get(list_user_sessions).delete(revoke_user_sessions),
+        )
+        .route("/users/:sub/:sid", delete(revoke_user_session))
+}
+
+async fn login(
+    method: Method,
+    OriginalUri(uri): OriginalUri,
+    headers: HeaderMap,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    State(state): State<AppState>,
+    Json(payload): Json<LoginPayload>,
+) -> impl IntoResponse {
+    let mut redis = state.redis.lock().await;
+    let origin = headers.get("Origin").and_then(|o| o.to_str().ok());
+    let client =
+        match clients::resolve_client(&state.db, &mut redis, &payload.client_id, origin).await {
+            Ok(client) => client,
+            Err(e) => return unverified(e.status, e.detail),
+        };
+    let jkt = match bind_dpop(&mut redis, &client.tenant, &method, &uri, &headers, &addr).await {
+        Ok(jkt) => jkt,
+        Err(e) => return unverified(StatusCode::BAD_REQUEST, json!(e)),
+    };
+    let token_type = if jkt.is_some() { DPOP } else { BEARER };
+
+    let result = logins::login(LoginParams {
+        db: &state.db,
+        redis: &mut redis,
+        req: &state.http,
+        host: &VerificationHost {
+            sms: &SMS_HOST,
+            smtp: &SMTP_HOST,
+            smtp_port: &env::SMTP_PORT.as_str().parse::<u16>().unwrap(),
+            smtp_pass: &env::SMTP_PASSWORD,
+            smtp_user: &env::SMTP_USERNAME,
+        },
+        client: &client,
+        username: &payload.username,
+        password: &payload.password,
+        jkt,
+        info: &session_info(&headers, &addr, payload.device),
+    })
+    .await;
+
+    match result {
+        Ok(Login::Token(token)) => verified(token, token_type),
+        Ok(Login::Challenge {
+            challenge,
+            recipient,
+        }) => (
+            StatusCode::ACCEPTED,
+            [("content-type", "application/json")],
+            json!({
+                "verified": false,
+                "challenge": challenge,
+                "sent_to": recipient,
+            })
+            .to_string(),
+        ),
+        Err(e) => unverified(e.status, e.detail),
+    }
+}
+
+async fn confirm_login(
+    method: Method,
+    OriginalUri(uri): OriginalUri,
+    headers: HeaderMap,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(challenge): Path<String>,
+    State(state): State<AppState>,
+    Json(payload): Json<ChallengePayload>,
+) -> impl IntoResponse {
+    let mut redis = state.redis.lock().await;
+    let origin = headers.get("Origin").and_then(|o| o.to_str().ok());
+    let client =
+        match clients::resolve_client(&state.db, &mut redis, &payload.client_id, origin).await {
+            Ok(client) => client,
+            Err(e) => return unverified(e.status, e.detail),
+        };
+    let jkt = match bind_dpop(&mut redis, &client.tenant, &method, &uri, &headers, &addr).await {
+        Ok(jkt) => jkt,
+        Err(e) => return unverified(StatusCode::BAD_REQUEST, json!(e)),
+    };
+    let token_type = if jkt.is_some() { DPOP } else { BEARER };
+
+    let info = session_info(&headers, &addr, payload.device);
+    match logins::confirm_login(
+        &state.db,
+        &mut redis,
+        &client,
+        &challenge,
+        &payload.code,
+        jkt,
+        &info,
+    )
+    .await
+    {
+        Ok(token) => verified(token, token_type),
+        Err(e) => unverified(e.status, e.detail),
+    }
+}
+
+async fn list_sessions(
+    method: Method,
+    OriginalUri(uri): OriginalUri,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let mut redis = state.redis.lock().await;
+    let (claims, tenant) =
+        match authenticate(&state.db, &mut redis, &method, &uri, &headers, &addr).await {
+            Ok(authenticated) => authenticated,
+            Err(e) => return respond(e.0, json!(e.1)),
+        };
+
+    let result = sessions::list_sessions(&mut redis, &tenant, &claims.sub).await;
+    respond(result.status, result.detail)
+}
+
+async fn revoke_sessions(
+    method: Method,
+    OriginalUri(uri): OriginalUri,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let mut redis = state.redis.lock().await;
+    let (claims, tenant) =
+        match authenticate(&state.db, &mut redis, &method, &uri, &headers, &addr).await {
+            Ok(authenticated) => authenticated,
+            Err(e) => return respond(e.0, json!(e.1)),
+        };
+
+    let result = sessions::revoke_sessions(&mut redis, &tenant, &claims.sub).await;
+    respond(result.status, result.detail)
+}
+
+async fn revoke_session(
+    method: Method,
+    OriginalUri(uri): OriginalUri,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(sid): Path<String>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let mut redis = state.redis.lock().await;
+    let (claims, tenant) =
+        match authenticate(&state.db, &mut redis, &method, &uri, &headers, &addr).await {
+            Ok(authenticated) => authenticated,
+            Err(e) => return respond(e.0, json!(e.1)),
+        };
+
+    let result = sessions::revoke_session(&mut redis, &tenant, &claims.sub, &sid).await;
+    respond(result.status, result.detail)
+}
+
+async fn list_user_sessions(
+    headers: HeaderMap,
+    Path(sub): Path<String>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let mut redis = state.redis.lock().await;
+    let v_result =
+        users::verify_tenant_jwt(&state.db, &mut redis, &headers, APP_SECRET.as_str()).await;
+    if v_result.0 != StatusCode::OK {
+        return respond(v_result.0, json!(v_result.1));
+    }
+
+    let result = sessions::list_sessions(&mut redis, &v_result.1, &sub).await;
+    respond(result.status, result.detail)
+}
+
+async fn revoke_user_sessions(
+    headers: HeaderMap,
+    Path(sub): Path<String>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let mut redis = state.redis.lock().await;
+    let v_result =
+        users::verify_tenant_jwt(&state.db, &mut redis, &headers, APP_SECRET.as_str()).await;
+    if v_result.0 != StatusCode::OK {
+        return respond(v_result.0, json!(v_result.1));
+    }
+
+    let result = sessions::revoke_sessions(&mut redis, &v_result.1, &sub).await;
+    respond(result.status, result.detail)
+}
+
+async fn revoke_user_session(
+    headers: HeaderMap,
+    Path((sub, sid)): Path<(String, String)>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let mut redis = state.redis.lock().await;
+    let v_result =
+        users::verify_tenant_jwt(&state.db, &mut redis, &headers, APP_SECRET.as_str()).await;
+    if v_result.0 != StatusCode::OK {
+        return respond(v_result.0, json!(v_result.1));
+    }
+
+    let result = sessions::revoke_session(&mut redis, &v_result.1, &sub, &sid).await;
+    respond(result.status, result.detail)
+}
+
+/// Authenticates the user and returns their claims along with the tenant their sessions are
+/// kept under.
+async fn authenticate(
+    db: &Surreal,
+    redis: &mut RedisClient,
+    method: &Method,
+    uri: &Uri,
+    headers: &HeaderMap,
+    addr: &SocketAddr,
+) -> Result<(UserClaims, String), (StatusCode, String)> {
+    let claims = jwts::authenticate(
+        db,
+        redis,
+        &jwts::JwtVerification {
+            headers,
+            htm: method.as_str(),
+            htu: &dpop::request_htu(headers, uri, addr),
+            secret: APP_SECRET.as_str(),
+        },
+    )
+    .await?;
+
+    match claims.tid.clone() {
+        Some(tenant) => Ok((claims, tenant)),
+        None => Err((
+            StatusCode::FORBIDDEN,
+            "Token does not belong to a tenant".to_string(),
+        )),
+    }
+}
+
+/// Checks the request's DPoP proof, if it sends one, and returns the thumbprint of its key so
+/// that the token issued can be bound to it.
+async fn bind_dpop(
+    redis: &mut RedisClient,
+    tenant: &str,
+    method: &Method,
+    uri: &Uri,
+    headers: &HeaderMap,
+    addr: &SocketAddr,
+) -> Result<Option<String>, String> {
+    match headers.get(DPOP).and_then(|p| p.to_str().ok()) {
+        Some(proof) => {
+            let htu = dpop::request_htu(headers, uri, addr);
+            jwts::check_dpop_proof(redis, tenant, proof, method.as_str(), &htu, None)
+                .await
+                .map(|proof| Some(proof.jkt))
+                .map_err(|e| e.to_string())
+        }
+        None => Ok(None),
+    }
+}
+
+fn session_info(headers: &HeaderMap, addr: &SocketAddr, device: Option<String>) -> SessionInfo {
+    SessionInfo {
+        device: device.unwrap_or_default(),
+        ip: request::client_ip(headers, addr),
+        user_agent: request::user_agent(headers),
+    }
+}
+
+fn verified(
+    token: String,
+    token_type: &str,
+) -> (StatusCode, [(&'static str, &'static str); 1], String) {
+    (
+        StatusCode::OK,
+        [("content-type", "application/json")],
+        json!({
+            "verified": true,
+            "access_token": token,
+            "token_type": token_type,
+        })
+        .to_string(),
+    )
+}
+
+fn unverified(
+    status: StatusCode,
+    detail: Value,
+) -> (StatusCode, [(&'static str, &'static str); 1], String) {
+    (
+        status,
+        [("content-type", "application/json")],
+        json!({ "verified": false, "detail": detail }).to_string(),
+    )
+}
+
+fn respond(
+    status: StatusCode,
+    detail: Value,
+) -> (StatusCode, [(&'static str, &'static str); 1], String) {
+    (
+        status,
+        [("content-type", "application/json")],
+        json!({ "detail": detail }).to_string(),
+    )
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct LoginPayload {
+    /// The registered client the user signs in through.
+    pub client_id: String,
+    pub username: String,
+    pub password: String,
+    /// A name for the device, shown when listing sessions.
+    pub device: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ChallengePayload {
+    /// The registered client the login was started through.
+    pub client_id: String,
+    /// The code sent to the user's second factor.
+    pub code: String,
+    pub device: Option<String>,
+}
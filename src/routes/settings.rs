@@ -0,0 +1,67 @@
+use crate::config::env::APP_SECRET;
+use crate::services::{settings, users};
+use crate::structs::AppState;
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+    routing::get,
+    Json, Router,
+};
+use serde_json::{json, Value};
+
+pub fn create_route() -> Router<AppState> {
+    Router::new().route("/", get(get_settings).patch(update_settings))
+}
+
+async fn get_settings(headers: HeaderMap, State(state): State<AppState>) -> impl IntoResponse {
+    let tenant = match verify_tenant(&headers, &state).await {
+        Ok(tenant) => tenant,
+        Err(e) => return e,
+    };
+
+    let result = settings::get_settings(&state.db, &tenant).await;
+    respond(result.status, result.detail)
+}
+
+async fn update_settings(
+    headers: HeaderMap,
+    State(state): State<AppState>,
+    payload: Json<settings::SettingsPatch>,
+) -> impl IntoResponse {
+    let tenant = match verify_tenant(&headers, &state).await {
+        Ok(tenant) => tenant,
+        Err(e) => return e,
+    };
+
+    let mut redis = state.redis.lock().await;
+    let result = settings::update_settings(&state.db, &mut redis, &tenant, &payload).await;
+    respond(result.status, result.detail)
+}
+
+async fn verify_tenant(
+    headers: &HeaderMap,
+    state: &AppState,
+) -> Result<String, (StatusCode, [(&'static str, &'static str); 1], String)> {
+    let mut redis = state.redis.lock().await;
+    let v_result =
+        users::verify_tenant_jwt(&state.db, &mut redis, headers, APP_SECRET.as_str()).await;
+    match v_result.0 {
+        StatusCode::OK => Ok(v_result.1),
+        status => Err(respond(status, json!(v_result.1))),
+    }
+}
+
+fn respond(
+    status: StatusCode,
+    detail: Value,
+) -> (StatusCode, [(&'static str, &'static str); 1], String) {
+    (
+        status,
+        [("content-type", "application/json")],
+        json!({
+            "detail": detail,
+        })
+        .to_string(),
+    )
+}
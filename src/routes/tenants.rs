@@ -1,26 +1,263 @@
 use crate::config::env;
-use crate::services::tenants;
+use crate::repositories::tenants::Quota;
+use crate::services::{archive, settings, tenants, usage};
 use crate::structs::AppState;
-use axum::{extract::State, response::IntoResponse, routing::post, Json, Router};
-use serde_json::json;
+use axum::{
+    extract::{DefaultBodyLimit, Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+    routing::{get, post},
+    Json, Router,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+/// The largest archive accepted by the import, in bytes.
+const MAX_ARCHIVE_SIZE: usize = 64 * 1024 * 1024;
 
 pub fn create_route() -> Router<AppState> {
-    Router::new().route("/signup", post(create_tenant))
+    Router::new()
+        .route("/signup", post(create_tenant))
+        .route("/", get(list_tenants))
+        .route("/:tenant", get(get_tenant).delete(delete_tenant))
+        .route(
+            "/:tenant/settings",
+            get(get_settings).patch(update_settings),
+        )
+        .route("/:tenant/suspend", post(suspend_tenant))
+        .route("/:tenant/resume", post(resume_tenant))
+        .route("/:tenant/export", get(export_tenant))
+        .route(
+            "/:tenant/import",
+            post(import_tenant).layer(DefaultBodyLimit::max(MAX_ARCHIVE_SIZE)),
+        )
+        .route("/:tenant/usage", get(get_usage))
+        .route("/:tenant/quotas", get(get_quotas).put(set_quotas))
 }
 
 async fn create_tenant(
     State(state): State<AppState>,
     payload: Json<tenants::Tenant>,
 ) -> impl IntoResponse {
-    let result =
-        tenants::create_tenant(&state.http, &env::DB_URL, &env::DB_AUTH, &payload.name).await;
+    let result = tenants::create_tenant(&state.db, &payload.name).await;
+
+    respond(result.status, result.detail)
+}
+
+async fn list_tenants(
+    headers: HeaderMap,
+    Query(page): Query<Pagination>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let v_result = tenants::verify_platform_jwt(&headers, env::APP_SECRET.as_str()).await;
+    if v_result.0 != StatusCode::OK {
+        return respond(v_result.0, json!(v_result.1));
+    }
+
+    let result = tenants::list_tenants(
+        &state.db,
+        page.page.unwrap_or(1),
+        page.per_page.unwrap_or(20),
+    )
+    .await;
+
+    respond(result.status, result.detail)
+}
+
+async fn get_tenant(
+    headers: HeaderMap,
+    Path(tenant): Path<String>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let v_result = tenants::verify_platform_jwt(&headers, env::APP_SECRET.as_str()).await;
+    if v_result.0 != StatusCode::OK {
+        return respond(v_result.0, json!(v_result.1));
+    }
+
+    let result = tenants::get_tenant(&state.db, &tenant).await;
+
+    respond(result.status, result.detail)
+}
+
+async fn get_settings(
+    headers: HeaderMap,
+    Path(tenant): Path<String>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let v_result = tenants::verify_platform_jwt(&headers, env::APP_SECRET.as_str()).await;
+    if v_result.0 != StatusCode::OK {
+        return respond(v_result.0, json!(v_result.1));
+    }
+
+    let result = settings::get_settings(&state.db, &tenant).await;
+
+    respond(result.status, result.detail)
+}
+
+async fn update_settings(
+    headers: HeaderMap,
+    Path(tenant): Path<String>,
+    State(state): State<AppState>,
+    payload: Json<settings::SettingsPatch>,
+) -> impl IntoResponse {
+    let v_result = tenants::verify_platform_jwt(&headers, env::APP_SECRET.as_str()).await;
+    if v_result.0 != StatusCode::OK {
+        return respond(v_result.0, json!(v_result.1));
+    }
+
+    let mut redis = state.redis.lock().await;
+    let result = settings::update_settings(&state.db, &mut redis, &tenant, &payload).await;
+
+    respond(result.status, result.detail)
+}
+
+async fn suspend_tenant(
+    headers: HeaderMap,
+    Path(tenant): Path<String>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    set_suspended(headers, tenant, state, true).await
+}
+
+async fn resume_tenant(
+    headers: HeaderMap,
+    Path(tenant): Path<String>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    set_suspended(headers, tenant, state, false).await
+}
+
+async fn delete_tenant(
+    headers: HeaderMap,
+    Path(tenant): Path<String>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let v_result = tenants::verify_platform_jwt(&headers, env::APP_SECRET.as_str()).await;
+    if v_result.0 != StatusCode::OK {
+        return respond(v_result.0, json!(v_result.1));
+    }
 
+    let mut redis = state.redis.lock().await;
+    let result = tenants::delete_tenant(&state.db, &mut redis, &tenant).await;
+
+    respond(result.status, result.detail)
+}
+
+async fn export_tenant(
+    headers: HeaderMap,
+    Path(tenant): Path<String>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let v_result = tenants::verify_platform_jwt(&headers, env::APP_SECRET.as_str()).await;
+    if v_result.0 != StatusCode::OK {
+        return respond(v_result.0, json!(v_result.1));
+    }
+
+    let result = archive::export_tenant(&state.db, &tenant).await;
+
+    respond(result.status, result.detail)
+}
+
+async fn import_tenant(
+    headers: HeaderMap,
+    Path(tenant): Path<String>,
+    Query(options): Query<archive::ImportOptions>,
+    State(state): State<AppState>,
+    payload: Json<archive::TenantArchive>,
+) -> impl IntoResponse {
+    let v_result = tenants::verify_platform_jwt(&headers, env::APP_SECRET.as_str()).await;
+    if v_result.0 != StatusCode::OK {
+        return respond(v_result.0, json!(v_result.1));
+    }
+
+    let mut redis = state.redis.lock().await;
+    let result = archive::import_tenant(&state.db, &mut redis, &tenant, &payload, &options).await;
+
+    respond(result.status, result.detail)
+}
+
+async fn get_usage(
+    headers: HeaderMap,
+    Path(tenant): Path<String>,
+    Query(query): Query<usage::UsageQuery>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let v_result = tenants::verify_platform_jwt(&headers, env::APP_SECRET.as_str()).await;
+    if v_result.0 != StatusCode::OK {
+        return respond(v_result.0, json!(v_result.1));
+    }
+
+    let mut redis = state.redis.lock().await;
+    let result = usage::get_usage(&state.db, &mut redis, &tenant, &query).await;
+
+    respond(result.status, result.detail)
+}
+
+async fn get_quotas(
+    headers: HeaderMap,
+    Path(tenant): Path<String>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let v_result = tenants::verify_platform_jwt(&headers, env::APP_SECRET.as_str()).await;
+    if v_result.0 != StatusCode::OK {
+        return respond(v_result.0, json!(v_result.1));
+    }
+
+    let result = usage::get_quotas(&state.db, &tenant).await;
+
+    respond(result.status, result.detail)
+}
+
+async fn set_quotas(
+    headers: HeaderMap,
+    Path(tenant): Path<String>,
+    State(state): State<AppState>,
+    payload: Json<Vec<Quota>>,
+) -> impl IntoResponse {
+    let v_result = tenants::verify_platform_jwt(&headers, env::APP_SECRET.as_str()).await;
+    if v_result.0 != StatusCode::OK {
+        return respond(v_result.0, json!(v_result.1));
+    }
+
+    let mut redis = state.redis.lock().await;
+    let result = usage::set_quotas(&state.db, &mut redis, &tenant, &payload).await;
+
+    respond(result.status, result.detail)
+}
+
+async fn set_suspended(
+    headers: HeaderMap,
+    tenant: String,
+    state: AppState,
+    suspended: bool,
+) -> (StatusCode, [(&'static str, &'static str); 1], String) {
+    let v_result = tenants::verify_platform_jwt(&headers, env::APP_SECRET.as_str()).await;
+    if v_result.0 != StatusCode::OK {
+        return respond(v_result.0, json!(v_result.1));
+    }
+
+    let mut redis = state.redis.lock().await;
+    let result = tenants::suspend_tenant(&state.db, &mut redis, &tenant, suspended).await;
+
+    respond(result.status, result.detail)
+}
+
+fn respond(
+    status: StatusCode,
+    detail: Value,
+) -> (StatusCode, [(&'static str, &'static str); 1], String) {
     (
-        result.status,
+        status,
         [("content-type", "application/json")],
         json!({
-            "detail": result.detail,
+            "detail": detail,
         })
         .to_string(),
     )
 }
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Pagination {
+    pub page: Option<u32>,
+    pub per_page: Option<u32>,
+}
@@ -1,12 +1,60 @@
 use crate::config::env::{APP_SECRET, SMS_HOST, SMTP_HOST};
+use crate::repositories::{invitations::InvitationStatus, users::UserFilter};
 use crate::structs::AppState;
-use crate::{config::env, services::users};
-use axum::{extract::State, http::HeaderMap, response::IntoResponse, routing::post, Json, Router};
+use crate::{
+    config::env,
+    services::{
+        access, accounts, clients, deletions, imports, invitations, messages, passwords, users,
+    },
+};
+use axum::{
+    extract::{Path, Query, State},
+    http::HeaderMap,
+    response::IntoResponse,
+    routing::{delete, get, post, put},
+    Json, Router,
+};
 use reqwest::StatusCode;
-use serde_json::json;
+use serde::Deserialize;
+use serde_json::{json, Value};
 
 pub fn create_route() -> Router<AppState> {
-    Router::new().route("/", post(store_user))
+    Router::new()
+        .route("/", get(list_users).post(store_user))
+        .route("/:id", get(get_user).patch(update_user).delete(delete_user))
+        .route("/:id/disable", post(disable_user))
+        .route("/:id/enable", post(enable_user))
+        .route("/:id/logout", post(logout_user))
+        .route("/:id/unlock", post(unlock_user))
+        .route("/:id/restore", post(restore_user))
+        .route("/:id/access", get(get_user_access))
+        .route("/:id/roles", put(set_user_roles))
+        .route("/:id/identifiers", post(add_identifier))
+        .route("/:id/identifiers/:value", delete(remove_identifier))
+        .route(
+            "/:id/identifiers/:value/primary",
+            post(set_primary_identifier),
+        )
+        .route("/deletions/:receipt_id", get(get_deletion_receipt))
+        .route("/imports", post(start_import))
+        .route("/imports/:job_id", get(get_import))
+        .route(
+            "/invitations",
+            get(list_invitations).post(create_invitation),
+        )
+        .route(
+            "/invitations/:invitation_id",
+            get(get_invitation).delete(revoke_invitation),
+        )
+        .route(
+            "/invitations/:invitation_id/resend",
+            post(resend_invitation),
+        )
+        .route("/invitations/accept", post(accept_invitation))
+        .route("/verification", post(confirm_verification))
+        .route("/verification/resend", post(resend_verification))
+        .route("/password-reset", post(request_password_reset))
+        .route("/password-reset/confirm", post(reset_password))
 }
 
 async fn store_user(
@@ -15,37 +63,64 @@ async fn store_user(
     payload: Json<users::User>,
 ) -> impl IntoResponse {
     let mut redis = state.redis.lock().await;
-    let v_result = users::verify_tenant_jwt(&headers, env::APP_SECRET.as_str()).await;
-    if v_result.0 != StatusCode::OK {
-        return (
-            v_result.0,
-            [("content-type", "application/json")],
-            json!({
-                "detail": v_result.1,
-            })
-            .to_string(),
-        );
-    }
+    let origin = headers.get("Origin").and_then(|o| o.to_str().ok());
+    let app = match clients::resolve_client(&state.db, &mut redis, &payload.client_id, origin).await
+    {
+        Ok(app) => app,
+        Err(e) => {
+            return (
+                e.status,
+                [("content-type", "application/json")],
+                json!({
+                    "detail": e.detail,
+                })
+                .to_string(),
+            )
+        }
+    };
+
+    // Without a tenant token, users sign themselves up through the client
+    let by_admin = headers.contains_key("Authorization");
+    let tenant = match by_admin {
+        true => {
+            let v_result =
+                users::verify_tenant_jwt(&state.db, &mut redis, &headers, env::APP_SECRET.as_str())
+                    .await;
+            if v_result.0 != StatusCode::OK {
+                return (
+                    v_result.0,
+                    [("content-type", "application/json")],
+                    json!({
+                        "detail": v_result.1,
+                    })
+                    .to_string(),
+                );
+            }
+            v_result.1
+        }
+        false => app.tenant.clone(),
+    };
 
     let s_result = users::store_user(&mut users::StoreUserParams {
         client: &state.http,
-        db_auth: &env::DB_AUTH,
-        db_url: &env::DB_URL,
+        db: &state.db,
         user: &users::User {
             username: payload.username.clone(),
             password: payload.password.clone(),
-            client_domain: payload.client_domain.clone(),
+            client_id: payload.client_id.clone(),
+            attributes: payload.attributes.clone(),
         },
-        tenant: &v_result.1,
+        app: &app,
+        tenant: &tenant,
+        by_admin,
         redis: &mut redis,
-        v_host: &users::VerificationHost {
+        v_host: &messages::VerificationHost {
             sms: &SMS_HOST,
             smtp: &SMTP_HOST,
             smtp_port: &env::SMTP_PORT.as_str().parse::<u16>().unwrap(),
             smtp_pass: &env::SMTP_PASSWORD,
             smtp_user: &env::SMTP_USERNAME,
         },
-        app_secret: &APP_SECRET,
     })
     .await;
 
@@ -58,3 +133,612 @@ async fn store_user(
         .to_string(),
     )
 }
+
+async fn confirm_verification(
+    headers: HeaderMap,
+    State(state): State<AppState>,
+    Json(payload): Json<VerificationPayload>,
+) -> impl IntoResponse {
+    let mut redis = state.redis.lock().await;
+    let origin = headers.get("Origin").and_then(|o| o.to_str().ok());
+    let app = match clients::resolve_client(&state.db, &mut redis, &payload.client_id, origin).await
+    {
+        Ok(app) => app,
+        Err(e) => return respond(e.status, json!(e.detail)),
+    };
+
+    let result = users::confirm_verification(
+        &state.db,
+        &mut redis,
+        &app,
+        &payload.username,
+        &payload.code,
+    )
+    .await;
+    respond(result.status, json!(result.detail))
+}
+
+async fn resend_verification(
+    headers: HeaderMap,
+    State(state): State<AppState>,
+    Json(payload): Json<ResendPayload>,
+) -> impl IntoResponse {
+    let mut redis = state.redis.lock().await;
+    let origin = headers.get("Origin").and_then(|o| o.to_str().ok());
+    let app = match clients::resolve_client(&state.db, &mut redis, &payload.client_id, origin).await
+    {
+        Ok(app) => app,
+        Err(e) => return respond(e.status, json!(e.detail)),
+    };
+
+    let result = users::resend_verification(users::ResendVerificationParams {
+        db: &state.db,
+        redis: &mut redis,
+        req: &state.http,
+        host: &messages::VerificationHost {
+            sms: &SMS_HOST,
+            smtp: &SMTP_HOST,
+            smtp_port: &env::SMTP_PORT.as_str().parse::<u16>().unwrap(),
+            smtp_pass: &env::SMTP_PASSWORD,
+            smtp_user: &env::SMTP_USERNAME,
+        },
+        client: &app,
+        username: &payload.username,
+    })
+    .await;
+    respond(result.status, json!(result.detail))
+}
+
+async fn request_password_reset(
+    headers: HeaderMap,
+    State(state): State<AppState>,
+    Json(payload): Json<ResetRequestPayload>,
+) -> impl IntoResponse {
+    let origin = headers.get("Origin").and_then(|o| o.to_str().ok());
+    let app = {
+        let mut redis = state.redis.lock().await;
+        match clients::resolve_client(&state.db, &mut redis, &payload.client_id, origin).await {
+            Ok(app) => app,
+            Err(e) => return respond(e.status, json!(e.detail)),
+        }
+    };
+
+    let result = passwords::request_reset(passwords::ResetRequestParams {
+        db: &state.db,
+        redis: state.redis.clone(),
+        req: &state.http,
+        host: &messages::VerificationHost {
+            sms: &SMS_HOST,
+            smtp: &SMTP_HOST,
+            smtp_port: &env::SMTP_PORT.as_str().parse::<u16>().unwrap(),
+            smtp_pass: &env::SMTP_PASSWORD,
+            smtp_user: &env::SMTP_USERNAME,
+        },
+        client: &app,
+        username: &payload.username,
+        redirect_uri: payload.redirect_uri.as_deref(),
+    })
+    .await;
+    respond(result.status, json!(result.detail))
+}
+
+async fn reset_password(
+    headers: HeaderMap,
+    State(state): State<AppState>,
+    Json(payload): Json<ResetPayload>,
+) -> impl IntoResponse {
+    let mut redis = state.redis.lock().await;
+    let origin = headers.get("Origin").and_then(|o| o.to_str().ok());
+    let app = match clients::resolve_client(&state.db, &mut redis, &payload.client_id, origin).await
+    {
+        Ok(app) => app,
+        Err(e) => return respond(e.status, json!(e.detail)),
+    };
+
+    let result = passwords::reset_password(
+        &state.db,
+        &mut redis,
+        &app,
+        &payload.username,
+        &payload.code,
+        &payload.password,
+    )
+    .await;
+    respond(result.status, json!(result.detail))
+}
+
+async fn list_users(
+    headers: HeaderMap,
+    Query(query): Query<UserQuery>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let tenant = match verify_tenant(&headers, &state).await {
+        Ok(tenant) => tenant,
+        Err(e) => return e,
+    };
+
+    let filter = UserFilter {
+        username: query.username,
+        verified: query.verified,
+        created_after: query.created_after,
+        created_before: query.created_before,
+        group: query.group,
+        role: query.role,
+    };
+    let result = accounts::list_users(
+        &state.db,
+        &tenant,
+        &filter,
+        query.page.unwrap_or(1),
+        query.per_page.unwrap_or(20),
+    )
+    .await;
+    respond(result.status, result.detail)
+}
+
+async fn get_user(
+    headers: HeaderMap,
+    Path(id): Path<String>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let tenant = match verify_tenant(&headers, &state).await {
+        Ok(tenant) => tenant,
+        Err(e) => return e,
+    };
+
+    let result = accounts::get_user(&state.db, &tenant, &id).await;
+    respond(result.status, result.detail)
+}
+
+async fn update_user(
+    headers: HeaderMap,
+    Path(id): Path<String>,
+    State(state): State<AppState>,
+    Json(update): Json<accounts::UserUpdate>,
+) -> impl IntoResponse {
+    let tenant = match verify_tenant(&headers, &state).await {
+        Ok(tenant) => tenant,
+        Err(e) => return e,
+    };
+
+    let mut redis = state.redis.lock().await;
+    let result = accounts::update_user(&state.db, &mut redis, &tenant, &id, &update).await;
+    respond(result.status, result.detail)
+}
+
+async fn delete_user(
+    headers: HeaderMap,
+    Path(id): Path<String>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let tenant = match verify_tenant(&headers, &state).await {
+        Ok(tenant) => tenant,
+        Err(e) => return e,
+    };
+
+    let mut redis = state.redis.lock().await;
+    let result = deletions::delete_user(&state.db, &mut redis, &tenant, &id).await;
+    respond(result.status, result.detail)
+}
+
+async fn restore_user(
+    headers: HeaderMap,
+    Path(id): Path<String>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let tenant = match verify_tenant(&headers, &state).await {
+        Ok(tenant) => tenant,
+        Err(e) => return e,
+    };
+
+    let result = deletions::restore_user(&state.db, &tenant, &id).await;
+    respond(result.status, result.detail)
+}
+
+async fn get_user_access(
+    headers: HeaderMap,
+    Path(id): Path<String>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let tenant = match verify_tenant(&headers, &state).await {
+        Ok(tenant) => tenant,
+        Err(e) => return e,
+    };
+
+    let result = access::get_user_access(&state.db, &tenant, &id).await;
+    respond(result.status, result.detail)
+}
+
+async fn set_user_roles(
+    headers: HeaderMap,
+    Path(id): Path<String>,
+    State(state): State<AppState>,
+    Json(payload): Json<RolesPayload>,
+) -> impl IntoResponse {
+    let tenant = match verify_tenant(&headers, &state).await {
+        Ok(tenant) => tenant,
+        Err(e) => return e,
+    };
+
+    let result = access::set_user_roles(&state.db, &tenant, &id, &payload.roles).await;
+    respond(result.status, result.detail)
+}
+
+async fn get_deletion_receipt(
+    headers: HeaderMap,
+    Path(receipt_id): Path<String>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let tenant = match verify_tenant(&headers, &state).await {
+        Ok(tenant) => tenant,
+        Err(e) => return e,
+    };
+
+    let result = deletions::get_receipt(&state.db, &tenant, &receipt_id).await;
+    respond(result.status, result.detail)
+}
+
+async fn disable_user(
+    headers: HeaderMap,
+    Path(id): Path<String>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    set_disabled(headers, id, state, true).await
+}
+
+async fn enable_user(
+    headers: HeaderMap,
+    Path(id): Path<String>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    set_disabled(headers, id, state, false).await
+}
+
+async fn set_disabled(
+    headers: HeaderMap,
+    id: String,
+    state: AppState,
+    disabled: bool,
+) -> (StatusCode, [(&'static str, &'static str); 1], String) {
+    let tenant = match verify_tenant(&headers, &state).await {
+        Ok(tenant) => tenant,
+        Err(e) => return e,
+    };
+
+    let mut redis = state.redis.lock().await;
+    let result = accounts::set_disabled(&state.db, &mut redis, &tenant, &id, disabled).await;
+    respond(result.status, result.detail)
+}
+
+async fn logout_user(
+    headers: HeaderMap,
+    Path(id): Path<String>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let tenant = match verify_tenant(&headers, &state).await {
+        Ok(tenant) => tenant,
+        Err(e) => return e,
+    };
+
+    let mut redis = state.redis.lock().await;
+    let result = accounts::logout_user(&state.db, &mut redis, &tenant, &id).await;
+    respond(result.status, result.detail)
+}
+
+async fn unlock_user(
+    headers: HeaderMap,
+    Path(id): Path<String>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let tenant = match verify_tenant(&headers, &state).await {
+        Ok(tenant) => tenant,
+        Err(e) => return e,
+    };
+
+    let mut redis = state.redis.lock().await;
+    let result = accounts::unlock_user(&state.db, &mut redis, &tenant, &id).await;
+    respond(result.status, result.detail)
+}
+
+async fn add_identifier(
+    headers: HeaderMap,
+    Path(id): Path<String>,
+    State(state): State<AppState>,
+    Json(identifier): Json<accounts::NewIdentifier>,
+) -> impl IntoResponse {
+    let tenant = match verify_tenant(&headers, &state).await {
+        Ok(tenant) => tenant,
+        Err(e) => return e,
+    };
+
+    let result = accounts::add_identifier(&state.db, &tenant, &id, &identifier).await;
+    respond(result.status, result.detail)
+}
+
+async fn remove_identifier(
+    headers: HeaderMap,
+    Path((id, value)): Path<(String, String)>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let tenant = match verify_tenant(&headers, &state).await {
+        Ok(tenant) => tenant,
+        Err(e) => return e,
+    };
+
+    let mut redis = state.redis.lock().await;
+    let result = accounts::remove_identifier(&state.db, &mut redis, &tenant, &id, &value).await;
+    respond(result.status, result.detail)
+}
+
+async fn set_primary_identifier(
+    headers: HeaderMap,
+    Path((id, value)): Path<(String, String)>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let tenant = match verify_tenant(&headers, &state).await {
+        Ok(tenant) => tenant,
+        Err(e) => return e,
+    };
+
+    let mut redis = state.redis.lock().await;
+    let result =
+        accounts::set_primary_identifier(&state.db, &mut redis, &tenant, &id, &value).await;
+    respond(result.status, result.detail)
+}
+
+async fn start_import(
+    headers: HeaderMap,
+    State(state): State<AppState>,
+    Json(request): Json<imports::ImportRequest>,
+) -> impl IntoResponse {
+    let tenant = match verify_tenant(&headers, &state).await {
+        Ok(tenant) => tenant,
+        Err(e) => return e,
+    };
+
+    let result =
+        imports::start_import(state.db.clone(), state.redis.clone(), tenant, request).await;
+    respond(result.status, result.detail)
+}
+
+async fn get_import(
+    headers: HeaderMap,
+    Path(job_id): Path<String>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let tenant = match verify_tenant(&headers, &state).await {
+        Ok(tenant) => tenant,
+        Err(e) => return e,
+    };
+
+    let mut redis = state.redis.lock().await;
+    let result = imports::get_import(&mut redis, &tenant, &job_id).await;
+    respond(result.status, result.detail)
+}
+
+async fn create_invitation(
+    headers: HeaderMap,
+    State(state): State<AppState>,
+    Json(request): Json<invitations::InvitationRequest>,
+) -> impl IntoResponse {
+    let tenant = match verify_tenant(&headers, &state).await {
+        Ok(tenant) => tenant,
+        Err(e) => return e,
+    };
+
+    let mut redis = state.redis.lock().await;
+    let origin = headers.get("Origin").and_then(|o| o.to_str().ok());
+    let app = match clients::resolve_client(&state.db, &mut redis, &request.client_id, origin).await
+    {
+        Ok(app) => app,
+        Err(e) => return respond(e.status, json!(e.detail)),
+    };
+
+    let result = invitations::create_invitation(invitations::InvitationParams {
+        db: &state.db,
+        redis: &mut redis,
+        req: &state.http,
+        host: &messages::VerificationHost {
+            sms: &SMS_HOST,
+            smtp: &SMTP_HOST,
+            smtp_port: &env::SMTP_PORT.as_str().parse::<u16>().unwrap(),
+            smtp_pass: &env::SMTP_PASSWORD,
+            smtp_user: &env::SMTP_USERNAME,
+        },
+        tenant: &tenant,
+        client: &app,
+        request: &request,
+    })
+    .await;
+    respond(result.status, result.detail)
+}
+
+async fn list_invitations(
+    headers: HeaderMap,
+    Query(query): Query<InvitationQuery>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let tenant = match verify_tenant(&headers, &state).await {
+        Ok(tenant) => tenant,
+        Err(e) => return e,
+    };
+
+    let result = invitations::list_invitations(
+        &state.db,
+        &tenant,
+        query.status,
+        query.page.unwrap_or(1),
+        query.per_page.unwrap_or(20),
+    )
+    .await;
+    respond(result.status, result.detail)
+}
+
+async fn get_invitation(
+    headers: HeaderMap,
+    Path(invitation_id): Path<String>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let tenant = match verify_tenant(&headers, &state).await {
+        Ok(tenant) => tenant,
+        Err(e) => return e,
+    };
+
+    let result = invitations::get_invitation(&state.db, &tenant, &invitation_id).await;
+    respond(result.status, result.detail)
+}
+
+async fn revoke_invitation(
+    headers: HeaderMap,
+    Path(invitation_id): Path<String>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let tenant = match verify_tenant(&headers, &state).await {
+        Ok(tenant) => tenant,
+        Err(e) => return e,
+    };
+
+    let result = invitations::revoke_invitation(&state.db, &tenant, &invitation_id).await;
+    respond(result.status, result.detail)
+}
+
+async fn resend_invitation(
+    headers: HeaderMap,
+    Path(invitation_id): Path<String>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let tenant = match verify_tenant(&headers, &state).await {
+        Ok(tenant) => tenant,
+        Err(e) => return e,
+    };
+
+    let mut redis = state.redis.lock().await;
+    let result = invitations::resend_invitation(invitations::ResendParams {
+        db: &state.db,
+        redis: &mut redis,
+        req: &state.http,
+        host: &messages::VerificationHost {
+            sms: &SMS_HOST,
+            smtp: &SMTP_HOST,
+            smtp_port: &env::SMTP_PORT.as_str().parse::<u16>().unwrap(),
+            smtp_pass: &env::SMTP_PASSWORD,
+            smtp_user: &env::SMTP_USERNAME,
+        },
+        tenant: &tenant,
+        invitation_id: &invitation_id,
+    })
+    .await;
+    respond(result.status, result.detail)
+}
+
+async fn accept_invitation(
+    headers: HeaderMap,
+    State(state): State<AppState>,
+    Json(payload): Json<AcceptancePayload>,
+) -> impl IntoResponse {
+    let mut redis = state.redis.lock().await;
+    let origin = headers.get("Origin").and_then(|o| o.to_str().ok());
+    let app = match clients::resolve_client(&state.db, &mut redis, &payload.client_id, origin).await
+    {
+        Ok(app) => app,
+        Err(e) => return respond(e.status, json!(e.detail)),
+    };
+
+    let result =
+        invitations::accept_invitation(&state.db, &mut redis, &app, &payload.acceptance).await;
+    respond(result.status, result.detail)
+}
+
+async fn verify_tenant(
+    headers: &HeaderMap,
+    state: &AppState,
+) -> Result<String, (StatusCode, [(&'static str, &'static str); 1], String)> {
+    let mut redis = state.redis.lock().await;
+    let v_result =
+        users::verify_tenant_jwt(&state.db, &mut redis, headers, APP_SECRET.as_str()).await;
+    match v_result.0 {
+        StatusCode::OK => Ok(v_result.1),
+        status => Err(respond(status, json!(v_result.1))),
+    }
+}
+
+fn respond(
+    status: StatusCode,
+    detail: Value,
+) -> (StatusCode, [(&'static str, &'static str); 1], String) {
+    (
+        status,
+        [("content-type", "application/json")],
+        json!({ "detail": detail }).to_string(),
+    )
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct VerificationPayload {
+    /// The registered client the code was sent for.
+    pub client_id: String,
+    pub username: String,
+    pub code: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ResendPayload {
+    pub client_id: String,
+    pub username: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ResetRequestPayload {
+    pub client_id: String,
+    pub username: String,
+    /// Where the reset link should point. Without it, a bare code is sent.
+    pub redirect_uri: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ResetPayload {
+    pub client_id: String,
+    pub username: String,
+    pub code: String,
+    /// The new password.
+    pub password: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct UserQuery {
+    pub page: Option<u32>,
+    pub per_page: Option<u32>,
+    /// Part of the username.
+    pub username: Option<String>,
+    pub verified: Option<bool>,
+    /// Only users created at or after this RFC 3339 timestamp.
+    pub created_after: Option<String>,
+    /// Only users created before this RFC 3339 timestamp.
+    pub created_before: Option<String>,
+    /// Only members of this group.
+    pub group: Option<String>,
+    /// Only users granted this role directly.
+    pub role: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct InvitationQuery {
+    pub page: Option<u32>,
+    pub per_page: Option<u32>,
+    /// Only invitations with this status.
+    pub status: Option<InvitationStatus>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct AcceptancePayload {
+    /// The registered client the invitation was sent for.
+    pub client_id: String,
+    #[serde(flatten)]
+    pub acceptance: invitations::Acceptance,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct RolesPayload {
+    /// The roles to grant the user directly, replacing those granted before.
+    pub roles: Vec<String>,
+}
@@ -0,0 +1,691 @@
+use crate::config::constants::PLATFORM_SCOPE_PREFIX;
+use crate::repositories::{
+    archive::record_key,
+    groups::{GroupRecord, GroupRepository},
+    roles::{RoleRecord, RoleRepository},
+    users::{UserFilter, UserPatch, UserRecord, UserRepository},
+    Ident, RepoError, Surreal,
+};
+use crate::services::accounts;
+use crate::utils::jwt::USER_SCOPE;
+use axum::http::StatusCode;
+use chrono::Utc;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::BTreeSet;
+
+/// The most permissions a role can grant.
+const MAX_PERMISSIONS: usize = 100;
+const MAX_PERMISSION_LEN: usize = 128;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct AccessResult {
+    pub detail: Value,
+    pub status: StatusCode,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct RoleRequest {
+    /// The name of the role. Letters, digits and underscores, starting with a letter.
+    pub name: String,
+    pub description: Option<String>,
+    /// The scopes the role grants, such as `documents:write`.
+    #[serde(default)]
+    pub permissions: Vec<String>,
+}
+
+/// Changes to a role. Fields left out are kept as they are.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct RoleUpdate {
+    pub description: Option<String>,
+    /// Replaces the permissions of the role.
+    pub permissions: Option<Vec<String>>,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct GroupRequest {
+    /// The name of the group. Letters, digits and underscores, starting with a letter.
+    pub name: String,
+    pub description: Option<String>,
+    /// The roles every member of the group is granted.
+    #[serde(default)]
+    pub roles: Vec<String>,
+}
+
+/// Changes to a group. Fields left out are kept as they are.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct GroupUpdate {
+    pub description: Option<String>,
+    /// Replaces the roles of the group.
+    pub roles: Option<Vec<String>>,
+}
+
+/// What a user is granted: the permissions of their roles, including the roles of their groups,
+/// and the groups themselves.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Grants {
+    pub permissions: Vec<String>,
+    pub groups: Vec<String>,
+}
+
+impl Grants {
+    /// Returns the scopes a token issued for the user carries: the user scope, then every
+    /// permission. Reserved scopes are left out, even if a role somehow came to grant them.
+    pub fn scopes(&self) -> Vec<String> {
+        std::iter::once(USER_SCOPE.to_owned())
+            .chain(
+                self.permissions
+                    .iter()
+                    .filter(|p| !is_reserved_scope(p))
+                    .cloned(),
+            )
+            .collect()
+    }
+
+    /// Returns the scopes as the space-separated `scope` claim.
+    pub fn scope(&self) -> String {
+        self.scopes().join(" ")
+    }
+}
+
+/// Works out what a user is granted. Roles and groups that were deleted grant nothing.
+///
+/// # Errors
+///
+/// Returns a `RepoError` if the user's groups or roles cannot be looked up.
+pub async fn grants(db: &Surreal, tenant: &str, user: &UserRecord) -> Result<Grants, RepoError> {
+    if user.roles.is_empty() && user.groups.is_empty() {
+        return Ok(Grants::default());
+    }
+    let ident = Ident::parse(tenant)?;
+    let groups = GroupRepository::new(db, ident.clone())
+        .find_many(&user.groups)
+        .await?;
+
+    let mut roles: BTreeSet<String> = user.roles.iter().cloned().collect();
+    roles.extend(groups.iter().flat_map(|g| g.roles.iter().cloned()));
+    let roles = RoleRepository::new(db, ident)
+        .find_many(&roles.into_iter().collect::<Vec<_>>())
+        .await?;
+
+    let permissions: BTreeSet<String> = roles.into_iter().flat_map(|r| r.permissions).collect();
+    let groups: BTreeSet<String> = groups.into_iter().map(|g| g.name).collect();
+
+    Ok(Grants {
+        permissions: permissions.into_iter().collect(),
+        groups: groups.into_iter().collect(),
+    })
+}
+
+/// Checks that every role exists in a tenant.
+///
+/// # Errors
+///
+/// Returns an `AccessResult` naming the first role that does not exist.
+pub async fn check_roles(db: &Surreal, tenant: &str, roles: &[String]) -> Result<(), AccessResult> {
+    if let Some(name) = roles.iter().find(|r| Ident::parse(r).is_err()) {
+        return Err(unknown_role(name));
+    }
+    let found = role_repository(db, tenant)
+        .map_err(handle_repo_error)?
+        .find_many(roles)
+        .await
+        .map_err(handle_repo_error)?;
+
+    match roles.iter().find(|r| !found.iter().any(|f| &f.name == *r)) {
+        Some(name) => Err(unknown_role(name)),
+        None => Ok(()),
+    }
+}
+
+pub async fn list_roles(db: &Surreal, tenant: &str) -> AccessResult {
+    let repo = match role_repository(db, tenant) {
+        Ok(repo) => repo,
+        Err(e) => return handle_repo_error(e),
+    };
+
+    match repo.list().await {
+        Ok(roles) => AccessResult {
+            detail: json!({ "roles": roles.iter().map(present).collect::<Vec<_>>() }),
+            status: StatusCode::OK,
+        },
+        Err(e) => handle_repo_error(e),
+    }
+}
+
+pub async fn create_role(db: &Surreal, tenant: &str, request: &RoleRequest) -> AccessResult {
+    if Ident::parse(&request.name).is_err() {
+        return invalid_name("Role");
+    }
+    let permissions = match check_permissions(&request.permissions) {
+        Ok(permissions) => permissions,
+        Err(e) => return e,
+    };
+    let repo = match role_repository(db, tenant) {
+        Ok(repo) => repo,
+        Err(e) => return handle_repo_error(e),
+    };
+
+    let now = Utc::now().to_rfc3339();
+    let role = RoleRecord {
+        id: None,
+        name: request.name.clone(),
+        description: request.description.clone(),
+        permissions,
+        created_at: Some(now.clone()),
+        updated_at: Some(now),
+    };
+    match repo.create(&role).await {
+        Ok(role) => AccessResult {
+            detail: present(&role),
+            status: StatusCode::CREATED,
+        },
+        Err(e) if e.status() == StatusCode::CONFLICT => already_exists("Role"),
+        Err(e) => handle_repo_error(e),
+    }
+}
+
+pub async fn get_role(db: &Surreal, tenant: &str, name: &str) -> AccessResult {
+    let repo = match role_repository(db, tenant) {
+        Ok(repo) => repo,
+        Err(e) => return handle_repo_error(e),
+    };
+
+    match repo.find(name).await {
+        Ok(Some(role)) => found(&role),
+        Ok(None) => not_found("Role"),
+        Err(e) => handle_repo_error(e),
+    }
+}
+
+/// Updates a role. Tokens issued before the update keep the permissions they were issued with.
+pub async fn update_role(
+    db: &Surreal,
+    tenant: &str,
+    name: &str,
+    update: &RoleUpdate,
+) -> AccessResult {
+    let repo = match role_repository(db, tenant) {
+        Ok(repo) => repo,
+        Err(e) => return handle_repo_error(e),
+    };
+    let mut role = match repo.find(name).await {
+        Ok(Some(role)) => role,
+        Ok(None) => return not_found("Role"),
+        Err(e) => return handle_repo_error(e),
+    };
+    if let Some(permissions) = &update.permissions {
+        role.permissions = match check_permissions(permissions) {
+            Ok(permissions) => permissions,
+            Err(e) => return e,
+        };
+    }
+    if let Some(description) = &update.description {
+        role.description = Some(description.clone());
+    }
+    role.updated_at = Some(Utc::now().to_rfc3339());
+
+    match repo.update(&role).await {
+        Ok(Some(role)) => found(&role),
+        Ok(None) => not_found("Role"),
+        Err(e) => handle_repo_error(e),
+    }
+}
+
+/// Deletes a role, taking it away from every group and user it was granted to.
+pub async fn delete_role(db: &Surreal, tenant: &str, name: &str) -> AccessResult {
+    let ident = match Ident::parse(tenant) {
+        Ok(ident) => ident,
+        Err(e) => return handle_repo_error(e),
+    };
+    let repo = RoleRepository::new(db, ident.clone());
+    match repo.find(name).await {
+        Ok(Some(_)) => (),
+        Ok(None) => return not_found("Role"),
+        Err(e) => return handle_repo_error(e),
+    }
+
+    if let Err(e) = GroupRepository::new(db, ident.clone())
+        .remove_role(name)
+        .await
+    {
+        return handle_repo_error(e);
+    }
+    if let Err(e) = UserRepository::new(db, ident).remove_role(name).await {
+        return handle_repo_error(e);
+    }
+    match repo.delete(name).await {
+        Ok(Some(role)) => found(&role),
+        Ok(None) => not_found("Role"),
+        Err(e) => handle_repo_error(e),
+    }
+}
+
+pub async fn list_groups(db: &Surreal, tenant: &str) -> AccessResult {
+    let repo = match group_repository(db, tenant) {
+        Ok(repo) => repo,
+        Err(e) => return handle_repo_error(e),
+    };
+
+    match repo.list().await {
+        Ok(groups) => AccessResult {
+            detail: json!({ "groups": groups.iter().map(present).collect::<Vec<_>>() }),
+            status: StatusCode::OK,
+        },
+        Err(e) => handle_repo_error(e),
+    }
+}
+
+pub async fn create_group(db: &Surreal, tenant: &str, request: &GroupRequest) -> AccessResult {
+    if Ident::parse(&request.name).is_err() {
+        return invalid_name("Group");
+    }
+    let roles = dedup(&request.roles);
+    if let Err(e) = check_roles(db, tenant, &roles).await {
+        return e;
+    }
+    let repo = match group_repository(db, tenant) {
+        Ok(repo) => repo,
+        Err(e) => return handle_repo_error(e),
+    };
+
+    let now = Utc::now().to_rfc3339();
+    let group = GroupRecord {
+        id: None,
+        name: request.name.clone(),
+        description: request.description.clone(),
+        roles,
+        created_at: Some(now.clone()),
+        updated_at: Some(now),
+    };
+    match repo.create(&group).await {
+        Ok(group) => AccessResult {
+            detail: present(&group),
+            status: StatusCode::CREATED,
+        },
+        Err(e) if e.status() == StatusCode::CONFLICT => already_exists("Group"),
+        Err(e) => handle_repo_error(e),
+    }
+}
+
+pub async fn get_group(db: &Surreal, tenant: &str, name: &str) -> AccessResult {
+    let repo = match group_repository(db, tenant) {
+        Ok(repo) => repo,
+        Err(e) => return handle_repo_error(e),
+    };
+
+    match repo.find(name).await {
+        Ok(Some(group)) => found(&group),
+        Ok(None) => not_found("Group"),
+        Err(e) => handle_repo_error(e),
+    }
+}
+
+/// Updates a group. Tokens issued before the update keep the scopes they were issued with.
+pub async fn update_group(
+    db: &Surreal,
+    tenant: &str,
+    name: &str,
+    update: &GroupUpdate,
+) -> AccessResult {
+    let repo = match group_repository(db, tenant) {
+        Ok(repo) => repo,
+        Err(e) => return handle_repo_error(e),
+    };
+    let mut group = match repo.find(name).await {
+        Ok(Some(group)) => group,
+        Ok(None) => return not_found("Group"),
+        Err(e) => return handle_repo_error(e),
+    };
+    if let Some(roles) = &update.roles {
+        let roles = dedup(roles);
+        if let Err(e) = check_roles(db, tenant, &roles).await {
+            return e;
+        }
+        group.roles = roles;
+    }
+    if let Some(description) = &update.description {
+        group.description = Some(description.clone());
+    }
+    group.updated_at = Some(Utc::now().to_rfc3339());
+
+    match repo.update(&group).await {
+        Ok(Some(group)) => found(&group),
+        Ok(None) => not_found("Group"),
+        Err(e) => handle_repo_error(e),
+    }
+}
+
+/// Deletes a group, removing every member from it.
+pub async fn delete_group(db: &Surreal, tenant: &str, name: &str) -> AccessResult {
+    let ident = match Ident::parse(tenant) {
+        Ok(ident) => ident,
+        Err(e) => return handle_repo_error(e),
+    };
+    let repo = GroupRepository::new(db, ident.clone());
+    match repo.find(name).await {
+        Ok(Some(_)) => (),
+        Ok(None) => return not_found("Group"),
+        Err(e) => return handle_repo_error(e),
+    }
+
+    if let Err(e) = UserRepository::new(db, ident).remove_group(name).await {
+        return handle_repo_error(e);
+    }
+    match repo.delete(name).await {
+        Ok(Some(group)) => found(&group),
+        Ok(None) => not_found("Group"),
+        Err(e) => handle_repo_error(e),
+    }
+}
+
+/// Lists the members of a group, a page at a time.
+pub async fn list_members(
+    db: &Surreal,
+    tenant: &str,
+    group: &str,
+    page: u32,
+    per_page: u32,
+) -> AccessResult {
+    if let Err(e) = find_group(db, tenant, group).await {
+        return e;
+    }
+    let filter = UserFilter {
+        group: Some(group.to_owned()),
+        ..Default::default()
+    };
+
+    let result = accounts::list_users(db, tenant, &filter, page, per_page).await;
+    AccessResult {
+        detail: result.detail,
+        status: result.status,
+    }
+}
+
+/// Adds a user to a group and returns what the user is granted.
+pub async fn add_member(db: &Surreal, tenant: &str, group: &str, user_id: &str) -> AccessResult {
+    if let Err(e) = find_group(db, tenant, group).await {
+        return e;
+    }
+    let repo = match user_repository(db, tenant) {
+        Ok(repo) => repo,
+        Err(e) => return handle_repo_error(e),
+    };
+
+    match repo.add_to_group(user_id, group).await {
+        Ok(Some(user)) => access_of(db, tenant, &user).await,
+        Ok(None) => not_found("User"),
+        Err(e) => handle_repo_error(e),
+    }
+}
+
+/// Removes a user from a group and returns what the user is granted.
+pub async fn remove_member(db: &Surreal, tenant: &str, group: &str, user_id: &str) -> AccessResult {
+    let repo = match user_repository(db, tenant) {
+        Ok(repo) => repo,
+        Err(e) => return handle_repo_error(e),
+    };
+    let user = match repo.find(user_id).await {
+        Ok(Some(user)) => user,
+        Ok(None) => return not_found("User"),
+        Err(e) => return handle_repo_error(e),
+    };
+    if !user.groups.iter().any(|g| g == group) {
+        return AccessResult {
+            detail: json!("User is not a member of the group"),
+            status: StatusCode::NOT_FOUND,
+        };
+    }
+
+    match repo.remove_from_group(user_id, group).await {
+        Ok(Some(user)) => access_of(db, tenant, &user).await,
+        Ok(None) => not_found("User"),
+        Err(e) => handle_repo_error(e),
+    }
+}
+
+/// Returns the roles a user is granted directly, their groups and the scopes their tokens carry.
+pub async fn get_user_access(db: &Surreal, tenant: &str, user_id: &str) -> AccessResult {
+    let repo = match user_repository(db, tenant) {
+        Ok(repo) => repo,
+        Err(e) => return handle_repo_error(e),
+    };
+
+    match repo.find(user_id).await {
+        Ok(Some(user)) => access_of(db, tenant, &user).await,
+        Ok(None) => not_found("User"),
+        Err(e) => handle_repo_error(e),
+    }
+}
+
+/// Replaces the roles a user is granted directly and returns what the user is granted.
+pub async fn set_user_roles(
+    db: &Surreal,
+    tenant: &str,
+    user_id: &str,
+    roles: &[String],
+) -> AccessResult {
+    let roles = dedup(roles);
+    if let Err(e) = check_roles(db, tenant, &roles).await {
+        return e;
+    }
+    let repo = match user_repository(db, tenant) {
+        Ok(repo) => repo,
+        Err(e) => return handle_repo_error(e),
+    };
+
+    let mut patch = UserPatch::new();
+    patch.roles = Some(roles);
+    match repo.update(user_id, &patch).await {
+        Ok(Some(user)) => access_of(db, tenant, &user).await,
+        Ok(None) => not_found("User"),
+        Err(e) => handle_repo_error(e),
+    }
+}
+
+async fn access_of(db: &Surreal, tenant: &str, user: &UserRecord) -> AccessResult {
+    match grants(db, tenant, user).await {
+        Ok(grants) => AccessResult {
+            detail: json!({
+                "roles": user.roles,
+                "groups": user.groups,
+                "scopes": grants.scopes(),
+            }),
+            status: StatusCode::OK,
+        },
+        Err(e) => handle_repo_error(e),
+    }
+}
+
+async fn find_group(db: &Surreal, tenant: &str, name: &str) -> Result<GroupRecord, AccessResult> {
+    match group_repository(db, tenant)
+        .map_err(handle_repo_error)?
+        .find(name)
+        .await
+    {
+        Ok(Some(group)) => Ok(group),
+        Ok(None) => Err(not_found("Group")),
+        Err(e) => Err(handle_repo_error(e)),
+    }
+}
+
+/// Checks that permissions can be carried in a `scope` claim, as RFC 6749, section 3.3 describes
+/// scope tokens, and returns them without duplicates.
+fn check_permissions(permissions: &[String]) -> Result<Vec<String>, AccessResult> {
+    let permissions = dedup(permissions);
+    if permissions.len() > MAX_PERMISSIONS {
+        return Err(AccessResult {
+            detail: json!(format!(
+                "A role grants at most {MAX_PERMISSIONS} permissions"
+            )),
+            status: StatusCode::UNPROCESSABLE_ENTITY,
+        });
+    }
+    let invalid = permissions.iter().find(|p| {
+        p.is_empty()
+            || p.len() > MAX_PERMISSION_LEN
+            || !p
+                .chars()
+                .all(|c| c.is_ascii_graphic() && c != '"' && c != '\\')
+    });
+    if let Some(permission) = invalid {
+        return Err(AccessResult {
+            detail: json!(format!("Invalid permission: {permission}")),
+            status: StatusCode::UNPROCESSABLE_ENTITY,
+        });
+    }
+    if let Some(permission) = permissions.iter().find(|p| is_reserved_scope(p)) {
+        return Err(AccessResult {
+            detail: json!(format!("Permission is reserved: {permission}")),
+            status: StatusCode::UNPROCESSABLE_ENTITY,
+        });
+    }
+
+    Ok(permissions)
+}
+
+/// Checks whether a scope belongs to Haltion itself rather than to a tenant: the user scope every
+/// token carries, and the scopes of the platform admin API.
+fn is_reserved_scope(scope: &str) -> bool {
+    scope == USER_SCOPE || scope.starts_with(PLATFORM_SCOPE_PREFIX)
+}
+
+/// Removes repeated values, keeping the first of each.
+fn dedup(values: &[String]) -> Vec<String> {
+    let mut seen = BTreeSet::new();
+    values
+        .iter()
+        .filter(|v| seen.insert(v.as_str()))
+        .cloned()
+        .collect()
+}
+
+fn role_repository<'a>(db: &'a Surreal, tenant: &str) -> Result<RoleRepository<'a>, RepoError> {
+    Ok(RoleRepository::new(db, Ident::parse(tenant)?))
+}
+
+fn group_repository<'a>(db: &'a Surreal, tenant: &str) -> Result<GroupRepository<'a>, RepoError> {
+    Ok(GroupRepository::new(db, Ident::parse(tenant)?))
+}
+
+fn user_repository<'a>(db: &'a Surreal, tenant: &str) -> Result<UserRepository<'a>, RepoError> {
+    Ok(UserRepository::new(db, Ident::parse(tenant)?))
+}
+
+fn present<T: serde::Serialize>(record: &T) -> Value {
+    let mut record = json!(record);
+    if let Some(record) = record.as_object_mut() {
+        if let Some(id) = record
+            .get("id")
+            .and_then(Value::as_str)
+            .and_then(record_key)
+        {
+            record.insert("id".to_owned(), json!(id));
+        }
+    }
+
+    record
+}
+
+fn found<T: serde::Serialize>(record: &T) -> AccessResult {
+    AccessResult {
+        detail: present(record),
+        status: StatusCode::OK,
+    }
+}
+
+fn invalid_name(kind: &str) -> AccessResult {
+    AccessResult {
+        detail: json!(format!(
+            "{kind} names must start with a letter and hold only letters, digits and underscores"
+        )),
+        status: StatusCode::UNPROCESSABLE_ENTITY,
+    }
+}
+
+fn unknown_role(name: &str) -> AccessResult {
+    AccessResult {
+        detail: json!(format!("Unknown role: {name}")),
+        status: StatusCode::UNPROCESSABLE_ENTITY,
+    }
+}
+
+fn already_exists(kind: &str) -> AccessResult {
+    AccessResult {
+        detail: json!(format!("{kind} already exists")),
+        status: StatusCode::CONFLICT,
+    }
+}
+
+fn not_found(kind: &str) -> AccessResult {
+    AccessResult {
+        detail: json!(format!("{kind} not found")),
+        status: StatusCode::NOT_FOUND,
+    }
+}
+
+fn handle_repo_error(e: RepoError) -> AccessResult {
+    AccessResult {
+        detail: json!(e.to_string()),
+        status: e.status(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|v| v.to_string()).collect()
+    }
+
+    #[test]
+    fn check_permissions_refuses_platform_scopes() {
+        let result = check_permissions(&strings(&["documents:read", "platform:admin"]));
+
+        assert_eq!(result.unwrap_err().status, StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    #[test]
+    fn check_permissions_removes_duplicates() {
+        let result = check_permissions(&strings(&["documents:read", "documents:read", "a"]));
+
+        assert_eq!(result.unwrap(), strings(&["documents:read", "a"]));
+    }
+
+    #[test]
+    fn check_permissions_refuses_malformed_permissions() {
+        for permission in ["", "documents read", "quote\"", "back\\slash"] {
+            assert!(
+                check_permissions(&strings(&[permission])).is_err(),
+                "{permission}"
+            );
+        }
+        let long = "a".repeat(MAX_PERMISSION_LEN + 1);
+        assert!(check_permissions(&[long]).is_err());
+    }
+
+    #[test]
+    fn check_permissions_limits_the_count() {
+        let permissions: Vec<String> = (0..=MAX_PERMISSIONS).map(|i| format!("p{i}")).collect();
+
+        assert!(check_permissions(&permissions).is_err());
+    }
+
+    #[test]
+    fn check_permissions_refuses_user_scope() {
+        let result = check_permissions(&strings(&["user"]));
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn scopes_leave_out_reserved_permissions() {
+        let grants = Grants {
+            permissions: strings(&["platform:admin", "documents:read", "user"]),
+            groups: Vec::new(),
+        };
+
+        assert_eq!(grants.scope(), "user documents:read");
+    }
+}
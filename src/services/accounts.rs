@@ -0,0 +1,434 @@
+use crate::repositories::{
+    archive::record_key,
+    clients::Channel,
+    users::{Identifier, UserFilter, UserPatch, UserRecord, UserRepository},
+    Ident, RepoError, Surreal,
+};
+use crate::services::{
+    attributes::{self, AttributeFailure},
+    lockouts, messages, sessions, settings,
+};
+use crate::utils::redis::{RedisClient, TenantKeys};
+use axum::http::StatusCode;
+use lettre::Address;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct AccountResult {
+    pub detail: Value,
+    pub status: StatusCode,
+}
+
+/// Changes a tenant admin can make to a user. Fields left out are kept as they are.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct UserUpdate {
+    /// Replaces the user's attributes.
+    pub attributes: Option<Map<String, Value>>,
+    pub verified: Option<bool>,
+}
+
+/// Lists the users of a tenant, a page at a time.
+///
+/// # Arguments
+///
+/// * `db` - The database the users are stored in.
+/// * `tenant` - The tenant whose users are listed.
+/// * `filter` - Narrows the listing by username, verified status and creation date.
+/// * `page` - The page to return, starting at 1.
+/// * `per_page` - How many users a page holds, at most 100.
+pub async fn list_users(
+    db: &Surreal,
+    tenant: &str,
+    filter: &UserFilter,
+    page: u32,
+    per_page: u32,
+) -> AccountResult {
+    let page = page.max(1);
+    let per_page = per_page.clamp(1, 100);
+    let repo = match repository(db, tenant) {
+        Ok(repo) => repo,
+        Err(e) => return handle_repo_error(e),
+    };
+
+    match repo
+        .list(
+            filter,
+            per_page,
+            page.saturating_sub(1).saturating_mul(per_page),
+        )
+        .await
+    {
+        Ok((users, total)) => AccountResult {
+            detail: json!({
+                "users": users.iter().map(present).collect::<Vec<_>>(),
+                "page": page,
+                "per_page": per_page,
+                "total": total,
+            }),
+            status: StatusCode::OK,
+        },
+        Err(e) => handle_repo_error(e),
+    }
+}
+
+pub async fn get_user(db: &Surreal, tenant: &str, id: &str) -> AccountResult {
+    let repo = match repository(db, tenant) {
+        Ok(repo) => repo,
+        Err(e) => return handle_repo_error(e),
+    };
+
+    match repo.find(id).await {
+        Ok(Some(user)) => found(&user),
+        Ok(None) => not_found(),
+        Err(e) => handle_repo_error(e),
+    }
+}
+
+/// Updates a user. New attributes are checked against the tenant's attribute definitions.
+pub async fn update_user(
+    db: &Surreal,
+    redis: &mut RedisClient,
+    tenant: &str,
+    id: &str,
+    update: &UserUpdate,
+) -> AccountResult {
+    let repo = match repository(db, tenant) {
+        Ok(repo) => repo,
+        Err(e) => return handle_repo_error(e),
+    };
+    let settings = match settings::lookup(db, redis, tenant).await {
+        Ok(settings) => settings,
+        Err(e) => {
+            return AccountResult {
+                detail: json!(e.to_string()),
+                status: e.status(),
+            }
+        }
+    };
+    if let Some(attributes) = &update.attributes {
+        if let Err(failures) = attributes::validate(&settings.attributes, attributes) {
+            return invalid_attributes(&failures, StatusCode::UNPROCESSABLE_ENTITY);
+        }
+    }
+
+    let mut patch = UserPatch::new();
+    patch.attributes = update.attributes.clone();
+    patch.verified = update.verified;
+    match repo.update(id, &patch).await {
+        Ok(Some(user)) => found(&user),
+        Ok(None) => not_found(),
+        Err(e) => match attributes::taken(&settings.attributes, &e) {
+            Some(failures) => invalid_attributes(&failures, StatusCode::CONFLICT),
+            None => handle_repo_error(e),
+        },
+    }
+}
+
+/// A phone number or email address to add to a user.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct NewIdentifier {
+    pub value: String,
+    /// Whether the admin vouches for the identifier. Unverified identifiers are confirmed by the
+    /// user with a verification code, and cannot be signed in with until then.
+    #[serde(default)]
+    pub verified: bool,
+}
+
+/// Adds a phone number or email address a user can sign in with.
+pub async fn add_identifier(
+    db: &Surreal,
+    tenant: &str,
+    id: &str,
+    new: &NewIdentifier,
+) -> AccountResult {
+    let repo = match repository(db, tenant) {
+        Ok(repo) => repo,
+        Err(e) => return handle_repo_error(e),
+    };
+    let value = messages::normalize(&new.value);
+    if messages::channel_of(&value) == Channel::Email && value.parse::<Address>().is_err() {
+        return AccountResult {
+            detail: json!("Identifier must be a phone number or an email address"),
+            status: StatusCode::UNPROCESSABLE_ENTITY,
+        };
+    }
+
+    let mut user = match repo.find(id).await {
+        Ok(Some(user)) => user,
+        Ok(None) => return not_found(),
+        Err(e) => return handle_repo_error(e),
+    };
+    match repo.find_by_identifier(&value).await {
+        Ok(None) => (),
+        Ok(Some(_)) => return identifier_taken(),
+        Err(e) => return handle_repo_error(e),
+    }
+
+    user.identifiers.push(Identifier {
+        value: value.clone(),
+        primary: false,
+        verified: false,
+        verified_at: None,
+    });
+    if new.verified {
+        user.verify_identifier(&value);
+    }
+    save_identifiers(&repo, &user).await
+}
+
+/// Removes one of a user's identifiers. The username cannot be removed.
+pub async fn remove_identifier(
+    db: &Surreal,
+    redis: &mut RedisClient,
+    tenant: &str,
+    id: &str,
+    value: &str,
+) -> AccountResult {
+    let repo = match repository(db, tenant) {
+        Ok(repo) => repo,
+        Err(e) => return handle_repo_error(e),
+    };
+    let value = messages::normalize(value);
+    let mut user = match repo.find(id).await {
+        Ok(Some(user)) => user,
+        Ok(None) => return not_found(),
+        Err(e) => return handle_repo_error(e),
+    };
+    match user.identifier(&value) {
+        Some(identifier) if identifier.primary => {
+            return AccountResult {
+                detail: json!(
+                    "The username cannot be removed. Make another identifier primary first"
+                ),
+                status: StatusCode::CONFLICT,
+            }
+        }
+        Some(_) => (),
+        None => return identifier_not_found(),
+    }
+
+    user.identifiers.retain(|i| i.value != value);
+    let key = TenantKeys::new(tenant).verification(&value);
+    if let Err(e) = redis.del_key(&key).await {
+        log::error!("Failed to delete {key}: {e}");
+    }
+    save_identifiers(&repo, &user).await
+}
+
+/// Makes one of a user's verified identifiers their username. Tokens are issued for the
+/// username, so the user's sessions are revoked.
+pub async fn set_primary_identifier(
+    db: &Surreal,
+    redis: &mut RedisClient,
+    tenant: &str,
+    id: &str,
+    value: &str,
+) -> AccountResult {
+    let repo = match repository(db, tenant) {
+        Ok(repo) => repo,
+        Err(e) => return handle_repo_error(e),
+    };
+    let value = messages::normalize(value);
+    let mut user = match repo.find(id).await {
+        Ok(Some(user)) => user,
+        Ok(None) => return not_found(),
+        Err(e) => return handle_repo_error(e),
+    };
+    match user.identifier(&value) {
+        Some(identifier) if identifier.primary => return found(&user),
+        Some(identifier) if identifier.verified => (),
+        Some(_) => {
+            return AccountResult {
+                detail: json!("Only verified identifiers can be made primary"),
+                status: StatusCode::CONFLICT,
+            }
+        }
+        None => return identifier_not_found(),
+    }
+
+    let previous = user.username.clone();
+    user.set_primary(&value);
+    let result = save_identifiers(&repo, &user).await;
+    if result.status != StatusCode::OK {
+        return result;
+    }
+    let revoked = sessions::revoke_sessions(redis, tenant, &previous).await;
+    if revoked.status != StatusCode::OK {
+        return AccountResult {
+            detail: revoked.detail,
+            status: revoked.status,
+        };
+    }
+
+    result
+}
+
+/// Disables or enables a user. Disabled users cannot sign in, and their sessions are revoked.
+pub async fn set_disabled(
+    db: &Surreal,
+    redis: &mut RedisClient,
+    tenant: &str,
+    id: &str,
+    disabled: bool,
+) -> AccountResult {
+    let repo = match repository(db, tenant) {
+        Ok(repo) => repo,
+        Err(e) => return handle_repo_error(e),
+    };
+
+    if !disabled {
+        match repo.find(id).await {
+            Ok(Some(user)) if user.deletion_receipt.is_some() => {
+                return AccountResult {
+                    detail: json!("User is scheduled for deletion. Restore them instead"),
+                    status: StatusCode::CONFLICT,
+                }
+            }
+            Ok(Some(_)) => (),
+            Ok(None) => return not_found(),
+            Err(e) => return handle_repo_error(e),
+        }
+    }
+
+    let mut patch = UserPatch::new();
+    patch.disabled = Some(disabled);
+    let user = match repo.update(id, &patch).await {
+        Ok(Some(user)) => user,
+        Ok(None) => return not_found(),
+        Err(e) => return handle_repo_error(e),
+    };
+    if disabled {
+        let revoked = sessions::revoke_sessions(redis, tenant, &user.username).await;
+        if revoked.status != StatusCode::OK {
+            return AccountResult {
+                detail: revoked.detail,
+                status: revoked.status,
+            };
+        }
+    }
+
+    found(&user)
+}
+
+/// Lifts a lockout of a user, and forgets their failed logins.
+pub async fn unlock_user(
+    db: &Surreal,
+    redis: &mut RedisClient,
+    tenant: &str,
+    id: &str,
+) -> AccountResult {
+    let repo = match repository(db, tenant) {
+        Ok(repo) => repo,
+        Err(e) => return handle_repo_error(e),
+    };
+
+    let user = match repo.find(id).await {
+        Ok(Some(user)) => user,
+        Ok(None) => return not_found(),
+        Err(e) => return handle_repo_error(e),
+    };
+    if let Err(e) = lockouts::clear(redis, tenant, &user.username).await {
+        return AccountResult {
+            detail: json!(e.detail().unwrap_or("Unknown error")),
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+        };
+    }
+
+    found(&user)
+}
+
+/// Signs a user out everywhere by revoking all of their sessions.
+pub async fn logout_user(
+    db: &Surreal,
+    redis: &mut RedisClient,
+    tenant: &str,
+    id: &str,
+) -> AccountResult {
+    let repo = match repository(db, tenant) {
+        Ok(repo) => repo,
+        Err(e) => return handle_repo_error(e),
+    };
+
+    let user = match repo.find(id).await {
+        Ok(Some(user)) => user,
+        Ok(None) => return not_found(),
+        Err(e) => return handle_repo_error(e),
+    };
+    let revoked = sessions::revoke_sessions(redis, tenant, &user.username).await;
+
+    AccountResult {
+        detail: revoked.detail,
+        status: revoked.status,
+    }
+}
+
+fn repository<'a>(db: &'a Surreal, tenant: &str) -> Result<UserRepository<'a>, RepoError> {
+    Ok(UserRepository::new(db, Ident::parse(tenant)?))
+}
+
+/// Returns a user as shown to admins: without password hashes, and with the id users are
+/// addressed by in the API.
+fn present(user: &UserRecord) -> Value {
+    let mut user = json!(user);
+    if let Some(user) = user.as_object_mut() {
+        user.remove("password");
+        user.remove("password_history");
+        if let Some(id) = user.get("id").and_then(Value::as_str).and_then(record_key) {
+            user.insert("id".to_owned(), json!(id));
+        }
+    }
+
+    user
+}
+
+fn found(user: &UserRecord) -> AccountResult {
+    AccountResult {
+        detail: present(user),
+        status: StatusCode::OK,
+    }
+}
+
+async fn save_identifiers(repo: &UserRepository<'_>, user: &UserRecord) -> AccountResult {
+    match repo.save_identifiers(user).await {
+        Ok(Some(user)) => found(&user),
+        Ok(None) => not_found(),
+        Err(e) if e.status() == StatusCode::CONFLICT => identifier_taken(),
+        Err(e) => handle_repo_error(e),
+    }
+}
+
+fn identifier_taken() -> AccountResult {
+    AccountResult {
+        detail: json!("Identifier is already in use"),
+        status: StatusCode::CONFLICT,
+    }
+}
+
+fn identifier_not_found() -> AccountResult {
+    AccountResult {
+        detail: json!("Identifier not found"),
+        status: StatusCode::NOT_FOUND,
+    }
+}
+
+fn invalid_attributes(failures: &[AttributeFailure], status: StatusCode) -> AccountResult {
+    AccountResult {
+        detail: attributes::failure_detail(failures),
+        status,
+    }
+}
+
+fn not_found() -> AccountResult {
+    AccountResult {
+        detail: json!("User not found"),
+        status: StatusCode::NOT_FOUND,
+    }
+}
+
+fn handle_repo_error(e: RepoError) -> AccountResult {
+    AccountResult {
+        detail: json!(e.to_string()),
+        status: e.status(),
+    }
+}
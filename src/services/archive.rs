@@ -0,0 +1,566 @@
+use crate::repositories::{
+    archive::{record_key, ArchiveRepository},
+    clients::{ClientRecord, ClientRepository},
+    tenants::{TenantRepository, TenantSettings},
+    Ident, RepoError, Surreal,
+};
+use crate::services::{settings, tenants::is_reserved};
+use crate::utils::redis::RedisClient;
+use axum::http::StatusCode;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+
+const ARCHIVE_FORMAT: &str = "haltion-tenant-archive";
+const ARCHIVE_VERSION: u32 = 1;
+
+/// The tables of a tenant's namespace that are carried in an archive.
+const USER_TABLE: &str = "user";
+const GROUP_TABLE: &str = "group";
+const ROLE_TABLE: &str = "role";
+const FACTOR_TABLE: &str = "factor";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ArchiveResult {
+    pub detail: Value,
+    pub status: StatusCode,
+}
+
+/// Everything a tenant owns, in a form that can be restored on another deployment. Users keep
+/// their credential hashes and every record keeps its id, so that references between records
+/// survive the move.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TenantArchive {
+    pub format: String,
+    pub version: u32,
+    pub tenant: String,
+    pub exported_at: String,
+    pub settings: TenantSettings,
+    #[serde(default)]
+    pub clients: Vec<ClientRecord>,
+    #[serde(default)]
+    pub users: Vec<Value>,
+    #[serde(default)]
+    pub groups: Vec<Value>,
+    #[serde(default)]
+    pub roles: Vec<Value>,
+    #[serde(default)]
+    pub factors: Vec<Value>,
+}
+
+/// What to do with archived records that already exist in the target tenant.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictPolicy {
+    /// Refuse the whole import.
+    #[default]
+    Fail,
+    /// Keep the existing records.
+    Skip,
+    /// Replace the existing records with the archived ones.
+    Overwrite,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+pub struct ImportOptions {
+    /// Report what the import would do without writing anything.
+    #[serde(default)]
+    pub dry_run: bool,
+    #[serde(default)]
+    pub on_conflict: ConflictPolicy,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Conflict {
+    pub id: String,
+    pub reason: &'static str,
+    /// Whether the conflict can be settled by overwriting the existing record.
+    pub resolvable: bool,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct EntityReport {
+    pub created: usize,
+    pub replaced: usize,
+    pub skipped: usize,
+    pub conflicts: Vec<Conflict>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct ImportReport {
+    pub tenant: String,
+    pub dry_run: bool,
+    pub tenant_created: bool,
+    pub settings: EntityReport,
+    pub clients: EntityReport,
+    pub users: EntityReport,
+    pub groups: EntityReport,
+    pub roles: EntityReport,
+    pub factors: EntityReport,
+}
+
+/// What to do with a single archived record.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Action {
+    Create,
+    Replace,
+    Skip,
+}
+
+/// Writes all of a tenant's data to a single archive.
+pub async fn export_tenant(db: &Surreal, tenant: &str) -> ArchiveResult {
+    let record = match TenantRepository::new(db).find(tenant).await {
+        Ok(Some(record)) => record,
+        Ok(None) => return not_found(),
+        Err(e) => return handle_repo_error(e),
+    };
+    let ns = match Ident::parse(&record.name) {
+        Ok(ns) => ns,
+        Err(e) => return handle_repo_error(e),
+    };
+    let clients = match ClientRepository::new(db).list(tenant).await {
+        Ok(clients) => clients,
+        Err(e) => return handle_repo_error(e),
+    };
+
+    let repo = ArchiveRepository::new(db, ns);
+    let mut tables = Vec::new();
+    for table in [USER_TABLE, GROUP_TABLE, ROLE_TABLE, FACTOR_TABLE] {
+        match repo.export(&Ident::parse(table).unwrap()).await {
+            Ok(records) => tables.push(records),
+            Err(e) => return handle_repo_error(e),
+        }
+    }
+    let factors = tables.pop().unwrap_or_default();
+    let roles = tables.pop().unwrap_or_default();
+    let groups = tables.pop().unwrap_or_default();
+    let users = tables.pop().unwrap_or_default();
+
+    ArchiveResult {
+        detail: json!(TenantArchive {
+            format: ARCHIVE_FORMAT.to_owned(),
+            version: ARCHIVE_VERSION,
+            tenant: record.name,
+            exported_at: Utc::now().to_rfc3339(),
+            settings: record.settings,
+            clients,
+            users,
+            groups,
+            roles,
+            factors,
+        }),
+        status: StatusCode::OK,
+    }
+}
+
+/// Restores an archive into a tenant, creating the tenant if it does not exist yet.
+///
+/// Records are matched by id. Whether existing records are kept or replaced is decided by
+/// `options.on_conflict`; with `ConflictPolicy::Fail` nothing is written if any record already
+/// exists. Users whose username is taken by a different record, and clients whose `client_id`
+/// belongs to another tenant, can never be imported and are always reported.
+///
+/// # Returns
+///
+/// Returns an `ArchiveResult` holding an `ImportReport`, with `409 Conflict` if the import was
+/// refused because of conflicts.
+pub async fn import_tenant(
+    db: &Surreal,
+    redis: &mut RedisClient,
+    tenant: &str,
+    archive: &TenantArchive,
+    options: &ImportOptions,
+) -> ArchiveResult {
+    if let Err(result) = check_archive(tenant, archive) {
+        return result;
+    }
+    let ns = match Ident::parse(tenant) {
+        Ok(ns) => ns,
+        Err(e) => return handle_repo_error(e),
+    };
+
+    let tenants = TenantRepository::new(db);
+    let existing = match tenants.find(tenant).await {
+        Ok(existing) => existing,
+        Err(e) => return handle_repo_error(e),
+    };
+    let mut report = ImportReport {
+        tenant: tenant.to_owned(),
+        dry_run: options.dry_run,
+        tenant_created: existing.is_none(),
+        ..Default::default()
+    };
+
+    // Plan every write before making any of them
+    let settings_action = match &existing {
+        None => Action::Create,
+        Some(record) if same_settings(&record.settings, &archive.settings) => Action::Skip,
+        Some(_) => {
+            report.settings.conflicts.push(Conflict {
+                id: tenant.to_owned(),
+                reason: "The tenant's settings differ from the archived ones",
+                resolvable: true,
+            });
+            resolve(options.on_conflict)
+        }
+    };
+    count(&mut report.settings, settings_action);
+
+    let client_plan = match plan_clients(db, tenant, archive, options, &mut report).await {
+        Ok(plan) => plan,
+        Err(e) => return handle_repo_error(e),
+    };
+
+    let repo = ArchiveRepository::new(db, ns.clone());
+    let mut table_plans = Vec::new();
+    for (table, records) in [
+        (USER_TABLE, &archive.users),
+        (GROUP_TABLE, &archive.groups),
+        (ROLE_TABLE, &archive.roles),
+        (FACTOR_TABLE, &archive.factors),
+    ] {
+        let entity = match table {
+            USER_TABLE => &mut report.users,
+            GROUP_TABLE => &mut report.groups,
+            ROLE_TABLE => &mut report.roles,
+            _ => &mut report.factors,
+        };
+        let table = Ident::parse(table).unwrap();
+        match plan_records(&repo, &table, records, options, entity).await {
+            Ok(plan) => table_plans.push((table, plan)),
+            Err(e) => return handle_repo_error(e),
+        }
+    }
+
+    let refused = options.on_conflict == ConflictPolicy::Fail && has_conflicts(&report);
+    if options.dry_run || refused {
+        return ArchiveResult {
+            detail: json!(report),
+            status: match refused {
+                true => StatusCode::CONFLICT,
+                false => StatusCode::OK,
+            },
+        };
+    }
+
+    // Apply the plan
+    if existing.is_none() {
+        if let Err(e) = tenants.define_namespace(&ns).await {
+            return handle_repo_error(e);
+        }
+        if let Err(e) = tenants.create(&ns).await {
+            return handle_repo_error(e);
+        }
+    }
+    if settings_action != Action::Skip {
+        let current = match tenants.find(tenant).await {
+            Ok(Some(record)) => record.settings.version,
+            Ok(None) => return not_found(),
+            Err(e) => return handle_repo_error(e),
+        };
+        let settings = TenantSettings {
+            version: current + 1,
+            ..archive.settings.clone()
+        };
+        match tenants.update_settings(tenant, &settings, current).await {
+            Ok(Some(_)) => (),
+            Ok(None) => {
+                return ArchiveResult {
+                    detail: json!("The tenant's settings changed during the import"),
+                    status: StatusCode::CONFLICT,
+                }
+            }
+            Err(e) => return handle_repo_error(e),
+        }
+        if let Err(e) = settings::clear_cache(redis, tenant).await {
+            log::error!("Failed to clear the cached settings of {tenant}: {e}");
+        }
+    }
+
+    let clients = ClientRepository::new(db);
+    for (client, action) in client_plan {
+        let written = match action {
+            Action::Create => clients.create(&client).await.map(|_| ()),
+            Action::Replace => clients.update(&client).await.map(|_| ()),
+            Action::Skip => Ok(()),
+        };
+        if let Err(e) = written {
+            return handle_repo_error(e);
+        }
+    }
+    for (table, plan) in table_plans {
+        for (id, record, action) in plan {
+            if action == Action::Skip {
+                continue;
+            }
+            if let Err(e) = repo
+                .write(&table, &id, record, action == Action::Replace)
+                .await
+            {
+                return handle_repo_error(e);
+            }
+        }
+    }
+
+    ArchiveResult {
+        detail: json!(report),
+        status: StatusCode::OK,
+    }
+}
+
+async fn plan_clients(
+    db: &Surreal,
+    tenant: &str,
+    archive: &TenantArchive,
+    options: &ImportOptions,
+    report: &mut ImportReport,
+) -> Result<Vec<(ClientRecord, Action)>, RepoError> {
+    let repo = ClientRepository::new(db);
+    let mut plan = Vec::new();
+    for client in &archive.clients {
+        let action = match repo.find(&client.client_id).await? {
+            None => Action::Create,
+            Some(existing) if existing.tenant != tenant => {
+                report.clients.conflicts.push(Conflict {
+                    id: client.client_id.clone(),
+                    reason: "The client_id belongs to another tenant",
+                    resolvable: false,
+                });
+                Action::Skip
+            }
+            Some(_) => {
+                report.clients.conflicts.push(Conflict {
+                    id: client.client_id.clone(),
+                    reason: "The client already exists",
+                    resolvable: true,
+                });
+                resolve(options.on_conflict)
+            }
+        };
+        count(&mut report.clients, action);
+
+        // Clients follow the tenant they are imported into
+        let client = ClientRecord {
+            tenant: tenant.to_owned(),
+            ..client.clone()
+        };
+        plan.push((client, action));
+    }
+
+    Ok(plan)
+}
+
+async fn plan_records<'r>(
+    repo: &ArchiveRepository<'_>,
+    table: &Ident,
+    records: &'r [Value],
+    options: &ImportOptions,
+    entity: &mut EntityReport,
+) -> Result<Vec<(String, &'r Value, Action)>, RepoError> {
+    let ids = repo.ids(table).await?;
+    let usernames: HashMap<String, String> = match table.as_str() {
+        USER_TABLE => repo.usernames().await?,
+        _ => HashMap::new(),
+    };
+
+    let mut plan = Vec::new();
+    for record in records {
+        let id = match record
+            .get("id")
+            .and_then(|id| id.as_str())
+            .and_then(record_key)
+        {
+            Some(id) => id,
+            None => {
+                entity.conflicts.push(Conflict {
+                    id: record
+                        .get("id")
+                        .map(|id| id.to_string())
+                        .unwrap_or_default(),
+                    reason: "The record has no valid id",
+                    resolvable: false,
+                });
+                count(entity, Action::Skip);
+                continue;
+            }
+        };
+        let username_owner = record
+            .get("username")
+            .and_then(|u| u.as_str())
+            .and_then(|u| usernames.get(u));
+
+        let action = match username_owner {
+            Some(owner) if *owner != id => {
+                entity.conflicts.push(Conflict {
+                    id: id.clone(),
+                    reason: "The username is taken by another user",
+                    resolvable: false,
+                });
+                Action::Skip
+            }
+            _ if ids.contains(&id) => {
+                entity.conflicts.push(Conflict {
+                    id: id.clone(),
+                    reason: "The record already exists",
+                    resolvable: true,
+                });
+                resolve(options.on_conflict)
+            }
+            _ => Action::Create,
+        };
+        count(entity, action);
+        plan.push((id, record, action));
+    }
+
+    Ok(plan)
+}
+
+/// Checks that an archive can be imported into a tenant at all.
+fn check_archive(tenant: &str, archive: &TenantArchive) -> Result<(), ArchiveResult> {
+    if archive.format != ARCHIVE_FORMAT || archive.version != ARCHIVE_VERSION {
+        return Err(ArchiveResult {
+            detail: json!(format!(
+                "Unsupported archive, expected {ARCHIVE_FORMAT} version {ARCHIVE_VERSION}"
+            )),
+            status: StatusCode::UNPROCESSABLE_ENTITY,
+        });
+    }
+    // A reserved name may be the platform's own namespace, which an archive must never write to
+    if is_reserved(tenant) {
+        return Err(ArchiveResult {
+            detail: json!("Tenant name is reserved"),
+            status: StatusCode::BAD_REQUEST,
+        });
+    }
+
+    Ok(())
+}
+
+/// Returns what to do with a record that already exists.
+fn resolve(policy: ConflictPolicy) -> Action {
+    match policy {
+        ConflictPolicy::Overwrite => Action::Replace,
+        ConflictPolicy::Fail | ConflictPolicy::Skip => Action::Skip,
+    }
+}
+
+fn count(entity: &mut EntityReport, action: Action) {
+    match action {
+        Action::Create => entity.created += 1,
+        Action::Replace => entity.replaced += 1,
+        Action::Skip => entity.skipped += 1,
+    }
+}
+
+fn has_conflicts(report: &ImportReport) -> bool {
+    [
+        &report.settings,
+        &report.clients,
+        &report.users,
+        &report.groups,
+        &report.roles,
+        &report.factors,
+    ]
+    .iter()
+    .any(|entity| !entity.conflicts.is_empty())
+}
+
+/// Compares settings regardless of their version.
+fn same_settings(a: &TenantSettings, b: &TenantSettings) -> bool {
+    TenantSettings {
+        version: b.version,
+        ..a.clone()
+    } == *b
+}
+
+fn not_found() -> ArchiveResult {
+    ArchiveResult {
+        detail: json!("Tenant not found"),
+        status: StatusCode::NOT_FOUND,
+    }
+}
+
+fn handle_repo_error(e: RepoError) -> ArchiveResult {
+    ArchiveResult {
+        detail: json!(e.to_string()),
+        status: e.status(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn archive(version: u32) -> TenantArchive {
+        serde_json::from_value(json!({
+            "format": ARCHIVE_FORMAT,
+            "version": version,
+            "tenant": "acme",
+            "exported_at": "2024-01-01T00:00:00Z",
+            "settings": TenantSettings::default(),
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn only_the_current_archive_version_is_imported() {
+        assert!(check_archive("acme", &archive(ARCHIVE_VERSION)).is_ok());
+        assert_eq!(
+            check_archive("acme", &archive(ARCHIVE_VERSION + 1))
+                .unwrap_err()
+                .status,
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+        let foreign = TenantArchive {
+            format: "other-archive".to_owned(),
+            ..archive(ARCHIVE_VERSION)
+        };
+        assert_eq!(
+            check_archive("acme", &foreign).unwrap_err().status,
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+    }
+
+    #[test]
+    fn archives_are_not_imported_into_reserved_tenants() {
+        assert_eq!(
+            check_archive("Admin", &archive(ARCHIVE_VERSION))
+                .unwrap_err()
+                .status,
+            StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[test]
+    fn conflicts_are_resolved_by_the_policy() {
+        assert_eq!(resolve(ConflictPolicy::Fail), Action::Skip);
+        assert_eq!(resolve(ConflictPolicy::Skip), Action::Skip);
+        assert_eq!(resolve(ConflictPolicy::Overwrite), Action::Replace);
+
+        let mut report = ImportReport::default();
+        assert!(!has_conflicts(&report));
+        report.factors.conflicts.push(Conflict {
+            id: "factor:1".to_owned(),
+            reason: "A record with this id already exists",
+            resolvable: true,
+        });
+        assert!(has_conflicts(&report));
+    }
+
+    #[test]
+    fn settings_are_compared_regardless_of_version() {
+        let settings = TenantSettings::default();
+        let newer = TenantSettings {
+            version: settings.version + 3,
+            ..settings.clone()
+        };
+        let renamed = TenantSettings {
+            display_name: "Other".to_owned(),
+            ..settings.clone()
+        };
+
+        assert!(same_settings(&settings, &newer));
+        assert!(!same_settings(&settings, &renamed));
+    }
+}
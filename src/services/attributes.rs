@@ -0,0 +1,326 @@
+use crate::repositories::{
+    clients::Channel,
+    tenants::{AttributeDefinition, AttributeType},
+    RepoError,
+};
+use crate::services::messages;
+use axum::http::StatusCode;
+use chrono::NaiveDate;
+use lazy_static::lazy_static;
+use lettre::Address;
+use regex::Regex;
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use std::collections::HashSet;
+
+/// Claims set by the server or read by any of its token verifiers, which attributes cannot be
+/// mapped into. `tenantid` is only carried by tenant tokens, but a user token carrying it could
+/// otherwise pass for one.
+const RESERVED_CLAIMS: [&str; 15] = [
+    "iss", "sub", "aud", "exp", "nbf", "iat", "jti", "typ", "scope", "groups", "tid", "tenantid",
+    "sid", "act", "cnf",
+];
+
+/// The most attributes a tenant can define.
+const MAX_ATTRIBUTES: usize = 50;
+
+lazy_static! {
+    /// The form of attribute and claim names.
+    static ref NAME: Regex = Regex::new(r"^[a-zA-Z][a-zA-Z0-9_]{0,63}$").unwrap();
+}
+
+/// Why an attribute was rejected.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct AttributeFailure {
+    pub attribute: String,
+    pub reason: String,
+}
+
+impl AttributeFailure {
+    fn new(attribute: &str, reason: &str) -> Self {
+        Self {
+            attribute: attribute.to_owned(),
+            reason: reason.to_owned(),
+        }
+    }
+}
+
+/// Checks that a tenant's attribute definitions are well formed.
+pub fn validate_schema(definitions: &[AttributeDefinition]) -> Result<(), &'static str> {
+    if definitions.len() > MAX_ATTRIBUTES {
+        return Err("attributes must have at most 50 entries");
+    }
+
+    let mut names = HashSet::new();
+    let mut claims = HashSet::new();
+    for definition in definitions {
+        if !NAME.is_match(&definition.name) {
+            return Err(
+                "attributes.name must be a letter followed by letters, digits or underscores",
+            );
+        }
+        if !names.insert(definition.name.as_str()) {
+            return Err("attributes.name must be unique");
+        }
+        if let Some(claim) = &definition.claim {
+            if !NAME.is_match(claim) {
+                return Err(
+                    "attributes.claim must be a letter followed by letters, digits or underscores",
+                );
+            }
+            if RESERVED_CLAIMS.contains(&claim.as_str()) {
+                return Err("attributes.claim must not be a reserved claim");
+            }
+            if !claims.insert(claim.as_str()) {
+                return Err("attributes.claim must be unique");
+            }
+        }
+        if let Some(pattern) = &definition.pattern {
+            if definition.kind == AttributeType::Boolean || Regex::new(pattern).is_err() {
+                return Err("attributes.pattern must be a regular expression for a text attribute");
+            }
+        }
+        if let (Some(min), Some(max)) = (definition.min, definition.max) {
+            if min > max {
+                return Err("attributes.min must not be greater than attributes.max");
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks a user's attributes against the tenant's definitions.
+///
+/// Attributes that are not defined are rejected, as are values of the wrong type or outside the
+/// bounds of their definition. Required attributes must be present and not null.
+///
+/// # Errors
+///
+/// Returns every failed attribute, so that all of them can be fixed at once.
+pub fn validate(
+    definitions: &[AttributeDefinition],
+    attributes: &Map<String, Value>,
+) -> Result<(), Vec<AttributeFailure>> {
+    let mut failures = Vec::new();
+    for name in attributes.keys() {
+        if !definitions.iter().any(|d| d.name == *name) {
+            failures.push(AttributeFailure::new(name, "Attribute is not defined"));
+        }
+    }
+    for definition in definitions {
+        match attributes.get(&definition.name) {
+            None | Some(Value::Null) if definition.required => {
+                failures.push(AttributeFailure::new(
+                    &definition.name,
+                    "Attribute is required",
+                ));
+            }
+            None | Some(Value::Null) => (),
+            Some(value) => {
+                if let Err(reason) = check_value(definition, value) {
+                    failures.push(AttributeFailure::new(&definition.name, reason));
+                }
+            }
+        }
+    }
+
+    match failures.is_empty() {
+        true => Ok(()),
+        false => Err(failures),
+    }
+}
+
+/// Describes failed attributes in a response body.
+pub fn failure_detail(failures: &[AttributeFailure]) -> Value {
+    json!({
+        "message": "Attributes do not match the tenant's schema",
+        "failures": failures,
+    })
+}
+
+/// Returns the failure for a unique attribute another user already has, when that is why a user
+/// could not be stored.
+pub fn taken(definitions: &[AttributeDefinition], e: &RepoError) -> Option<Vec<AttributeFailure>> {
+    if e.status() != StatusCode::CONFLICT {
+        return None;
+    }
+
+    let detail = e.to_string();
+    definitions
+        .iter()
+        .find(|d| d.unique && detail.contains(&format!("user_attr_{}", d.name)))
+        .map(|d| vec![AttributeFailure::new(&d.name, "Attribute is already taken")])
+}
+
+/// Returns the claims a user's attributes are mapped into, keyed by claim name. Attributes
+/// without a claim, or that the user does not have, are left out, as are reserved claims that a
+/// schema saved before they were reserved may still map into.
+pub fn claims(
+    definitions: &[AttributeDefinition],
+    attributes: &Map<String, Value>,
+) -> Map<String, Value> {
+    definitions
+        .iter()
+        .filter_map(|d| {
+            let claim = d
+                .claim
+                .as_ref()
+                .filter(|c| !RESERVED_CLAIMS.contains(&c.as_str()))?;
+            match attributes.get(&d.name) {
+                Some(Value::Null) | None => None,
+                Some(value) => Some((claim.clone(), value.clone())),
+            }
+        })
+        .collect()
+}
+
+fn check_value(definition: &AttributeDefinition, value: &Value) -> Result<(), &'static str> {
+    match definition.kind {
+        AttributeType::Boolean => {
+            if !value.is_boolean() {
+                return Err("Attribute must be true or false");
+            }
+        }
+        AttributeType::Integer | AttributeType::Number => {
+            let number = match value.as_f64() {
+                Some(number) => number,
+                None => return Err("Attribute must be a number"),
+            };
+            if definition.kind == AttributeType::Integer && !(value.is_i64() || value.is_u64()) {
+                return Err("Attribute must be a whole number");
+            }
+            check_bounds(definition, number)?;
+        }
+        AttributeType::String
+        | AttributeType::Date
+        | AttributeType::Email
+        | AttributeType::Phone => {
+            let text = match value.as_str() {
+                Some(text) => text,
+                None => return Err("Attribute must be a string"),
+            };
+            match definition.kind {
+                AttributeType::Date if NaiveDate::parse_from_str(text, "%Y-%m-%d").is_err() => {
+                    return Err("Attribute must be a date such as 2024-01-31")
+                }
+                AttributeType::Email if text.parse::<Address>().is_err() => {
+                    return Err("Attribute must be an email address")
+                }
+                AttributeType::Phone if messages::channel_of(text) != Channel::Sms => {
+                    return Err("Attribute must be a phone number")
+                }
+                _ => (),
+            }
+            if let Some(pattern) = &definition.pattern {
+                if !Regex::new(pattern).is_ok_and(|re| re.is_match(text)) {
+                    return Err("Attribute does not match the required pattern");
+                }
+            }
+            check_bounds(definition, text.chars().count() as f64)?;
+        }
+    }
+    if !definition.options.is_empty() && !definition.options.contains(value) {
+        return Err("Attribute must be one of the allowed options");
+    }
+
+    Ok(())
+}
+
+fn check_bounds(definition: &AttributeDefinition, n: f64) -> Result<(), &'static str> {
+    if definition.min.is_some_and(|min| n < min) {
+        return Err("Attribute is below its minimum");
+    }
+    if definition.max.is_some_and(|max| n > max) {
+        return Err("Attribute is above its maximum");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn definition(name: &str, claim: Option<&str>) -> AttributeDefinition {
+        AttributeDefinition {
+            name: name.to_owned(),
+            kind: AttributeType::String,
+            required: false,
+            unique: false,
+            pattern: None,
+            min: None,
+            max: None,
+            options: Vec::new(),
+            claim: claim.map(str::to_owned),
+            editable: false,
+        }
+    }
+
+    #[test]
+    fn validate_schema_refuses_claims_read_by_verifiers() {
+        for claim in ["tenantid", "typ", "scope", "tid"] {
+            let result = validate_schema(&[definition("org", Some(claim))]);
+
+            assert_eq!(
+                result,
+                Err("attributes.claim must not be a reserved claim"),
+                "{claim}"
+            );
+        }
+    }
+
+    #[test]
+    fn validate_schema_accepts_well_formed_definitions() {
+        let definitions = [definition("name", Some("name")), definition("team", None)];
+
+        assert_eq!(validate_schema(&definitions), Ok(()));
+    }
+
+    #[test]
+    fn validate_schema_refuses_malformed_definitions() {
+        let duplicate = [definition("team", None), definition("team", None)];
+        assert_eq!(
+            validate_schema(&duplicate),
+            Err("attributes.name must be unique")
+        );
+
+        let shared_claim = [
+            definition("team", Some("team")),
+            definition("squad", Some("team")),
+        ];
+        assert_eq!(
+            validate_schema(&shared_claim),
+            Err("attributes.claim must be unique")
+        );
+
+        let mut pattern = definition("team", None);
+        pattern.pattern = Some("(".to_owned());
+        assert!(validate_schema(&[pattern]).is_err());
+
+        assert!(validate_schema(&[definition("1team", None)]).is_err());
+    }
+
+    #[test]
+    fn claims_leave_out_attributes_without_claims() {
+        let definitions = [definition("team", None), definition("org", Some("org"))];
+        let attributes = json!({ "team": "blue", "org": null });
+
+        let claims = claims(&definitions, attributes.as_object().unwrap());
+
+        assert!(claims.is_empty());
+    }
+
+    #[test]
+    fn claims_leave_out_reserved_claims() {
+        let definitions = [
+            definition("org", Some("tenantid")),
+            definition("team", Some("team")),
+        ];
+        let attributes = json!({ "org": "acme", "team": "blue" });
+
+        let claims = claims(&definitions, attributes.as_object().unwrap());
+
+        assert_eq!(Value::Object(claims), json!({ "team": "blue" }));
+    }
+}
@@ -0,0 +1,282 @@
+use crate::config::constants::{DEFAULT_ACCESS_TOKEN_TTL, DEFAULT_OTP_TTL, MAX_OTP_TTL, MIN_TTL};
+use crate::repositories::{
+    clients::{Channel, ClientRecord, ClientRepository},
+    RepoError, Surreal,
+};
+use crate::services::{sessions::SESSION_TTL, settings, tenants};
+use crate::utils::{random, redis::RedisClient};
+use axum::http::StatusCode;
+use chrono::Utc;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use url::Url;
+
+const CLIENT_ID_LEN: usize = 24;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClientResult {
+    pub detail: Value,
+    pub status: StatusCode,
+}
+
+/// The fields of a client a tenant admin can set. Fields left out keep their current value, or
+/// their default when the client is created.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct ClientPayload {
+    pub name: Option<String>,
+    pub allowed_origins: Option<Vec<String>>,
+    pub redirect_uris: Option<Vec<String>>,
+    pub access_token_ttl: Option<i64>,
+    pub otp_ttl: Option<i64>,
+    pub allowed_channels: Option<Vec<Channel>>,
+}
+
+pub async fn create_client(db: &Surreal, tenant: &str, payload: &ClientPayload) -> ClientResult {
+    let now = Utc::now().to_rfc3339();
+    let client = apply(
+        ClientRecord {
+            client_id: random::alphanumeric(CLIENT_ID_LEN),
+            tenant: tenant.to_owned(),
+            name: String::new(),
+            allowed_origins: Vec::new(),
+            redirect_uris: Vec::new(),
+            access_token_ttl: DEFAULT_ACCESS_TOKEN_TTL,
+            otp_ttl: DEFAULT_OTP_TTL,
+            allowed_channels: vec![Channel::Sms, Channel::Email],
+            created_at: now.clone(),
+            updated_at: now,
+        },
+        payload,
+    );
+    if let Err(e) = validate(&client) {
+        return e;
+    }
+
+    match ClientRepository::new(db).create(&client).await {
+        Ok(client) => ClientResult {
+            detail: json!(client),
+            status: StatusCode::CREATED,
+        },
+        Err(e) => handle_repo_error(e),
+    }
+}
+
+pub async fn list_clients(db: &Surreal, tenant: &str) -> ClientResult {
+    match ClientRepository::new(db).list(tenant).await {
+        Ok(clients) => ClientResult {
+            detail: json!(clients),
+            status: StatusCode::OK,
+        },
+        Err(e) => handle_repo_error(e),
+    }
+}
+
+pub async fn get_client(db: &Surreal, tenant: &str, client_id: &str) -> ClientResult {
+    match find_tenant_client(db, tenant, client_id).await {
+        Ok(client) => ClientResult {
+            detail: json!(client),
+            status: StatusCode::OK,
+        },
+        Err(e) => e,
+    }
+}
+
+pub async fn update_client(
+    db: &Surreal,
+    tenant: &str,
+    client_id: &str,
+    payload: &ClientPayload,
+) -> ClientResult {
+    let mut client = match find_tenant_client(db, tenant, client_id).await {
+        Ok(client) => apply(client, payload),
+        Err(e) => return e,
+    };
+    client.updated_at = Utc::now().to_rfc3339();
+    if let Err(e) = validate(&client) {
+        return e;
+    }
+
+    match ClientRepository::new(db).update(&client).await {
+        Ok(Some(client)) => ClientResult {
+            detail: json!(client),
+            status: StatusCode::OK,
+        },
+        Ok(None) => not_found(),
+        Err(e) => handle_repo_error(e),
+    }
+}
+
+pub async fn delete_client(db: &Surreal, tenant: &str, client_id: &str) -> ClientResult {
+    if let Err(e) = find_tenant_client(db, tenant, client_id).await {
+        return e;
+    }
+
+    match ClientRepository::new(db).delete(client_id).await {
+        Ok(_) => ClientResult {
+            detail: json!("Client deleted"),
+            status: StatusCode::OK,
+        },
+        Err(e) => handle_repo_error(e),
+    }
+}
+
+/// Looks up the client a request was made for and checks that it may be served.
+///
+/// # Arguments
+///
+/// * `db` - The database holding the client registry.
+/// * `redis` - A mutable reference to a Redis client instance, used to check the tenant's status.
+/// * `client_id` - The `client_id` sent with the request.
+/// * `origin` - The request's `Origin` header, if any.
+///
+/// # Errors
+///
+/// Returns a `ClientResult` if the client is unknown, its tenant is suspended or the origin is
+/// not allowed. The returned client's channels and lifetimes are narrowed to what the tenant's
+/// settings allow.
+pub async fn resolve_client(
+    db: &Surreal,
+    redis: &mut RedisClient,
+    client_id: &str,
+    origin: Option<&str>,
+) -> Result<ClientRecord, ClientResult> {
+    let mut client = match ClientRepository::new(db).find(client_id).await {
+        Ok(Some(client)) => client,
+        Ok(None) => {
+            return Err(ClientResult {
+                detail: json!("Unknown client_id"),
+                status: StatusCode::UNAUTHORIZED,
+            })
+        }
+        Err(e) => return Err(handle_repo_error(e)),
+    };
+
+    match tenants::is_suspended(db, redis, &client.tenant).await {
+        Ok(false) => (),
+        Ok(true) => {
+            return Err(ClientResult {
+                detail: json!("Tenant is suspended"),
+                status: StatusCode::FORBIDDEN,
+            })
+        }
+        Err(e) => {
+            return Err(ClientResult {
+                detail: e.detail,
+                status: e.status,
+            })
+        }
+    }
+
+    if !client.allows_origin(origin) {
+        return Err(ClientResult {
+            detail: json!("Origin is not allowed for this client"),
+            status: StatusCode::FORBIDDEN,
+        });
+    }
+
+    // The tenant's settings bound what any of its clients may do
+    match settings::lookup(db, redis, &client.tenant).await {
+        Ok(settings) => {
+            client
+                .allowed_channels
+                .retain(|c| settings.allowed_channels.contains(c));
+            client.access_token_ttl = client
+                .access_token_ttl
+                .min(settings.tokens.access_token_ttl);
+            client.otp_ttl = client.otp_ttl.min(settings.otp.ttl);
+        }
+        Err(e) => {
+            return Err(ClientResult {
+                detail: json!(e.to_string()),
+                status: e.status(),
+            })
+        }
+    }
+
+    Ok(client)
+}
+
+async fn find_tenant_client(
+    db: &Surreal,
+    tenant: &str,
+    client_id: &str,
+) -> Result<ClientRecord, ClientResult> {
+    match ClientRepository::new(db).find(client_id).await {
+        Ok(Some(client)) if client.tenant == tenant => Ok(client),
+        Ok(_) => Err(not_found()),
+        Err(e) => Err(handle_repo_error(e)),
+    }
+}
+
+fn apply(mut client: ClientRecord, payload: &ClientPayload) -> ClientRecord {
+    if let Some(name) = &payload.name {
+        client.name = name.trim().to_owned();
+    }
+    if let Some(allowed_origins) = &payload.allowed_origins {
+        client.allowed_origins = allowed_origins.clone();
+    }
+    if let Some(redirect_uris) = &payload.redirect_uris {
+        client.redirect_uris = redirect_uris.clone();
+    }
+    if let Some(ttl) = payload.access_token_ttl {
+        client.access_token_ttl = ttl;
+    }
+    if let Some(ttl) = payload.otp_ttl {
+        client.otp_ttl = ttl;
+    }
+    if let Some(channels) = &payload.allowed_channels {
+        client.allowed_channels = channels.clone();
+    }
+
+    client
+}
+
+fn validate(client: &ClientRecord) -> Result<(), ClientResult> {
+    let invalid = |detail: &str| {
+        Err(ClientResult {
+            detail: json!(detail),
+            status: StatusCode::UNPROCESSABLE_ENTITY,
+        })
+    };
+
+    if client.name.is_empty() {
+        return invalid("name is required");
+    }
+    if !(MIN_TTL..=SESSION_TTL).contains(&client.access_token_ttl) {
+        return invalid("access_token_ttl is out of range");
+    }
+    if !(MIN_TTL..=MAX_OTP_TTL).contains(&client.otp_ttl) {
+        return invalid("otp_ttl is out of range");
+    }
+    if client.allowed_channels.is_empty() {
+        return invalid("allowed_channels must not be empty");
+    }
+    for origin in &client.allowed_origins {
+        match Url::parse(origin) {
+            Ok(url) if url.origin().ascii_serialization() == *origin => (),
+            _ => return invalid("allowed_origins must be origins such as https://example.com"),
+        }
+    }
+    for uri in &client.redirect_uris {
+        match Url::parse(uri) {
+            Ok(url) if url.fragment().is_none() => (),
+            _ => return invalid("redirect_uris must be absolute URIs without a fragment"),
+        }
+    }
+
+    Ok(())
+}
+
+fn not_found() -> ClientResult {
+    ClientResult {
+        detail: json!("Client not found"),
+        status: StatusCode::NOT_FOUND,
+    }
+}
+
+fn handle_repo_error(e: RepoError) -> ClientResult {
+    ClientResult {
+        detail: json!(e.to_string()),
+        status: e.status(),
+    }
+}
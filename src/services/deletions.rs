@@ -0,0 +1,499 @@
+use crate::config::env::APP_SECRET;
+use crate::repositories::{
+    archive::record_key,
+    deletions::{DeletionReceipt, DeletionRepository, DeletionStatus, Requester},
+    factors::FactorRepository,
+    invitations::InvitationRepository,
+    tenants::TenantRepository,
+    users::{UserRecord, UserRepository},
+    Ident, RepoError, Surreal,
+};
+use crate::services::{lockouts, sessions, settings};
+use crate::utils::{
+    random,
+    redis::{RedisClient, TenantKeys},
+};
+use axum::http::StatusCode;
+use chrono::{Duration, Utc};
+use hmac::{Hmac, Mac};
+use redis::RedisError;
+use serde_json::{json, Value};
+use sha2::Sha256;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+const RECEIPT_ID_LEN: usize = 24;
+/// How often, in seconds, users whose grace period ended are looked for.
+const SWEEP_INTERVAL: u64 = 3_600;
+/// How many tenants, and users of a tenant, are read at a time while sweeping.
+const SWEEP_BATCH: u32 = 100;
+
+/// The data removed from Redis as soon as deletion is requested.
+const PURGED: [&str; 3] = ["sessions", "one_time_codes", "login_failures"];
+/// The records removed from the database once the user is erased.
+const ERASED: [&str; 5] = [
+    "profile",
+    "credentials",
+    "identifiers",
+    "second_factors",
+    "invitations",
+];
+/// The records that keep the user's pseudonym in place of their username.
+const PSEUDONYMIZED: [&str; 1] = ["active_user_records"];
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeletionResult {
+    pub detail: Value,
+    pub status: StatusCode,
+}
+
+/// Deletes a user on behalf of a tenant admin. See `request_deletion`.
+pub async fn delete_user(
+    db: &Surreal,
+    redis: &mut RedisClient,
+    tenant: &str,
+    id: &str,
+) -> DeletionResult {
+    let repo = match repository(db, tenant) {
+        Ok(repo) => repo,
+        Err(e) => return handle_repo_error(e),
+    };
+
+    match repo.find(id).await {
+        Ok(Some(user)) => request_deletion(db, redis, tenant, &user, Requester::Admin).await,
+        Ok(None) => not_found(),
+        Err(e) => handle_repo_error(e),
+    }
+}
+
+/// Deletes the signed-in user's own account. See `request_deletion`.
+pub async fn delete_own_account(
+    db: &Surreal,
+    redis: &mut RedisClient,
+    tenant: &str,
+    sub: &str,
+) -> DeletionResult {
+    let repo = match repository(db, tenant) {
+        Ok(repo) => repo,
+        Err(e) => return handle_repo_error(e),
+    };
+
+    match repo.find_by_username(sub).await {
+        Ok(Some(user)) => request_deletion(db, redis, tenant, &user, Requester::User).await,
+        Ok(None) => not_found(),
+        Err(e) => handle_repo_error(e),
+    }
+}
+
+/// Deletes a user and returns the receipt of the deletion.
+///
+/// The user is disabled right away, their sessions are revoked, and the codes and lockouts kept
+/// for them in Redis are purged. Records of their activity keep a pseudonym in place of their
+/// username. The user is erased once the tenant's grace period ends, or right away when it is
+/// `0`; until then a tenant admin can restore them.
+///
+/// # Errors
+///
+/// Returns a `DeletionResult` if the user's deletion is already scheduled, or their data cannot
+/// be purged.
+async fn request_deletion(
+    db: &Surreal,
+    redis: &mut RedisClient,
+    tenant: &str,
+    user: &UserRecord,
+    requested_by: Requester,
+) -> DeletionResult {
+    if let Some(receipt_id) = &user.deletion_receipt {
+        return DeletionResult {
+            detail: json!({
+                "message": "Deletion is already scheduled",
+                "receipt_id": receipt_id,
+            }),
+            status: StatusCode::CONFLICT,
+        };
+    }
+    let grace_period = match settings::lookup(db, redis, tenant).await {
+        Ok(settings) => settings.deletion.grace_period,
+        Err(e) => {
+            return DeletionResult {
+                detail: json!(e.to_string()),
+                status: e.status(),
+            }
+        }
+    };
+    let users = match repository(db, tenant) {
+        Ok(repo) => repo,
+        Err(e) => return handle_repo_error(e),
+    };
+
+    let now = Utc::now();
+    let mut receipt = DeletionReceipt {
+        id: None,
+        receipt_id: random::alphanumeric(RECEIPT_ID_LEN),
+        subject: pseudonym(tenant, &user.username),
+        requested_by,
+        status: DeletionStatus::Scheduled,
+        requested_at: now.to_rfc3339(),
+        delete_after: (now + Duration::seconds(grace_period)).to_rfc3339(),
+        completed_at: None,
+        erased: PURGED.map(str::to_owned).to_vec(),
+        pseudonymized: PSEUDONYMIZED.map(str::to_owned).to_vec(),
+    };
+
+    if grace_period > 0 {
+        let id = user.id.as_deref().and_then(record_key).unwrap_or_default();
+        match users
+            .schedule_deletion(&id, &receipt.delete_after, &receipt.receipt_id)
+            .await
+        {
+            Ok(Some(_)) => (),
+            Ok(None) => return not_found(),
+            Err(e) => return handle_repo_error(e),
+        }
+    }
+    if let Err(e) = purge(redis, tenant, user).await {
+        return e;
+    }
+    if grace_period == 0 {
+        if let Err(e) = erase_records(db, tenant, user).await {
+            return handle_repo_error(e);
+        }
+        complete(&mut receipt);
+    }
+
+    let receipts = match receipts(db, tenant) {
+        Ok(repo) => repo,
+        Err(e) => return handle_repo_error(e),
+    };
+    match receipts.create(&receipt).await {
+        Ok(receipt) => DeletionResult {
+            status: match receipt.status {
+                DeletionStatus::Scheduled => StatusCode::ACCEPTED,
+                _ => StatusCode::OK,
+            },
+            detail: present(&receipt),
+        },
+        Err(e) => handle_repo_error(e),
+    }
+}
+
+/// Restores a user whose deletion is scheduled, before their grace period ends. Data purged when
+/// the deletion was requested is not restored, so the user signs in again.
+pub async fn restore_user(db: &Surreal, tenant: &str, id: &str) -> DeletionResult {
+    let users = match repository(db, tenant) {
+        Ok(repo) => repo,
+        Err(e) => return handle_repo_error(e),
+    };
+    let receipt_id = match users.find(id).await {
+        Ok(Some(user)) => match user.deletion_receipt {
+            Some(receipt_id) => receipt_id,
+            None => {
+                return DeletionResult {
+                    detail: json!("User is not scheduled for deletion"),
+                    status: StatusCode::CONFLICT,
+                }
+            }
+        },
+        Ok(None) => return not_found(),
+        Err(e) => return handle_repo_error(e),
+    };
+
+    let user = match users.cancel_deletion(id).await {
+        Ok(Some(user)) => user,
+        Ok(None) => return not_found(),
+        Err(e) => return handle_repo_error(e),
+    };
+    let receipts = match receipts(db, tenant) {
+        Ok(repo) => repo,
+        Err(e) => return handle_repo_error(e),
+    };
+    match receipts.find(&receipt_id).await {
+        Ok(Some(mut receipt)) => {
+            receipt.status = DeletionStatus::Cancelled;
+            receipt.completed_at = Some(Utc::now().to_rfc3339());
+            if let Err(e) = receipts.update(&receipt).await {
+                log::error!("Failed to cancel deletion receipt {receipt_id}: {e}");
+            }
+        }
+        Ok(None) => (),
+        Err(e) => log::error!("Failed to find deletion receipt {receipt_id}: {e}"),
+    }
+
+    DeletionResult {
+        detail: json!({
+            "message": "User restored",
+            "username": user.username,
+        }),
+        status: StatusCode::OK,
+    }
+}
+
+/// Returns the receipt of a deletion.
+pub async fn get_receipt(db: &Surreal, tenant: &str, receipt_id: &str) -> DeletionResult {
+    let receipts = match receipts(db, tenant) {
+        Ok(repo) => repo,
+        Err(e) => return handle_repo_error(e),
+    };
+
+    match receipts.find(receipt_id).await {
+        Ok(Some(receipt)) => DeletionResult {
+            detail: present(&receipt),
+            status: StatusCode::OK,
+        },
+        Ok(None) => DeletionResult {
+            detail: json!("Deletion receipt not found"),
+            status: StatusCode::NOT_FOUND,
+        },
+        Err(e) => handle_repo_error(e),
+    }
+}
+
+/// Erases users whose grace period has ended, checking every tenant once an hour. Runs until the
+/// server stops.
+pub async fn run_sweeper(db: Surreal, redis: Arc<Mutex<RedisClient>>) {
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(SWEEP_INTERVAL));
+    loop {
+        interval.tick().await;
+        if let Err(e) = sweep(&db, &redis).await {
+            log::error!("Failed to erase deleted users: {e}");
+        }
+    }
+}
+
+async fn sweep(db: &Surreal, redis: &Mutex<RedisClient>) -> Result<(), RepoError> {
+    let tenants = TenantRepository::new(db);
+    let mut start = 0;
+    loop {
+        let (page, _) = tenants.list(SWEEP_BATCH, start).await?;
+        for tenant in &page {
+            if let Err(e) = sweep_tenant(db, redis, &tenant.name).await {
+                log::error!("Failed to erase deleted users of {}: {e}", tenant.name);
+            }
+        }
+        if page.len() < SWEEP_BATCH as usize {
+            return Ok(());
+        }
+        start += SWEEP_BATCH;
+    }
+}
+
+async fn sweep_tenant(
+    db: &Surreal,
+    redis: &Mutex<RedisClient>,
+    tenant: &str,
+) -> Result<(), RepoError> {
+    let users = repository(db, tenant)?;
+    let receipts = receipts(db, tenant)?;
+    loop {
+        let due = users
+            .due_for_deletion(&Utc::now().to_rfc3339(), SWEEP_BATCH)
+            .await?;
+        let mut erased = 0;
+        for user in &due {
+            // Sessions cannot be created for disabled users, but purging again is cheap
+            if let Err(e) = purge(&mut *redis.lock().await, tenant, user).await {
+                log::error!(
+                    "Failed to purge {} of {tenant}: {}",
+                    user.username,
+                    e.detail
+                );
+            }
+            if let Err(e) = erase_records(db, tenant, user).await {
+                log::error!("Failed to erase {} of {tenant}: {e}", user.username);
+                continue;
+            }
+            erased += 1;
+
+            let receipt_id = match user.deletion_receipt.as_deref() {
+                Some(receipt_id) => receipt_id,
+                None => {
+                    log::error!("{} of {tenant} was erased without a receipt", user.username);
+                    continue;
+                }
+            };
+            match receipts.find(receipt_id).await {
+                Ok(Some(mut receipt)) => {
+                    complete(&mut receipt);
+                    if let Err(e) = receipts.update(&receipt).await {
+                        log::error!("Failed to complete deletion receipt {receipt_id}: {e}");
+                    }
+                }
+                Ok(None) => log::error!("Deletion receipt {receipt_id} of {tenant} is missing"),
+                Err(e) => log::error!("Failed to find deletion receipt {receipt_id}: {e}"),
+            }
+        }
+        // Users that could not be erased stay due, and are tried again by the next sweep
+        if due.len() < SWEEP_BATCH as usize || erased == 0 {
+            return Ok(());
+        }
+    }
+}
+
+/// Removes what Redis keeps about a user, and replaces their username in records of their
+/// activity with their pseudonym.
+async fn purge(
+    redis: &mut RedisClient,
+    tenant: &str,
+    user: &UserRecord,
+) -> Result<(), DeletionResult> {
+    let revoked = sessions::revoke_sessions(redis, tenant, &user.username).await;
+    if revoked.status != StatusCode::OK {
+        return Err(DeletionResult {
+            detail: revoked.detail,
+            status: revoked.status,
+        });
+    }
+
+    purge_codes(redis, tenant, user)
+        .await
+        .map_err(handle_redis_error)?;
+    lockouts::forget(redis, tenant, &user.username)
+        .await
+        .map_err(handle_redis_error)?;
+    pseudonymize_activity(redis, tenant, &user.username)
+        .await
+        .map_err(handle_redis_error)
+}
+
+/// Deletes the codes waiting to be used by any of the user's identifiers.
+async fn purge_codes(
+    redis: &mut RedisClient,
+    tenant: &str,
+    user: &UserRecord,
+) -> Result<(), RedisError> {
+    let keys = TenantKeys::new(tenant);
+    let mut values: Vec<&str> = user.identifiers.iter().map(|i| i.value.as_str()).collect();
+    values.push(&user.username);
+
+    for value in &values {
+        redis.del_key(&keys.verification(value)).await?;
+        redis.del_key(&keys.password_reset(value)).await?;
+    }
+    redis
+        .del_key(&keys.identifier_change(&user.username))
+        .await?;
+
+    // OTPs and login challenges are keyed by a random challenge, so every one of them is looked at
+    for key in redis.scan_prefix(&keys.otp_prefix()).await? {
+        let entry = redis.get_hash(&key).await?;
+        if entry
+            .get("recipient")
+            .is_some_and(|r| values.contains(&r.as_str()))
+        {
+            redis.del_key(&key).await?;
+        }
+    }
+    for key in redis.scan_prefix(&keys.login_challenge_prefix()).await? {
+        let entry = redis.get_hash(&key).await?;
+        if entry.get("username") == Some(&user.username) {
+            redis.del_key(&key).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Replaces the user's username with their pseudonym in the sets of active users, so that usage
+/// reports keep their counts.
+async fn pseudonymize_activity(
+    redis: &mut RedisClient,
+    tenant: &str,
+    username: &str,
+) -> Result<(), RedisError> {
+    let pseudonym = pseudonym(tenant, username);
+    let prefix = TenantKeys::new(tenant).active_users_prefix();
+    for key in redis.scan_prefix(&prefix).await? {
+        if redis.is_member(&key, username).await? {
+            redis.add_member(&key, &pseudonym, None).await?;
+            redis.remove_member(&key, username).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Deletes the user's records from the database, including the invitations sent to any of
+/// their identifiers.
+async fn erase_records(db: &Surreal, tenant: &str, user: &UserRecord) -> Result<(), RepoError> {
+    let id = match user.id.as_deref().and_then(record_key) {
+        Some(id) => id,
+        None => {
+            return Err(RepoError::Query {
+                status: StatusCode::INTERNAL_SERVER_ERROR,
+                detail: format!("User {} has no record id", user.username),
+            })
+        }
+    };
+    let tenant = Ident::parse(tenant)?;
+    FactorRepository::new(db, tenant.clone())
+        .delete_by_username(&user.username)
+        .await?;
+    let mut identifiers: Vec<String> = user.identifiers.iter().map(|i| i.value.clone()).collect();
+    identifiers.push(user.username.clone());
+    InvitationRepository::new(db, tenant.clone())
+        .delete_by_identifiers(&identifiers)
+        .await?;
+    UserRepository::new(db, tenant).delete(&id).await?;
+
+    Ok(())
+}
+
+fn complete(receipt: &mut DeletionReceipt) {
+    receipt.status = DeletionStatus::Completed;
+    receipt.completed_at = Some(Utc::now().to_rfc3339());
+    receipt.erased.extend(ERASED.map(str::to_owned));
+}
+
+/// Returns the pseudonym of a user: a hash of their username keyed with the app secret, so that
+/// it cannot be reversed without the secret.
+fn pseudonym(tenant: &str, username: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(APP_SECRET.as_bytes())
+        .expect("HMAC accepts keys of any length");
+    mac.update(format!("{tenant}:{username}").as_bytes());
+    let digest: String = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect();
+
+    format!("deleted:{digest}")
+}
+
+fn present(receipt: &DeletionReceipt) -> Value {
+    let mut receipt = json!(receipt);
+    if let Some(receipt) = receipt.as_object_mut() {
+        receipt.remove("id");
+    }
+
+    receipt
+}
+
+fn repository<'a>(db: &'a Surreal, tenant: &str) -> Result<UserRepository<'a>, RepoError> {
+    Ok(UserRepository::new(db, Ident::parse(tenant)?))
+}
+
+fn receipts<'a>(db: &'a Surreal, tenant: &str) -> Result<DeletionRepository<'a>, RepoError> {
+    Ok(DeletionRepository::new(db, Ident::parse(tenant)?))
+}
+
+fn not_found() -> DeletionResult {
+    DeletionResult {
+        detail: json!("User not found"),
+        status: StatusCode::NOT_FOUND,
+    }
+}
+
+fn handle_redis_error(e: RedisError) -> DeletionResult {
+    DeletionResult {
+        detail: json!(e.detail().unwrap_or("Unknown error")),
+        status: StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+fn handle_repo_error(e: RepoError) -> DeletionResult {
+    DeletionResult {
+        detail: json!(e.to_string()),
+        status: e.status(),
+    }
+}
@@ -0,0 +1,582 @@
+use crate::repositories::{
+    clients::Channel,
+    tenants::{AttributeDefinition, AttributeType},
+    users::{Identifier, UserRecord, UserRepository},
+    Ident, RepoError, Surreal,
+};
+use crate::services::{attributes, messages, settings};
+use crate::utils::{
+    password, random,
+    redis::{RedisClient, TenantKeys},
+};
+use axum::http::StatusCode;
+use chrono::Utc;
+use lettre::Address;
+use redis::RedisError;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::{collections::HashMap, sync::Arc};
+use tokio::sync::Mutex;
+
+const JOB_ID_LEN: usize = 16;
+/// How long, in seconds, the report of an import is kept.
+const JOB_TTL: i64 = 7 * 86_400;
+/// How many rows are imported between progress updates.
+const PROGRESS_INTERVAL: usize = 100;
+/// How many failed rows are reported with their errors. Further failures are only counted.
+const MAX_REPORTED_ERRORS: usize = 1000;
+/// The columns of a CSV file that are not attributes.
+const COLUMNS: [&str; 5] = [
+    "username",
+    "password_hash",
+    "hash_format",
+    "salt",
+    "verified",
+];
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ImportResult {
+    pub detail: Value,
+    pub status: StatusCode,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImportFormat {
+    Csv,
+    Ndjson,
+}
+
+/// The hash parameters of a Firebase project, as shown in its password hash settings.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct FirebaseHashConfig {
+    pub signer_key: String,
+    pub salt_separator: String,
+    pub rounds: u32,
+    pub mem_cost: u32,
+}
+
+/// Users to import into a tenant.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct ImportRequest {
+    pub format: ImportFormat,
+    /// The users, as CSV with a header row or as one JSON object per line.
+    pub data: String,
+    /// Needed to import hashes exported from Firebase Authentication.
+    pub firebase: Option<FirebaseHashConfig>,
+}
+
+/// The format of an imported password hash.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HashFormat {
+    /// A hash that names its own format, such as a PHC string or a bcrypt hash.
+    Auto,
+    /// A base64 hash exported from Firebase Authentication, with its salt in `salt`.
+    FirebaseScrypt,
+}
+
+/// A user to import.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+struct ImportRow {
+    username: String,
+    #[serde(default)]
+    password_hash: Option<String>,
+    #[serde(default)]
+    hash_format: Option<HashFormat>,
+    #[serde(default)]
+    salt: Option<String>,
+    #[serde(default)]
+    verified: bool,
+    #[serde(default)]
+    attributes: Map<String, Value>,
+}
+
+/// A row read from the data, with its line, or why it could not be read.
+type ParsedRow = (usize, Result<ImportRow, String>);
+
+/// Why a row was not imported.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RowError {
+    /// The line of the row in the data, starting at 1.
+    pub line: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    pub error: String,
+}
+
+/// Checks users to import and starts importing them in the background.
+///
+/// Rows are imported one at a time and each is checked on its own: a row that fails is reported
+/// and the rest are still imported. Imported users keep their password hash, which is replaced
+/// with a native one the first time they sign in. The progress and the errors of each row are
+/// kept with the job for a week.
+///
+/// # Errors
+///
+/// Returns an `ImportResult` if the data cannot be read at all, as when a CSV file has no
+/// `username` column.
+pub async fn start_import(
+    db: Surreal,
+    redis: Arc<Mutex<RedisClient>>,
+    tenant: String,
+    request: ImportRequest,
+) -> ImportResult {
+    if let Err(e) = Ident::parse(&tenant) {
+        return handle_repo_error(e);
+    }
+    let definitions = {
+        let mut redis = redis.lock().await;
+        match settings::lookup(&db, &mut redis, &tenant).await {
+            Ok(settings) => settings.attributes,
+            Err(e) => {
+                return ImportResult {
+                    detail: json!(e.to_string()),
+                    status: e.status(),
+                }
+            }
+        }
+    };
+    let rows = match request.format {
+        ImportFormat::Csv => parse_csv(&request.data, &definitions),
+        ImportFormat::Ndjson => Ok(parse_ndjson(&request.data)),
+    };
+    let rows = match rows {
+        Ok(rows) => rows,
+        Err(detail) => {
+            return ImportResult {
+                detail: json!(detail),
+                status: StatusCode::UNPROCESSABLE_ENTITY,
+            }
+        }
+    };
+
+    let job_id = random::alphanumeric(JOB_ID_LEN);
+    let key = TenantKeys::new(&tenant).import_job(&job_id);
+    let total = rows.len();
+    let total_text = total.to_string();
+    let created_at = Utc::now().to_rfc3339();
+    let fields = [
+        ("status", "running"),
+        ("total", total_text.as_str()),
+        ("imported", "0"),
+        ("failed", "0"),
+        ("errors", "[]"),
+        ("created_at", created_at.as_str()),
+    ];
+    if let Err(e) = redis.lock().await.set_key_map(&key, &fields, JOB_TTL).await {
+        return handle_redis_error(e);
+    }
+
+    tokio::spawn(run_import(
+        db,
+        redis,
+        tenant,
+        key,
+        rows,
+        definitions,
+        request.firebase,
+    ));
+
+    ImportResult {
+        detail: json!({
+            "job_id": job_id,
+            "status": "running",
+            "total": total,
+        }),
+        status: StatusCode::ACCEPTED,
+    }
+}
+
+/// Returns the progress of an import, and the errors of the first rows that failed so far.
+pub async fn get_import(redis: &mut RedisClient, tenant: &str, job_id: &str) -> ImportResult {
+    let job = match redis
+        .get_hash(&TenantKeys::new(tenant).import_job(job_id))
+        .await
+    {
+        Ok(job) if !job.is_empty() => job,
+        Ok(_) => {
+            return ImportResult {
+                detail: json!("Import not found"),
+                status: StatusCode::NOT_FOUND,
+            }
+        }
+        Err(e) => return handle_redis_error(e),
+    };
+
+    let count = |field: &str| {
+        job.get(field)
+            .and_then(|v| v.parse::<u64>().ok())
+            .unwrap_or(0)
+    };
+    let errors: Vec<RowError> = job
+        .get("errors")
+        .and_then(|e| serde_json::from_str(e).ok())
+        .unwrap_or_default();
+
+    ImportResult {
+        detail: json!({
+            "job_id": job_id,
+            "status": job.get("status"),
+            "total": count("total"),
+            "imported": count("imported"),
+            "failed": count("failed"),
+            "errors": errors,
+            "created_at": job.get("created_at"),
+            "finished_at": job.get("finished_at"),
+        }),
+        status: StatusCode::OK,
+    }
+}
+
+async fn run_import(
+    db: Surreal,
+    redis: Arc<Mutex<RedisClient>>,
+    tenant: String,
+    key: String,
+    rows: Vec<ParsedRow>,
+    definitions: Vec<AttributeDefinition>,
+    firebase: Option<FirebaseHashConfig>,
+) {
+    // The tenant was checked before the job started
+    let repo = UserRepository::new(&db, Ident::parse(&tenant).unwrap());
+    let (mut imported, mut failed) = (0, 0);
+    let mut errors = Vec::new();
+
+    for (i, (line, row)) in rows.into_iter().enumerate() {
+        let result = match row {
+            Ok(row) => import_row(&repo, row, &definitions, firebase.as_ref()).await,
+            Err(error) => Err(RowError {
+                line,
+                username: None,
+                error,
+            }),
+        };
+        match result {
+            Ok(()) => imported += 1,
+            Err(mut error) => {
+                failed += 1;
+                if errors.len() < MAX_REPORTED_ERRORS {
+                    error.line = line;
+                    errors.push(error);
+                }
+            }
+        }
+
+        if (i + 1) % PROGRESS_INTERVAL == 0 {
+            report(&redis, &key, "running", imported, failed, &errors).await;
+        }
+    }
+
+    report(&redis, &key, "completed", imported, failed, &errors).await;
+}
+
+/// Stores a user from an import. The line of a returned error is set by the caller.
+async fn import_row(
+    repo: &UserRepository<'_>,
+    row: ImportRow,
+    definitions: &[AttributeDefinition],
+    firebase: Option<&FirebaseHashConfig>,
+) -> Result<(), RowError> {
+    let username = messages::normalize(&row.username);
+    let error = |error: &str| RowError {
+        line: 0,
+        username: Some(username.clone()),
+        error: error.to_owned(),
+    };
+    if username.is_empty()
+        || (messages::channel_of(&username) == Channel::Email
+            && username.parse::<Address>().is_err())
+    {
+        return Err(error("Username must be a phone number or an email address"));
+    }
+
+    let hash = match (
+        row.password_hash,
+        row.hash_format.unwrap_or(HashFormat::Auto),
+    ) {
+        (None, _) => String::new(),
+        (Some(hash), HashFormat::Auto) if password::is_supported(&hash) => hash,
+        (Some(_), HashFormat::Auto) => return Err(error("Password hash format is not supported")),
+        (Some(hash), HashFormat::FirebaseScrypt) => match (firebase, &row.salt) {
+            (Some(config), Some(salt)) => password::firebase_hash(
+                &hash,
+                salt,
+                &config.salt_separator,
+                &config.signer_key,
+                config.rounds,
+                config.mem_cost,
+            ),
+            (None, _) => return Err(error("Firebase hash parameters are required")),
+            (_, None) => return Err(error("Firebase hashes need a salt")),
+        },
+    };
+    if !hash.is_empty() && !password::is_supported(&hash) {
+        return Err(error("Password hash is malformed"));
+    }
+    if let Err(failures) = attributes::validate(definitions, &row.attributes) {
+        let reasons: Vec<String> = failures
+            .iter()
+            .map(|f| format!("{}: {}", f.attribute, f.reason))
+            .collect();
+        return Err(error(&reasons.join("; ")));
+    }
+
+    match repo.find_by_identifier(&username).await {
+        Ok(None) => (),
+        Ok(Some(_)) => return Err(error("Username is already registered")),
+        Err(e) => return Err(error(&e.to_string())),
+    }
+
+    let now = Utc::now().to_rfc3339();
+    let verified_at = row.verified.then(|| now.clone());
+    let record = UserRecord {
+        id: None,
+        username: username.clone(),
+        password: hash,
+        password_history: Vec::new(),
+        identifiers: vec![Identifier {
+            value: username.clone(),
+            primary: true,
+            verified: row.verified,
+            verified_at: verified_at.clone(),
+        }],
+        verified: row.verified,
+        verified_at,
+        disabled: false,
+        attributes: row.attributes,
+        roles: Vec::new(),
+        groups: Vec::new(),
+        delete_after: None,
+        deletion_receipt: None,
+        created_at: Some(now.clone()),
+        updated_at: Some(now),
+    };
+    match repo.create(&record).await {
+        Ok(_) => Ok(()),
+        Err(e) => match attributes::taken(definitions, &e) {
+            Some(failures) => Err(error(&format!(
+                "{}: {}",
+                failures[0].attribute, failures[0].reason
+            ))),
+            None if e.status() == StatusCode::CONFLICT => {
+                Err(error("Username is already registered"))
+            }
+            None => Err(error(&e.to_string())),
+        },
+    }
+}
+
+/// Writes the progress of an import to its job.
+async fn report(
+    redis: &Mutex<RedisClient>,
+    key: &str,
+    status: &str,
+    imported: usize,
+    failed: usize,
+    errors: &[RowError],
+) {
+    let imported = imported.to_string();
+    let failed = failed.to_string();
+    let errors = json!(errors).to_string();
+    let finished_at = Utc::now().to_rfc3339();
+    let mut fields = vec![
+        ("status", status),
+        ("imported", imported.as_str()),
+        ("failed", failed.as_str()),
+        ("errors", errors.as_str()),
+    ];
+    if status != "running" {
+        fields.push(("finished_at", finished_at.as_str()));
+    }
+
+    if let Err(e) = redis.lock().await.set_key_map(key, &fields, JOB_TTL).await {
+        log::error!("Failed to record the progress of import {key}: {e}");
+    }
+}
+
+/// Reads the rows of a CSV file with a header row. Columns other than the known ones are
+/// attributes, whose text is read as their type.
+fn parse_csv(data: &str, definitions: &[AttributeDefinition]) -> Result<Vec<ParsedRow>, String> {
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(data.as_bytes());
+    let headers = reader
+        .headers()
+        .map_err(|e| format!("Failed to read the CSV header: {e}"))?
+        .clone();
+    if !headers.iter().any(|h| h == "username") {
+        return Err("The CSV header must have a username column".to_owned());
+    }
+
+    Ok(reader
+        .records()
+        .enumerate()
+        .map(|(i, record)| {
+            // Lines are counted from the header
+            let line = record
+                .as_ref()
+                .ok()
+                .and_then(|r| r.position())
+                .map_or(i + 2, |p| p.line() as usize);
+            let record = match record {
+                Ok(record) => record,
+                Err(e) => return (line, Err(format!("Malformed row: {e}"))),
+            };
+            let fields: HashMap<&str, &str> = headers.iter().zip(record.iter()).collect();
+            let text = |name: &str| {
+                fields
+                    .get(name)
+                    .filter(|v| !v.is_empty())
+                    .map(|v| v.to_string())
+            };
+            let hash_format = match text("hash_format").as_deref() {
+                None | Some("auto") => None,
+                Some("firebase_scrypt") => Some(HashFormat::FirebaseScrypt),
+                Some(_) => return (line, Err("Unknown hash_format".to_owned())),
+            };
+            let attributes = fields
+                .iter()
+                .filter(|(name, value)| !COLUMNS.contains(name) && !value.is_empty())
+                .map(|(name, value)| (name.to_string(), from_text(definitions, name, value)))
+                .collect();
+
+            let row = ImportRow {
+                username: text("username").unwrap_or_default(),
+                password_hash: text("password_hash"),
+                hash_format,
+                salt: text("salt"),
+                verified: matches!(text("verified").as_deref(), Some("true" | "1" | "yes")),
+                attributes,
+            };
+            (line, Ok(row))
+        })
+        .collect())
+}
+
+/// Reads one JSON object per line, skipping blank lines.
+fn parse_ndjson(data: &str) -> Vec<ParsedRow> {
+    data.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            let row = serde_json::from_str(line).map_err(|e| format!("Malformed row: {e}"));
+            (i + 1, row)
+        })
+        .collect()
+}
+
+/// Reads the text of a CSV field as the type of the attribute it is for. Text that does not
+/// parse is kept as text, so that the attribute's validation reports it.
+fn from_text(definitions: &[AttributeDefinition], name: &str, text: &str) -> Value {
+    let kind = definitions.iter().find(|d| d.name == name).map(|d| d.kind);
+    let parsed = match kind {
+        Some(AttributeType::Integer) => text.parse::<i64>().ok().map(Value::from),
+        Some(AttributeType::Number) => text.parse::<f64>().ok().map(Value::from),
+        Some(AttributeType::Boolean) => text.parse::<bool>().ok().map(Value::from),
+        _ => None,
+    };
+
+    parsed.unwrap_or_else(|| json!(text))
+}
+
+fn handle_redis_error(e: RedisError) -> ImportResult {
+    ImportResult {
+        detail: json!(e.detail().unwrap_or("Unknown error")),
+        status: StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+fn handle_repo_error(e: RepoError) -> ImportResult {
+    ImportResult {
+        detail: json!(e.to_string()),
+        status: e.status(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn definition(name: &str, kind: AttributeType) -> AttributeDefinition {
+        AttributeDefinition {
+            name: name.to_owned(),
+            kind,
+            required: false,
+            unique: false,
+            pattern: None,
+            min: None,
+            max: None,
+            options: Vec::new(),
+            claim: None,
+            editable: false,
+        }
+    }
+
+    #[test]
+    fn parse_csv_reads_columns_and_typed_attributes() {
+        let definitions = [
+            definition("age", AttributeType::Integer),
+            definition("member", AttributeType::Boolean),
+        ];
+        let data = "username,password_hash,hash_format,salt,verified,age,member,team\n\
+                    +15550100,hash,firebase_scrypt,c2FsdA==,yes,42,true,red\n\
+                    ann@example.com,,,,,old,,\n";
+
+        let rows = parse_csv(data, &definitions).unwrap();
+
+        assert_eq!(rows.len(), 2);
+        let (line, row) = &rows[0];
+        let row = row.as_ref().unwrap();
+        assert_eq!(*line, 2);
+        assert_eq!(row.username, "+15550100");
+        assert_eq!(row.password_hash.as_deref(), Some("hash"));
+        assert_eq!(row.hash_format, Some(HashFormat::FirebaseScrypt));
+        assert_eq!(row.salt.as_deref(), Some("c2FsdA=="));
+        assert!(row.verified);
+        assert_eq!(row.attributes.get("age"), Some(&json!(42)));
+        assert_eq!(row.attributes.get("member"), Some(&json!(true)));
+        assert_eq!(row.attributes.get("team"), Some(&json!("red")));
+
+        let (line, row) = &rows[1];
+        let row = row.as_ref().unwrap();
+        assert_eq!(*line, 3);
+        assert_eq!(row.password_hash, None);
+        assert_eq!(row.hash_format, None);
+        assert!(!row.verified);
+        // Text that is not of the attribute's type is left for validation to report
+        assert_eq!(row.attributes.get("age"), Some(&json!("old")));
+        assert!(!row.attributes.contains_key("member"));
+    }
+
+    #[test]
+    fn parse_csv_reports_bad_rows_by_line() {
+        let data = "username,hash_format\n+15550100,md5\n+15550101,auto\n";
+
+        let rows = parse_csv(data, &[]).unwrap();
+
+        assert_eq!(rows[0], (2, Err("Unknown hash_format".to_owned())));
+        assert_eq!(rows[1].0, 3);
+        assert_eq!(rows[1].1.as_ref().unwrap().hash_format, None);
+    }
+
+    #[test]
+    fn parse_csv_needs_a_username_column() {
+        assert!(parse_csv("email\nann@example.com\n", &[]).is_err());
+    }
+
+    #[test]
+    fn parse_ndjson_skips_blank_lines_and_keeps_numbering() {
+        let data = "{\"username\": \"+15550100\"}\n\n  \nnot json\n{\"username\": \"ann@example.com\", \"verified\": true}\n";
+
+        let rows = parse_ndjson(data);
+
+        assert_eq!(
+            rows.iter().map(|(line, _)| *line).collect::<Vec<_>>(),
+            [1, 4, 5]
+        );
+        assert_eq!(rows[0].1.as_ref().unwrap().username, "+15550100");
+        assert!(rows[1].1.is_err());
+        assert!(rows[2].1.as_ref().unwrap().verified);
+    }
+}
@@ -0,0 +1,693 @@
+use crate::repositories::{
+    archive::record_key,
+    clients::{Channel, ClientRecord},
+    invitations::{InvitationRecord, InvitationRepository, InvitationStatus},
+    tenants::SignupMode,
+    users::{Identifier, UserRecord, UserRepository},
+    Ident, RepoError, Surreal,
+};
+use crate::services::{
+    access, attributes,
+    messages::{self, Message, VerificationHost},
+    passwords, settings,
+};
+use crate::utils::{password, random, redis::RedisClient};
+use axum::http::StatusCode;
+use chrono::{DateTime, Duration, Utc};
+use lettre::Address;
+use reqwest::Client;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use sha2::{Digest, Sha256};
+use url::Url;
+
+const INVITATION_ID_LEN: usize = 20;
+/// Invitations last for days rather than minutes, so their typed codes are longer than others.
+const CODE_LEN: usize = 8;
+/// Links carry a longer code, since they are not typed in.
+const LINK_CODE_LEN: usize = 32;
+/// How long an invitation lasts when the request does not say, in seconds.
+const DEFAULT_EXPIRES_IN: i64 = 7 * 24 * 60 * 60;
+const MAX_EXPIRES_IN: i64 = 30 * 24 * 60 * 60;
+/// How many wrong codes revoke an invitation.
+const MAX_ATTEMPTS: u32 = 5;
+const EMAIL_SUBJECT: &str = "You have been invited";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct InvitationResult {
+    pub detail: Value,
+    pub status: StatusCode,
+}
+
+/// An invitation a tenant admin sends.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct InvitationRequest {
+    /// The registered client the invitation is accepted through.
+    pub client_id: String,
+    /// The phone number or email address to invite. It becomes the invited user's username.
+    pub identifier: String,
+    /// The roles the invited user is granted.
+    #[serde(default)]
+    pub roles: Vec<String>,
+    /// The attributes the invited user is created with, checked against the tenant's attribute
+    /// definitions.
+    #[serde(default)]
+    pub attributes: Map<String, Value>,
+    /// When given, a link to this URI carrying the code is sent instead of the bare code. It
+    /// must be one of the client's redirect URIs.
+    pub redirect_uri: Option<String>,
+    /// How long, in seconds, the invitation lasts. Defaults to 7 days.
+    pub expires_in: Option<i64>,
+}
+
+/// The answer to an invitation.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct Acceptance {
+    /// The phone number or email address the invitation was sent to.
+    pub identifier: String,
+    /// The code sent with the invitation.
+    pub code: String,
+    /// The invited user's password. Without one, the user signs in with one-time passwords or
+    /// sets a password through a reset.
+    pub password: Option<String>,
+}
+
+/// Parameters for inviting someone to a tenant.
+pub struct InvitationParams<'a> {
+    pub db: &'a Surreal,
+    pub redis: &'a mut RedisClient,
+    /// The HTTP client used to send the invitation.
+    pub req: &'a Client,
+    pub host: &'a VerificationHost<'a>,
+    /// The tenant the invitation is for.
+    pub tenant: &'a str,
+    /// The registered client named in the request.
+    pub client: &'a ClientRecord,
+    pub request: &'a InvitationRequest,
+}
+
+/// Parameters for sending an invitation again.
+pub struct ResendParams<'a> {
+    pub db: &'a Surreal,
+    pub redis: &'a mut RedisClient,
+    /// The HTTP client used to send the invitation.
+    pub req: &'a Client,
+    pub host: &'a VerificationHost<'a>,
+    pub tenant: &'a str,
+    pub invitation_id: &'a str,
+}
+
+/// Invites someone to a tenant by sending a single-use code, or a link carrying one, to their
+/// phone number or email address.
+///
+/// # Errors
+///
+/// Returns an `InvitationResult` if sign-up is closed, the identifier is already registered or
+/// already has a pending invitation, the request is invalid, or the invitation cannot be sent.
+pub async fn create_invitation(params: InvitationParams<'_>) -> InvitationResult {
+    let request = params.request;
+    if params.client.tenant != params.tenant {
+        return InvitationResult {
+            detail: json!("Unknown client_id"),
+            status: StatusCode::UNAUTHORIZED,
+        };
+    }
+    let repo = match repository(params.db, params.tenant) {
+        Ok(repo) => repo,
+        Err(e) => return handle_repo_error(e),
+    };
+    let settings = match settings::lookup(params.db, params.redis, params.tenant).await {
+        Ok(settings) => settings,
+        Err(e) => {
+            return InvitationResult {
+                detail: json!(e.to_string()),
+                status: e.status(),
+            }
+        }
+    };
+    if settings.signup == SignupMode::Closed {
+        return signup_closed();
+    }
+
+    let identifier = messages::normalize(&request.identifier);
+    let channel = messages::channel_of(&identifier);
+    if !params.client.allows_channel(channel) {
+        return InvitationResult {
+            detail: json!(format!("{channel:?} is not allowed for this client")),
+            status: StatusCode::FORBIDDEN,
+        };
+    }
+    if channel == Channel::Email && identifier.parse::<Address>().is_err() {
+        return unprocessable("Identifier must be a phone number or an email address");
+    }
+    if let Some(uri) = &request.redirect_uri {
+        if !params.client.redirect_uris.contains(uri) || Url::parse(uri).is_err() {
+            return unprocessable("redirect_uri is not registered for this client");
+        }
+    }
+    if let Err(e) = access::check_roles(params.db, params.tenant, &request.roles).await {
+        return InvitationResult {
+            detail: e.detail,
+            status: e.status,
+        };
+    }
+    let expires_in = request.expires_in.unwrap_or(DEFAULT_EXPIRES_IN);
+    if !(1..=MAX_EXPIRES_IN).contains(&expires_in) {
+        return unprocessable("expires_in must be between 1 second and 30 days");
+    }
+    if let Err(failures) = attributes::validate(&settings.attributes, &request.attributes) {
+        return InvitationResult {
+            detail: attributes::failure_detail(&failures),
+            status: StatusCode::UNPROCESSABLE_ENTITY,
+        };
+    }
+
+    match find_user(params.db, params.tenant, &identifier).await {
+        Ok(None) => (),
+        Ok(Some(_)) => return already_registered(),
+        Err(e) => return handle_repo_error(e),
+    }
+    match repo.find_pending(&identifier).await {
+        Ok(Some(pending)) if pending.current_status() == InvitationStatus::Pending => {
+            return InvitationResult {
+                detail: json!({
+                    "message": "An invitation is already pending for this identifier",
+                    "invitation_id": pending.invitation_id,
+                }),
+                status: StatusCode::CONFLICT,
+            }
+        }
+        // An expired invitation is replaced by the new one
+        Ok(Some(mut expired)) => {
+            revoke(&mut expired);
+            if let Err(e) = repo.update(&expired).await {
+                return handle_repo_error(e);
+            }
+        }
+        Ok(None) => (),
+        Err(e) => return handle_repo_error(e),
+    }
+
+    let now = Utc::now();
+    let code = new_code(request.redirect_uri.is_some());
+    let mut invitation = InvitationRecord {
+        id: None,
+        invitation_id: random::alphanumeric(INVITATION_ID_LEN),
+        identifier,
+        client_id: params.client.client_id.clone(),
+        roles: request.roles.clone(),
+        attributes: request.attributes.clone(),
+        redirect_uri: request.redirect_uri.clone(),
+        status: InvitationStatus::Pending,
+        code_hash: hash_code(&code),
+        attempts: 0,
+        expires_in,
+        expires_at: (now + Duration::seconds(expires_in)).to_rfc3339(),
+        sent_at: now.to_rfc3339(),
+        created_at: now.to_rfc3339(),
+        accepted_at: None,
+        revoked_at: None,
+    };
+    if let Err(e) = repo.create(&invitation).await {
+        return handle_repo_error(e);
+    }
+
+    let sent = send(
+        params.redis,
+        params.req,
+        params.host,
+        params.tenant,
+        &settings.display_name,
+        &invitation,
+        &code,
+    )
+    .await;
+    if let Err(e) = sent {
+        // An invitation that never arrived cannot be accepted, so it does not block a new one
+        revoke(&mut invitation);
+        if let Err(e) = repo.update(&invitation).await {
+            log::error!("Failed to revoke an unsent invitation: {e}");
+        }
+        return e;
+    }
+
+    InvitationResult {
+        detail: present(&invitation),
+        status: StatusCode::CREATED,
+    }
+}
+
+/// Sends a pending or expired invitation again with a new code, which replaces the code sent
+/// before. The invitation lasts as long as it did when it was first sent, counted from now.
+pub async fn resend_invitation(params: ResendParams<'_>) -> InvitationResult {
+    let repo = match repository(params.db, params.tenant) {
+        Ok(repo) => repo,
+        Err(e) => return handle_repo_error(e),
+    };
+    let mut invitation = match repo.find(params.invitation_id).await {
+        Ok(Some(invitation)) => invitation,
+        Ok(None) => return not_found(),
+        Err(e) => return handle_repo_error(e),
+    };
+    if invitation.status != InvitationStatus::Pending {
+        return not_pending();
+    }
+    let settings = match settings::lookup(params.db, params.redis, params.tenant).await {
+        Ok(settings) => settings,
+        Err(e) => {
+            return InvitationResult {
+                detail: json!(e.to_string()),
+                status: e.status(),
+            }
+        }
+    };
+    let now = Utc::now();
+    let sent_recently = DateTime::parse_from_rfc3339(&invitation.sent_at).is_ok_and(|t| {
+        (now - t.with_timezone(&Utc)).num_seconds() < settings.verification.resend_interval
+    });
+    if sent_recently {
+        return InvitationResult {
+            detail: json!("The invitation was sent recently, try again later"),
+            status: StatusCode::TOO_MANY_REQUESTS,
+        };
+    }
+
+    let code = new_code(invitation.redirect_uri.is_some());
+    invitation.code_hash = hash_code(&code);
+    invitation.attempts = 0;
+    invitation.sent_at = now.to_rfc3339();
+    invitation.expires_at = (now + Duration::seconds(invitation.expires_in)).to_rfc3339();
+    let invitation = match repo.update(&invitation).await {
+        Ok(Some(invitation)) => invitation,
+        Ok(None) => return not_found(),
+        Err(e) => return handle_repo_error(e),
+    };
+
+    let sent = send(
+        params.redis,
+        params.req,
+        params.host,
+        params.tenant,
+        &settings.display_name,
+        &invitation,
+        &code,
+    )
+    .await;
+    if let Err(e) = sent {
+        return e;
+    }
+
+    InvitationResult {
+        detail: present(&invitation),
+        status: StatusCode::OK,
+    }
+}
+
+/// Revokes a pending invitation, so that its code stops working.
+pub async fn revoke_invitation(
+    db: &Surreal,
+    tenant: &str,
+    invitation_id: &str,
+) -> InvitationResult {
+    let repo = match repository(db, tenant) {
+        Ok(repo) => repo,
+        Err(e) => return handle_repo_error(e),
+    };
+    let mut invitation = match repo.find(invitation_id).await {
+        Ok(Some(invitation)) => invitation,
+        Ok(None) => return not_found(),
+        Err(e) => return handle_repo_error(e),
+    };
+    if invitation.status != InvitationStatus::Pending {
+        return not_pending();
+    }
+
+    revoke(&mut invitation);
+    match repo.update(&invitation).await {
+        Ok(Some(invitation)) => InvitationResult {
+            detail: present(&invitation),
+            status: StatusCode::OK,
+        },
+        Ok(None) => not_found(),
+        Err(e) => handle_repo_error(e),
+    }
+}
+
+/// Lists the invitations of a tenant, newest first, a page at a time.
+///
+/// # Arguments
+///
+/// * `db` - The database the invitations are stored in.
+/// * `tenant` - The tenant whose invitations are listed.
+/// * `status` - Only invitations with this status. Without it, every invitation is listed.
+/// * `page` - The page to return, starting at 1.
+/// * `per_page` - How many invitations a page holds, at most 100.
+pub async fn list_invitations(
+    db: &Surreal,
+    tenant: &str,
+    status: Option<InvitationStatus>,
+    page: u32,
+    per_page: u32,
+) -> InvitationResult {
+    let page = page.max(1);
+    let per_page = per_page.clamp(1, 100);
+    let repo = match repository(db, tenant) {
+        Ok(repo) => repo,
+        Err(e) => return handle_repo_error(e),
+    };
+
+    match repo
+        .list(
+            status,
+            per_page,
+            page.saturating_sub(1).saturating_mul(per_page),
+        )
+        .await
+    {
+        Ok((invitations, total)) => InvitationResult {
+            detail: json!({
+                "invitations": invitations.iter().map(present).collect::<Vec<_>>(),
+                "page": page,
+                "per_page": per_page,
+                "total": total,
+            }),
+            status: StatusCode::OK,
+        },
+        Err(e) => handle_repo_error(e),
+    }
+}
+
+pub async fn get_invitation(db: &Surreal, tenant: &str, invitation_id: &str) -> InvitationResult {
+    let repo = match repository(db, tenant) {
+        Ok(repo) => repo,
+        Err(e) => return handle_repo_error(e),
+    };
+
+    match repo.find(invitation_id).await {
+        Ok(Some(invitation)) => InvitationResult {
+            detail: present(&invitation),
+            status: StatusCode::OK,
+        },
+        Ok(None) => not_found(),
+        Err(e) => handle_repo_error(e),
+    }
+}
+
+/// Accepts an invitation with the code sent with it, creating the invited user. Since the code
+/// proves the user controls the identifier it was sent to, the user is created verified.
+///
+/// Accepting an invitation is how users join tenants whose sign-up is by invitation only.
+///
+/// # Errors
+///
+/// Returns an `InvitationResult` if the code is wrong or the invitation is no longer pending,
+/// the password breaks the tenant's password policy, or the identifier was registered in the
+/// meantime. An invitation is revoked after too many wrong codes.
+pub async fn accept_invitation(
+    db: &Surreal,
+    redis: &mut RedisClient,
+    client: &ClientRecord,
+    acceptance: &Acceptance,
+) -> InvitationResult {
+    let repo = match repository(db, &client.tenant) {
+        Ok(repo) => repo,
+        Err(e) => return handle_repo_error(e),
+    };
+    let identifier = messages::normalize(&acceptance.identifier);
+    let mut invitation = match repo.find_pending(&identifier).await {
+        Ok(Some(invitation))
+            if invitation.client_id == client.client_id
+                && invitation.current_status() == InvitationStatus::Pending =>
+        {
+            invitation
+        }
+        Ok(_) => return invalid_code(),
+        Err(e) => return handle_repo_error(e),
+    };
+    if !password::constant_time_eq(
+        hash_code(&acceptance.code).as_bytes(),
+        invitation.code_hash.as_bytes(),
+    ) {
+        if let Err(e) = repo
+            .record_wrong_code(&invitation.invitation_id, MAX_ATTEMPTS)
+            .await
+        {
+            return handle_repo_error(e);
+        }
+        return invalid_code();
+    }
+
+    let settings = match settings::lookup(db, redis, &client.tenant).await {
+        Ok(settings) => settings,
+        Err(e) => {
+            return InvitationResult {
+                detail: json!(e.to_string()),
+                status: e.status(),
+            }
+        }
+    };
+    if settings.signup == SignupMode::Closed {
+        return signup_closed();
+    }
+    let hash = match &acceptance.password {
+        Some(new_password) => {
+            if let Err(failures) =
+                passwords::check_policy(&settings.password, new_password, None).await
+            {
+                return InvitationResult {
+                    detail: passwords::policy_detail(&failures),
+                    status: StatusCode::UNPROCESSABLE_ENTITY,
+                };
+            }
+            match password::hash(new_password).await {
+                Ok(hash) => hash,
+                Err(e) => {
+                    return InvitationResult {
+                        detail: json!(format!("Failed to store user: {e}")),
+                        status: StatusCode::INTERNAL_SERVER_ERROR,
+                    }
+                }
+            }
+        }
+        // No password can be checked against an empty hash
+        None => String::new(),
+    };
+
+    let now = Utc::now().to_rfc3339();
+    let user = UserRecord {
+        id: None,
+        username: identifier.clone(),
+        password: hash,
+        password_history: Vec::new(),
+        identifiers: vec![Identifier {
+            value: identifier.clone(),
+            primary: true,
+            verified: true,
+            verified_at: Some(now.clone()),
+        }],
+        verified: true,
+        verified_at: Some(now.clone()),
+        disabled: false,
+        attributes: invitation.attributes.clone(),
+        roles: invitation.roles.clone(),
+        groups: Vec::new(),
+        delete_after: None,
+        deletion_receipt: None,
+        created_at: Some(now.clone()),
+        updated_at: Some(now.clone()),
+    };
+    match find_user(db, &client.tenant, &identifier).await {
+        Ok(None) => (),
+        Ok(Some(_)) => return already_registered(),
+        Err(e) => return handle_repo_error(e),
+    }
+    let created = match Ident::parse(&client.tenant) {
+        Ok(tenant) => UserRepository::new(db, tenant).create(&user).await,
+        Err(e) => Err(e),
+    };
+    match created {
+        Ok(_) => (),
+        // Registered by a concurrent request, or another user holds a unique attribute
+        Err(e) if e.status() == StatusCode::CONFLICT => {
+            return match attributes::taken(&settings.attributes, &e) {
+                Some(failures) => InvitationResult {
+                    detail: attributes::failure_detail(&failures),
+                    status: StatusCode::CONFLICT,
+                },
+                None => already_registered(),
+            }
+        }
+        Err(e) => return handle_repo_error(e),
+    }
+
+    invitation.status = InvitationStatus::Accepted;
+    invitation.accepted_at = Some(now);
+    if let Err(e) = repo.update(&invitation).await {
+        log::error!(
+            "Failed to mark invitation {} as accepted: {e}",
+            invitation.invitation_id
+        );
+    }
+
+    InvitationResult {
+        detail: json!({
+            "message": "Invitation accepted",
+            "username": identifier,
+        }),
+        status: StatusCode::CREATED,
+    }
+}
+
+/// Finds the user who has an identifier, verified or not.
+async fn find_user(
+    db: &Surreal,
+    tenant: &str,
+    identifier: &str,
+) -> Result<Option<UserRecord>, RepoError> {
+    UserRepository::new(db, Ident::parse(tenant)?)
+        .find_by_identifier(identifier)
+        .await
+}
+
+/// Sends an invitation's code, or a link carrying it, to the identifier it is for.
+async fn send(
+    redis: &mut RedisClient,
+    req: &Client,
+    host: &VerificationHost<'_>,
+    tenant: &str,
+    sender: &str,
+    invitation: &InvitationRecord,
+    code: &str,
+) -> Result<(), InvitationResult> {
+    let body = match invitation.redirect_uri.as_deref().map(Url::parse) {
+        Some(Ok(mut url)) => {
+            url.query_pairs_mut()
+                .append_pair("identifier", &invitation.identifier)
+                .append_pair("code", code);
+            url.to_string()
+        }
+        _ => code.to_owned(),
+    };
+    let message = Message {
+        tenant,
+        recipient: &invitation.identifier,
+        sender,
+        subject: EMAIL_SUBJECT,
+        body: &body,
+    };
+
+    messages::send(redis, req, host, &message)
+        .await
+        .map_err(|e| InvitationResult {
+            detail: json!(e.detail),
+            status: e.status,
+        })
+}
+
+fn new_code(link: bool) -> String {
+    match link {
+        true => random::alphanumeric(LINK_CODE_LEN),
+        false => random::numeric(CODE_LEN),
+    }
+}
+
+fn hash_code(code: &str) -> String {
+    Sha256::digest(code.as_bytes())
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+fn revoke(invitation: &mut InvitationRecord) {
+    invitation.status = InvitationStatus::Revoked;
+    invitation.revoked_at = Some(Utc::now().to_rfc3339());
+}
+
+fn repository<'a>(db: &'a Surreal, tenant: &str) -> Result<InvitationRepository<'a>, RepoError> {
+    Ok(InvitationRepository::new(db, Ident::parse(tenant)?))
+}
+
+fn present(invitation: &InvitationRecord) -> Value {
+    let status = invitation.current_status();
+    let mut invitation = json!(invitation);
+    if let Some(invitation) = invitation.as_object_mut() {
+        invitation.remove("code_hash");
+        invitation.insert("status".to_owned(), json!(status));
+        if let Some(id) = invitation
+            .get("id")
+            .and_then(Value::as_str)
+            .and_then(record_key)
+        {
+            invitation.insert("id".to_owned(), json!(id));
+        }
+    }
+
+    invitation
+}
+
+fn signup_closed() -> InvitationResult {
+    InvitationResult {
+        detail: json!("Sign-up is closed"),
+        status: StatusCode::FORBIDDEN,
+    }
+}
+
+fn already_registered() -> InvitationResult {
+    InvitationResult {
+        detail: json!("Username is already registered"),
+        status: StatusCode::CONFLICT,
+    }
+}
+
+fn invalid_code() -> InvitationResult {
+    InvitationResult {
+        detail: json!("Invalid or expired code"),
+        status: StatusCode::BAD_REQUEST,
+    }
+}
+
+fn not_pending() -> InvitationResult {
+    InvitationResult {
+        detail: json!("Invitation is no longer pending"),
+        status: StatusCode::CONFLICT,
+    }
+}
+
+fn unprocessable(detail: &str) -> InvitationResult {
+    InvitationResult {
+        detail: json!(detail),
+        status: StatusCode::UNPROCESSABLE_ENTITY,
+    }
+}
+
+fn not_found() -> InvitationResult {
+    InvitationResult {
+        detail: json!("Invitation not found"),
+        status: StatusCode::NOT_FOUND,
+    }
+}
+
+fn handle_repo_error(e: RepoError) -> InvitationResult {
+    InvitationResult {
+        detail: json!(e.to_string()),
+        status: e.status(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hash_code_is_hex_sha256() {
+        assert_eq!(
+            hash_code("abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn hash_code_differs_between_codes() {
+        assert_ne!(hash_code("482913"), hash_code("482914"));
+    }
+}
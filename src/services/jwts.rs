@@ -1,38 +1,467 @@
-use crate::utils::jwt::UserClaims;
-use axum::http::{HeaderMap, HeaderValue, StatusCode};
+use crate::config::constants::{BEARER, DPOP};
+use crate::repositories::{
+    clients::{ClientRecord, ClientRepository},
+    tenants::Metric,
+    Surreal,
+};
+use crate::services::{sessions, tenants, usage};
+use crate::utils::{
+    dpop::{self, DpopError, DpopProof},
+    jwt::{self, Actor, Confirmation, UserClaims, USER_TOKEN},
+    redis::{RedisClient, TenantKeys},
+};
+use axum::http::{HeaderMap, StatusCode};
+use chrono::Utc;
 use jsonwebtoken::{decode, Algorithm, DecodingKey, Validation};
+use serde_json::{json, Value};
 
-pub async fn verify_jwt(headers: &HeaderMap, secret: &'static str) -> (StatusCode, &'static str) {
-    let auth = headers.get("Authorization");
-    let result = match auth {
-        Some(auth_header) => verify_auth_header(auth_header, secret),
-        None => (StatusCode::BAD_REQUEST, "Authorization header is required"),
-    };
+pub const TOKEN_EXCHANGE_GRANT: &str = "urn:ietf:params:oauth:grant-type:token-exchange";
+pub const ACCESS_TOKEN_TYPE: &str = "urn:ietf:params:oauth:token-type:access_token";
+pub const JWT_TOKEN_TYPE: &str = "urn:ietf:params:oauth:token-type:jwt";
 
-    result
+/// A token exchange request as described in RFC 8693, section 2.1.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TokenExchange<'a> {
+    /// The registered client making the request. Tokens are only exchanged within its tenant.
+    pub client: &'a ClientRecord,
+    pub grant_type: &'a str,
+    pub subject_token: &'a str,
+    pub subject_token_type: &'a str,
+    pub actor_token: Option<&'a str>,
+    pub actor_token_type: Option<&'a str>,
+    pub audience: Option<&'a str>,
+    pub scope: Option<&'a str>,
+    /// The thumbprint of the key of a verified DPoP proof sent with the request.
+    pub dpop_jkt: Option<&'a str>,
 }
 
-fn verify_auth_header(
-    auth_header: &HeaderValue,
-    secret: &'static str,
-) -> (StatusCode, &'static str) {
-    let auth_header_str = auth_header.to_str().unwrap_or("");
+#[derive(Clone, Debug, PartialEq)]
+pub struct IssuedToken {
+    pub access_token: String,
+    pub token_type: &'static str,
+    pub expires_in: i64,
+    pub scope: String,
+}
 
-    if !auth_header_str.starts_with("Bearer ") {
-        return (
-            StatusCode::BAD_REQUEST,
-            "Authorization header must start with Bearer",
-        );
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExchangeResult {
+    /// The OAuth error code, empty when the exchange succeeded.
+    pub error: &'static str,
+    pub detail: String,
+    pub status: StatusCode,
+    pub token: Option<IssuedToken>,
+}
+
+/// Parameters for verifying an access token presented to a protected resource.
+pub struct JwtVerification<'a> {
+    /// The headers of the request, carrying `Authorization` and, for bound tokens, `DPoP`.
+    pub headers: &'a HeaderMap,
+
+    /// The HTTP method of the protected resource request.
+    pub htm: &'a str,
+
+    /// The HTTP URI of the protected resource request.
+    pub htu: &'a str,
+
+    /// The secret used to sign access tokens.
+    pub secret: &'static str,
+}
+
+/// Verifies an access token for a gateway or resource server, returning the subject along with
+/// the scopes and groups the token carries so that the caller can authorize the request.
+pub async fn verify_jwt(
+    db: &Surreal,
+    redis: &mut RedisClient,
+    req: &JwtVerification<'_>,
+) -> (StatusCode, Value) {
+    match authenticate(db, redis, req).await {
+        Ok(claims) => (
+            StatusCode::OK,
+            json!({
+                "message": "Valid token",
+                "sub": claims.sub,
+                "tid": claims.tid,
+                "scopes": claims.scope.split_whitespace().collect::<Vec<_>>(),
+                "groups": claims.groups,
+            }),
+        ),
+        Err(e) => (e.0, json!(e.1)),
     }
+}
+
+/// Authenticates the access token of a request and returns its claims.
+///
+/// Bound tokens are only accepted with the DPoP scheme and a proof signed by the bound key.
+/// Tokens issued for a session are rejected once the session has been revoked.
+///
+/// # Errors
+///
+/// Returns the status code and detail message to respond with if the token is missing or invalid.
+pub async fn authenticate(
+    db: &Surreal,
+    redis: &mut RedisClient,
+    req: &JwtVerification<'_>,
+) -> Result<UserClaims, (StatusCode, String)> {
+    let auth = match req.headers.get("Authorization") {
+        Some(auth_header) => auth_header.to_str().unwrap_or(""),
+        None => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "Authorization header is required".to_string(),
+            ))
+        }
+    };
 
-    let token = auth_header_str.trim_start_matches("Bearer ");
+    let (scheme, token) = match auth.split_once(' ') {
+        Some((scheme, token)) if scheme == BEARER || scheme == DPOP => (scheme, token),
+        _ => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "Authorization header must start with Bearer or DPoP".to_string(),
+            ))
+        }
+    };
 
-    match decode::<UserClaims>(
+    let claims = match decode::<UserClaims>(
         token,
-        &DecodingKey::from_secret(secret.as_ref()),
+        &DecodingKey::from_secret(req.secret.as_ref()),
         &Validation::new(Algorithm::HS256),
     ) {
-        Ok(_) => (StatusCode::OK, "Valid token"),
-        Err(_) => (StatusCode::UNAUTHORIZED, "Invalid token"),
+        Ok(token_) if token_.claims.typ == USER_TOKEN => token_.claims,
+        _ => return Err((StatusCode::UNAUTHORIZED, "Invalid token".to_string())),
+    };
+
+    match (scheme, &claims.cnf) {
+        (DPOP, Some(cnf)) => {
+            let proof = match req.headers.get(DPOP).and_then(|p| p.to_str().ok()) {
+                Some(proof) => proof,
+                None => {
+                    return Err((
+                        StatusCode::UNAUTHORIZED,
+                        "DPoP proof is required".to_string(),
+                    ))
+                }
+            };
+            let tenant = claims.tid.as_deref().unwrap_or_default();
+            match check_dpop_proof(redis, tenant, proof, req.htm, req.htu, Some(token)).await {
+                Ok(proof) if proof.jkt == cnf.jkt => (),
+                Ok(_) => {
+                    return Err((
+                        StatusCode::UNAUTHORIZED,
+                        "DPoP proof key does not match the token".to_string(),
+                    ))
+                }
+                Err(e) => return Err((StatusCode::UNAUTHORIZED, e.to_string())),
+            }
+        }
+        (BEARER, None) => (),
+        _ => {
+            return Err((
+                StatusCode::UNAUTHORIZED,
+                "Authorization scheme does not match the token binding".to_string(),
+            ))
+        }
+    };
+
+    if let Some(sid) = &claims.sid {
+        // Sessions live in the tenant's keyspace, so a session without a tenant cannot exist
+        let tenant = claims.tid.as_deref().unwrap_or_default();
+        match sessions::touch_session(redis, tenant, sid).await {
+            Ok(true) => (),
+            Ok(false) => {
+                return Err((
+                    StatusCode::UNAUTHORIZED,
+                    "Session has been revoked".to_string(),
+                ))
+            }
+            Err(_) => {
+                return Err((
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "Failed to look up session".to_string(),
+                ))
+            }
+        }
+    }
+
+    if let Some(tenant) = &claims.tid {
+        match tenants::is_suspended(db, redis, tenant).await {
+            Ok(false) => (),
+            Ok(true) => return Err((StatusCode::FORBIDDEN, "Tenant is suspended".to_string())),
+            Err(_) => {
+                return Err((
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "Failed to look up tenant".to_string(),
+                ))
+            }
+        }
+    }
+
+    Ok(claims)
+}
+
+/// Verifies a DPoP proof and records its `jti` so that it cannot be replayed.
+///
+/// # Arguments
+///
+/// * `redis` - A mutable reference to a Redis client instance, used for replay protection.
+/// * `tenant` - The tenant the request is made in, whose keyspace the proof is recorded in.
+/// * `proof` - The compact JWS from the `DPoP` header.
+/// * `htm` - The HTTP method of the request.
+/// * `htu` - The HTTP URI of the request.
+/// * `access_token` - The access token sent with the proof, if any.
+///
+/// # Errors
+///
+/// Returns a `DpopError` if the proof is invalid or its `jti` has been seen before.
+pub async fn check_dpop_proof(
+    redis: &mut RedisClient,
+    tenant: &str,
+    proof: &str,
+    htm: &str,
+    htu: &str,
+    access_token: Option<&str>,
+) -> Result<DpopProof, DpopError> {
+    let proof = dpop::verify_proof(proof, htm, htu, access_token)?;
+
+    match redis
+        .set_key_nx(
+            &TenantKeys::new(tenant).dpop_proof(&proof.jkt, &proof.claims.jti),
+            "1",
+            dpop::PROOF_LIFETIME + dpop::CLOCK_SKEW,
+        )
+        .await
+    {
+        Ok(true) => Ok(proof),
+        Ok(false) => Err(DpopError::Replayed),
+        Err(_) => Err(DpopError::Unavailable),
+    }
+}
+
+/// Exchanges a subject token, and optionally an actor token, for a new access token.
+///
+/// The issued token never carries more scopes than the subject token and never outlives it.
+/// When an actor token is given, the actor becomes the outermost entry of the `act` claim and
+/// any actor already present on the subject token is kept as the prior link of the chain.
+/// Without an actor token the new token impersonates the subject. If the request came with a
+/// DPoP proof, the issued token is bound to the proof's key.
+///
+/// Both tokens must belong to the requesting client's tenant, their sessions must still be live
+/// and bound tokens must come with a proof signed by their key. The audience must be a client of
+/// the same tenant.
+///
+/// # Arguments
+///
+/// * `db` - The database holding the client registry, used to look up the audience.
+/// * `redis` - A mutable reference to a Redis client instance, used to check the tokens' sessions.
+/// * `req` - The token exchange request parameters.
+///
+/// # Errors
+///
+/// Returns an `ExchangeResult` carrying an RFC 8693 error code if the grant type is not
+/// supported, a token cannot be validated, the audience is unknown or the requested scopes
+/// exceed the subject's scopes.
+pub async fn exchange_token(
+    db: &Surreal,
+    redis: &mut RedisClient,
+    req: &TokenExchange<'_>,
+) -> ExchangeResult {
+    if req.grant_type != TOKEN_EXCHANGE_GRANT {
+        return exchange_error("unsupported_grant_type", "Unsupported grant type");
+    }
+    if !is_supported_token_type(req.subject_token_type) {
+        return exchange_error("invalid_request", "Unsupported subject_token_type");
+    }
+
+    let tenant = req.client.tenant.as_str();
+    let subject = match jwt::verify(req.subject_token).await {
+        Ok(claims) => claims,
+        Err(_) => return exchange_error("invalid_grant", "Invalid subject token"),
+    };
+    if subject.tid.as_deref() != Some(tenant) {
+        return exchange_error("invalid_grant", "Subject token belongs to another tenant");
+    }
+    if let Err(detail) = check_holder(redis, &subject, req.dpop_jkt, "Subject").await {
+        return exchange_error("invalid_grant", &detail);
+    }
+    if tenants::is_suspended(db, redis, tenant)
+        .await
+        .unwrap_or(true)
+    {
+        return exchange_error("invalid_grant", "Tenant is suspended");
+    }
+    if let Err(e) = usage::check_quota(redis, tenant, Metric::TokensIssued).await {
+        return ExchangeResult {
+            error: "invalid_request",
+            detail: e.to_string(),
+            status: e.status(),
+            token: None,
+        };
+    }
+
+    let actor = match (req.actor_token, req.actor_token_type) {
+        (Some(token), Some(token_type)) => {
+            if !is_supported_token_type(token_type) {
+                return exchange_error("invalid_request", "Unsupported actor_token_type");
+            }
+            let actor = match jwt::verify(token).await {
+                Ok(claims) => claims,
+                Err(_) => return exchange_error("invalid_grant", "Invalid actor token"),
+            };
+            if actor.tid.as_deref() != Some(tenant) {
+                return exchange_error("invalid_grant", "Actor token belongs to another tenant");
+            }
+            if let Err(detail) = check_holder(redis, &actor, req.dpop_jkt, "Actor").await {
+                return exchange_error("invalid_grant", &detail);
+            }
+            Some(actor)
+        }
+        (None, None) => None,
+        _ => {
+            return exchange_error(
+                "invalid_request",
+                "actor_token and actor_token_type must be given together",
+            )
+        }
+    };
+
+    let scope = match req.scope {
+        Some(requested) => match downscope(&subject.scope, requested) {
+            Some(scope) => scope,
+            None => {
+                return exchange_error("invalid_scope", "Requested scope exceeds subject scope")
+            }
+        },
+        None => subject.scope.clone(),
+    };
+
+    let audience = match req.audience {
+        Some(aud) if aud.trim().is_empty() => {
+            return exchange_error("invalid_target", "Audience must not be empty")
+        }
+        Some(aud) => match ClientRepository::new(db).find(aud).await {
+            Ok(Some(client)) if client.tenant == tenant => client.client_id,
+            Ok(_) => {
+                return exchange_error("invalid_target", "Audience is not a client of the tenant")
+            }
+            Err(e) => {
+                return ExchangeResult {
+                    error: "server_error",
+                    detail: e.to_string(),
+                    status: StatusCode::INTERNAL_SERVER_ERROR,
+                    token: None,
+                }
+            }
+        },
+        None => subject.aud.clone(),
+    };
+
+    let mut claims = UserClaims::new(subject.sub.clone(), audience).await;
+    claims.exp = claims.exp.min(subject.exp);
+    claims.tid = subject.tid.clone();
+    claims.sid = subject.sid.clone();
+    claims.scope = scope.clone();
+    claims.groups = subject.groups.clone();
+    claims.profile = subject.profile.clone();
+    claims.act = match actor {
+        Some(actor) => Some(Actor {
+            sub: actor.sub,
+            act: subject.act.map(Box::new),
+        }),
+        None => subject.act,
+    };
+    claims.cnf = req.dpop_jkt.map(|jkt| Confirmation {
+        jkt: jkt.to_owned(),
+    });
+
+    match jwt::encode(&claims).await {
+        Ok(access_token) => {
+            usage::record(redis, tenant, Metric::TokensIssued).await;
+            ExchangeResult {
+                error: "",
+                detail: "Token issued".to_owned(),
+                status: StatusCode::OK,
+                token: Some(IssuedToken {
+                    access_token,
+                    token_type: if claims.cnf.is_some() { DPOP } else { BEARER },
+                    expires_in: claims.exp - Utc::now().timestamp(),
+                    scope,
+                }),
+            }
+        }
+        Err(e) => ExchangeResult {
+            error: "server_error",
+            detail: format!("Failed to sign token: {e}"),
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+            token: None,
+        },
+    }
+}
+
+/// Checks that a token presented for exchange is still in use by its holder: its session, if it
+/// has one, has not been revoked, and a bound token comes with a proof signed by its key.
+async fn check_holder(
+    redis: &mut RedisClient,
+    claims: &UserClaims,
+    dpop_jkt: Option<&str>,
+    kind: &str,
+) -> Result<(), String> {
+    if let Some(sid) = &claims.sid {
+        let tenant = claims.tid.as_deref().unwrap_or_default();
+        if !sessions::touch_session(redis, tenant, sid)
+            .await
+            .unwrap_or(false)
+        {
+            return Err(format!("{kind} token session has been revoked"));
+        }
+    }
+    match &claims.cnf {
+        Some(cnf) if dpop_jkt != Some(cnf.jkt.as_str()) => {
+            Err(format!("{kind} token requires a DPoP proof"))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn is_supported_token_type(token_type: &str) -> bool {
+    token_type == ACCESS_TOKEN_TYPE || token_type == JWT_TOKEN_TYPE
+}
+
+/// Returns the requested scopes if every one of them is granted by `granted`.
+fn downscope(granted: &str, requested: &str) -> Option<String> {
+    let granted: Vec<&str> = granted.split_whitespace().collect();
+    let requested: Vec<&str> = requested.split_whitespace().collect();
+
+    if requested.is_empty() || !requested.iter().all(|s| granted.contains(s)) {
+        return None;
+    }
+
+    Some(requested.join(" "))
+}
+
+fn exchange_error(error: &'static str, detail: &str) -> ExchangeResult {
+    ExchangeResult {
+        error,
+        detail: detail.to_owned(),
+        status: StatusCode::BAD_REQUEST,
+        token: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn downscope_keeps_requested_subset() {
+        assert_eq!(
+            downscope("user documents:read documents:write", "documents:read"),
+            Some("documents:read".to_owned())
+        );
+    }
+
+    #[test]
+    fn downscope_refuses_scopes_beyond_the_subject() {
+        assert_eq!(downscope("user", "user platform:admin"), None);
+        assert_eq!(downscope("user", " "), None);
     }
 }
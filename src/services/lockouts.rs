@@ -0,0 +1,244 @@
+use crate::repositories::tenants::LockoutPolicy;
+use crate::utils::redis::{RedisClient, TenantKeys};
+use chrono::Utc;
+use lazy_static::lazy_static;
+use redis::{RedisError, Script};
+use std::collections::HashMap;
+
+const COUNT: &str = "count";
+const LAST_AT: &str = "last_at";
+const LOCKED_UNTIL: &str = "locked_until";
+
+/// How long, in seconds, an IP address an account signed in from stays known.
+const KNOWN_IP_TTL: i64 = 30 * 86_400;
+
+lazy_static! {
+    /// Counts a failure in the hash at `KEYS[1]`, given the time `ARGV[1]`, the limit `ARGV[2]`,
+    /// the time to live `ARGV[3]` and the lockout duration `ARGV[4]`. Returns 1 if it locked.
+    static ref COUNT_FAILURE: Script = Script::new(
+        r#"
+        local count = redis.call('HINCRBY', KEYS[1], 'count', 1)
+        redis.call('HSET', KEYS[1], 'last_at', ARGV[1])
+        redis.call('EXPIRE', KEYS[1], ARGV[3])
+        local max = tonumber(ARGV[2])
+        if max == 0 or count < max then
+            return 0
+        end
+        redis.call('HSET', KEYS[1], 'count', 0, 'locked_until', ARGV[1] + ARGV[4])
+        return 1
+        "#
+    );
+}
+
+/// Why a login was refused before its password was checked.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Throttle {
+    /// The account failed too recently, and must wait before trying again.
+    Backoff { retry_after: i64 },
+    /// The account or IP address failed too often, and is locked.
+    Locked { retry_after: i64 },
+}
+
+impl Throttle {
+    /// How many seconds to wait before trying again.
+    pub fn retry_after(&self) -> i64 {
+        match self {
+            Throttle::Backoff { retry_after } | Throttle::Locked { retry_after } => *retry_after,
+        }
+    }
+}
+
+/// Checks whether a password login for an account, from an IP address, may be attempted.
+///
+/// Returns the throttle that applies, if any. Accounts are locked or backed off, IP addresses
+/// are only locked. An account's backoff does not apply to the IP addresses it recently signed
+/// in from, so that failures from elsewhere slow its owner down less; its lock always applies.
+pub async fn check(
+    redis: &mut RedisClient,
+    tenant: &str,
+    policy: &LockoutPolicy,
+    username: &str,
+    ip: &str,
+) -> Result<Option<Throttle>, RedisError> {
+    let keys = TenantKeys::new(tenant);
+    let now = Utc::now().timestamp();
+
+    if !ip.is_empty() {
+        let failures = redis.get_hash(&keys.ip_login_failures(ip)).await?;
+        if let Some(until) = field(&failures, LOCKED_UNTIL).filter(|until| *until > now) {
+            return Ok(Some(Throttle::Locked {
+                retry_after: until - now,
+            }));
+        }
+    }
+
+    let failures = redis.get_hash(&keys.login_failures(username)).await?;
+    if let Some(until) = field(&failures, LOCKED_UNTIL).filter(|until| *until > now) {
+        return Ok(Some(Throttle::Locked {
+            retry_after: until - now,
+        }));
+    }
+    let count = field(&failures, COUNT).unwrap_or(0);
+    let last_at = field(&failures, LAST_AT).unwrap_or(0);
+    let known = !ip.is_empty() && redis.is_member(&keys.known_login_ips(username), ip).await?;
+    if policy.backoff_base > 0 && count > 0 && !known {
+        let wait = backoff(policy, count);
+        if now < last_at + wait {
+            return Ok(Some(Throttle::Backoff {
+                retry_after: last_at + wait - now,
+            }));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Counts a failed password login against an account and an IP address, locking either when it
+/// has failed too often.
+///
+/// Returns whether the account was locked by this failure.
+pub async fn record_failure(
+    redis: &mut RedisClient,
+    tenant: &str,
+    policy: &LockoutPolicy,
+    username: &str,
+    ip: &str,
+) -> Result<bool, RedisError> {
+    let keys = TenantKeys::new(tenant);
+    if !ip.is_empty() {
+        count_failure(
+            redis,
+            policy,
+            &keys.ip_login_failures(ip),
+            policy.max_ip_attempts,
+        )
+        .await?;
+    }
+
+    count_failure(
+        redis,
+        policy,
+        &keys.login_failures(username),
+        policy.max_attempts,
+    )
+    .await
+}
+
+/// Forgets the failed logins of an account after a successful login, and remembers the IP
+/// address it came from.
+pub async fn record_success(
+    redis: &mut RedisClient,
+    tenant: &str,
+    username: &str,
+    ip: &str,
+) -> Result<(), RedisError> {
+    clear(redis, tenant, username).await?;
+    if ip.is_empty() {
+        return Ok(());
+    }
+
+    redis
+        .add_member(
+            &TenantKeys::new(tenant).known_login_ips(username),
+            ip,
+            Some(KNOWN_IP_TTL),
+        )
+        .await
+}
+
+/// Forgets the failed logins of an account, as when an admin unlocks it or its password
+/// changes.
+pub async fn clear(
+    redis: &mut RedisClient,
+    tenant: &str,
+    username: &str,
+) -> Result<(), RedisError> {
+    redis
+        .del_key(&TenantKeys::new(tenant).login_failures(username))
+        .await
+}
+
+/// Counts a failure in the hash at `key`. Once `max` failures are counted, the count starts over
+/// and the hash is locked for the lockout duration. Returns whether it was locked.
+///
+/// The count is read and reset in a single script, so that concurrent failures cannot slip past
+/// the limit between the two.
+async fn count_failure(
+    redis: &mut RedisClient,
+    policy: &LockoutPolicy,
+    key: &str,
+    max: u32,
+) -> Result<bool, RedisError> {
+    let now = Utc::now().timestamp();
+    // Failures are kept for as long as a lockout lasts, even when the window is shorter
+    let ttl = policy.window.max(policy.lockout_duration);
+
+    let locked: i64 = redis
+        .run_script(
+            &COUNT_FAILURE,
+            &[key],
+            &[now, i64::from(max), ttl, policy.lockout_duration],
+        )
+        .await?;
+
+    Ok(locked == 1)
+}
+
+/// Returns how long to wait after `count` failures.
+fn backoff(policy: &LockoutPolicy, count: i64) -> i64 {
+    let doublings = count.saturating_sub(1).clamp(0, 30) as u32;
+
+    policy
+        .backoff_base
+        .saturating_mul(1 << doublings)
+        .min(policy.backoff_max)
+}
+
+/// Forgets everything kept about an account's logins, including where it signed in from.
+pub async fn forget(
+    redis: &mut RedisClient,
+    tenant: &str,
+    username: &str,
+) -> Result<(), RedisError> {
+    clear(redis, tenant, username).await?;
+    redis
+        .del_key(&TenantKeys::new(tenant).known_login_ips(username))
+        .await
+}
+
+fn field(hash: &HashMap<String, String>, name: &str) -> Option<i64> {
+    hash.get(name).and_then(|v| v.parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_with_each_failure() {
+        let policy = LockoutPolicy::default();
+
+        let waits: Vec<i64> = (1..=4).map(|count| backoff(&policy, count)).collect();
+
+        assert_eq!(waits, vec![1, 2, 4, 8]);
+    }
+
+    #[test]
+    fn backoff_is_capped() {
+        let policy = LockoutPolicy::default();
+
+        assert_eq!(backoff(&policy, 7), policy.backoff_max);
+        assert_eq!(backoff(&policy, i64::MAX), policy.backoff_max);
+    }
+
+    #[test]
+    fn backoff_does_not_overflow_large_bases() {
+        let policy = LockoutPolicy {
+            backoff_base: i64::MAX / 2,
+            backoff_max: i64::MAX,
+            ..LockoutPolicy::default()
+        };
+
+        assert_eq!(backoff(&policy, 40), i64::MAX);
+    }
+}
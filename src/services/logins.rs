@@ -0,0 +1,383 @@
+use crate::repositories::{
+    clients::ClientRecord, factors::FactorRepository, tenants::TenantSettings,
+    users::UserRepository, Ident, RepoError, Surreal,
+};
+use crate::services::{
+    access, attributes,
+    lockouts::{self, Throttle},
+    messages::{self, Message, VerificationHost},
+    sessions::{self, SessionInfo},
+    settings, users,
+};
+use crate::utils::{
+    random,
+    redis::{RedisClient, TenantKeys},
+};
+use axum::http::StatusCode;
+use redis::RedisError;
+use reqwest::Client;
+use serde_json::{json, Value};
+
+const CHALLENGE_LEN: usize = 32;
+const CODE_LEN: usize = 6;
+const EMAIL_SUBJECT: &str = "Your sign-in code";
+const LOCKED_SUBJECT: &str = "Your account was locked";
+const INVALID_CREDENTIALS: &str = "Invalid username or password";
+const INVALID_CHALLENGE: &str = "Invalid or expired code";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct LoginResult {
+    pub detail: Value,
+    pub status: StatusCode,
+}
+
+/// The outcome of a successful step of a login.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Login {
+    /// The user is signed in with the given access token.
+    Token(String),
+    /// The user has a second factor enrolled, and a code was sent to it. The login is finished by
+    /// confirming the challenge with that code.
+    Challenge {
+        challenge: String,
+        recipient: String,
+    },
+}
+
+/// Parameters for signing a user in with a username and password.
+pub struct LoginParams<'a> {
+    pub db: &'a Surreal,
+    pub redis: &'a mut RedisClient,
+    /// The HTTP client used to send second-factor codes.
+    pub req: &'a Client,
+    /// The services second-factor codes are sent through.
+    pub host: &'a VerificationHost<'a>,
+    /// The registered client the user signs in through. The user is looked up in its tenant.
+    pub client: &'a ClientRecord,
+    pub username: &'a str,
+    pub password: &'a str,
+    /// The thumbprint of a verified DPoP proof key the token is bound to, if any.
+    pub jkt: Option<String>,
+    /// Details about the client, recorded on the session.
+    pub info: &'a SessionInfo,
+}
+
+/// Signs a user in with a username, or another of their verified identifiers, and password.
+/// Whichever identifier is used, the token is issued for the user's username.
+///
+/// Unknown users and wrong passwords are rejected alike. Failures are counted per account and per
+/// IP address, which are backed off and locked as the tenant's lockout policy sets. Users that
+/// have not been verified are refused, unless the tenant lets them sign in. When the user has
+/// enrolled a second factor, a code is sent to it and a challenge is returned instead of a token.
+///
+/// # Errors
+///
+/// Returns a `LoginResult` if the login is throttled, the credentials are wrong, the user is not
+/// verified, the tenant's quotas are exhausted, or the code or token cannot be issued.
+pub async fn login(mut params: LoginParams<'_>) -> Result<Login, LoginResult> {
+    let tenant = &params.client.tenant;
+    let settings = match settings::lookup(params.db, params.redis, tenant).await {
+        Ok(settings) => settings,
+        Err(e) => return Err(error(e.status(), &e.to_string())),
+    };
+    let username = messages::normalize(params.username);
+    let user = users::find_account(params.db, tenant, &username)
+        .await
+        .map_err(handle_repo_error)?;
+
+    // Unknown usernames are throttled too, so that throttling does not reveal which exist
+    let account = user
+        .as_ref()
+        .map_or(username.clone(), |u| u.username.clone());
+    let ip = &params.info.ip;
+    match lockouts::check(params.redis, tenant, &settings.lockout, &account, ip).await {
+        Ok(None) => (),
+        Ok(Some(throttle)) => return Err(throttled(throttle)),
+        Err(e) => return Err(handle_redis_error(e)),
+    }
+
+    let valid = users::verify_password(params.db, tenant, user.as_ref(), params.password)
+        .await
+        .map_err(|e| LoginResult {
+            detail: e.detail,
+            status: e.status,
+        })?;
+    let exists = user.is_some();
+    let user = match (valid, user) {
+        (true, Some(user)) => user,
+        _ => {
+            let locked =
+                lockouts::record_failure(params.redis, tenant, &settings.lockout, &account, ip)
+                    .await
+                    .map_err(handle_redis_error)?;
+            if locked && settings.lockout.notify && exists {
+                notify_locked(&mut params, &settings, &account).await;
+            }
+            return Err(unauthorized(INVALID_CREDENTIALS));
+        }
+    };
+    if let Err(e) = lockouts::record_success(params.redis, tenant, &user.username, ip).await {
+        log::error!(
+            "Failed to clear the failed logins of {}: {e}",
+            user.username
+        );
+    }
+
+    if user.disabled {
+        return Err(error(StatusCode::FORBIDDEN, "Account is disabled"));
+    }
+    let identifier = user.identifier(&username);
+    if !identifier.is_some_and(|i| i.verified) && settings.verification.required_for_login {
+        return Err(error(StatusCode::FORBIDDEN, "Account is not verified"));
+    }
+    if let Err(e) = sessions::check_sign_in(params.redis, params.client, &user.username).await {
+        return Err(error(e.status(), &e.to_string()));
+    }
+
+    let ident = Ident::parse(tenant).map_err(handle_repo_error)?;
+    let factor = FactorRepository::new(params.db, ident)
+        .find_by_username(&user.username)
+        .await
+        .map_err(handle_repo_error)?;
+    let factor = match factor {
+        Some(factor) => factor,
+        None => {
+            let grants = access::grants(params.db, tenant, &user)
+                .await
+                .map_err(handle_repo_error)?;
+            return sessions::sign_in(
+                params.redis,
+                params.client,
+                &user.username,
+                params.jkt,
+                params.info,
+                attributes::claims(&settings.attributes, &user.attributes),
+                grants,
+            )
+            .await
+            .map(Login::Token)
+            .map_err(|e| LoginResult {
+                detail: e.detail,
+                status: e.status,
+            });
+        }
+    };
+    if !params.client.allows_channel(factor.channel) {
+        return Err(error(
+            StatusCode::FORBIDDEN,
+            &format!("{:?} is not allowed for this client", factor.channel),
+        ));
+    }
+
+    let challenge = random::alphanumeric(CHALLENGE_LEN);
+    let code = random::numeric(CODE_LEN);
+    params
+        .redis
+        .set_key_map(
+            &TenantKeys::new(tenant).login_challenge(&challenge),
+            &[
+                ("username", user.username.as_str()),
+                ("client_id", params.client.client_id.as_str()),
+                ("code", code.as_str()),
+                ("attempts", "0"),
+            ],
+            params.client.otp_ttl,
+        )
+        .await
+        .map_err(handle_redis_error)?;
+
+    let message = Message {
+        tenant,
+        recipient: &factor.recipient,
+        sender: &settings.display_name,
+        subject: EMAIL_SUBJECT,
+        body: &code,
+    };
+    if let Err(e) = messages::send(params.redis, params.req, params.host, &message).await {
+        return Err(error(e.status, &e.detail));
+    }
+
+    Ok(Login::Challenge {
+        challenge,
+        recipient: mask(&factor.recipient),
+    })
+}
+
+/// Finishes a login that was challenged for a second factor.
+///
+/// # Arguments
+///
+/// * `db` - The database the user is stored in.
+/// * `redis` - A mutable reference to a Redis client instance.
+/// * `client` - The registered client the login was started through.
+/// * `challenge` - The challenge returned when the login was started.
+/// * `code` - The code sent to the user's second factor.
+/// * `jkt` - The thumbprint of a verified DPoP proof key the token is bound to, if any.
+/// * `info` - Details about the client, recorded on the session.
+///
+/// # Errors
+///
+/// Returns a `LoginResult` if the challenge is unknown, expired or belongs to another client, the
+/// code is wrong, or the token cannot be issued. A challenge is dropped after too many wrong
+/// codes.
+pub async fn confirm_login(
+    db: &Surreal,
+    redis: &mut RedisClient,
+    client: &ClientRecord,
+    challenge: &str,
+    code: &str,
+    jkt: Option<String>,
+    info: &SessionInfo,
+) -> Result<String, LoginResult> {
+    let key = TenantKeys::new(&client.tenant).login_challenge(challenge);
+    let entry = messages::check_code(redis, &key, code)
+        .await
+        .map_err(handle_redis_error)?
+        .unwrap_or_default();
+    let username = match (entry.get("username"), entry.get("client_id")) {
+        (Some(username), Some(client_id)) if *client_id == client.client_id => username,
+        _ => return Err(unauthorized(INVALID_CHALLENGE)),
+    };
+
+    if let Err(e) = sessions::check_sign_in(redis, client, username).await {
+        return Err(error(e.status(), &e.to_string()));
+    }
+    // Delete the challenge to prevent reuse
+    redis.del_key(&key).await.map_err(handle_redis_error)?;
+
+    let settings = match settings::lookup(db, redis, &client.tenant).await {
+        Ok(settings) => settings,
+        Err(e) => return Err(error(e.status(), &e.to_string())),
+    };
+    let ident = Ident::parse(&client.tenant).map_err(handle_repo_error)?;
+    let user = UserRepository::new(db, ident)
+        .find_by_username(username)
+        .await
+        .map_err(handle_repo_error)?;
+    let user = match user {
+        Some(user) => user,
+        None => return Err(unauthorized(INVALID_CHALLENGE)),
+    };
+    let profile = attributes::claims(&settings.attributes, &user.attributes);
+    let grants = access::grants(db, &client.tenant, &user)
+        .await
+        .map_err(handle_repo_error)?;
+
+    sessions::sign_in(redis, client, username, jkt, info, profile, grants)
+        .await
+        .map_err(|e| LoginResult {
+            detail: e.detail,
+            status: e.status,
+        })
+}
+
+/// Tells a user that their account was locked. Failures to send are only logged, since the login
+/// is refused either way.
+async fn notify_locked(params: &mut LoginParams<'_>, settings: &TenantSettings, username: &str) {
+    let minutes = (settings.lockout.lockout_duration + 59) / 60;
+    let body = format!(
+        "Sign-in to your {} account was locked for {minutes} minutes after too many failed \
+         attempts. If this was not you, reset your password.",
+        settings.display_name
+    );
+    let message = Message {
+        tenant: &params.client.tenant,
+        recipient: username,
+        sender: &settings.display_name,
+        subject: LOCKED_SUBJECT,
+        body: &body,
+    };
+    if let Err(e) = messages::send(params.redis, params.req, params.host, &message).await {
+        log::error!(
+            "Failed to notify a user of {} of a lockout: {}",
+            params.client.tenant,
+            e.detail
+        );
+    }
+}
+
+fn throttled(throttle: Throttle) -> LoginResult {
+    let message = match throttle {
+        Throttle::Backoff { .. } => "Too many failed attempts, try again shortly",
+        Throttle::Locked { .. } => "Too many failed attempts, sign-in is locked",
+    };
+
+    LoginResult {
+        detail: json!({
+            "message": message,
+            "retry_after": throttle.retry_after(),
+        }),
+        status: StatusCode::TOO_MANY_REQUESTS,
+    }
+}
+
+/// Hides most of a phone number or email address, so that the user can recognize where a code
+/// was sent without the response revealing it.
+fn mask(recipient: &str) -> String {
+    match recipient.split_once('@') {
+        Some((local, domain)) => {
+            let first: String = local.chars().take(1).collect();
+            format!("{first}***@{domain}")
+        }
+        None => {
+            let count = recipient.chars().count();
+            let last: String = recipient.chars().skip(count.saturating_sub(2)).collect();
+            format!("***{last}")
+        }
+    }
+}
+
+fn unauthorized(detail: &str) -> LoginResult {
+    error(StatusCode::UNAUTHORIZED, detail)
+}
+
+fn error(status: StatusCode, detail: &str) -> LoginResult {
+    LoginResult {
+        detail: json!(detail),
+        status,
+    }
+}
+
+fn handle_redis_error(e: RedisError) -> LoginResult {
+    error(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        e.detail().unwrap_or("Unknown error"),
+    )
+}
+
+fn handle_repo_error(e: RepoError) -> LoginResult {
+    error(e.status(), &e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mask_hides_most_of_the_recipient() {
+        assert_eq!(mask("ann@example.com"), "a***@example.com");
+        assert_eq!(mask("+12025550130"), "***30");
+        assert_eq!(mask("7"), "***7");
+    }
+
+    #[test]
+    fn throttled_logins_say_when_to_retry() {
+        assert_eq!(
+            throttled(Throttle::Backoff { retry_after: 4 }),
+            LoginResult {
+                detail: json!({
+                    "message": "Too many failed attempts, try again shortly",
+                    "retry_after": 4,
+                }),
+                status: StatusCode::TOO_MANY_REQUESTS,
+            }
+        );
+        assert_eq!(
+            throttled(Throttle::Locked { retry_after: 840 }).detail,
+            json!({
+                "message": "Too many failed attempts, sign-in is locked",
+                "retry_after": 840,
+            })
+        );
+    }
+}
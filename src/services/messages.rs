@@ -0,0 +1,241 @@
+use crate::repositories::{clients::Channel, tenants::Metric};
+use crate::services::usage;
+use crate::utils::{
+    mailer::{self, Mailer},
+    redis::RedisClient,
+};
+use axum::http::StatusCode;
+use lazy_static::lazy_static;
+use lettre::message::Mailbox;
+use redis::RedisError;
+use regex::Regex;
+use reqwest::Client;
+use std::collections::HashMap;
+
+/// How many wrong attempts a code accepts before it is dropped.
+const MAX_CODE_ATTEMPTS: i64 = 5;
+
+lazy_static! {
+    /// The form of phone numbers.
+    static ref PHONE_NUMBER: Regex = Regex::new(
+        r#"^(\+\d{1,3})?[-\s.]?(\(\d{1,3}\)|\d{1,3})[-\s.]?(\d{3,4})[-\s.]?(\d{4})$"#
+    )
+    .unwrap();
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct MessageResult {
+    pub detail: String,
+    pub status: StatusCode,
+}
+
+/// The services that deliver messages to users.
+#[derive(Clone, Debug, PartialEq)]
+pub struct VerificationHost<'a> {
+    pub sms: &'a str,
+    pub smtp: &'a str,
+    pub smtp_port: &'a u16,
+    pub smtp_user: &'a str,
+    pub smtp_pass: &'a str,
+}
+
+/// A message carrying a one-time code.
+pub struct Message<'a> {
+    /// The tenant the message is sent for, whose quotas and usage it counts against.
+    pub tenant: &'a str,
+    pub recipient: &'a str,
+    /// The name the message is sent under, usually the tenant's display name.
+    pub sender: &'a str,
+    /// The subject of the message when it is sent by email.
+    pub subject: &'a str,
+    pub body: &'a str,
+}
+
+/// Checks if the given string is a valid phone number based on the provided format.
+///
+/// The phone number must match one of the following patterns:
+/// - +X-YYY-ZZZ-ZZZZ: country code followed by hyphenated area code and phone number
+/// - +XX-YYY-ZZZ-ZZZZ: country code followed by hyphenated area code and phone number
+/// - +XXX-YYY-ZZZ-ZZZZ: country code followed by hyphenated area code and phone number
+/// - (YYY)ZZZ-ZZZZ: area code in parentheses followed by hyphenated phone number
+/// - YYY-ZZZ-ZZZZ: hyphenated area code and phone number
+/// - YYYYYYYYYY: 10-digit phone number with no separators
+///
+/// # Arguments
+///
+/// * `s` - A string slice that contains the phone number to check.
+///
+/// # Examples
+///
+/// ```
+/// assert!(is_phone_number("+639123456789"));
+/// assert!(is_phone_number("+1-202-555-0130"));
+/// assert!(is_phone_number("0919123456789"));
+/// assert!(is_phone_number("202-555-0130"));
+/// assert!(!is_phone_number("123-456-789")); // invalid format
+/// assert!(!is_phone_number("12345")); // too short
+/// assert!(!is_phone_number("1234567890123456")); // too long
+/// ```
+fn is_phone_number(s: &str) -> bool {
+    PHONE_NUMBER.is_match(s)
+}
+
+/// Returns the channel messages to a recipient are sent through: SMS for phone numbers and email
+/// for everything else.
+pub fn channel_of(recipient: &str) -> Channel {
+    match is_phone_number(recipient) {
+        true => Channel::Sms,
+        false => Channel::Email,
+    }
+}
+
+/// Normalizes a phone number or email address, so that the same one is always stored and looked
+/// up the same way. Separators are removed from phone numbers, and email addresses are
+/// lowercased.
+pub fn normalize(recipient: &str) -> String {
+    let recipient = recipient.trim();
+    match channel_of(recipient) {
+        Channel::Sms => recipient
+            .chars()
+            .filter(|c| !matches!(c, ' ' | '-' | '.' | '(' | ')'))
+            .collect(),
+        Channel::Email => recipient.to_lowercase(),
+    }
+}
+
+/// Sends a message by SMS or email, depending on the recipient, and counts it against the
+/// tenant's usage.
+///
+/// # Errors
+///
+/// Returns a `MessageResult` if the tenant's quota for the channel is exhausted, the recipient is
+/// not a phone number or an email address, or the message cannot be delivered.
+pub async fn send(
+    redis: &mut RedisClient,
+    req: &Client,
+    host: &VerificationHost<'_>,
+    message: &Message<'_>,
+) -> Result<(), MessageResult> {
+    let metric = match channel_of(message.recipient) {
+        Channel::Sms => Metric::OtpSms,
+        Channel::Email => Metric::OtpEmail,
+    };
+    if let Err(e) = usage::check_quota(redis, message.tenant, metric).await {
+        return Err(MessageResult {
+            detail: e.to_string(),
+            status: e.status(),
+        });
+    }
+
+    match metric {
+        Metric::OtpSms => send_sms(req, host, message).await?,
+        _ => send_email(host, message).await?,
+    }
+    usage::record(redis, message.tenant, metric).await;
+
+    Ok(())
+}
+
+/// Checks a code against the one stored in the `code` field of the hash at `key`.
+///
+/// Returns the hash when the code matches, and `None` when there is no code or it does not
+/// match. Wrong codes are counted in the hash's `attempts` field, and the hash is dropped after
+/// too many of them.
+pub async fn check_code(
+    redis: &mut RedisClient,
+    key: &str,
+    code: &str,
+) -> Result<Option<HashMap<String, String>>, RedisError> {
+    let entry = redis.get_hash(key).await?;
+    match entry.get("code") {
+        Some(stored) if stored == code => return Ok(Some(entry)),
+        Some(_) => (),
+        None => return Ok(None),
+    }
+
+    let attempts = entry
+        .get("attempts")
+        .and_then(|a| a.parse::<i64>().ok())
+        .unwrap_or(0)
+        + 1;
+    match attempts >= MAX_CODE_ATTEMPTS {
+        true => redis.del_key(key).await?,
+        false => {
+            redis
+                .set_hash_field(key, "attempts", &attempts.to_string())
+                .await?
+        }
+    }
+
+    Ok(None)
+}
+
+async fn send_sms(
+    req: &Client,
+    host: &VerificationHost<'_>,
+    message: &Message<'_>,
+) -> Result<(), MessageResult> {
+    let mut map = HashMap::new();
+    map.insert("recipient", message.recipient);
+    map.insert("content", message.body);
+
+    match req
+        .post(format!("{}/messages", host.sms))
+        .json(&map)
+        .send()
+        .await
+    {
+        Ok(_) => Ok(()),
+        Err(e) => Err(MessageResult {
+            detail: format!("Failed to send SMS: {e}"),
+            status: StatusCode::BAD_GATEWAY,
+        }),
+    }
+}
+
+async fn send_email(
+    host: &VerificationHost<'_>,
+    message: &Message<'_>,
+) -> Result<(), MessageResult> {
+    let email = match message.recipient.parse() {
+        Ok(email) => email,
+        Err(_) => {
+            return Err(MessageResult {
+                detail: "Recipient must be a phone number or an email address".to_owned(),
+                status: StatusCode::UNPROCESSABLE_ENTITY,
+            })
+        }
+    };
+
+    match mailer::send_mail(
+        mailer::EnvelopeContent {
+            from: Mailbox {
+                name: Some(message.sender.to_owned()),
+                email: host.smtp_user.parse().unwrap(),
+            },
+            to: Mailbox {
+                name: Some("User".to_owned()),
+                email,
+            },
+            subject: message.subject.to_owned(),
+            body: message.body.to_owned(),
+        },
+        Mailer {
+            host_addr: host.smtp,
+            username: host.smtp_user.to_string(),
+            password: host.smtp_pass.to_string(),
+        },
+    )
+    .await
+    {
+        Ok(resp) if resp.is_positive() => Ok(()),
+        Ok(resp) => Err(MessageResult {
+            detail: format!("Failed to send email: {}", resp.code()),
+            status: StatusCode::BAD_GATEWAY,
+        }),
+        Err(e) => Err(MessageResult {
+            detail: format!("Failed to send email: {e}"),
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+        }),
+    }
+}
@@ -1,4 +1,20 @@
+pub mod access;
+pub mod accounts;
+pub mod archive;
+pub mod attributes;
+pub mod clients;
+pub mod deletions;
+pub mod imports;
+pub mod invitations;
 pub mod jwts;
+pub mod lockouts;
+pub mod logins;
+pub mod messages;
 pub mod otps;
+pub mod passwords;
+pub mod profiles;
+pub mod sessions;
+pub mod settings;
 pub mod tenants;
+pub mod usage;
 pub mod users;
@@ -1,8 +1,29 @@
-use crate::utils::{jwt, redis::RedisClient, topt};
+use crate::repositories::{
+    clients::{Channel, ClientRecord},
+    Surreal,
+};
+use crate::services::{
+    access::{self, Grants},
+    attributes,
+    messages::{self, Message, VerificationHost},
+    sessions::{self, SessionInfo},
+    settings,
+    usage::QuotaError,
+    users,
+};
+use crate::utils::{
+    random,
+    redis::{RedisClient, TenantKeys},
+};
 use axum::http::StatusCode;
 use redis::{ErrorKind, RedisError};
 use reqwest::Client;
-use std::collections::HashMap;
+use serde_json::Map;
+
+const RECIPIENT: &str = "recipient";
+const CLIENT_ID: &str = "client_id";
+const CHALLENGE_LEN: usize = 32;
+const CODE_LEN: usize = 6;
 
 #[derive(Clone, Debug, PartialEq, Default)]
 pub struct OtpResult {
@@ -10,126 +31,247 @@ pub struct OtpResult {
     pub status: StatusCode,
 }
 
-/// Verify an OTP and return the associated phone number.
+/// Verify an OTP and issue an access token for the phone number it was sent to.
+///
+/// When the phone number the challenge was sent to is the username or a verified identifier of a
+/// user, the token is issued for that user's username. The phone number is only resolved once the
+/// challenge and its code have been checked and the challenge is spent. Disabled users are
+/// refused, and so are users the phone number is not verified for, unless the tenant lets them
+/// sign in.
 ///
 /// # Arguments
 ///
+/// * `db` - The database users are stored in.
 /// * `redis` - A mutable reference to a Redis client instance.
+/// * `challenge` - The challenge returned when the OTP was sent.
 /// * `otp` - The OTP to verify.
+/// * `client` - The registered client the OTP was requested for.
+/// * `jkt` - The thumbprint of a verified DPoP proof key. When given, the issued token is bound
+///   to that key.
+/// * `info` - Details about the client, recorded on the session created for the token.
 ///
 /// # Returns
 ///
-/// Returns the phone number associated with the given OTP, if it is valid. If the OTP is invalid
-/// or has expired, returns a `OtpResult`.
+/// Returns an `OtpResult` carrying the access token, if the OTP is valid. If the OTP is invalid,
+/// has expired or does not belong to the challenge, the result is `404 Not Found`. A challenge is
+/// dropped after too many wrong codes.
 ///
 /// # Errors
 ///
-/// Returns a `OtpResult` if an error occurs while communicating with the Redis server or if the
-/// JWT signing operation fails.
+/// Returns a `OtpResult` if the user is disabled or not verified, an error occurs while
+/// communicating with the Redis server or if the JWT signing operation fails.
 ///
 /// # Examples
 ///
 /// ```
-/// # use myapp::RedisClient;
-/// # use myapp::verify_otp;
+/// # use myapp::{verify_otp, ClientRecord, RedisClient, SessionInfo, Surreal};
 /// #
-/// # async fn example() -> Result<(), Box<dyn std::error::Error>> {
-/// let mut redis = RedisClient::connect("redis://localhost").await?;
+/// # async fn example(db: &Surreal, redis: &mut RedisClient, client: &ClientRecord, challenge: &str) {
+/// let result = verify_otp(db, redis, challenge, "123456", client, None, &SessionInfo::default()).await;
 ///
-/// let otp = "123456".to_string();
-/// let phone_number = verify_otp(&mut redis, &otp).await?;
-///
-/// println!("Phone number: {}", phone_number);
-/// # Ok(())
+/// if result.status == http::StatusCode::OK {
+///     println!("Access token: {}", result.detail);
+/// }
 /// # }
 /// ```
-pub async fn verify_otp(redis: &mut RedisClient, otp: &str) -> OtpResult {
-    let domain = match redis.get_key(otp).await {
-        Ok(domain) => domain,
+pub async fn verify_otp(
+    db: &Surreal,
+    redis: &mut RedisClient,
+    challenge: &str,
+    otp: &str,
+    client: &ClientRecord,
+    jkt: Option<String>,
+    info: &SessionInfo,
+) -> OtpResult {
+    let key = TenantKeys::new(&client.tenant).otp(challenge);
+    let entry = match messages::check_code(redis, &key, otp).await {
+        Ok(entry) => entry.unwrap_or_default(),
         Err(e) => return handle_redis_error(e),
     };
+    let phone_number = match (entry.get(RECIPIENT), entry.get(CLIENT_ID)) {
+        (Some(phone_number), Some(client_id)) if *client_id == client.client_id => {
+            phone_number.to_owned()
+        }
+        _ => {
+            return OtpResult {
+                detail: "Invalid or expired OTP".to_owned(),
+                status: StatusCode::NOT_FOUND,
+            }
+        }
+    };
 
-    let phone_number = match redis.get_key(otp).await {
-        Ok(phone_number) => phone_number,
-        Err(e) => return handle_redis_error(e),
+    // Delete OTP to prevent reuse. The challenge is spent before the phone number is resolved to
+    // an account, so that an OTP only ever signs in the one recipient it was sent to, once.
+    if let Err(e) = redis.del_key(&key).await {
+        return handle_redis_error(e);
+    }
+
+    // A phone number that belongs to a user signs in as that user
+    let (sub, profile, grants) = match users::find_account(db, &client.tenant, &phone_number).await
+    {
+        Ok(Some(user)) if user.disabled => {
+            return OtpResult {
+                detail: "Account is disabled".to_owned(),
+                status: StatusCode::FORBIDDEN,
+            }
+        }
+        Ok(Some(user)) => {
+            let settings = match settings::lookup(db, redis, &client.tenant).await {
+                Ok(settings) => settings,
+                Err(e) => {
+                    return OtpResult {
+                        detail: e.to_string(),
+                        status: e.status(),
+                    }
+                }
+            };
+            let identifier = user.identifier(&phone_number);
+            if !identifier.is_some_and(|i| i.verified) && settings.verification.required_for_login {
+                return OtpResult {
+                    detail: "Account is not verified".to_owned(),
+                    status: StatusCode::FORBIDDEN,
+                };
+            }
+            let grants = match access::grants(db, &client.tenant, &user).await {
+                Ok(grants) => grants,
+                Err(e) => {
+                    return OtpResult {
+                        detail: e.to_string(),
+                        status: e.status(),
+                    }
+                }
+            };
+            (
+                user.username,
+                attributes::claims(&settings.attributes, &user.attributes),
+                grants,
+            )
+        }
+        Ok(None) => (phone_number, Map::new(), Grants::default()),
+        Err(e) => {
+            return OtpResult {
+                detail: e.to_string(),
+                status: e.status(),
+            }
+        }
     };
-    let token = jwt::sign(phone_number, domain).await.unwrap();
 
-    // Delete OTP to prevent reuse
-    redis.del_key(otp).await.unwrap();
+    if let Err(e) = sessions::check_sign_in(redis, client, &sub).await {
+        return handle_quota_error(e);
+    }
+
+    let token = match sessions::sign_in(redis, client, &sub, jkt, info, profile, grants).await {
+        Ok(token) => token,
+        Err(e) => {
+            return OtpResult {
+                detail: e.detail.as_str().unwrap_or_default().to_owned(),
+                status: e.status,
+            }
+        }
+    };
 
     OtpResult {
         detail: token,
         status: StatusCode::OK,
     }
 }
+
 /// Generates a one-time password (OTP) and sends it to the user's phone number via SMS.
 ///
+/// Each OTP is stored under a random challenge, which is returned in the result's detail and must
+/// be presented along with the OTP to verify it.
+///
 /// # Arguments
 ///
 /// * `redis` - A mutable reference to a `RedisClient` for storing the OTP.
 /// * `phone_number` - A reference to a `String` containing the user's phone number.
-/// * `sms_host` - A reference to a `String` containing the URL of the SMS API endpoint.
+/// * `client` - The registered client the OTP is requested for. It must allow the SMS channel.
 /// * `req` - A `Client` for sending HTTP requests to the SMS API endpoint.
-/// * `secret_key` - A reference to a `String` containing the secret key for generating the OTP.
+/// * `host` - The services that deliver the OTP.
 ///
 /// # Errors
 ///
-/// Returns a `OtpResult` if there is an error generating the JWT, sending the SMS, or adding the token to Redis.
+/// Returns a `OtpResult` if the phone number is invalid, the tenant's SMS quota is exhausted, or
+/// there is an error sending the SMS or adding the OTP to Redis.
 ///
 /// # Example
 ///
 /// ```
 /// let mut redis = RedisClient::new("redis://localhost").await?;
 /// let phone_number = "+1234567890".to_owned();
-/// let sms_host = "https://example.com/sms".to_owned();
 /// let req = reqwest::Client::new();
-/// let secret_key = "mysecretkey".to_owned();
-/// authorize_user(&mut redis, &phone_number, &sms_host, req, &secret_key).await?;
+/// let challenge = authorize_user(&mut redis, &phone_number, &client, &req, &host).await.detail;
 /// ```
 pub async fn authorize_user(
     redis: &mut RedisClient,
     phone_number: &String,
-    domain: &String,
-    sms_host: &String,
-    req: Client,
-    secret_key: &String,
+    client: &ClientRecord,
+    req: &Client,
+    host: &VerificationHost<'_>,
 ) -> OtpResult {
+    if !client.allows_channel(Channel::Sms) {
+        return OtpResult {
+            detail: "SMS is not allowed for this client".to_owned(),
+            status: StatusCode::FORBIDDEN,
+        };
+    }
+    if messages::channel_of(phone_number) != Channel::Sms {
+        return OtpResult {
+            detail: "Invalid phone number".to_owned(),
+            status: StatusCode::UNPROCESSABLE_ENTITY,
+        };
+    }
+
     // Generate OTP
-    let otp = match topt::generate_token(secret_key).await {
-        Ok(otp) => otp,
-        Err(e) => return handle_generic_error(e, "Failed to generate OTP"),
-    };
+    let challenge = random::alphanumeric(CHALLENGE_LEN);
+    let otp = random::numeric(CODE_LEN);
 
     // Add token to redis
     match redis
-        .set_key_map(&otp, &[(phone_number.to_owned(), domain.to_owned())])
+        .set_key_map(
+            &TenantKeys::new(&client.tenant).otp(&challenge),
+            &[
+                (RECIPIENT.to_owned(), phone_number.to_owned()),
+                (CLIENT_ID.to_owned(), client.client_id.clone()),
+                ("code".to_owned(), otp.clone()),
+                ("attempts".to_owned(), "0".to_owned()),
+            ],
+            client.otp_ttl,
+        )
         .await
     {
         Ok(_) => (),
         Err(e) => return handle_redis_error(e),
     };
 
-    // Send SMS
-    let mut map = HashMap::new();
-    map.insert("recipient", phone_number);
-    map.insert("content", &otp);
-    match req
-        .post(format!("{sms_host}/messages"))
-        .json(&map)
-        .send()
-        .await
-    {
-        Ok(_) => (),
-        Err(e) => return handle_reqwest_error(e),
+    // Send SMS. SMS messages carry neither a sender nor a subject.
+    let message = Message {
+        tenant: &client.tenant,
+        recipient: phone_number,
+        sender: &client.tenant,
+        subject: "",
+        body: &otp,
     };
+    if let Err(e) = messages::send(redis, req, host, &message).await {
+        return OtpResult {
+            detail: e.detail,
+            status: e.status,
+        };
+    }
 
     OtpResult {
-        detail: "OTP sent".to_owned(),
+        detail: challenge,
         status: StatusCode::OK,
     }
 }
 
+fn handle_quota_error(e: QuotaError) -> OtpResult {
+    OtpResult {
+        detail: e.to_string(),
+        status: e.status(),
+    }
+}
+
 /// Convert a RedisError into a OtpResult.
 ///
 /// # Arguments
@@ -186,46 +328,3 @@ fn handle_redis_error(e: RedisError) -> OtpResult {
 
     OtpResult { detail, status }
 }
-
-/// Returns a `OtpResult` with a bad gateway status and a detail message
-/// containing information about the Reqwest error that occurred.
-///
-/// # Arguments
-///
-/// * `e` - The `reqwest::Error` that occurred.
-///
-/// # Returns
-///
-/// A `OtpResult` with a bad gateway status and a detail message
-/// containing information about the Reqwest error that occurred.
-///
-/// # Example
-///
-/// ```
-/// let e = reqwest::Error::new(std::io::Error::new(std::io::ErrorKind::Other, "Custom error"));
-/// let service_error = handle_reqwest_error(e);
-/// assert_eq!(service_error.status, StatusCode::BAD_GATEWAY);
-/// assert_eq!(service_error.detail, "Reqwest error: error sending request for `http://example.com`: Custom error");
-/// ```
-fn handle_reqwest_error(e: reqwest::Error) -> OtpResult {
-    OtpResult {
-        detail: format!("Reqwest error: {e}"),
-        status: StatusCode::BAD_GATEWAY,
-    }
-}
-
-/// Maps an error of a boxed trait object that implements the `std::error::Error` trait to a `OtpResult` type.
-///
-/// # Arguments
-///
-/// * `e` - A boxed trait object that implements the `std::error::Error` trait.
-///
-/// # Returns
-///
-/// A `OtpResult` struct that contains the error message and status code.
-fn handle_generic_error(e: Box<dyn std::error::Error>, title: &'static str) -> OtpResult {
-    OtpResult {
-        detail: format!("{title}: {e}"),
-        status: StatusCode::INTERNAL_SERVER_ERROR,
-    }
-}
@@ -0,0 +1,659 @@
+use crate::repositories::{
+    clients::{Channel, ClientRecord},
+    tenants::PasswordPolicy,
+    users::{UserRecord, UserRepository},
+    Ident, RepoError, Surreal,
+};
+use crate::services::{
+    messages::{self, Message, VerificationHost},
+    sessions, settings, users,
+};
+use crate::utils::{
+    password, random,
+    redis::{RedisClient, TenantKeys},
+};
+use axum::http::StatusCode;
+use chrono::Utc;
+use lettre::Address;
+use redis::RedisError;
+use reqwest::Client;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use url::Url;
+
+const CODE_LEN: usize = 6;
+/// Links carry a longer code, since they are not typed in.
+const LINK_CODE_LEN: usize = 32;
+const EMAIL_SUBJECT: &str = "Reset your password";
+const RESET_SENT: &str = "If the account exists, a reset code has been sent";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PasswordResult {
+    pub detail: Value,
+    pub status: StatusCode,
+}
+
+/// A rule of the password policy that a password breaks.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct PolicyFailure {
+    /// The setting of the policy the rule comes from, such as `min_length`.
+    pub rule: &'static str,
+    pub reason: String,
+}
+
+/// Parameters for sending a user a password reset code.
+pub struct ResetRequestParams<'a> {
+    pub db: &'a Surreal,
+    /// The code is sent in the background, which locks the client itself.
+    pub redis: Arc<Mutex<RedisClient>>,
+    /// The HTTP client used to send the code.
+    pub req: &'a Client,
+    pub host: &'a VerificationHost<'a>,
+    /// The registered client the user resets their password through.
+    pub client: &'a ClientRecord,
+    pub username: &'a str,
+    /// When given, a link to this URI carrying the code is sent instead of the bare code. It
+    /// must be one of the client's redirect URIs.
+    pub redirect_uri: Option<&'a str>,
+}
+
+/// Checks a new password against a tenant's password policy.
+///
+/// # Arguments
+///
+/// * `policy` - The tenant's password policy.
+/// * `password` - The new password.
+/// * `user` - The user whose password is changed, if they exist yet. Their current and earlier
+///   passwords are checked against the policy's history.
+///
+/// # Errors
+///
+/// Returns every rule the password breaks.
+pub async fn check_policy(
+    policy: &PasswordPolicy,
+    password: &str,
+    user: Option<&UserRecord>,
+) -> Result<(), Vec<PolicyFailure>> {
+    let mut failures = Vec::new();
+    let mut fail = |rule, reason: String| failures.push(PolicyFailure { rule, reason });
+
+    let len = password.chars().count();
+    if len < policy.min_length {
+        fail(
+            "min_length",
+            format!("Password must be at least {} characters", policy.min_length),
+        );
+    }
+    if len > policy.max_length {
+        fail(
+            "max_length",
+            format!("Password must be at most {} characters", policy.max_length),
+        );
+    }
+    let classes = [
+        (
+            policy.require_lowercase,
+            "require_lowercase",
+            "a lowercase letter",
+            char::is_lowercase as fn(char) -> bool,
+        ),
+        (
+            policy.require_uppercase,
+            "require_uppercase",
+            "an uppercase letter",
+            char::is_uppercase,
+        ),
+        (
+            policy.require_digit,
+            "require_digit",
+            "a digit",
+            |c: char| c.is_ascii_digit(),
+        ),
+        (
+            policy.require_symbol,
+            "require_symbol",
+            "a symbol",
+            |c: char| !c.is_alphanumeric() && !c.is_whitespace(),
+        ),
+    ];
+    for (required, rule, class, matches) in classes {
+        if required && !password.chars().any(matches) {
+            fail(rule, format!("Password must contain {class}"));
+        }
+    }
+
+    if policy.reject_breached {
+        match password::is_breached(password).await {
+            Ok(true) => fail(
+                "reject_breached",
+                "Password has appeared in a data breach".to_owned(),
+            ),
+            Ok(false) => (),
+            Err(e) => log::error!("Failed to check the breached-password corpus: {e}"),
+        }
+    }
+
+    if let Some(user) = user {
+        for hash in previous_passwords(policy, user) {
+            match password::verify(password, Some(hash)).await {
+                Ok(verification) if verification.valid => {
+                    fail(
+                        "history",
+                        format!(
+                            "Password must differ from the last {} passwords",
+                            policy.history
+                        ),
+                    );
+                    break;
+                }
+                Ok(_) => (),
+                Err(e) => log::error!("Failed to check the password history: {e}"),
+            }
+        }
+    }
+
+    match failures.is_empty() {
+        true => Ok(()),
+        false => Err(failures),
+    }
+}
+
+/// Returns the detail to respond with when a password breaks the policy.
+pub fn policy_detail(failures: &[PolicyFailure]) -> Value {
+    json!({
+        "message": "Password does not meet the password policy",
+        "failures": failures,
+    })
+}
+
+/// Returns the password history to keep once a user's current password is replaced.
+pub fn next_history(policy: &PasswordPolicy, user: &UserRecord) -> Vec<String> {
+    previous_passwords(policy, user).cloned().collect()
+}
+
+/// The user's current password followed by their earlier ones, as many as the policy checks.
+fn previous_passwords<'a>(
+    policy: &PasswordPolicy,
+    user: &'a UserRecord,
+) -> impl Iterator<Item = &'a String> {
+    std::iter::once(&user.password)
+        .chain(user.password_history.iter())
+        .take(policy.history)
+}
+
+/// Sends a single-use password reset code, or a link carrying one, to a user's username.
+///
+/// The response is the same whether or not the user exists, so that it does not reveal which
+/// usernames are registered. The user is looked up and the code sent in the background once the
+/// request is checked, so that the time taken does not reveal it either, and failures are logged
+/// rather than returned. A new code replaces any code sent before, and codes are not sent more
+/// often than the tenant's resend interval allows.
+pub async fn request_reset(params: ResetRequestParams<'_>) -> PasswordResult {
+    let (username, link) =
+        match check_reset_request(params.client, params.username, params.redirect_uri) {
+            Ok(checked) => checked,
+            Err(result) => return result,
+        };
+
+    let db = params.db.clone();
+    let redis = params.redis;
+    let req = params.req.clone();
+    let client = params.client.clone();
+    let (sms, smtp, smtp_port, smtp_user, smtp_pass) = (
+        params.host.sms.to_owned(),
+        params.host.smtp.to_owned(),
+        *params.host.smtp_port,
+        params.host.smtp_user.to_owned(),
+        params.host.smtp_pass.to_owned(),
+    );
+    tokio::spawn(async move {
+        let host = VerificationHost {
+            sms: &sms,
+            smtp: &smtp,
+            smtp_port: &smtp_port,
+            smtp_user: &smtp_user,
+            smtp_pass: &smtp_pass,
+        };
+        let mut redis = redis.lock().await;
+        if let Err(e) = send_reset(&db, &mut redis, &req, &host, &client, &username, link).await {
+            log::error!(
+                "Failed to send a password reset code for {}: {}",
+                client.tenant,
+                e.detail
+            );
+        }
+    });
+
+    reset_sent()
+}
+
+/// Checks a password reset request without looking up the user. Returns the normalized
+/// username and the link to send, if any.
+fn check_reset_request(
+    client: &ClientRecord,
+    username: &str,
+    redirect_uri: Option<&str>,
+) -> Result<(String, Option<Url>), PasswordResult> {
+    let username = messages::normalize(username);
+    let channel = messages::channel_of(&username);
+    if !client.allows_channel(channel) {
+        return Err(PasswordResult {
+            detail: json!(format!("{channel:?} is not allowed for this client")),
+            status: StatusCode::FORBIDDEN,
+        });
+    }
+    if channel == Channel::Email && username.parse::<Address>().is_err() {
+        return Err(PasswordResult {
+            detail: json!("Username must be a phone number or an email address".to_owned()),
+            status: StatusCode::UNPROCESSABLE_ENTITY,
+        });
+    }
+    let link = match redirect_uri {
+        Some(uri) if client.redirect_uris.iter().any(|u| u == uri) => match Url::parse(uri) {
+            Ok(url) => Some(url),
+            Err(_) => return Err(invalid_redirect_uri()),
+        },
+        Some(_) => return Err(invalid_redirect_uri()),
+        None => None,
+    };
+
+    Ok((username, link))
+}
+
+/// Sends a reset code to a user, if they exist and no code was sent too recently.
+async fn send_reset(
+    db: &Surreal,
+    redis: &mut RedisClient,
+    req: &Client,
+    host: &VerificationHost<'_>,
+    client: &ClientRecord,
+    username: &str,
+    link: Option<Url>,
+) -> Result<(), PasswordResult> {
+    match users::find_account(db, &client.tenant, username).await {
+        Ok(Some(_)) => (),
+        Ok(None) => return Ok(()),
+        Err(e) => return Err(handle_repo_error(e)),
+    }
+    let settings = settings::lookup(db, redis, &client.tenant)
+        .await
+        .map_err(|e| PasswordResult {
+            detail: json!(e.to_string()),
+            status: e.status(),
+        })?;
+
+    let key = TenantKeys::new(&client.tenant).password_reset(username);
+    let now = Utc::now().timestamp();
+    let sent_at = match redis.get_hash(&key).await {
+        Ok(entry) => entry.get("sent_at").and_then(|t| t.parse::<i64>().ok()),
+        Err(e) => return Err(handle_redis_error(e)),
+    };
+    if sent_at.is_some_and(|t| now - t < settings.verification.resend_interval) {
+        return Ok(());
+    }
+
+    // Replace any earlier code
+    let code = match link {
+        Some(_) => random::alphanumeric(LINK_CODE_LEN),
+        None => random::numeric(CODE_LEN),
+    };
+    redis.del_key(&key).await.map_err(handle_redis_error)?;
+    redis
+        .set_key_map(
+            &key,
+            &[
+                ("code", code.as_str()),
+                ("client_id", client.client_id.as_str()),
+                ("attempts", "0"),
+                ("sent_at", now.to_string().as_str()),
+            ],
+            client.otp_ttl,
+        )
+        .await
+        .map_err(handle_redis_error)?;
+
+    let body = match link {
+        Some(mut url) => {
+            url.query_pairs_mut()
+                .append_pair("username", username)
+                .append_pair("code", &code);
+            url.to_string()
+        }
+        None => code,
+    };
+    let message = Message {
+        tenant: &client.tenant,
+        recipient: username,
+        sender: &settings.display_name,
+        subject: EMAIL_SUBJECT,
+        body: &body,
+    };
+    messages::send(redis, req, host, &message)
+        .await
+        .map(|_| ())
+        .map_err(|e| PasswordResult {
+            detail: json!(e.detail),
+            status: e.status,
+        })
+}
+
+/// Sets a new password with a reset code, then revokes all of the user's sessions so that
+/// tokens issued before the reset stop working.
+///
+/// Since the code proves the user controls their username, an unverified user is verified too.
+///
+/// # Arguments
+///
+/// * `db` - The database the user is stored in.
+/// * `redis` - A mutable reference to a Redis client instance.
+/// * `client` - The registered client the code was sent for.
+/// * `username` - The user whose password is reset.
+/// * `code` - The code that was sent to the username.
+/// * `new_password` - The new password. It must pass the password policy.
+///
+/// # Errors
+///
+/// Returns a `PasswordResult` if the password is refused, the code is wrong, has expired or was
+/// replaced by a newer one, or the user cannot be updated. A code is dropped after too many
+/// wrong attempts.
+pub async fn reset_password(
+    db: &Surreal,
+    redis: &mut RedisClient,
+    client: &ClientRecord,
+    username: &str,
+    code: &str,
+    new_password: &str,
+) -> PasswordResult {
+    let tenant = match Ident::parse(&client.tenant) {
+        Ok(tenant) => tenant,
+        Err(e) => return handle_repo_error(e),
+    };
+
+    let username = &messages::normalize(username);
+    let key = TenantKeys::new(&client.tenant).password_reset(username);
+    match messages::check_code(redis, &key, code).await {
+        Ok(Some(entry)) if entry.get("client_id") == Some(&client.client_id) => (),
+        Ok(_) => {
+            return PasswordResult {
+                detail: json!("Invalid or expired code"),
+                status: StatusCode::BAD_REQUEST,
+            }
+        }
+        Err(e) => return handle_redis_error(e),
+    }
+
+    let repo = UserRepository::new(db, tenant);
+    let mut user = match users::find_account(db, &client.tenant, username).await {
+        Ok(Some(user)) => user,
+        Ok(None) => {
+            return PasswordResult {
+                detail: json!("Invalid or expired code"),
+                status: StatusCode::BAD_REQUEST,
+            }
+        }
+        Err(e) => return handle_repo_error(e),
+    };
+    let settings = match settings::lookup(db, redis, &client.tenant).await {
+        Ok(settings) => settings,
+        Err(e) => {
+            return PasswordResult {
+                detail: json!(e.to_string()),
+                status: e.status(),
+            }
+        }
+    };
+    // The code stays valid, so that another password can be tried
+    if let Err(failures) = check_policy(&settings.password, new_password, Some(&user)).await {
+        return PasswordResult {
+            detail: policy_detail(&failures),
+            status: StatusCode::UNPROCESSABLE_ENTITY,
+        };
+    }
+
+    // Delete the code to prevent reuse
+    if let Err(e) = redis.del_key(&key).await {
+        return handle_redis_error(e);
+    }
+
+    let hash = match password::hash(new_password).await {
+        Ok(hash) => hash,
+        Err(e) => {
+            return PasswordResult {
+                detail: json!(e.to_string()),
+                status: StatusCode::INTERNAL_SERVER_ERROR,
+            }
+        }
+    };
+    let history = next_history(&settings.password, &user);
+    if let Err(e) = repo.change_password(&user.username, &hash, &history).await {
+        return handle_repo_error(e);
+    }
+    if user.identifier(username).is_some_and(|i| !i.verified) {
+        user.verify_identifier(username);
+        if let Err(e) = repo.save_identifiers(&user).await {
+            log::error!("Failed to verify {username} after a password reset: {e}");
+        }
+    }
+
+    let revoked = sessions::revoke_sessions(redis, &client.tenant, &user.username).await;
+    if revoked.status != StatusCode::OK {
+        return PasswordResult {
+            detail: json!(format!(
+                "Password was reset, but sessions could not be revoked: {}",
+                revoked.detail
+            )),
+            status: revoked.status,
+        };
+    }
+
+    PasswordResult {
+        detail: json!("Password reset"),
+        status: StatusCode::OK,
+    }
+}
+
+fn reset_sent() -> PasswordResult {
+    PasswordResult {
+        detail: json!(RESET_SENT.to_owned()),
+        status: StatusCode::ACCEPTED,
+    }
+}
+
+fn invalid_redirect_uri() -> PasswordResult {
+    PasswordResult {
+        detail: json!("redirect_uri is not registered for this client".to_owned()),
+        status: StatusCode::UNPROCESSABLE_ENTITY,
+    }
+}
+
+fn handle_redis_error(e: RedisError) -> PasswordResult {
+    PasswordResult {
+        detail: json!(e.detail().unwrap_or("Unknown error")),
+        status: StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+fn handle_repo_error(e: RepoError) -> PasswordResult {
+    PasswordResult {
+        detail: json!(e.to_string()),
+        status: e.status(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client() -> ClientRecord {
+        ClientRecord {
+            client_id: "app".to_owned(),
+            tenant: "acme".to_owned(),
+            name: "App".to_owned(),
+            allowed_origins: Vec::new(),
+            redirect_uris: vec!["https://app.example.com/reset".to_owned()],
+            access_token_ttl: 3600,
+            otp_ttl: 300,
+            allowed_channels: vec![Channel::Email],
+            created_at: String::new(),
+            updated_at: String::new(),
+        }
+    }
+
+    fn policy() -> PasswordPolicy {
+        PasswordPolicy {
+            reject_breached: false,
+            ..PasswordPolicy::default()
+        }
+    }
+
+    fn user(password: String, password_history: Vec<String>) -> UserRecord {
+        UserRecord {
+            id: None,
+            username: "ann@example.com".to_owned(),
+            password,
+            password_history,
+            identifiers: Vec::new(),
+            verified: true,
+            verified_at: None,
+            disabled: false,
+            attributes: Default::default(),
+            roles: Vec::new(),
+            groups: Vec::new(),
+            delete_after: None,
+            deletion_receipt: None,
+            created_at: None,
+            updated_at: None,
+        }
+    }
+
+    fn rules(result: Result<(), Vec<PolicyFailure>>) -> Vec<&'static str> {
+        result
+            .err()
+            .unwrap_or_default()
+            .iter()
+            .map(|f| f.rule)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn check_policy_enforces_length() {
+        let policy = PasswordPolicy {
+            min_length: 8,
+            max_length: 12,
+            ..policy()
+        };
+
+        assert_eq!(
+            rules(check_policy(&policy, "short", None).await),
+            ["min_length"]
+        );
+        assert_eq!(
+            rules(check_policy(&policy, "far-too-long-password", None).await),
+            ["max_length"]
+        );
+        // Length is counted in characters, not bytes
+        assert!(check_policy(&policy, "pässwörd", None).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn check_policy_reports_every_missing_class() {
+        let policy = PasswordPolicy {
+            require_lowercase: true,
+            require_uppercase: true,
+            require_digit: true,
+            require_symbol: true,
+            ..policy()
+        };
+
+        assert_eq!(
+            rules(check_policy(&policy, "PASSWORDS", None).await),
+            ["require_lowercase", "require_digit", "require_symbol"]
+        );
+        assert_eq!(
+            rules(check_policy(&policy, "pass words", None).await),
+            ["require_uppercase", "require_digit", "require_symbol"]
+        );
+        assert!(check_policy(&policy, "Passw0rd!", None).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn check_policy_refuses_recent_passwords() {
+        let policy = PasswordPolicy {
+            history: 2,
+            ..policy()
+        };
+        let user = user(
+            password::hash("current-password").await.unwrap(),
+            vec![
+                password::hash("previous-password").await.unwrap(),
+                password::hash("oldest-password").await.unwrap(),
+            ],
+        );
+
+        for reused in ["current-password", "previous-password"] {
+            let failures = check_policy(&policy, reused, Some(&user))
+                .await
+                .unwrap_err();
+            assert_eq!(
+                failures,
+                [PolicyFailure {
+                    rule: "history",
+                    reason: "Password must differ from the last 2 passwords".to_owned(),
+                }]
+            );
+        }
+        // Only as many passwords as the history keeps are checked
+        assert!(check_policy(&policy, "oldest-password", Some(&user))
+            .await
+            .is_ok());
+        assert_eq!(
+            next_history(&policy, &user),
+            [user.password.clone(), user.password_history[0].clone()]
+        );
+    }
+
+    #[test]
+    fn reset_requests_are_checked_without_the_user() {
+        let (username, link) = check_reset_request(
+            &client(),
+            " Ann@Example.com ",
+            Some("https://app.example.com/reset"),
+        )
+        .unwrap();
+
+        assert_eq!(username, "ann@example.com");
+        assert_eq!(link.unwrap().as_str(), "https://app.example.com/reset");
+        assert_eq!(
+            reset_sent(),
+            PasswordResult {
+                detail: json!(RESET_SENT),
+                status: StatusCode::ACCEPTED,
+            }
+        );
+    }
+
+    #[test]
+    fn reset_requests_refuse_other_channels_and_redirects() {
+        let client = client();
+
+        assert_eq!(
+            check_reset_request(&client, "+12025550130", None)
+                .unwrap_err()
+                .status,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            check_reset_request(&client, "not-an-address@", None)
+                .unwrap_err()
+                .status,
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+        assert_eq!(
+            check_reset_request(&client, "ann@example.com", Some("https://evil.example.com"))
+                .unwrap_err(),
+            invalid_redirect_uri()
+        );
+    }
+}
@@ -0,0 +1,552 @@
+use crate::repositories::{
+    archive::record_key,
+    clients::{Channel, ClientRecord},
+    tenants::TenantSettings,
+    users::{Identifier, UserPatch, UserRecord, UserRepository},
+    Ident, RepoError, Surreal,
+};
+use crate::services::{
+    attributes::{self, AttributeFailure},
+    lockouts::{self, Throttle},
+    messages::{self, Message, VerificationHost},
+    passwords, sessions, settings, users,
+};
+use crate::utils::{
+    password, random,
+    redis::{RedisClient, TenantKeys},
+};
+use axum::http::StatusCode;
+use chrono::Utc;
+use lettre::Address;
+use redis::RedisError;
+use reqwest::Client;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+
+const CODE_LEN: usize = 6;
+const EMAIL_SUBJECT: &str = "Confirm your new sign-in address";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProfileResult {
+    pub detail: Value,
+    pub status: StatusCode,
+}
+
+/// A user's request to change their password.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct PasswordChange {
+    pub current_password: String,
+    pub new_password: String,
+}
+
+/// A user's request to change to a new phone number or email address.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct IdentifierChange {
+    pub value: String,
+    /// The identifier the new one replaces once it is verified. When left out, the new one is
+    /// added alongside the user's other identifiers.
+    pub replaces: Option<String>,
+}
+
+/// Parameters for sending a code to a user's new phone number or email address.
+pub struct IdentifierChangeParams<'a> {
+    pub db: &'a Surreal,
+    pub redis: &'a mut RedisClient,
+    /// The HTTP client used to send the code.
+    pub req: &'a Client,
+    pub host: &'a VerificationHost<'a>,
+    /// The client the user's token was issued to. It must allow the channel of the new
+    /// identifier.
+    pub client: &'a ClientRecord,
+    pub sub: &'a str,
+    pub change: &'a IdentifierChange,
+}
+
+/// Returns the profile of the signed-in user.
+pub async fn get_profile(db: &Surreal, tenant: &str, sub: &str) -> ProfileResult {
+    match find_user(db, tenant, sub).await {
+        Ok(user) => found(&user),
+        Err(e) => e,
+    }
+}
+
+/// Changes the attributes of the signed-in user.
+///
+/// Only attributes the tenant marks as `editable` can be changed. Attributes left out are kept,
+/// and attributes set to `null` are removed. The result must still match the tenant's schema.
+pub async fn update_profile(
+    db: &Surreal,
+    redis: &mut RedisClient,
+    tenant: &str,
+    sub: &str,
+    changes: &Map<String, Value>,
+) -> ProfileResult {
+    let settings = match lookup_settings(db, redis, tenant).await {
+        Ok(settings) => settings,
+        Err(e) => return e,
+    };
+    let user = match find_user(db, tenant, sub).await {
+        Ok(user) => user,
+        Err(e) => return e,
+    };
+
+    let locked: Vec<AttributeFailure> = changes
+        .keys()
+        .filter(|name| {
+            settings
+                .attributes
+                .iter()
+                .any(|d| d.name == **name && !d.editable)
+        })
+        .map(|name| AttributeFailure {
+            attribute: name.clone(),
+            reason: "Attribute cannot be changed".to_owned(),
+        })
+        .collect();
+    if !locked.is_empty() {
+        return invalid_attributes(&locked, StatusCode::FORBIDDEN);
+    }
+
+    let mut attributes = user.attributes.clone();
+    for (name, value) in changes {
+        match value {
+            Value::Null => attributes.remove(name),
+            value => attributes.insert(name.clone(), value.clone()),
+        };
+    }
+    if let Err(failures) = attributes::validate(&settings.attributes, &attributes) {
+        return invalid_attributes(&failures, StatusCode::UNPROCESSABLE_ENTITY);
+    }
+    // Removed attributes are nulled, since patches are merged into the record
+    for name in user.attributes.keys() {
+        attributes.entry(name.clone()).or_insert(Value::Null);
+    }
+
+    let mut patch = UserPatch::new();
+    patch.attributes = Some(attributes);
+    let id = user.id.as_deref().and_then(record_key).unwrap_or_default();
+    let repo = match repository(db, tenant) {
+        Ok(repo) => repo,
+        Err(e) => return handle_repo_error(e),
+    };
+    match repo.update(&id, &patch).await {
+        Ok(Some(user)) => found(&user),
+        Ok(None) => not_found(),
+        Err(e) => match attributes::taken(&settings.attributes, &e) {
+            Some(failures) => invalid_attributes(&failures, StatusCode::CONFLICT),
+            None => handle_repo_error(e),
+        },
+    }
+}
+
+/// Changes the password of the signed-in user, who must give their current one.
+///
+/// Wrong current passwords count against the account like failed logins, so that a stolen token
+/// cannot be used to guess the password. The user's other sessions are revoked; the session the
+/// password was changed from is kept.
+///
+/// # Arguments
+///
+/// * `db` - The database the user is stored in.
+/// * `redis` - A mutable reference to a Redis client instance.
+/// * `tenant` - The tenant the user belongs to.
+/// * `sub` - The signed-in user.
+/// * `sid` - The session of the request, if its token has one.
+/// * `ip` - The address the request came from.
+/// * `change` - The current and new passwords.
+pub async fn change_password(
+    db: &Surreal,
+    redis: &mut RedisClient,
+    tenant: &str,
+    sub: &str,
+    sid: Option<&str>,
+    ip: &str,
+    change: &PasswordChange,
+) -> ProfileResult {
+    let settings = match lookup_settings(db, redis, tenant).await {
+        Ok(settings) => settings,
+        Err(e) => return e,
+    };
+    let user = match find_user(db, tenant, sub).await {
+        Ok(user) => user,
+        Err(e) => return e,
+    };
+
+    match lockouts::check(redis, tenant, &settings.lockout, &user.username, ip).await {
+        Ok(None) => (),
+        Ok(Some(throttle)) => return throttled(throttle),
+        Err(e) => return handle_redis_error(e),
+    }
+    match users::verify_password(db, tenant, Some(&user), &change.current_password).await {
+        Ok(true) => (),
+        Ok(false) => {
+            if let Err(e) =
+                lockouts::record_failure(redis, tenant, &settings.lockout, &user.username, ip).await
+            {
+                return handle_redis_error(e);
+            }
+            return ProfileResult {
+                detail: json!("Current password is incorrect"),
+                status: StatusCode::UNAUTHORIZED,
+            };
+        }
+        Err(e) => {
+            return ProfileResult {
+                detail: e.detail,
+                status: e.status,
+            }
+        }
+    }
+    if let Err(e) = lockouts::clear(redis, tenant, &user.username).await {
+        log::error!(
+            "Failed to clear the failed logins of {}: {e}",
+            user.username
+        );
+    }
+
+    if let Err(failures) =
+        passwords::check_policy(&settings.password, &change.new_password, Some(&user)).await
+    {
+        return ProfileResult {
+            detail: passwords::policy_detail(&failures),
+            status: StatusCode::UNPROCESSABLE_ENTITY,
+        };
+    }
+    let hash = match password::hash(&change.new_password).await {
+        Ok(hash) => hash,
+        Err(e) => {
+            return ProfileResult {
+                detail: json!(e.to_string()),
+                status: StatusCode::INTERNAL_SERVER_ERROR,
+            }
+        }
+    };
+    let repo = match repository(db, tenant) {
+        Ok(repo) => repo,
+        Err(e) => return handle_repo_error(e),
+    };
+    let history = passwords::next_history(&settings.password, &user);
+    if let Err(e) = repo.change_password(&user.username, &hash, &history).await {
+        return handle_repo_error(e);
+    }
+
+    let revoked = sessions::revoke_other_sessions(redis, tenant, &user.username, sid).await;
+    if revoked.status != StatusCode::OK {
+        return ProfileResult {
+            detail: json!(format!(
+                "Password was changed, but other sessions could not be revoked: {}",
+                revoked.detail
+            )),
+            status: revoked.status,
+        };
+    }
+
+    ProfileResult {
+        detail: json!("Password changed"),
+        status: StatusCode::OK,
+    }
+}
+
+/// Sends a code to a phone number or email address the signed-in user wants to change to.
+///
+/// Nothing changes until the code is confirmed with `confirm_identifier_change`. A new request
+/// replaces any earlier one, and codes are not sent more often than the tenant's resend interval
+/// allows.
+///
+/// # Errors
+///
+/// Returns a `ProfileResult` if the new identifier is malformed, not allowed for the client or
+/// already in use, or if the identifier it replaces is not the user's.
+pub async fn request_identifier_change(params: IdentifierChangeParams<'_>) -> ProfileResult {
+    let tenant = &params.client.tenant;
+    let value = messages::normalize(&params.change.value);
+    let channel = messages::channel_of(&value);
+    if !params.client.allows_channel(channel) {
+        return ProfileResult {
+            detail: json!(format!("{channel:?} is not allowed for this client")),
+            status: StatusCode::FORBIDDEN,
+        };
+    }
+    if channel == Channel::Email && value.parse::<Address>().is_err() {
+        return ProfileResult {
+            detail: json!("Identifier must be a phone number or an email address"),
+            status: StatusCode::UNPROCESSABLE_ENTITY,
+        };
+    }
+
+    let user = match find_user(params.db, tenant, params.sub).await {
+        Ok(user) => user,
+        Err(e) => return e,
+    };
+    let replaces = params
+        .change
+        .replaces
+        .as_deref()
+        .map(messages::normalize)
+        .unwrap_or_default();
+    if !replaces.is_empty() && user.identifier(&replaces).is_none() {
+        return identifier_not_found();
+    }
+    if let Err(e) = check_available(params.db, tenant, &value).await {
+        return e;
+    }
+    let settings = match lookup_settings(params.db, params.redis, tenant).await {
+        Ok(settings) => settings,
+        Err(e) => return e,
+    };
+
+    let key = TenantKeys::new(tenant).identifier_change(&user.username);
+    let now = Utc::now().timestamp();
+    let sent_at = match params.redis.get_hash(&key).await {
+        Ok(entry) => entry.get("sent_at").and_then(|t| t.parse::<i64>().ok()),
+        Err(e) => return handle_redis_error(e),
+    };
+    if sent_at.is_some_and(|t| now - t < settings.verification.resend_interval) {
+        return ProfileResult {
+            detail: json!("A code was sent recently, try again later"),
+            status: StatusCode::TOO_MANY_REQUESTS,
+        };
+    }
+
+    // Replace any earlier request
+    let code = random::numeric(CODE_LEN);
+    if let Err(e) = params.redis.del_key(&key).await {
+        return handle_redis_error(e);
+    }
+    if let Err(e) = params
+        .redis
+        .set_key_map(
+            &key,
+            &[
+                ("code", code.as_str()),
+                ("value", value.as_str()),
+                ("replaces", replaces.as_str()),
+                ("attempts", "0"),
+                ("sent_at", now.to_string().as_str()),
+            ],
+            params.client.otp_ttl,
+        )
+        .await
+    {
+        return handle_redis_error(e);
+    }
+
+    let message = Message {
+        tenant,
+        recipient: &value,
+        sender: &settings.display_name,
+        subject: EMAIL_SUBJECT,
+        body: &code,
+    };
+    if let Err(e) = messages::send(params.redis, params.req, params.host, &message).await {
+        return ProfileResult {
+            detail: json!(e.detail),
+            status: e.status,
+        };
+    }
+
+    ProfileResult {
+        detail: json!("Verification code sent"),
+        status: StatusCode::ACCEPTED,
+    }
+}
+
+/// Confirms a change of phone number or email address with the code sent to the new one.
+///
+/// The new identifier is added as verified, in place of the one it replaces. When that was the
+/// username, the new identifier becomes the username and the user's sessions are revoked, since
+/// their tokens carry the old `sub`.
+///
+/// # Errors
+///
+/// Returns a `ProfileResult` if the code is wrong or has expired, or the new identifier was
+/// taken in the meantime. A code is dropped after too many wrong attempts.
+pub async fn confirm_identifier_change(
+    db: &Surreal,
+    redis: &mut RedisClient,
+    tenant: &str,
+    sub: &str,
+    code: &str,
+) -> ProfileResult {
+    let key = TenantKeys::new(tenant).identifier_change(sub);
+    let entry = match messages::check_code(redis, &key, code).await {
+        Ok(Some(entry)) => entry,
+        Ok(None) => {
+            return ProfileResult {
+                detail: json!("Invalid or expired code"),
+                status: StatusCode::BAD_REQUEST,
+            }
+        }
+        Err(e) => return handle_redis_error(e),
+    };
+    let value = entry.get("value").cloned().unwrap_or_default();
+    let replaces = entry.get("replaces").cloned().unwrap_or_default();
+
+    let mut user = match find_user(db, tenant, sub).await {
+        Ok(user) => user,
+        Err(e) => return e,
+    };
+    if let Err(e) = check_available(db, tenant, &value).await {
+        return e;
+    }
+
+    user.identifiers.push(Identifier {
+        value: value.clone(),
+        primary: false,
+        verified: false,
+        verified_at: None,
+    });
+    user.verify_identifier(&value);
+    let was_primary = user.identifier(&replaces).is_some_and(|i| i.primary);
+    if was_primary {
+        user.set_primary(&value);
+    }
+    if !replaces.is_empty() {
+        user.identifiers.retain(|i| i.value != replaces);
+    }
+
+    let repo = match repository(db, tenant) {
+        Ok(repo) => repo,
+        Err(e) => return handle_repo_error(e),
+    };
+    let user = match repo.save_identifiers(&user).await {
+        Ok(Some(user)) => user,
+        Ok(None) => return not_found(),
+        Err(e) if e.status() == StatusCode::CONFLICT => return identifier_taken(),
+        Err(e) => return handle_repo_error(e),
+    };
+    if let Err(e) = redis.del_key(&key).await {
+        log::error!("Failed to delete the identifier change of {sub}: {e}");
+    }
+    if !replaces.is_empty() {
+        let key = TenantKeys::new(tenant).verification(&replaces);
+        if let Err(e) = redis.del_key(&key).await {
+            log::error!("Failed to delete {key}: {e}");
+        }
+    }
+
+    if was_primary {
+        let revoked = sessions::revoke_sessions(redis, tenant, sub).await;
+        if revoked.status != StatusCode::OK {
+            return ProfileResult {
+                detail: revoked.detail,
+                status: revoked.status,
+            };
+        }
+    }
+
+    found(&user)
+}
+
+/// Finds the signed-in user by the `sub` of their token.
+async fn find_user(db: &Surreal, tenant: &str, sub: &str) -> Result<UserRecord, ProfileResult> {
+    let repo = repository(db, tenant).map_err(handle_repo_error)?;
+    match repo.find_by_username(sub).await {
+        Ok(Some(user)) if user.disabled => Err(ProfileResult {
+            detail: json!("Account is disabled"),
+            status: StatusCode::FORBIDDEN,
+        }),
+        Ok(Some(user)) => Ok(user),
+        Ok(None) => Err(not_found()),
+        Err(e) => Err(handle_repo_error(e)),
+    }
+}
+
+/// Checks that no user has the identifier yet.
+async fn check_available(db: &Surreal, tenant: &str, value: &str) -> Result<(), ProfileResult> {
+    let repo = repository(db, tenant).map_err(handle_repo_error)?;
+    match repo.find_by_identifier(value).await {
+        Ok(None) => Ok(()),
+        Ok(Some(_)) => Err(identifier_taken()),
+        Err(e) => Err(handle_repo_error(e)),
+    }
+}
+
+async fn lookup_settings(
+    db: &Surreal,
+    redis: &mut RedisClient,
+    tenant: &str,
+) -> Result<TenantSettings, ProfileResult> {
+    settings::lookup(db, redis, tenant)
+        .await
+        .map_err(|e| ProfileResult {
+            detail: json!(e.to_string()),
+            status: e.status(),
+        })
+}
+
+fn repository<'a>(db: &'a Surreal, tenant: &str) -> Result<UserRepository<'a>, RepoError> {
+    Ok(UserRepository::new(db, Ident::parse(tenant)?))
+}
+
+/// Returns a user's profile as shown to themselves: without password hashes or record ids.
+fn present(user: &UserRecord) -> Value {
+    let mut user = json!(user);
+    if let Some(user) = user.as_object_mut() {
+        user.remove("id");
+        user.remove("password");
+        user.remove("password_history");
+        user.remove("disabled");
+    }
+
+    user
+}
+
+fn found(user: &UserRecord) -> ProfileResult {
+    ProfileResult {
+        detail: present(user),
+        status: StatusCode::OK,
+    }
+}
+
+fn throttled(throttle: Throttle) -> ProfileResult {
+    ProfileResult {
+        detail: json!({
+            "message": "Too many failed attempts, try again later",
+            "retry_after": throttle.retry_after(),
+        }),
+        status: StatusCode::TOO_MANY_REQUESTS,
+    }
+}
+
+fn invalid_attributes(failures: &[AttributeFailure], status: StatusCode) -> ProfileResult {
+    ProfileResult {
+        detail: attributes::failure_detail(failures),
+        status,
+    }
+}
+
+fn identifier_taken() -> ProfileResult {
+    ProfileResult {
+        detail: json!("Identifier is already in use"),
+        status: StatusCode::CONFLICT,
+    }
+}
+
+fn identifier_not_found() -> ProfileResult {
+    ProfileResult {
+        detail: json!("Identifier not found"),
+        status: StatusCode::NOT_FOUND,
+    }
+}
+
+fn not_found() -> ProfileResult {
+    ProfileResult {
+        detail: json!("User not found"),
+        status: StatusCode::NOT_FOUND,
+    }
+}
+
+fn handle_redis_error(e: RedisError) -> ProfileResult {
+    ProfileResult {
+        detail: json!(e.detail().unwrap_or("Unknown error")),
+        status: StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+fn handle_repo_error(e: RepoError) -> ProfileResult {
+    ProfileResult {
+        detail: json!(e.to_string()),
+        status: e.status(),
+    }
+}
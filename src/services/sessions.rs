@@ -0,0 +1,406 @@
+use crate::repositories::{clients::ClientRecord, tenants::Metric};
+use crate::services::{
+    access::Grants,
+    usage::{self, QuotaError},
+};
+use crate::utils::{
+    jwt, random,
+    redis::{RedisClient, TenantKeys},
+};
+use axum::http::StatusCode;
+use chrono::Utc;
+use redis::RedisError;
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+
+/// How long, in seconds, a session lives. Matches the lifetime of the tokens issued for it.
+pub const SESSION_TTL: i64 = 86_400;
+
+const SESSION_ID_LEN: usize = 32;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SessionResult {
+    pub detail: Value,
+    pub status: StatusCode,
+}
+
+/// Details about the client a session was created for.
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct SessionInfo {
+    pub device: String,
+    pub ip: String,
+    pub user_agent: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Session {
+    pub sid: String,
+    pub sub: String,
+    pub aud: String,
+    pub tenant: String,
+    pub device: String,
+    pub ip: String,
+    pub user_agent: String,
+    pub created_at: i64,
+    pub last_seen: i64,
+}
+
+impl Session {
+    fn from_hash(sid: &str, hash: &HashMap<String, String>) -> Self {
+        let field = |name: &str| hash.get(name).cloned().unwrap_or_default();
+        let timestamp = |name: &str| hash.get(name).and_then(|v| v.parse().ok()).unwrap_or(0);
+
+        Self {
+            sid: sid.to_owned(),
+            sub: field("sub"),
+            aud: field("aud"),
+            tenant: field("tenant"),
+            device: field("device"),
+            ip: field("ip"),
+            user_agent: field("user_agent"),
+            created_at: timestamp("created_at"),
+            last_seen: timestamp("last_seen"),
+        }
+    }
+}
+
+/// Creates a session for a user and returns its id.
+///
+/// # Arguments
+///
+/// * `redis` - A mutable reference to a Redis client instance.
+/// * `sub` - The subject the session belongs to.
+/// * `aud` - The audience of the tokens issued for the session.
+/// * `tenant` - The tenant the user belongs to. The session is stored in the tenant's keyspace.
+/// * `info` - Details about the client.
+///
+/// # Errors
+///
+/// Returns a `RedisError` if the session cannot be stored.
+pub async fn create_session(
+    redis: &mut RedisClient,
+    sub: &str,
+    aud: &str,
+    tenant: &str,
+    info: &SessionInfo,
+) -> Result<String, RedisError> {
+    let sid = random::alphanumeric(SESSION_ID_LEN);
+    let now = Utc::now().timestamp().to_string();
+    let keys = TenantKeys::new(tenant);
+
+    redis
+        .set_key_map(
+            &keys.session(&sid),
+            &[
+                ("sub", sub),
+                ("aud", aud),
+                ("tenant", tenant),
+                ("device", &info.device),
+                ("ip", &info.ip),
+                ("user_agent", &info.user_agent),
+                ("created_at", &now),
+                ("last_seen", &now),
+            ],
+            SESSION_TTL,
+        )
+        .await?;
+    redis
+        .add_member(&keys.user_sessions(sub), &sid, Some(SESSION_TTL))
+        .await?;
+
+    Ok(sid)
+}
+
+/// Checks that the tenant's quotas leave room for a user to sign in.
+///
+/// # Errors
+///
+/// Returns a `QuotaError` if the tenant has used up its verifications or tokens, or the user
+/// would take it over its limit of active users.
+pub async fn check_sign_in(
+    redis: &mut RedisClient,
+    client: &ClientRecord,
+    sub: &str,
+) -> Result<(), QuotaError> {
+    for metric in [Metric::Verifications, Metric::TokensIssued] {
+        usage::check_quota(redis, &client.tenant, metric).await?;
+    }
+
+    usage::check_active_user(redis, &client.tenant, sub).await
+}
+
+/// Signs a user in: creates a session for the client, counts the sign-in against the tenant's
+/// usage and returns an access token for the session. Quotas should be checked beforehand with
+/// `check_sign_in`.
+///
+/// # Arguments
+///
+/// * `redis` - A mutable reference to a Redis client instance.
+/// * `client` - The registered client the user signs in through.
+/// * `sub` - The subject the token is issued for.
+/// * `jkt` - The thumbprint of a verified DPoP proof key the token is bound to, if any.
+/// * `info` - Details about the client, recorded on the session.
+/// * `profile` - Claims mapped from the user's profile attributes.
+/// * `grants` - The scopes and groups the user is granted.
+///
+/// # Errors
+///
+/// Returns a `SessionResult` if the session cannot be created or the token cannot be signed.
+pub async fn sign_in(
+    redis: &mut RedisClient,
+    client: &ClientRecord,
+    sub: &str,
+    jkt: Option<String>,
+    info: &SessionInfo,
+    profile: Map<String, Value>,
+    grants: Grants,
+) -> Result<String, SessionResult> {
+    let sid = create_session(redis, sub, &client.client_id, &client.tenant, info)
+        .await
+        .map_err(handle_redis_error)?;
+    usage::record(redis, &client.tenant, Metric::Verifications).await;
+    usage::record(redis, &client.tenant, Metric::TokensIssued).await;
+    usage::record_active_user(redis, &client.tenant, sub).await;
+
+    jwt::sign(jwt::SignParams {
+        sub: sub.to_owned(),
+        aud: client.client_id.clone(),
+        tenant: Some(client.tenant.clone()),
+        sid: Some(sid),
+        jkt,
+        ttl: Some(client.access_token_ttl),
+        scope: Some(grants.scope()),
+        groups: grants.groups,
+        profile,
+    })
+    .await
+    .map_err(|e| SessionResult {
+        detail: json!(format!("Failed to sign token: {e}")),
+        status: StatusCode::INTERNAL_SERVER_ERROR,
+    })
+}
+
+/// Records activity on a session. Returns `false` if the session has expired or was revoked.
+pub async fn touch_session(
+    redis: &mut RedisClient,
+    tenant: &str,
+    sid: &str,
+) -> Result<bool, RedisError> {
+    let key = TenantKeys::new(tenant).session(sid);
+    if !redis.exists(&key).await? {
+        return Ok(false);
+    }
+
+    redis
+        .set_hash_field(&key, "last_seen", &Utc::now().timestamp().to_string())
+        .await?;
+
+    Ok(true)
+}
+
+/// Lists the active sessions of a user.
+///
+/// # Arguments
+///
+/// * `redis` - A mutable reference to a Redis client instance.
+/// * `tenant` - The tenant the user belongs to.
+/// * `sub` - The subject whose sessions are listed.
+pub async fn list_sessions(redis: &mut RedisClient, tenant: &str, sub: &str) -> SessionResult {
+    match find_sessions(redis, tenant, sub).await {
+        Ok(sessions) => SessionResult {
+            detail: json!(sessions),
+            status: StatusCode::OK,
+        },
+        Err(e) => handle_redis_error(e),
+    }
+}
+
+/// Revokes a single session of a user. Tokens issued for it are rejected from then on.
+///
+/// # Arguments
+///
+/// * `redis` - A mutable reference to a Redis client instance.
+/// * `tenant` - The tenant the user belongs to.
+/// * `sub` - The subject the session must belong to.
+/// * `sid` - The session to revoke.
+pub async fn revoke_session(
+    redis: &mut RedisClient,
+    tenant: &str,
+    sub: &str,
+    sid: &str,
+) -> SessionResult {
+    let session = match redis.get_hash(&TenantKeys::new(tenant).session(sid)).await {
+        Ok(hash) if !hash.is_empty() => Session::from_hash(sid, &hash),
+        Ok(_) => return not_found(),
+        Err(e) => return handle_redis_error(e),
+    };
+    if session.sub != sub {
+        return not_found();
+    }
+
+    match delete_session(redis, tenant, sub, sid).await {
+        Ok(_) => SessionResult {
+            detail: json!("Session revoked"),
+            status: StatusCode::OK,
+        },
+        Err(e) => handle_redis_error(e),
+    }
+}
+
+/// Revokes every session of a user.
+///
+/// # Arguments
+///
+/// * `redis` - A mutable reference to a Redis client instance.
+/// * `tenant` - The tenant the user belongs to.
+/// * `sub` - The subject whose sessions are revoked.
+pub async fn revoke_sessions(redis: &mut RedisClient, tenant: &str, sub: &str) -> SessionResult {
+    let sessions = match find_sessions(redis, tenant, sub).await {
+        Ok(sessions) => sessions,
+        Err(e) => return handle_redis_error(e),
+    };
+
+    for session in &sessions {
+        if let Err(e) = delete_session(redis, tenant, sub, &session.sid).await {
+            return handle_redis_error(e);
+        }
+    }
+
+    SessionResult {
+        detail: json!(format!("{} session(s) revoked", sessions.len())),
+        status: StatusCode::OK,
+    }
+}
+
+/// Revokes every session of a user except the one given, as when the user changes their
+/// password from it.
+///
+/// # Arguments
+///
+/// * `redis` - A mutable reference to a Redis client instance.
+/// * `tenant` - The tenant the user belongs to.
+/// * `sub` - The subject whose sessions are revoked.
+/// * `keep` - The session to keep, if any.
+pub async fn revoke_other_sessions(
+    redis: &mut RedisClient,
+    tenant: &str,
+    sub: &str,
+    keep: Option<&str>,
+) -> SessionResult {
+    let sessions = match find_sessions(redis, tenant, sub).await {
+        Ok(sessions) => sessions,
+        Err(e) => return handle_redis_error(e),
+    };
+
+    let mut revoked = 0;
+    for session in sessions.iter().filter(|s| Some(s.sid.as_str()) != keep) {
+        if let Err(e) = delete_session(redis, tenant, sub, &session.sid).await {
+            return handle_redis_error(e);
+        }
+        revoked += 1;
+    }
+
+    SessionResult {
+        detail: json!(format!("{revoked} session(s) revoked")),
+        status: StatusCode::OK,
+    }
+}
+
+async fn find_sessions(
+    redis: &mut RedisClient,
+    tenant: &str,
+    sub: &str,
+) -> Result<Vec<Session>, RedisError> {
+    let keys = TenantKeys::new(tenant);
+    let index = keys.user_sessions(sub);
+    let mut sessions = Vec::new();
+
+    for sid in redis.get_members(&index).await? {
+        let hash = redis.get_hash(&keys.session(&sid)).await?;
+        if hash.is_empty() {
+            // The session expired, drop it from the index
+            redis.remove_member(&index, &sid).await?;
+            continue;
+        }
+
+        sessions.push(Session::from_hash(&sid, &hash));
+    }
+    sessions.sort_by_key(|session| std::cmp::Reverse(session.last_seen));
+
+    Ok(sessions)
+}
+
+async fn delete_session(
+    redis: &mut RedisClient,
+    tenant: &str,
+    sub: &str,
+    sid: &str,
+) -> Result<(), RedisError> {
+    let keys = TenantKeys::new(tenant);
+    redis.del_key(&keys.session(sid)).await?;
+    redis.remove_member(&keys.user_sessions(sub), sid).await
+}
+
+fn not_found() -> SessionResult {
+    SessionResult {
+        detail: json!("Session not found"),
+        status: StatusCode::NOT_FOUND,
+    }
+}
+
+fn handle_redis_error(e: RedisError) -> SessionResult {
+    SessionResult {
+        detail: json!(e.detail().unwrap_or("Unknown error")),
+        status: StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sessions_are_listed_with_their_client_details() {
+        let hash: HashMap<String, String> = [
+            ("sub", "user:ann"),
+            ("aud", "app"),
+            ("tenant", "acme"),
+            ("device", "Pixel 8"),
+            ("ip", "203.0.113.7"),
+            ("user_agent", "Mozilla/5.0"),
+            ("created_at", "1700000000"),
+            ("last_seen", "1700000600"),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_owned(), v.to_owned()))
+        .collect();
+
+        assert_eq!(
+            json!(Session::from_hash("s1", &hash)),
+            json!({
+                "sid": "s1",
+                "sub": "user:ann",
+                "aud": "app",
+                "tenant": "acme",
+                "device": "Pixel 8",
+                "ip": "203.0.113.7",
+                "user_agent": "Mozilla/5.0",
+                "created_at": 1_700_000_000,
+                "last_seen": 1_700_000_600,
+            })
+        );
+    }
+
+    #[test]
+    fn sessions_missing_fields_are_listed_empty() {
+        let hash = HashMap::from([("sub".to_owned(), "user:ann".to_owned())]);
+
+        let session = Session::from_hash("s1", &hash);
+
+        assert_eq!(session.sub, "user:ann");
+        assert_eq!(session.device, "");
+        assert_eq!(session.created_at, 0);
+        assert_eq!(session.last_seen, 0);
+    }
+}
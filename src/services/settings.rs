@@ -0,0 +1,370 @@
+use crate::config::constants::{MAX_OTP_TTL, MIN_TTL};
+use crate::repositories::{
+    clients::Channel,
+    tenants::{
+        AttributeDefinition, Branding, DeletionPolicy, LockoutPolicy, OtpPolicy, PasswordPolicy,
+        SignupMode, TenantRepository, TenantSettings, TokenLifetimes, VerificationPolicy,
+    },
+    users::UserRepository,
+    Ident, RepoError, Surreal,
+};
+use crate::services::{attributes, sessions::SESSION_TTL};
+use crate::utils::redis::{RedisClient, TenantKeys};
+use axum::http::StatusCode;
+use lazy_static::lazy_static;
+use lettre::Address;
+use redis::RedisError;
+use regex::Regex;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use url::Url;
+
+const MAX_DISPLAY_NAME_LEN: usize = 100;
+const MAX_PASSWORD_LEN: usize = 1024;
+const MAX_PASSWORD_HISTORY: usize = 24;
+const MAX_LOCKOUT_DURATION: i64 = 86_400;
+const MAX_BACKOFF: i64 = 3_600;
+const MAX_GRACE_PERIOD: i64 = 365 * 86_400;
+
+lazy_static! {
+    static ref HEX_COLOR: Regex = Regex::new(r"^#[0-9a-fA-F]{6}$").unwrap();
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SettingsResult {
+    pub detail: Value,
+    pub status: StatusCode,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum SettingsError {
+    #[error("Tenant not found")]
+    NotFound,
+    #[error(transparent)]
+    Repo(#[from] RepoError),
+}
+
+impl SettingsError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            SettingsError::NotFound => StatusCode::NOT_FOUND,
+            SettingsError::Repo(e) => e.status(),
+        }
+    }
+}
+
+/// Changes to a tenant's settings. Sections left out are kept as they are, sections given
+/// replace the current ones. When `version` is given, the update is only applied if the settings
+/// are still at that version.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct SettingsPatch {
+    pub version: Option<u64>,
+    pub display_name: Option<String>,
+    pub branding: Option<Branding>,
+    pub otp: Option<OtpPolicy>,
+    pub tokens: Option<TokenLifetimes>,
+    pub allowed_channels: Option<Vec<Channel>>,
+    pub signup: Option<SignupMode>,
+    pub verification: Option<VerificationPolicy>,
+    pub password: Option<PasswordPolicy>,
+    pub lockout: Option<LockoutPolicy>,
+    pub deletion: Option<DeletionPolicy>,
+    pub attributes: Option<Vec<AttributeDefinition>>,
+}
+
+/// Returns a tenant's settings, reading them from the cache when possible.
+///
+/// # Arguments
+///
+/// * `db` - The database holding the tenant registry.
+/// * `redis` - A mutable reference to a Redis client instance, used as the cache.
+/// * `tenant` - The name of the tenant.
+///
+/// # Errors
+///
+/// Returns a `SettingsError` if the tenant does not exist or the database cannot be reached.
+pub async fn lookup(
+    db: &Surreal,
+    redis: &mut RedisClient,
+    tenant: &str,
+) -> Result<TenantSettings, SettingsError> {
+    let key = TenantKeys::new(tenant).settings();
+    if let Ok(cached) = redis.get_key(&key).await {
+        if let Ok(settings) = serde_json::from_str(&cached) {
+            return Ok(settings);
+        }
+    }
+
+    let settings = match TenantRepository::new(db).find(tenant).await? {
+        Some(record) => record.settings,
+        None => return Err(SettingsError::NotFound),
+    };
+    if let Err(e) = redis.set_key(&key, &json!(settings).to_string()).await {
+        log::error!("Failed to cache the settings of {tenant}: {e}");
+    }
+
+    Ok(settings)
+}
+
+pub async fn get_settings(db: &Surreal, tenant: &str) -> SettingsResult {
+    match TenantRepository::new(db).find(tenant).await {
+        Ok(Some(record)) => SettingsResult {
+            detail: json!(record.settings),
+            status: StatusCode::OK,
+        },
+        Ok(None) => not_found(),
+        Err(e) => handle_repo_error(e),
+    }
+}
+
+/// Validates and applies a change to a tenant's settings, then drops the cached copy.
+pub async fn update_settings(
+    db: &Surreal,
+    redis: &mut RedisClient,
+    tenant: &str,
+    patch: &SettingsPatch,
+) -> SettingsResult {
+    let repo = TenantRepository::new(db);
+    let current = match repo.find(tenant).await {
+        Ok(Some(record)) => record.settings,
+        Ok(None) => return not_found(),
+        Err(e) => return handle_repo_error(e),
+    };
+    if patch.version.is_some_and(|v| v != current.version) {
+        return conflict(current.version);
+    }
+
+    let settings = apply(current.clone(), patch);
+    if let Err(detail) = validate(&settings) {
+        return SettingsResult {
+            detail: json!(detail),
+            status: StatusCode::UNPROCESSABLE_ENTITY,
+        };
+    }
+
+    // Unique attributes are enforced by the database, so their indexes are defined before the
+    // settings are saved. Existing users sharing a value make this fail with a conflict.
+    let users = match Ident::parse(tenant) {
+        Ok(ident) => UserRepository::new(db, ident),
+        Err(e) => return handle_repo_error(e),
+    };
+    for name in unique_attributes(&settings).difference(&unique_attributes(&current)) {
+        if let Err(e) = users.define_attribute_index(name).await {
+            return handle_repo_error(e);
+        }
+    }
+
+    let record = match repo
+        .update_settings(tenant, &settings, current.version)
+        .await
+    {
+        Ok(Some(record)) => record,
+        Ok(None) => return conflict(current.version),
+        Err(e) => return handle_repo_error(e),
+    };
+    for name in unique_attributes(&current).difference(&unique_attributes(&settings)) {
+        if let Err(e) = users.remove_attribute_index(name).await {
+            log::error!("Failed to remove the index of {name} in {tenant}: {e}");
+        }
+    }
+    if let Err(e) = clear_cache(redis, tenant).await {
+        log::error!("Failed to clear the cached settings of {tenant}: {e}");
+    }
+
+    SettingsResult {
+        detail: json!(record.settings),
+        status: StatusCode::OK,
+    }
+}
+
+/// Removes a tenant's cached settings.
+pub async fn clear_cache(redis: &mut RedisClient, tenant: &str) -> Result<(), RedisError> {
+    redis.del_key(&TenantKeys::new(tenant).settings()).await
+}
+
+/// Returns the names of the attributes that must be unique.
+fn unique_attributes(settings: &TenantSettings) -> HashSet<Ident> {
+    settings
+        .attributes
+        .iter()
+        .filter(|a| a.unique)
+        .filter_map(|a| Ident::parse(&a.name).ok())
+        .collect()
+}
+
+fn apply(mut settings: TenantSettings, patch: &SettingsPatch) -> TenantSettings {
+    settings.version += 1;
+    if let Some(display_name) = &patch.display_name {
+        settings.display_name = display_name.trim().to_owned();
+    }
+    if let Some(branding) = &patch.branding {
+        settings.branding = branding.clone();
+    }
+    if let Some(otp) = &patch.otp {
+        settings.otp = otp.clone();
+    }
+    if let Some(tokens) = &patch.tokens {
+        settings.tokens = tokens.clone();
+    }
+    if let Some(channels) = &patch.allowed_channels {
+        settings.allowed_channels = channels.clone();
+    }
+    if let Some(signup) = patch.signup {
+        settings.signup = signup;
+    }
+    if let Some(verification) = &patch.verification {
+        settings.verification = verification.clone();
+    }
+    if let Some(password) = &patch.password {
+        settings.password = password.clone();
+    }
+    if let Some(lockout) = &patch.lockout {
+        settings.lockout = lockout.clone();
+    }
+    if let Some(deletion) = &patch.deletion {
+        settings.deletion = deletion.clone();
+    }
+    if let Some(attributes) = &patch.attributes {
+        settings.attributes = attributes.clone();
+    }
+
+    settings
+}
+
+fn validate(settings: &TenantSettings) -> Result<(), &'static str> {
+    if settings.display_name.is_empty() || settings.display_name.len() > MAX_DISPLAY_NAME_LEN {
+        return Err("display_name must be between 1 and 100 characters");
+    }
+    if let Some(logo_url) = &settings.branding.logo_url {
+        match Url::parse(logo_url) {
+            Ok(url) if url.scheme() == "https" => (),
+            _ => return Err("branding.logo_url must be an https URL"),
+        }
+    }
+    if let Some(color) = &settings.branding.primary_color {
+        if !HEX_COLOR.is_match(color) {
+            return Err("branding.primary_color must be a hex color such as #1a2b3c");
+        }
+    }
+    if let Some(email) = &settings.branding.support_email {
+        if email.parse::<Address>().is_err() {
+            return Err("branding.support_email must be an email address");
+        }
+    }
+    if !(MIN_TTL..=MAX_OTP_TTL).contains(&settings.otp.ttl) {
+        return Err("otp.ttl is out of range");
+    }
+    if !(MIN_TTL..=SESSION_TTL).contains(&settings.tokens.access_token_ttl) {
+        return Err("tokens.access_token_ttl is out of range");
+    }
+    if !(0..=MAX_OTP_TTL).contains(&settings.verification.resend_interval) {
+        return Err("verification.resend_interval is out of range");
+    }
+    let password = &settings.password;
+    if password.min_length < 1 || password.min_length > password.max_length {
+        return Err("password.min_length must be between 1 and password.max_length");
+    }
+    if password.max_length > MAX_PASSWORD_LEN {
+        return Err("password.max_length must be at most 1024");
+    }
+    if password.history > MAX_PASSWORD_HISTORY {
+        return Err("password.history must be at most 24");
+    }
+    let lockout = &settings.lockout;
+    if !(1..=MAX_LOCKOUT_DURATION).contains(&lockout.lockout_duration) {
+        return Err("lockout.lockout_duration is out of range");
+    }
+    if !(1..=MAX_LOCKOUT_DURATION).contains(&lockout.window) {
+        return Err("lockout.window is out of range");
+    }
+    if lockout.backoff_base < 0 || lockout.backoff_base > lockout.backoff_max {
+        return Err("lockout.backoff_base must be between 0 and lockout.backoff_max");
+    }
+    if lockout.backoff_max > MAX_BACKOFF {
+        return Err("lockout.backoff_max must be at most 3600");
+    }
+    if !(0..=MAX_GRACE_PERIOD).contains(&settings.deletion.grace_period) {
+        return Err("deletion.grace_period must be between 0 and 365 days");
+    }
+    if settings.allowed_channels.is_empty() {
+        return Err("allowed_channels must not be empty");
+    }
+    attributes::validate_schema(&settings.attributes)?;
+
+    Ok(())
+}
+
+fn conflict(version: u64) -> SettingsResult {
+    SettingsResult {
+        detail: json!({
+            "message": "Settings have changed since they were read",
+            "version": version,
+        }),
+        status: StatusCode::CONFLICT,
+    }
+}
+
+fn not_found() -> SettingsResult {
+    SettingsResult {
+        detail: json!("Tenant not found"),
+        status: StatusCode::NOT_FOUND,
+    }
+}
+
+fn handle_repo_error(e: RepoError) -> SettingsResult {
+    SettingsResult {
+        detail: json!(e.to_string()),
+        status: e.status(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings() -> TenantSettings {
+        TenantSettings {
+            display_name: "Acme".to_owned(),
+            ..TenantSettings::default()
+        }
+    }
+
+    #[test]
+    fn validate_accepts_default_settings() {
+        assert_eq!(validate(&settings()), Ok(()));
+    }
+
+    #[test]
+    fn validate_refuses_empty_display_name() {
+        assert!(validate(&TenantSettings::default()).is_err());
+    }
+
+    #[test]
+    fn validate_checks_branding() {
+        let mut settings = settings();
+        settings.branding.primary_color = Some("#1a2b3c".to_owned());
+        assert_eq!(validate(&settings), Ok(()));
+
+        settings.branding.primary_color = Some("#1a2b3".to_owned());
+        assert_eq!(
+            validate(&settings),
+            Err("branding.primary_color must be a hex color such as #1a2b3c")
+        );
+
+        settings.branding.primary_color = None;
+        settings.branding.logo_url = Some("http://example.com/logo.png".to_owned());
+        assert_eq!(
+            validate(&settings),
+            Err("branding.logo_url must be an https URL")
+        );
+    }
+
+    #[test]
+    fn validate_bounds_lifetimes() {
+        let mut settings = settings();
+        settings.otp.ttl = MAX_OTP_TTL + 1;
+
+        assert_eq!(validate(&settings), Err("otp.ttl is out of range"));
+    }
+}
@@ -1,6 +1,24 @@
-use axum::http::StatusCode;
-use reqwest::Client;
+use crate::config::constants::{PLATFORM_ADMIN_SCOPE, RESERVED_TENANT_NAMES};
+use crate::repositories::{
+    clients::ClientRepository,
+    tenants::{TenantPatch, TenantRepository},
+    users::UserRepository,
+    Ident, RepoError, Surreal,
+};
+use crate::utils::{
+    jwt::{PlatformClaims, PLATFORM_TOKEN},
+    redis::{RedisClient, TenantKeys},
+};
+use axum::http::{HeaderMap, StatusCode};
+use jsonwebtoken::{decode, Algorithm, DecodingKey, Validation};
+use redis::RedisError;
 use serde::Deserialize;
+use serde_json::{json, Value};
+
+const SUSPENDED_TENANTS: &str = "suspended_tenants";
+/// Set for five minutes after `SUSPENDED_TENANTS` is loaded from the database, so that an empty
+/// set can be told apart from one that was lost with a flush or a failover.
+const SUSPENDED_TENANTS_LOADED: &str = "suspended_tenants:loaded";
 
 #[derive(Clone, Debug, PartialEq, Deserialize)]
 pub struct Tenant {
@@ -9,40 +27,365 @@ pub struct Tenant {
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct TenantResult {
-    pub detail: String,
+    pub detail: Value,
     pub status: StatusCode,
 }
 
-pub async fn create_tenant(
-    client: &Client,
-    db_url: &String,
-    auth: &String,
-    tenant: &String,
+/// Creates the tenant's namespace and registers the tenant on the platform.
+pub async fn create_tenant(db: &Surreal, tenant: &str) -> TenantResult {
+    if is_reserved(tenant) {
+        return TenantResult {
+            detail: json!("Tenant name is reserved"),
+            status: StatusCode::BAD_REQUEST,
+        };
+    }
+    let tenant = match Ident::parse(tenant) {
+        Ok(tenant) => tenant,
+        Err(e) => return handle_repo_error(e),
+    };
+    let repo = TenantRepository::new(db);
+
+    if let Err(e) = repo.define_namespace(&tenant).await {
+        return handle_repo_error(e);
+    }
+    if let Err(e) = UserRepository::new(db, tenant.clone())
+        .define_indexes()
+        .await
+    {
+        return handle_repo_error(e);
+    }
+    match repo.create(&tenant).await {
+        Ok(_) => TenantResult {
+            detail: json!("Tenant created"),
+            status: StatusCode::CREATED,
+        },
+        Err(e) => handle_repo_error(e),
+    }
+}
+
+/// Lists tenants ordered by creation date.
+///
+/// # Arguments
+///
+/// * `db` - The database to query.
+/// * `page` - The page to return, starting at 1.
+/// * `per_page` - The number of tenants per page.
+pub async fn list_tenants(db: &Surreal, page: u32, per_page: u32) -> TenantResult {
+    let page = page.max(1);
+    let per_page = per_page.clamp(1, 100);
+
+    match TenantRepository::new(db)
+        .list(per_page, page.saturating_sub(1).saturating_mul(per_page))
+        .await
+    {
+        Ok((tenants, total)) => TenantResult {
+            detail: json!({
+                "tenants": tenants,
+                "page": page,
+                "per_page": per_page,
+                "total": total,
+            }),
+            status: StatusCode::OK,
+        },
+        Err(e) => handle_repo_error(e),
+    }
+}
+
+pub async fn get_tenant(db: &Surreal, tenant: &str) -> TenantResult {
+    match TenantRepository::new(db).find(tenant).await {
+        Ok(Some(record)) => TenantResult {
+            detail: json!(record),
+            status: StatusCode::OK,
+        },
+        Ok(None) => not_found(),
+        Err(e) => handle_repo_error(e),
+    }
+}
+
+/// Suspends a tenant or lifts its suspension. All authentication is refused for a suspended tenant.
+pub async fn suspend_tenant(
+    db: &Surreal,
+    redis: &mut RedisClient,
+    tenant: &str,
+    suspended: bool,
 ) -> TenantResult {
-    let resp = match client
-        .post(db_url)
-        .header("Accept", "application/json")
-        .header("Authorization", auth)
-        .header("DB", tenant)
-        .header("NS", tenant)
-        .body(format!("DEFINE NAMESPACE {tenant}"))
-        .send()
+    let patch = TenantPatch {
+        suspended: Some(suspended),
+        ..TenantPatch::new()
+    };
+    let result = update_record(db, tenant, &patch).await;
+    if result.status != StatusCode::OK {
+        return result;
+    }
+
+    let cached = match suspended {
+        true => redis.add_member(SUSPENDED_TENANTS, tenant, None).await,
+        false => redis.remove_member(SUSPENDED_TENANTS, tenant).await,
+    };
+    match cached {
+        Ok(_) => result,
+        Err(e) => handle_redis_error(e),
+    }
+}
+
+/// Deletes a tenant along with its namespace, its clients and every key in its part of the Redis
+/// keyspace. Tenants under a reserved name are never deleted, since their namespace may be the
+/// platform's own.
+pub async fn delete_tenant(db: &Surreal, redis: &mut RedisClient, tenant: &str) -> TenantResult {
+    let repo = TenantRepository::new(db);
+    let name = match repo.find(tenant).await {
+        Ok(Some(record)) => record.name,
+        Ok(None) => return not_found(),
+        Err(e) => return handle_repo_error(e),
+    };
+    if is_reserved(tenant) || is_reserved(&name) {
+        return TenantResult {
+            detail: json!("Tenant name is reserved"),
+            status: StatusCode::FORBIDDEN,
+        };
+    }
+    let ns = match Ident::parse(&name) {
+        Ok(ns) => ns,
+        Err(e) => return handle_repo_error(e),
+    };
+
+    if let Err(e) = repo.remove_namespace(&ns).await {
+        return handle_repo_error(e);
+    }
+    if let Err(e) = ClientRepository::new(db).delete_all(tenant).await {
+        return handle_repo_error(e);
+    }
+    if let Err(e) = repo.delete(tenant).await {
+        return handle_repo_error(e);
+    }
+    if let Err(e) = redis.remove_member(SUSPENDED_TENANTS, tenant).await {
+        return handle_redis_error(e);
+    }
+    if let Err(e) = redis.delete_prefix(&TenantKeys::new(tenant).prefix()).await {
+        return handle_redis_error(e);
+    }
+
+    TenantResult {
+        detail: json!("Tenant deleted"),
+        status: StatusCode::OK,
+    }
+}
+
+/// Checks whether a tenant name belongs to the platform. Namespaces are matched regardless of
+/// case.
+pub fn is_reserved(tenant: &str) -> bool {
+    RESERVED_TENANT_NAMES
+        .iter()
+        .any(|name| name.eq_ignore_ascii_case(tenant))
+}
+
+/// Checks whether a tenant has been suspended.
+///
+/// Suspensions are read from a cache in Redis, which is loaded from the database again whenever
+/// it is missing and at least every five minutes.
+pub async fn is_suspended(
+    db: &Surreal,
+    redis: &mut RedisClient,
+    tenant: &str,
+) -> Result<bool, TenantResult> {
+    if !redis
+        .exists(SUSPENDED_TENANTS_LOADED)
         .await
+        .map_err(handle_redis_error)?
     {
-        Ok(resp) => resp,
-        Err(err) => {
-            return TenantResult {
-                detail: err.to_string(),
-                status: StatusCode::INTERNAL_SERVER_ERROR,
-            };
+        load_suspensions(db, redis).await?;
+    }
+
+    redis
+        .is_member(SUSPENDED_TENANTS, tenant)
+        .await
+        .map_err(handle_redis_error)
+}
+
+/// Loads the cache of suspended tenants from the database, replacing whatever it held.
+pub async fn load_suspensions(db: &Surreal, redis: &mut RedisClient) -> Result<(), TenantResult> {
+    let suspended = TenantRepository::new(db)
+        .list_suspended()
+        .await
+        .map_err(handle_repo_error)?;
+
+    redis
+        .replace_members(SUSPENDED_TENANTS, &suspended)
+        .await
+        .map_err(handle_redis_error)?;
+    redis
+        .set_key(SUSPENDED_TENANTS_LOADED, "1")
+        .await
+        .map(|_| ())
+        .map_err(handle_redis_error)
+}
+
+pub async fn verify_platform_jwt(
+    headers: &HeaderMap,
+    secret: &'static str,
+) -> (StatusCode, String) {
+    let auth_header_str = match headers.get("Authorization") {
+        Some(auth_header) => auth_header.to_str().unwrap_or(""),
+        None => {
+            return (
+                StatusCode::BAD_REQUEST,
+                "Authorization header is required".to_string(),
+            )
         }
     };
+    if !auth_header_str.starts_with("Bearer ") {
+        return (
+            StatusCode::BAD_REQUEST,
+            "Authorization header must start with Bearer".to_string(),
+        );
+    }
 
-    return TenantResult {
-        status: resp.status(),
-        detail: match resp.json::<String>().await {
-            Ok(json) => json,
-            Err(err) => err.to_string(),
+    let token = auth_header_str.trim_start_matches("Bearer ");
+
+    match decode::<PlatformClaims>(
+        token,
+        &DecodingKey::from_secret(secret.as_ref()),
+        &Validation::new(Algorithm::HS256),
+    ) {
+        Ok(token_) if token_.claims.typ != PLATFORM_TOKEN => {
+            (StatusCode::UNAUTHORIZED, "Invalid token".to_string())
+        }
+        Ok(token_)
+            if token_
+                .claims
+                .scope
+                .split_whitespace()
+                .any(|s| s == PLATFORM_ADMIN_SCOPE) =>
+        {
+            (StatusCode::OK, token_.claims.sub)
+        }
+        Ok(_) => (StatusCode::FORBIDDEN, "Insufficient scope".to_string()),
+        Err(_) => (StatusCode::UNAUTHORIZED, "Invalid token".to_string()),
+    }
+}
+
+async fn update_record(db: &Surreal, tenant: &str, patch: &TenantPatch) -> TenantResult {
+    match TenantRepository::new(db).update(tenant, patch).await {
+        Ok(Some(record)) => TenantResult {
+            detail: json!(record),
+            status: StatusCode::OK,
         },
-    };
+        Ok(None) => not_found(),
+        Err(e) => handle_repo_error(e),
+    }
+}
+
+fn not_found() -> TenantResult {
+    TenantResult {
+        detail: json!("Tenant not found"),
+        status: StatusCode::NOT_FOUND,
+    }
+}
+
+fn handle_redis_error(e: RedisError) -> TenantResult {
+    TenantResult {
+        detail: json!(e.detail().unwrap_or("Unknown error")),
+        status: StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+fn handle_repo_error(e: RepoError) -> TenantResult {
+    TenantResult {
+        detail: json!(e.to_string()),
+        status: e.status(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::constants::PLATFORM_NS;
+    use chrono::Utc;
+    use jsonwebtoken::{encode, EncodingKey, Header};
+
+    const SECRET: &str = "secret";
+
+    fn bearer(claims: Value) -> HeaderMap {
+        let token = encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(SECRET.as_bytes()),
+        )
+        .unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert("Authorization", format!("Bearer {token}").parse().unwrap());
+        headers
+    }
+
+    fn claims(typ: &str, scope: &str) -> Value {
+        let now = Utc::now().timestamp();
+        json!({
+            "typ": typ,
+            "iss": "haltion",
+            "iat": now,
+            "exp": now + 60,
+            "aud": "app",
+            "sub": "ops",
+            "tid": "acme",
+            "scope": scope,
+        })
+    }
+
+    #[test]
+    fn platform_namespace_is_reserved() {
+        assert!(is_reserved(PLATFORM_NS));
+        assert!(is_reserved("Haltion"));
+        assert!(!is_reserved("acme"));
+    }
+
+    #[tokio::test]
+    async fn reserved_tenants_are_not_created() {
+        // Never reached: the name is refused before the database is queried
+        let db = Surreal {
+            client: reqwest::Client::new(),
+            url: "http://127.0.0.1:9".to_owned(),
+            auth: String::new(),
+        };
+
+        let result = create_tenant(&db, PLATFORM_NS).await;
+
+        assert_eq!(result.status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn accepts_platform_admin_token() {
+        let headers = bearer(claims(PLATFORM_TOKEN, PLATFORM_ADMIN_SCOPE));
+
+        let result = verify_platform_jwt(&headers, SECRET).await;
+
+        assert_eq!(result, (StatusCode::OK, "ops".to_string()));
+    }
+
+    #[tokio::test]
+    async fn refuses_platform_token_without_admin_scope() {
+        let headers = bearer(claims(PLATFORM_TOKEN, "platform:read"));
+
+        let result = verify_platform_jwt(&headers, SECRET).await;
+
+        assert_eq!(result.0, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn refuses_user_token_carrying_admin_scope() {
+        let headers = bearer(claims("user", "user platform:admin"));
+
+        let result = verify_platform_jwt(&headers, SECRET).await;
+
+        assert_eq!(result.0, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn refuses_token_without_type() {
+        let mut claims = claims(PLATFORM_TOKEN, PLATFORM_ADMIN_SCOPE);
+        claims.as_object_mut().unwrap().remove("typ");
+
+        let result = verify_platform_jwt(&bearer(claims), SECRET).await;
+
+        assert_eq!(result.0, StatusCode::UNAUTHORIZED);
+    }
 }
@@ -0,0 +1,510 @@
+use crate::repositories::{
+    tenants::{Metric, Period, Quota, TenantPatch, TenantRepository},
+    RepoError, Surreal,
+};
+use crate::utils::redis::{RedisClient, TenantKeys};
+use axum::http::StatusCode;
+use chrono::{DateTime, Months, NaiveDate, Utc};
+use redis::RedisError;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::collections::{HashMap, HashSet};
+
+/// How long daily counters are kept, in seconds.
+const DAY_RETENTION: i64 = 90 * 86_400;
+/// How long monthly counters are kept, in seconds.
+const MONTH_RETENTION: i64 = 400 * 86_400;
+/// The largest number of periods returned by a single usage query.
+const MAX_PERIODS: usize = 92;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct UsageResult {
+    pub detail: Value,
+    pub status: StatusCode,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum QuotaError {
+    #[error("The {} quota for this {} has been exceeded", .0.as_str(), .1.as_str())]
+    Exceeded(Metric, Period),
+    #[error("{}", .0.detail().unwrap_or("Unknown error"))]
+    Redis(#[from] RedisError),
+}
+
+impl QuotaError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            QuotaError::Exceeded(..) => StatusCode::TOO_MANY_REQUESTS,
+            QuotaError::Redis(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// The range of periods to report usage for. Dates are `YYYY-MM-DD` for days and `YYYY-MM` for
+/// months, and default to the current period.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct UsageQuery {
+    pub period: Option<Period>,
+    pub from: Option<String>,
+    pub to: Option<String>,
+}
+
+/// Checks a tenant's quotas for `metric` before it is recorded.
+///
+/// # Errors
+///
+/// Returns `QuotaError::Exceeded` if a hard quota for the current day or month has been reached.
+/// Reaching a soft quota is only logged.
+pub async fn check_quota(
+    redis: &mut RedisClient,
+    tenant: &str,
+    metric: Metric,
+) -> Result<(), QuotaError> {
+    let now = Utc::now();
+    for quota in quotas(redis, tenant).await? {
+        if quota.metric != metric {
+            continue;
+        }
+
+        let date = period_date(quota.period, now);
+        let used = counter(redis, tenant, metric, quota.period, &date).await?;
+        enforce(tenant, &quota, used)?;
+    }
+
+    Ok(())
+}
+
+/// Checks a tenant's active user quotas before `sub` is counted. Users already counted in a
+/// period are let through.
+///
+/// # Errors
+///
+/// Returns `QuotaError::Exceeded` if `sub` would exceed a hard quota.
+pub async fn check_active_user(
+    redis: &mut RedisClient,
+    tenant: &str,
+    sub: &str,
+) -> Result<(), QuotaError> {
+    let now = Utc::now();
+    for quota in quotas(redis, tenant).await? {
+        if quota.metric != Metric::ActiveUsers {
+            continue;
+        }
+
+        let key = active_users_key(tenant, quota.period, &period_date(quota.period, now));
+        if redis.is_member(&key, sub).await? {
+            continue;
+        }
+        let used = redis.count_members(&key).await?;
+        enforce(tenant, &quota, used)?;
+    }
+
+    Ok(())
+}
+
+/// Counts one occurrence of `metric` for the current day and month. Failures are logged rather
+/// than returned, as they must not fail a request that has already been served.
+pub async fn record(redis: &mut RedisClient, tenant: &str, metric: Metric) {
+    let now = Utc::now();
+    for (period, ttl) in [
+        (Period::Day, DAY_RETENTION),
+        (Period::Month, MONTH_RETENTION),
+    ] {
+        let key = usage_key(tenant, period, &period_date(period, now));
+        if let Err(e) = redis.incr_hash_field(&key, metric.as_str(), 1, ttl).await {
+            log::error!("Failed to record {} for {tenant}: {e}", metric.as_str());
+        }
+    }
+}
+
+/// Counts `sub` as an active user of the current day and month.
+pub async fn record_active_user(redis: &mut RedisClient, tenant: &str, sub: &str) {
+    let now = Utc::now();
+    for (period, ttl) in [
+        (Period::Day, DAY_RETENTION),
+        (Period::Month, MONTH_RETENTION),
+    ] {
+        let key = active_users_key(tenant, period, &period_date(period, now));
+        if let Err(e) = redis.add_member(&key, sub, Some(ttl)).await {
+            log::error!("Failed to record an active user for {tenant}: {e}");
+        }
+    }
+}
+
+/// Reports a tenant's usage for a range of days or months, along with the state of its quotas in
+/// the current period.
+pub async fn get_usage(
+    db: &Surreal,
+    redis: &mut RedisClient,
+    tenant: &str,
+    query: &UsageQuery,
+) -> UsageResult {
+    match TenantRepository::new(db).find(tenant).await {
+        Ok(Some(_)) => (),
+        Ok(None) => return not_found(),
+        Err(e) => return handle_repo_error(e),
+    }
+
+    let period = query.period.unwrap_or(Period::Month);
+    let dates = match period_range(period, query.from.as_deref(), query.to.as_deref()) {
+        Ok(dates) => dates,
+        Err(detail) => {
+            return UsageResult {
+                detail: json!(detail),
+                status: StatusCode::UNPROCESSABLE_ENTITY,
+            }
+        }
+    };
+
+    let mut usage = Vec::with_capacity(dates.len());
+    for date in dates {
+        match usage_for(redis, tenant, period, &date).await {
+            Ok(mut counters) => {
+                counters.insert("date".to_owned(), json!(date));
+                usage.push(Value::Object(counters));
+            }
+            Err(e) => return handle_redis_error(e),
+        }
+    }
+
+    let now = Utc::now();
+    let mut states = Vec::new();
+    let tenant_quotas = match quotas(redis, tenant).await {
+        Ok(tenant_quotas) => tenant_quotas,
+        Err(e) => return handle_redis_error(e),
+    };
+    for quota in tenant_quotas {
+        let date = period_date(quota.period, now);
+        let used = match quota.metric {
+            Metric::ActiveUsers => {
+                redis
+                    .count_members(&active_users_key(tenant, quota.period, &date))
+                    .await
+            }
+            metric => counter(redis, tenant, metric, quota.period, &date).await,
+        };
+        let used = match used {
+            Ok(used) => used,
+            Err(e) => return handle_redis_error(e),
+        };
+        let state = match (quota.soft, quota.hard) {
+            (_, Some(hard)) if used >= hard => "hard_exceeded",
+            (Some(soft), _) if used >= soft => "soft_exceeded",
+            _ => "ok",
+        };
+        states.push(json!({
+            "metric": quota.metric,
+            "period": quota.period,
+            "soft": quota.soft,
+            "hard": quota.hard,
+            "used": used,
+            "state": state,
+        }));
+    }
+
+    UsageResult {
+        detail: json!({
+            "tenant": tenant,
+            "period": period,
+            "usage": usage,
+            "quotas": states,
+        }),
+        status: StatusCode::OK,
+    }
+}
+
+pub async fn get_quotas(db: &Surreal, tenant: &str) -> UsageResult {
+    match TenantRepository::new(db).find(tenant).await {
+        Ok(Some(record)) => UsageResult {
+            detail: json!(record.quotas),
+            status: StatusCode::OK,
+        },
+        Ok(None) => not_found(),
+        Err(e) => handle_repo_error(e),
+    }
+}
+
+/// Replaces a tenant's quotas and caches them in Redis, where they are read on every request.
+pub async fn set_quotas(
+    db: &Surreal,
+    redis: &mut RedisClient,
+    tenant: &str,
+    new_quotas: &[Quota],
+) -> UsageResult {
+    if let Err(detail) = validate(new_quotas) {
+        return UsageResult {
+            detail: json!(detail),
+            status: StatusCode::UNPROCESSABLE_ENTITY,
+        };
+    }
+
+    let patch = TenantPatch {
+        quotas: Some(new_quotas.to_vec()),
+        ..TenantPatch::new()
+    };
+    match TenantRepository::new(db).update(tenant, &patch).await {
+        Ok(Some(_)) => (),
+        Ok(None) => return not_found(),
+        Err(e) => return handle_repo_error(e),
+    }
+
+    let items: Vec<(String, String)> = new_quotas
+        .iter()
+        .map(|q| (quota_field(q), json!(q).to_string()))
+        .collect();
+    match redis.replace_hash(&quotas_key(tenant), &items).await {
+        Ok(_) => UsageResult {
+            detail: json!(new_quotas),
+            status: StatusCode::OK,
+        },
+        Err(e) => handle_redis_error(e),
+    }
+}
+
+async fn quotas(redis: &mut RedisClient, tenant: &str) -> Result<Vec<Quota>, RedisError> {
+    let cached = redis.get_hash(&quotas_key(tenant)).await?;
+
+    Ok(cached
+        .values()
+        .filter_map(|q| serde_json::from_str(q).ok())
+        .collect())
+}
+
+async fn counter(
+    redis: &mut RedisClient,
+    tenant: &str,
+    metric: Metric,
+    period: Period,
+    date: &str,
+) -> Result<u64, RedisError> {
+    let counters = redis.get_hash(&usage_key(tenant, period, date)).await?;
+
+    Ok(parse_counter(&counters, metric))
+}
+
+async fn usage_for(
+    redis: &mut RedisClient,
+    tenant: &str,
+    period: Period,
+    date: &str,
+) -> Result<Map<String, Value>, RedisError> {
+    let counters = redis.get_hash(&usage_key(tenant, period, date)).await?;
+    let active_users = redis
+        .count_members(&active_users_key(tenant, period, date))
+        .await?;
+
+    let mut usage = Map::new();
+    for metric in Metric::ALL {
+        let value = match metric {
+            Metric::ActiveUsers => active_users,
+            metric => parse_counter(&counters, metric),
+        };
+        usage.insert(metric.as_str().to_owned(), json!(value));
+    }
+
+    Ok(usage)
+}
+
+fn enforce(tenant: &str, quota: &Quota, used: u64) -> Result<(), QuotaError> {
+    if quota.hard.is_some_and(|hard| used >= hard) {
+        return Err(QuotaError::Exceeded(quota.metric, quota.period));
+    }
+    if quota.soft.is_some_and(|soft| used >= soft) {
+        log::warn!(
+            "{tenant} has exceeded its soft {} quota for this {}",
+            quota.metric.as_str(),
+            quota.period.as_str()
+        );
+    }
+
+    Ok(())
+}
+
+fn validate(quotas: &[Quota]) -> Result<(), &'static str> {
+    let mut seen = HashSet::new();
+    for quota in quotas {
+        if !seen.insert((quota.metric, quota.period)) {
+            return Err("Only one quota may be set per metric and period");
+        }
+        match (quota.soft, quota.hard) {
+            (None, None) => return Err("A quota needs a soft or a hard limit"),
+            (Some(soft), Some(hard)) if soft > hard => {
+                return Err("The soft limit must not be above the hard limit")
+            }
+            _ => (),
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the dates of every period from `from` to `to`, inclusive.
+fn period_range(
+    period: Period,
+    from: Option<&str>,
+    to: Option<&str>,
+) -> Result<Vec<String>, &'static str> {
+    let parse = |date: Option<&str>| -> Result<NaiveDate, &'static str> {
+        let date = match date {
+            Some(date) => date.to_owned(),
+            None => period_date(period, Utc::now()),
+        };
+        let parsed = match period {
+            Period::Day => NaiveDate::parse_from_str(&date, "%Y-%m-%d"),
+            Period::Month => NaiveDate::parse_from_str(&format!("{date}-01"), "%Y-%m-%d"),
+        };
+        parsed.map_err(|_| "Dates must be YYYY-MM-DD for days and YYYY-MM for months")
+    };
+    let (from, to) = (parse(from)?, parse(to)?);
+
+    let mut dates = Vec::new();
+    let mut date = from;
+    while date <= to {
+        if dates.len() == MAX_PERIODS {
+            return Err("The requested range is too large");
+        }
+        dates.push(format_date(period, date));
+        date = match period {
+            Period::Day => date.succ_opt(),
+            Period::Month => date.checked_add_months(Months::new(1)),
+        }
+        .ok_or("The requested range is out of bounds")?;
+    }
+
+    Ok(dates)
+}
+
+fn period_date(period: Period, now: DateTime<Utc>) -> String {
+    format_date(period, now.date_naive())
+}
+
+fn format_date(period: Period, date: NaiveDate) -> String {
+    match period {
+        Period::Day => date.format("%Y-%m-%d").to_string(),
+        Period::Month => date.format("%Y-%m").to_string(),
+    }
+}
+
+fn parse_counter(counters: &HashMap<String, String>, metric: Metric) -> u64 {
+    counters
+        .get(metric.as_str())
+        .and_then(|c| c.parse().ok())
+        .unwrap_or(0)
+}
+
+fn usage_key(tenant: &str, period: Period, date: &str) -> String {
+    TenantKeys::new(tenant).usage(period.as_str(), date)
+}
+
+fn active_users_key(tenant: &str, period: Period, date: &str) -> String {
+    TenantKeys::new(tenant).active_users(period.as_str(), date)
+}
+
+fn quotas_key(tenant: &str) -> String {
+    TenantKeys::new(tenant).quotas()
+}
+
+fn quota_field(quota: &Quota) -> String {
+    format!("{}:{}", quota.period.as_str(), quota.metric.as_str())
+}
+
+fn not_found() -> UsageResult {
+    UsageResult {
+        detail: json!("Tenant not found"),
+        status: StatusCode::NOT_FOUND,
+    }
+}
+
+fn handle_redis_error(e: RedisError) -> UsageResult {
+    UsageResult {
+        detail: json!(e.detail().unwrap_or("Unknown error")),
+        status: StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+fn handle_repo_error(e: RepoError) -> UsageResult {
+    UsageResult {
+        detail: json!(e.to_string()),
+        status: e.status(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn quota(soft: Option<u64>, hard: Option<u64>) -> Quota {
+        Quota {
+            metric: Metric::OtpSms,
+            period: Period::Month,
+            soft,
+            hard,
+        }
+    }
+
+    #[test]
+    fn enforce_rejects_only_at_the_hard_limit() {
+        let limits = quota(Some(5), Some(10));
+
+        assert!(enforce("acme", &limits, 4).is_ok());
+        // The soft limit is only logged
+        assert!(enforce("acme", &limits, 5).is_ok());
+        assert!(enforce("acme", &limits, 9).is_ok());
+        assert!(matches!(
+            enforce("acme", &limits, 10),
+            Err(QuotaError::Exceeded(Metric::OtpSms, Period::Month))
+        ));
+        assert!(enforce("acme", &quota(Some(5), None), 1_000).is_ok());
+    }
+
+    #[test]
+    fn validate_refuses_empty_inverted_and_duplicate_quotas() {
+        assert!(validate(&[quota(Some(5), Some(10)), quota(None, Some(1))]).is_err());
+        assert!(validate(&[quota(None, None)]).is_err());
+        assert!(validate(&[quota(Some(10), Some(5))]).is_err());
+        assert!(validate(&[
+            quota(Some(5), Some(10)),
+            Quota {
+                period: Period::Day,
+                ..quota(None, Some(1))
+            },
+        ])
+        .is_ok());
+    }
+
+    #[test]
+    fn periods_are_keyed_by_day_and_month() {
+        let now = Utc.with_ymd_and_hms(2024, 2, 29, 23, 59, 59).unwrap();
+
+        assert_eq!(period_date(Period::Day, now), "2024-02-29");
+        assert_eq!(period_date(Period::Month, now), "2024-02");
+        assert_eq!(
+            usage_key("acme", Period::Month, "2024-02"),
+            "tenant:acme:usage:month:2024-02"
+        );
+        assert_eq!(
+            active_users_key("acme", Period::Day, "2024-02-29"),
+            "tenant:acme:active_users:day:2024-02-29"
+        );
+    }
+
+    #[test]
+    fn period_range_lists_every_period() {
+        assert_eq!(
+            period_range(Period::Day, Some("2024-02-28"), Some("2024-03-01")).unwrap(),
+            ["2024-02-28", "2024-02-29", "2024-03-01"]
+        );
+        assert_eq!(
+            period_range(Period::Month, Some("2023-11"), Some("2024-01")).unwrap(),
+            ["2023-11", "2023-12", "2024-01"]
+        );
+        assert!(
+            period_range(Period::Day, Some("2024-03-01"), Some("2024-02-01"))
+                .unwrap()
+                .is_empty()
+        );
+        assert!(period_range(Period::Month, Some("2024-13"), None).is_err());
+        assert!(period_range(Period::Day, Some("2020-01-01"), Some("2024-01-01")).is_err());
+    }
+}
@@ -1,24 +1,31 @@
+use crate::repositories::{
+    clients::{Channel, ClientRecord},
+    tenants::{ReregisterPolicy, SignupMode},
+    users::{Identifier, UserRecord, UserRepository},
+    Ident, RepoError, Surreal,
+};
+use crate::services::{attributes, messages, passwords, settings, tenants};
 use crate::utils::{
-    jwt::{self, TenantClaims},
-    mailer::{self, Mailer},
-    redis::RedisClient,
-    topt,
+    jwt::{TenantClaims, TENANT_TOKEN},
+    password, random,
+    redis::{RedisClient, TenantKeys},
 };
 use axum::http::{HeaderMap, StatusCode};
+use chrono::Utc;
 use jsonwebtoken::{decode, Algorithm, DecodingKey, Validation};
-use lettre::message::Mailbox;
+use lettre::Address;
 use redis::{ErrorKind, RedisError};
 use reqwest::Client;
 use serde::Deserialize;
-use std::collections::HashMap;
-use url::Url;
+use serde_json::{json, Map, Value};
 
 const EMAIL_SUBJECT: &str = "Your verification code";
 const CODE_SENT: &str = "Verification code sent";
+const VERIFICATION_CODE_LEN: usize = 6;
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct ServiceResult {
-    pub detail: String,
+    pub detail: Value,
     pub status: StatusCode,
 }
 
@@ -33,16 +40,21 @@ pub struct DatabaseConfig {
 pub struct User {
     pub username: String,
     pub password: String,
-    pub client_domain: String,
+    pub client_id: String,
+    /// Profile attributes, checked against the tenant's attribute definitions.
+    #[serde(default)]
+    pub attributes: Map<String, Value>,
 }
 
 pub struct UserVerificationParams<'a> {
     redis: &'a mut RedisClient,
     username: &'a String,
-    domain: &'a String,
-    host: &'a VerificationHost<'a>,
+    client: &'a ClientRecord,
+    sender: &'a str,
+    host: &'a messages::VerificationHost<'a>,
     req: &'a Client,
-    secret_key: &'a String,
+    /// How long, in seconds, the user must wait between codes.
+    resend_interval: i64,
 }
 
 /// Parameters for storing a user in the database and sending a verification message.
@@ -50,103 +62,228 @@ pub struct StoreUserParams<'a> {
     /// The HTTP client used to send verification requests.
     pub client: &'a Client,
 
-    /// The authentication information for the database.
-    pub db_auth: &'a String,
-
-    /// The URL of the database.
-    pub db_url: &'a String,
+    /// The database the user is stored in.
+    pub db: &'a Surreal,
 
     /// The user to be stored and verified.
     pub user: &'a User,
 
+    /// The registered client the user signs up through.
+    pub app: &'a ClientRecord,
+
     /// The tenant to which the user belongs.
     pub tenant: &'a String,
 
+    /// Whether a tenant admin is adding the user, rather than the user signing up.
+    pub by_admin: bool,
+
     /// The Redis client used to cache verification tokens.
     pub redis: &'a mut RedisClient,
 
     /// The verification host that sends the verification message to the user.
-    pub v_host: &'a VerificationHost<'a>,
-
-    /// The secret key used to sign verification tokens.
-    pub app_secret: &'a String,
-}
-
-#[derive(Clone, Debug, PartialEq)]
-pub struct VerificationHost<'a> {
-    pub sms: &'a str,
-    pub smtp: &'a str,
-    pub smtp_port: &'a u16,
-    pub smtp_user: &'a str,
-    pub smtp_pass: &'a str,
+    pub v_host: &'a messages::VerificationHost<'a>,
 }
 
 pub async fn store_user(params: &mut StoreUserParams<'_>) -> ServiceResult {
-    let resp = match params
-        .client
-        .post(
-            Url::parse(params.db_url)
-                .unwrap()
-                .join("sql")
-                .unwrap()
-                .as_str(),
-        )
-        .headers(create_req_headers(params))
-        .body(create_req_body(params))
-        .send()
-        .await
-    {
-        Ok(resp) => resp,
-        Err(err) => {
+    let tenant = match Ident::parse(params.tenant) {
+        Ok(tenant) => tenant,
+        Err(e) => return handle_repo_error(e),
+    };
+    if params.app.tenant != *params.tenant {
+        return ServiceResult {
+            detail: json!("Unknown client_id".to_owned()),
+            status: StatusCode::UNAUTHORIZED,
+        };
+    }
+
+    let settings = match settings::lookup(params.db, params.redis, params.tenant).await {
+        Ok(settings) => settings,
+        Err(e) => {
             return ServiceResult {
-                detail: err.to_string(),
-                status: StatusCode::INTERNAL_SERVER_ERROR,
-            };
+                detail: json!(e.to_string()),
+                status: e.status(),
+            }
         }
     };
+    let refused = match (settings.signup, params.by_admin) {
+        (SignupMode::Closed, _) => Some("Sign-up is closed"),
+        (SignupMode::InviteOnly, false) => Some("Sign-up is by invitation only"),
+        _ => None,
+    };
+    if let Some(detail) = refused {
+        return ServiceResult {
+            detail: json!(detail.to_owned()),
+            status: StatusCode::FORBIDDEN,
+        };
+    }
 
-    let q_resp_stat = resp.status();
-    if q_resp_stat != StatusCode::OK {
+    if let Err(failures) =
+        passwords::check_policy(&settings.password, &params.user.password, None).await
+    {
         return ServiceResult {
-            status: q_resp_stat,
-            detail: resp.text().await.unwrap_or("".to_string()),
+            detail: passwords::policy_detail(&failures),
+            status: StatusCode::UNPROCESSABLE_ENTITY,
         };
+    }
+
+    if let Err(failures) = attributes::validate(&settings.attributes, &params.user.attributes) {
+        return invalid_attributes(&failures);
+    }
+
+    let username = messages::normalize(&params.user.username);
+    let repo = UserRepository::new(params.db, tenant);
+    let existing = match repo.find_by_identifier(&username).await {
+        Ok(existing) => existing,
+        Err(e) => return handle_repo_error(e),
+    };
+    let replace = match &existing {
+        // Another user has it as one of their identifiers
+        Some(user) if user.username != username => false,
+        Some(user) if !user.verified => {
+            settings.verification.reregister_unverified == ReregisterPolicy::Replace
+        }
+        Some(_) => false,
+        None => true,
+    };
+    if !replace {
+        return already_registered();
+    }
+
+    let hash = match password::hash(&params.user.password).await {
+        Ok(hash) => hash,
+        Err(e) => return handle_generic_error(Box::new(e), "Failed to store user"),
+    };
+    let stored = match existing {
+        // The earlier registration was never verified, so this one takes it over
+        Some(_) => repo
+            .replace_unverified(&username, &hash, &params.user.attributes)
+            .await
+            .map(|u| u.is_some()),
+        None => {
+            let now = Utc::now().to_rfc3339();
+            let record = UserRecord {
+                id: None,
+                username: username.clone(),
+                password: hash,
+                password_history: Vec::new(),
+                identifiers: vec![Identifier {
+                    value: username.clone(),
+                    primary: true,
+                    verified: false,
+                    verified_at: None,
+                }],
+                verified: false,
+                verified_at: None,
+                disabled: false,
+                attributes: params.user.attributes.clone(),
+                roles: Vec::new(),
+                groups: Vec::new(),
+                delete_after: None,
+                deletion_receipt: None,
+                created_at: Some(now.clone()),
+                updated_at: Some(now),
+            };
+            repo.create(&record).await.map(|_| true)
+        }
     };
+    match stored {
+        Ok(true) => (),
+        // The user was verified in the meantime, or registered by a concurrent request
+        Ok(false) => return already_registered(),
+        Err(e) if e.status() == StatusCode::CONFLICT => {
+            return match attributes::taken(&settings.attributes, &e) {
+                Some(failures) => ServiceResult {
+                    status: StatusCode::CONFLICT,
+                    ..invalid_attributes(&failures)
+                },
+                None => already_registered(),
+            }
+        }
+        Err(e) => return handle_repo_error(e),
+    }
 
     // Send verification code
     let result = verify_username(UserVerificationParams {
         redis: params.redis,
-        username: &params.user.username,
-        domain: &params.user.client_domain,
+        username: &username,
+        client: params.app,
+        sender: &settings.display_name,
         host: params.v_host,
         req: params.client,
-        secret_key: params.app_secret,
+        resend_interval: settings.verification.resend_interval,
     })
     .await;
 
     result
 }
 
-fn create_req_headers(params: &StoreUserParams) -> HeaderMap {
-    let mut headers = HeaderMap::new();
-    headers.insert("Accept", "application/json".parse().unwrap());
-    headers.insert(
-        "Authorization",
-        format!("Basic {}", params.db_auth).parse().unwrap(),
-    );
-    headers.insert("DB", params.tenant.parse().unwrap());
-    headers.insert("NS", params.tenant.parse().unwrap());
-    headers
+/// Checks a user's password. When the stored hash was made with outdated parameters, it is
+/// replaced with a fresh hash of the password.
+///
+/// # Arguments
+///
+/// * `db` - The database the user is stored in.
+/// * `tenant` - The tenant the user belongs to.
+/// * `user` - The user, or `None` if there is no such user.
+/// * `password` - The password to check.
+///
+/// # Returns
+///
+/// Returns whether the password is correct. It is never correct without a user, and both cases
+/// take the same time.
+///
+/// # Errors
+///
+/// Returns a `ServiceResult` if the password cannot be checked.
+pub async fn verify_password(
+    db: &Surreal,
+    tenant: &str,
+    user: Option<&UserRecord>,
+    password: &str,
+) -> Result<bool, ServiceResult> {
+    let verification = password::verify(password, user.map(|u| u.password.as_str()))
+        .await
+        .map_err(|e| handle_generic_error(Box::new(e), "Failed to check password"))?;
+
+    if let (true, true, Some(user)) = (verification.valid, verification.needs_rehash, user) {
+        let username = &user.username;
+        let repo = UserRepository::new(db, Ident::parse(tenant).map_err(handle_repo_error)?);
+        match password::hash(password).await {
+            Ok(hash) => {
+                if let Err(e) = repo.set_password(username, &hash).await {
+                    log::error!("Failed to rehash the password of {username}: {e}");
+                }
+            }
+            Err(e) => log::error!("Failed to rehash the password of {username}: {e}"),
+        }
+    }
+
+    Ok(verification.valid)
 }
 
-fn create_req_body(params: &StoreUserParams) -> String {
-    format!(
-        "CREATE user CONTENT {{\"username\": \"{}\", \"password\": \"{}\", \"verified\": false}}",
-        params.user.username, params.user.password
-    )
+/// Finds the user a phone number or email address signs in as: the user whose username it is,
+/// or who has it as another verified identifier.
+pub async fn find_account(
+    db: &Surreal,
+    tenant: &str,
+    identifier: &str,
+) -> Result<Option<UserRecord>, RepoError> {
+    let identifier = messages::normalize(identifier);
+    let user = UserRepository::new(db, Ident::parse(tenant)?)
+        .find_by_identifier(&identifier)
+        .await?;
+
+    Ok(user.filter(|user| {
+        user.username == identifier || user.identifier(&identifier).is_some_and(|i| i.verified)
+    }))
 }
 
-pub async fn verify_tenant_jwt(headers: &HeaderMap, secret: &'static str) -> (StatusCode, String) {
+pub async fn verify_tenant_jwt(
+    db: &Surreal,
+    redis: &mut RedisClient,
+    headers: &HeaderMap,
+    secret: &'static str,
+) -> (StatusCode, String) {
     let auth = headers.get("Authorization");
     let result = match auth {
         Some(auth_header) => {
@@ -165,14 +302,20 @@ pub async fn verify_tenant_jwt(headers: &HeaderMap, secret: &'static str) -> (St
                 &DecodingKey::from_secret(secret.as_ref()),
                 &Validation::new(Algorithm::HS256),
             ) {
-                Ok(token_) => (StatusCode::OK, token_.claims.tenantid),
-                Err(_) => (StatusCode::UNAUTHORIZED, "Invalid token".to_string()),
+                Ok(token_) if token_.claims.typ == TENANT_TOKEN => {
+                    (StatusCode::OK, token_.claims.tenantid)
+                }
+                _ => (StatusCode::UNAUTHORIZED, "Invalid token".to_string()),
             };
             if v_result.0 != StatusCode::OK {
                 return v_result;
             }
 
-            v_result
+            match tenants::is_suspended(db, redis, &v_result.1).await {
+                Ok(false) => v_result,
+                Ok(true) => (StatusCode::FORBIDDEN, "Tenant is suspended".to_string()),
+                Err(e) => (e.status, e.detail.as_str().unwrap_or_default().to_owned()),
+            }
         }
         None => {
             return (
@@ -185,196 +328,217 @@ pub async fn verify_tenant_jwt(headers: &HeaderMap, secret: &'static str) -> (St
     result
 }
 
-/// Checks if the given string is a valid phone number based on the provided format.
+/// Generates a verification code and sends it to the user's username via SMS or email, depending on whether the username is a phone number or an email address.
 ///
-/// The phone number must match one of the following patterns:
-/// - +X-YYY-ZZZ-ZZZZ: country code followed by hyphenated area code and phone number
-/// - +XX-YYY-ZZZ-ZZZZ: country code followed by hyphenated area code and phone number
-/// - +XXX-YYY-ZZZ-ZZZZ: country code followed by hyphenated area code and phone number
-/// - (YYY)ZZZ-ZZZZ: area code in parentheses followed by hyphenated phone number
-/// - YYY-ZZZ-ZZZZ: hyphenated area code and phone number
-/// - YYYYYYYYYY: 10-digit phone number with no separators
+/// A new code replaces any code sent before, which stops working. Codes are not sent more often
+/// than the tenant's resend interval allows.
 ///
 /// # Arguments
 ///
-/// * `s` - A string slice that contains the phone number to check.
-///
-/// # Examples
-///
-/// ```
-/// assert!(is_phone_number("+639123456789"));
-/// assert!(is_phone_number("+1-202-555-0130"));
-/// assert!(is_phone_number("0919123456789"));
-/// assert!(is_phone_number("202-555-0130"));
-/// assert!(!is_phone_number("123-456-789")); // invalid format
-/// assert!(!is_phone_number("12345")); // too short
-/// assert!(!is_phone_number("1234567890123456")); // too long
-/// ```
-fn is_phone_number(s: &str) -> bool {
-    let re = regex::Regex::new(
-        r#"^(\+\d{1,3})?[-\s.]?(\(\d{1,3}\)|\d{1,3})[-\s.]?(\d{3,4})[-\s.]?(\d{4})$"#,
-    )
-    .unwrap();
-    re.is_match(s)
-}
-
-/// Verify an OTP and return the associated phone number.
-///
-/// # Arguments
-///
-/// * `redis` - A mutable reference to a Redis client instance.
-/// * `otp` - The OTP to verify.
-///
-/// # Returns
-///
-/// Returns the phone number associated with the given OTP, if it is valid. If the OTP is invalid
-/// or has expired, returns a `SessionResult`.
+/// * redis - A mutable reference to a RedisClient for storing the code.
+/// * username - A reference to a String containing the user's username, which can be a phone number or an email address.
+/// * client - The registered client the code is sent for. It must allow the channel used.
+/// * sender - The name the code is sent under, usually the tenant's display name.
+/// * host - The services the code is sent through.
+/// * req - A Client for sending HTTP requests to the SMS API endpoint.
+/// * resend_interval - How long, in seconds, the user must wait between codes.
 ///
 /// # Errors
 ///
-/// Returns a `SessionResult` if an error occurs while communicating with the Redis server or if the
-/// JWT signing operation fails.
-///
-/// # Examples
-///
-/// ```
-/// # use myapp::RedisClient;
-/// # use myapp::verify_otp;
-/// #
-/// # async fn example() -> Result<(), Box<dyn std::error::Error>> {
-/// let mut redis = RedisClient::connect("redis://localhost").await?;
-///
-/// let otp = "123456".to_str ing();
-/// let phone_number = verify_otp(&mut redis, &otp).await?;
-///
-/// println!("Phone number: {}", phone_number);
-/// # Ok(())
-/// # }
-/// ```
-pub async fn verify_otp(redis: &mut RedisClient, otp: &str) -> ServiceResult {
-    let domain = match redis.get_key(otp).await {
-        Ok(domain) => domain,
-        Err(e) => return handle_redis_error(e),
-    };
+/// Returns a ServiceResult if the code was sent too recently, or there is an error sending the SMS or email, or adding the code to Redis.
+pub async fn verify_username(verif: UserVerificationParams<'_>) -> ServiceResult {
+    let channel = messages::channel_of(verif.username);
+    if !verif.client.allows_channel(channel) {
+        return ServiceResult {
+            detail: json!(format!("{channel:?} is not allowed for this client")),
+            status: StatusCode::FORBIDDEN,
+        };
+    }
+    if channel == Channel::Email && verif.username.parse::<Address>().is_err() {
+        return ServiceResult {
+            detail: json!("Username must be a phone number or an email address".to_owned()),
+            status: StatusCode::UNPROCESSABLE_ENTITY,
+        };
+    }
 
-    let phone_number = match redis.get_key(otp).await {
-        Ok(phone_number) => phone_number,
+    let key = TenantKeys::new(&verif.client.tenant).verification(verif.username);
+    let now = Utc::now().timestamp();
+    let sent_at = match verif.redis.get_hash(&key).await {
+        Ok(entry) => entry.get("sent_at").and_then(|t| t.parse::<i64>().ok()),
         Err(e) => return handle_redis_error(e),
     };
-    let token = jwt::sign(phone_number, domain).await.unwrap();
+    if sent_at.is_some_and(|t| now - t < verif.resend_interval) {
+        return ServiceResult {
+            detail: json!("A code was sent recently, try again later".to_owned()),
+            status: StatusCode::TOO_MANY_REQUESTS,
+        };
+    }
 
-    // Delete OTP to prevent reuse
-    redis.del_key(otp).await.unwrap();
+    // Replace any earlier code
+    let code = random::numeric(VERIFICATION_CODE_LEN);
+    if let Err(e) = verif.redis.del_key(&key).await {
+        return handle_redis_error(e);
+    }
+    if let Err(e) = verif
+        .redis
+        .set_key_map(
+            &key,
+            &[
+                ("code", code.as_str()),
+                ("client_id", verif.client.client_id.as_str()),
+                ("attempts", "0"),
+                ("sent_at", now.to_string().as_str()),
+            ],
+            verif.client.otp_ttl,
+        )
+        .await
+    {
+        return handle_redis_error(e);
+    }
+
+    // Send code via SMS or email
+    let message = messages::Message {
+        tenant: &verif.client.tenant,
+        recipient: verif.username,
+        sender: verif.sender,
+        subject: EMAIL_SUBJECT,
+        body: &code,
+    };
+    if let Err(e) = messages::send(verif.redis, verif.req, verif.host, &message).await {
+        return ServiceResult {
+            detail: json!(e.detail),
+            status: e.status,
+        };
+    }
 
     ServiceResult {
-        detail: token,
+        detail: json!(CODE_SENT.to_owned()),
         status: StatusCode::OK,
     }
 }
 
-/// Generates a one-time password (OTP) and sends it to the user's username via SMS or email, depending on whether the username is a phone number or an email address.
+/// Parameters for sending a user another verification code.
+pub struct ResendVerificationParams<'a> {
+    pub db: &'a Surreal,
+    pub redis: &'a mut RedisClient,
+    /// The HTTP client used to send the code.
+    pub req: &'a Client,
+    pub host: &'a messages::VerificationHost<'a>,
+    /// The registered client the user signed up through.
+    pub client: &'a ClientRecord,
+    pub username: &'a String,
+}
+
+/// Sends a new verification code to an unverified username or identifier, replacing the previous
+/// one.
+///
+/// The response is the same whether or not the user exists or is already verified, so that it
+/// does not reveal which usernames are registered.
+pub async fn resend_verification(params: ResendVerificationParams<'_>) -> ServiceResult {
+    let tenant = match Ident::parse(&params.client.tenant) {
+        Ok(tenant) => tenant,
+        Err(e) => return handle_repo_error(e),
+    };
+    let username = messages::normalize(params.username);
+    match UserRepository::new(params.db, tenant)
+        .find_by_identifier(&username)
+        .await
+    {
+        Ok(Some(user)) if user.identifier(&username).is_some_and(|i| !i.verified) => (),
+        Ok(_) => {
+            return ServiceResult {
+                detail: json!(CODE_SENT.to_owned()),
+                status: StatusCode::OK,
+            }
+        }
+        Err(e) => return handle_repo_error(e),
+    }
+
+    let settings = match settings::lookup(params.db, params.redis, &params.client.tenant).await {
+        Ok(settings) => settings,
+        Err(e) => {
+            return ServiceResult {
+                detail: json!(e.to_string()),
+                status: e.status(),
+            }
+        }
+    };
+
+    verify_username(UserVerificationParams {
+        redis: params.redis,
+        username: &username,
+        client: params.client,
+        sender: &settings.display_name,
+        host: params.host,
+        req: params.req,
+        resend_interval: settings.verification.resend_interval,
+    })
+    .await
+}
+
+/// Confirms a user's username, or another of their identifiers, with the code sent to it. The user
+/// is verified once their username is.
 ///
 /// # Arguments
 ///
-/// * redis - A mutable reference to a RedisClient for storing the OTP.
-/// * username - A reference to a String containing the user's username, which can be a phone number or an email address.
-/// * domain - A reference to a String containing the domain of the user's tenant.
-/// * host - A reference to a String containing the URL of the SMS or email API endpoint, depending on the user's username.
-/// * req - A Client for sending HTTP requests to the SMS or email API endpoint.
-/// * secret_key - A reference to a String containing the secret key for generating the OTP.
+/// * `db` - The database the user is stored in.
+/// * `redis` - A mutable reference to a Redis client instance.
+/// * `client` - The registered client the code was sent for.
+/// * `username` - The username or identifier being confirmed.
+/// * `code` - The code sent to it.
 ///
 /// # Errors
 ///
-/// Returns a ServiceResult if there is an error generating the JWT, sending the SMS or email, or adding the token to Redis.
-///
-/// # Example
-///
-///
-/// ```
-/// let mut redis = RedisClient::new("redis://localhost").await?;
-/// let username = "john@example.com".to_owned();
-/// let domain = "example.com".to_owned();
-/// let host = "https://example.com".to_owned();
-/// let req = reqwest::Client::new();
-/// let secret_key = "mysecretkey".to_owned();
-/// verify_username(&mut redis, &username, &domain, &host, &req, &secret_key).await?;
-/// ```
-pub async fn verify_username(verif: UserVerificationParams<'_>) -> ServiceResult {
-    // Generate OTP
-    let otp = match topt::generate_token(verif.secret_key).await {
-        Ok(otp) => otp,
-        Err(e) => return handle_generic_error(e, "Failed to generate OTP"),
+/// Returns a `ServiceResult` if the code is wrong, has expired or was replaced by a newer one, or
+/// the user cannot be updated. A code is dropped after too many wrong attempts.
+pub async fn confirm_verification(
+    db: &Surreal,
+    redis: &mut RedisClient,
+    client: &ClientRecord,
+    username: &str,
+    code: &str,
+) -> ServiceResult {
+    let tenant = match Ident::parse(&client.tenant) {
+        Ok(tenant) => tenant,
+        Err(e) => return handle_repo_error(e),
     };
-
-    // Add token to redis
-    match verif
-        .redis
-        .set_key_map(
-            &otp,
-            &[(verif.username.to_owned(), verif.domain.to_owned())],
-        )
-        .await
-    {
-        Ok(_) => (),
+    let username = &messages::normalize(username);
+    let key = TenantKeys::new(&client.tenant).verification(username);
+    match messages::check_code(redis, &key, code).await {
+        Ok(Some(entry)) if entry.get("client_id") == Some(&client.client_id) => (),
+        Ok(_) => return invalid_code(),
         Err(e) => return handle_redis_error(e),
-    };
-
-    // Send OTP via SMS or email
-    if is_phone_number(verif.username) {
-        let mut map = HashMap::new();
-        map.insert("recipient", verif.username);
-        map.insert("content", &otp);
-
-        match verif
-            .req
-            .post(format!("{}/messages", verif.host.sms))
-            .json(&map)
-            .send()
-            .await
-        {
-            Ok(_) => (),
-            Err(e) => return handle_reqwest_error(e),
-        };
     }
 
-    match mailer::send_mail(
-        mailer::EnvelopeContent {
-            from: Mailbox {
-                name: Some("Haltion".to_owned()),
-                email: verif.host.smtp_user.parse().unwrap(),
-            },
-            to: Mailbox {
-                name: Some("User".to_owned()),
-                email: verif.username.parse().unwrap(),
-            },
-            subject: EMAIL_SUBJECT.to_owned(),
-            body: otp,
-        },
-        Mailer {
-            host_addr: verif.host.smtp,
-            username: verif.host.smtp_user.to_string(),
-            password: verif.host.smtp_pass.to_string(),
-        },
-    )
-    .await
-    {
-        Ok(resp) => {
-            if !resp.is_positive() {
-                return ServiceResult {
-                    detail: format!("Failed to send email: {}", resp.code()),
-                    status: StatusCode::BAD_GATEWAY,
-                };
-            }
-        }
-        Err(e) => return handle_generic_error(Box::new(e), "Failed to send email"),
+    let repo = UserRepository::new(db, tenant);
+    let mut user = match repo.find_by_identifier(username).await {
+        Ok(Some(user)) => user,
+        Ok(None) => return invalid_code(),
+        Err(e) => return handle_repo_error(e),
     };
+    user.verify_identifier(username);
+    match repo.save_identifiers(&user).await {
+        Ok(Some(_)) => (),
+        Ok(None) => return invalid_code(),
+        Err(e) => return handle_repo_error(e),
+    }
+    if let Err(e) = redis.del_key(&key).await {
+        log::error!("Failed to delete the verification code of {username}: {e}");
+    }
 
     ServiceResult {
-        detail: CODE_SENT.to_owned(),
+        detail: json!("User verified".to_owned()),
         status: StatusCode::OK,
     }
 }
 
+fn invalid_code() -> ServiceResult {
+    ServiceResult {
+        detail: json!("Invalid or expired code".to_owned()),
+        status: StatusCode::BAD_REQUEST,
+    }
+}
+
 /// Convert a RedisError into a SessionResult.
 ///
 /// # Arguments
@@ -429,33 +593,9 @@ fn handle_redis_error(e: RedisError) -> ServiceResult {
         }
     };
 
-    ServiceResult { detail, status }
-}
-
-/// Returns a `SessionResult` with a bad gateway status and a detail message
-/// containing information about the Reqwest error that occurred.
-///
-/// # Arguments
-///
-/// * `e` - The `reqwest::Error` that occurred.
-///
-/// # Returns
-///
-/// A `SessionResult` with a bad gateway status and a detail message
-/// containing information about the Reqwest error that occurred.
-///
-/// # Example
-///
-/// ```
-/// let e = reqwest::Error::new(std::io::Error::new(std::io::ErrorKind::Other, "Custom error"));
-/// let service_error = handle_reqwest_error(e);
-/// assert_eq!(service_error.status, StatusCode::BAD_GATEWAY);
-/// assert_eq!(service_error.detail, "Reqwest error: error sending request for `http://example.com`: Custom error");
-/// ```
-fn handle_reqwest_error(e: reqwest::Error) -> ServiceResult {
     ServiceResult {
-        detail: format!("Reqwest error: {e}"),
-        status: StatusCode::BAD_GATEWAY,
+        detail: json!(detail),
+        status,
     }
 }
 
@@ -470,7 +610,28 @@ fn handle_reqwest_error(e: reqwest::Error) -> ServiceResult {
 /// A `SessionResult` struct that contains the error message and status code.
 fn handle_generic_error(e: Box<dyn std::error::Error>, title: &'static str) -> ServiceResult {
     ServiceResult {
-        detail: format!("{title}: {e}"),
+        detail: json!(format!("{title}: {e}")),
         status: StatusCode::INTERNAL_SERVER_ERROR,
     }
 }
+
+fn already_registered() -> ServiceResult {
+    ServiceResult {
+        detail: json!("Username is already registered".to_owned()),
+        status: StatusCode::CONFLICT,
+    }
+}
+
+fn invalid_attributes(failures: &[attributes::AttributeFailure]) -> ServiceResult {
+    ServiceResult {
+        detail: attributes::failure_detail(failures),
+        status: StatusCode::UNPROCESSABLE_ENTITY,
+    }
+}
+
+fn handle_repo_error(e: RepoError) -> ServiceResult {
+    ServiceResult {
+        detail: json!(e.to_string()),
+        status: e.status(),
+    }
+}
@@ -1,3 +1,4 @@
+use crate::repositories::Surreal;
 use crate::utils::redis::RedisClient;
 use std::sync::Arc;
 use tokio::sync::Mutex;
@@ -7,4 +8,5 @@ use tokio::sync::Mutex;
 pub struct AppState {
     pub(crate) redis: Arc<Mutex<RedisClient>>,
     pub(crate) http: reqwest::Client,
+    pub(crate) db: Surreal,
 }
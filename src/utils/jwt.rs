@@ -1,17 +1,61 @@
 use chrono::{Duration, Utc};
-use jsonwebtoken::{DecodingKey, EncodingKey, Header, Validation};
+use jsonwebtoken::{errors::ErrorKind, DecodingKey, EncodingKey, Header, Validation};
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
 
 use crate::config::env;
 
-#[derive(Debug, Serialize, Deserialize)]
+/// The scope every user token carries, whatever else the user is granted.
+pub const USER_SCOPE: &str = "user";
+
+/// The `typ` claim of each kind of token. Every kind is signed with the same secret, so verifiers
+/// check it to refuse a token issued for another purpose.
+pub const USER_TOKEN: &str = "user";
+pub const TENANT_TOKEN: &str = "tenant";
+pub const PLATFORM_TOKEN: &str = "platform";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UserClaims {
-    iss: String,
-    iat: i64,
-    exp: i64,
-    aud: String,
-    sub: String,
-    scope: String,
+    /// The kind of token, always `user`.
+    pub typ: String,
+    pub iss: String,
+    pub iat: i64,
+    pub exp: i64,
+    pub aud: String,
+    pub sub: String,
+    /// The scopes the token grants, separated by spaces.
+    pub scope: String,
+    /// The groups the subject is a member of.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub groups: Vec<String>,
+    /// The tenant the subject belongs to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tid: Option<String>,
+    /// The server-side session the token was issued for.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sid: Option<String>,
+    /// The acting party when the token was obtained through delegation (RFC 8693).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub act: Option<Actor>,
+    /// The key the token is bound to when issued against a DPoP proof (RFC 9449).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cnf: Option<Confirmation>,
+    /// Claims mapped from the subject's profile attributes, as the tenant defines them.
+    #[serde(flatten)]
+    pub profile: Map<String, Value>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Confirmation {
+    pub jkt: String,
+}
+
+/// An `act` claim. Nested actors record the prior links of a delegation chain.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Actor {
+    pub sub: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub act: Option<Box<Actor>>,
 }
 
 impl UserClaims {
@@ -20,35 +64,88 @@ impl UserClaims {
         let exp = iat + Duration::hours(24);
 
         Self {
+            typ: USER_TOKEN.to_string(),
             iss: env::APP_NAME.to_string(),
             aud,
             sub,
             iat: iat.timestamp(),
             exp: exp.timestamp(),
-            scope: "user".to_string(),
+            scope: USER_SCOPE.to_string(),
+            groups: Vec::new(),
+            tid: None,
+            sid: None,
+            act: None,
+            cnf: None,
+            profile: Map::new(),
         }
     }
 }
 
-pub async fn sign(sub: String, aud: String) -> Result<String, jsonwebtoken::errors::Error> {
+/// Parameters for issuing a user access token.
+#[derive(Debug, Clone, Default)]
+pub struct SignParams {
+    pub sub: String,
+    pub aud: String,
+    /// The tenant the user belongs to.
+    pub tenant: Option<String>,
+    /// The session the token is issued for.
+    pub sid: Option<String>,
+    /// The thumbprint of the DPoP key the token is bound to.
+    pub jkt: Option<String>,
+    /// How long, in seconds, the token is valid. Defaults to 24 hours.
+    pub ttl: Option<i64>,
+    /// The scopes the token grants, separated by spaces. Defaults to the user scope alone.
+    pub scope: Option<String>,
+    /// The groups the user is a member of.
+    pub groups: Vec<String>,
+    /// Claims mapped from the user's profile attributes.
+    pub profile: Map<String, Value>,
+}
+
+pub async fn sign(params: SignParams) -> Result<String, jsonwebtoken::errors::Error> {
+    let mut claims = UserClaims::new(params.sub, params.aud).await;
+    if let Some(ttl) = params.ttl {
+        claims.exp = claims.iat + ttl;
+    }
+    claims.tid = params.tenant;
+    claims.sid = params.sid;
+    claims.cnf = params.jkt.map(|jkt| Confirmation { jkt });
+    if let Some(scope) = params.scope {
+        claims.scope = scope;
+    }
+    claims.groups = params.groups;
+    claims.profile = params.profile;
+
+    encode(&claims).await
+}
+
+pub async fn encode(claims: &UserClaims) -> Result<String, jsonwebtoken::errors::Error> {
     jsonwebtoken::encode(
         &Header::default(),
-        &UserClaims::new(sub, aud).await,
+        claims,
         &EncodingKey::from_secret(env::APP_SECRET.as_bytes()),
     )
 }
 
-pub async fn verify(token: &str) -> Result<String, jsonwebtoken::errors::Error> {
-    jsonwebtoken::decode(
+/// Verifies a user token and returns its claims. Tokens of any other kind are refused.
+pub async fn verify(token: &str) -> Result<UserClaims, jsonwebtoken::errors::Error> {
+    let claims: UserClaims = jsonwebtoken::decode(
         token,
         &DecodingKey::from_secret(env::APP_SECRET.as_bytes()),
         &Validation::default(),
-    )
-    .map(|data| data.claims)
+    )?
+    .claims;
+
+    match claims.typ == USER_TOKEN {
+        true => Ok(claims),
+        false => Err(ErrorKind::InvalidToken.into()),
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct TenantClaims {
+    /// The kind of token, always `tenant`.
+    pub typ: String,
     iss: String,
     iat: i64,
     exp: i64,
@@ -56,3 +153,58 @@ pub struct TenantClaims {
     sub: String,
     pub tenantid: String,
 }
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PlatformClaims {
+    /// The kind of token, always `platform`.
+    pub typ: String,
+    iss: String,
+    iat: i64,
+    exp: i64,
+    pub sub: String,
+    pub scope: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn use_test_env() {
+        std::env::set_var("APP_NAME", "haltion");
+        std::env::set_var("APP_SECRET", "secret");
+    }
+
+    #[tokio::test]
+    async fn verify_accepts_user_tokens() {
+        use_test_env();
+        let token = sign(SignParams {
+            sub: "jane@example.com".to_owned(),
+            aud: "app".to_owned(),
+            tenant: Some("acme".to_owned()),
+            ..SignParams::default()
+        })
+        .await
+        .unwrap();
+
+        let claims = verify(&token).await.unwrap();
+
+        assert_eq!(claims.typ, USER_TOKEN);
+        assert_eq!(claims.scope, USER_SCOPE);
+    }
+
+    #[tokio::test]
+    async fn verify_refuses_other_kinds_of_token() {
+        use_test_env();
+        let mut claims = json!(UserClaims::new("ops".to_owned(), "app".to_owned()).await);
+        claims["typ"] = json!(PLATFORM_TOKEN);
+        let token = jsonwebtoken::encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(env::APP_SECRET.as_bytes()),
+        )
+        .unwrap();
+
+        assert!(verify(&token).await.is_err());
+    }
+}
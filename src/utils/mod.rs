@@ -1,4 +1,7 @@
+pub mod dpop;
 pub mod jwt;
 pub mod mailer;
+pub mod password;
+pub mod random;
 pub mod redis;
-pub mod topt;
+pub mod request;
@@ -0,0 +1,531 @@
+use crate::config::env;
+use aes::Aes256;
+use argon2::{
+    password_hash::{rand_core::OsRng, SaltString},
+    Algorithm, Argon2, Params, PasswordHash, PasswordHasher, PasswordVerifier, Version,
+};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use ctr::{
+    cipher::{KeyIvInit, StreamCipher},
+    Ctr128BE,
+};
+use lazy_static::lazy_static;
+use sha1::{Digest, Sha1};
+use sha2::Sha256;
+use std::{io, path::Path};
+
+/// The prefix of hashes imported from Firebase Authentication, which are stored as
+/// `$firebase-scrypt$mem_cost=M,rounds=R$<salt>$<salt separator>$<signer key>$<hash>` with every
+/// part in standard base64.
+const FIREBASE_SCRYPT: &str = "$firebase-scrypt$";
+/// The prefix of PBKDF2-SHA256 hashes in Django's format, `pbkdf2_sha256$<iterations>$<salt>$<hash>`.
+const DJANGO_PBKDF2: &str = "pbkdf2_sha256$";
+
+/// The highest bcrypt cost accepted in imported hashes.
+const MAX_BCRYPT_COST: u32 = 14;
+/// The most PBKDF2 rounds accepted in imported hashes.
+const MAX_PBKDF2_ROUNDS: u32 = 2_000_000;
+/// The longest PBKDF2 output, in bytes, accepted in imported hashes.
+const MAX_PBKDF2_LENGTH: usize = 64;
+/// The most scrypt work, `N * r * p`, accepted in imported hashes. It also bounds the memory
+/// taken, `128 * N * r` bytes, to 128 MiB.
+const MAX_SCRYPT_WORK: u64 = 1 << 20;
+
+lazy_static! {
+    /// The Argon2id parameters new hashes are made with.
+    static ref PARAMS: Params = Params::new(
+        env::ARGON2_MEMORY_KIB.parse().unwrap_or(Params::DEFAULT_M_COST),
+        env::ARGON2_ITERATIONS.parse().unwrap_or(Params::DEFAULT_T_COST),
+        env::ARGON2_PARALLELISM.parse().unwrap_or(Params::DEFAULT_P_COST),
+        None,
+    )
+    .expect("invalid Argon2 parameters");
+
+    /// A hash verified in place of a missing one, so that unknown users take as long to reject
+    /// as wrong passwords.
+    static ref DUMMY_HASH: String = hash_blocking("haltion-dummy-password").unwrap();
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PasswordError {
+    #[error("Failed to hash password: {0}")]
+    Hash(argon2::password_hash::Error),
+    #[error("Password hashing task failed")]
+    Task(#[from] tokio::task::JoinError),
+}
+
+/// The outcome of checking a password against a stored hash.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Verification {
+    pub valid: bool,
+    /// Whether the hash was made with other parameters than the current ones and should be
+    /// replaced, now that the password is known.
+    pub needs_rehash: bool,
+}
+
+/// Hashes a password with Argon2id and a random salt.
+///
+/// The result is a PHC string such as `$argon2id$v=19$m=19456,t=2,p=1$<salt>$<hash>`, which
+/// records the algorithm and parameters along with the hash.
+///
+/// # Errors
+///
+/// Returns a `PasswordError` if hashing fails.
+pub async fn hash(password: &str) -> Result<String, PasswordError> {
+    let password = password.to_owned();
+    tokio::task::spawn_blocking(move || hash_blocking(&password)).await?
+}
+
+/// Checks a password against a stored hash.
+///
+/// Besides Argon2 hashes, the hashes of other systems users are imported from are accepted:
+/// bcrypt, scrypt and PBKDF2-SHA256 PHC strings, PBKDF2-SHA256 in Django's format, and Firebase
+/// scrypt. A password matching one of these always needs a rehash. When there is no stored hash,
+/// a dummy hash is checked instead and the password is reported as invalid, so that the time
+/// taken does not reveal whether the user exists. Hashes are compared in constant time. Hashes
+/// that cannot be parsed, or whose costs are above the limits of `is_supported`, never match.
+pub async fn verify(password: &str, stored: Option<&str>) -> Result<Verification, PasswordError> {
+    let password = password.to_owned();
+    let stored = stored.map(str::to_owned);
+
+    tokio::task::spawn_blocking(move || {
+        // Hashes imported from other systems are replaced once the password is known
+        if let Some(valid) = stored.as_deref().and_then(|s| verify_foreign(&password, s)) {
+            return Verification {
+                valid,
+                needs_rehash: valid,
+            };
+        }
+
+        let (hash, known) = match stored.as_deref().map(PasswordHash::new) {
+            Some(Ok(hash)) => (hash, true),
+            _ => (PasswordHash::new(&DUMMY_HASH).unwrap(), false),
+        };
+        let valid = Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok();
+
+        Verification {
+            valid: valid && known,
+            needs_rehash: valid && known && is_outdated(&hash),
+        }
+    })
+    .await
+    .map_err(PasswordError::from)
+}
+
+/// Checks whether a stored hash is in a format passwords can be checked against.
+///
+/// Hashes of other systems are only supported up to a cost, so that an imported hash cannot
+/// make each login attempt against it take minutes or gigabytes.
+pub fn is_supported(hash: &str) -> bool {
+    match foreign_kind(hash) {
+        Some(Foreign::Bcrypt) => hash
+            .parse::<bcrypt::HashParts>()
+            .is_ok_and(|parts| parts.get_cost() <= MAX_BCRYPT_COST),
+        Some(Foreign::Firebase) => parse_firebase(hash)
+            .is_some_and(|params| is_scrypt_affordable(params.mem_cost, params.rounds, 1)),
+        Some(Foreign::Django) => {
+            parse_django(hash).is_some_and(|(iterations, ..)| iterations <= MAX_PBKDF2_ROUNDS)
+        }
+        Some(Foreign::Phc) => {
+            PasswordHash::new(hash).is_ok_and(|parsed| is_phc_affordable(&parsed))
+        }
+        None => PasswordHash::new(hash).is_ok(),
+    }
+}
+
+/// Builds the stored form of a hash exported from Firebase Authentication. The salt and hash are
+/// the user's, the rest are the hash parameters of the Firebase project, all in standard base64.
+pub fn firebase_hash(
+    hash: &str,
+    salt: &str,
+    salt_separator: &str,
+    signer_key: &str,
+    rounds: u32,
+    mem_cost: u32,
+) -> String {
+    format!(
+        "{FIREBASE_SCRYPT}mem_cost={mem_cost},rounds={rounds}${salt}${salt_separator}${signer_key}${hash}"
+    )
+}
+
+/// Checks whether a password appears in the breached-password corpus.
+///
+/// The corpus is a directory of Have I Been Pwned range files, as set by
+/// `BREACHED_PASSWORDS_DIR`. Each file is named by the first five hex digits of a SHA-1 hash,
+/// with or without a `.txt` extension, and lists the rest of each hash as `SUFFIX:COUNT` lines.
+/// Nothing is sent over the network. Without a corpus, no password is reported as breached.
+///
+/// # Errors
+///
+/// Returns an `io::Error` if a range file exists but cannot be read.
+pub async fn is_breached(password: &str) -> Result<bool, io::Error> {
+    if env::BREACHED_PASSWORDS_DIR.is_empty() {
+        return Ok(false);
+    }
+
+    let hash: String = Sha1::digest(password.as_bytes())
+        .iter()
+        .map(|b| format!("{b:02X}"))
+        .collect();
+    let (prefix, suffix) = hash.split_at(5);
+    let dir = Path::new(env::BREACHED_PASSWORDS_DIR.as_str());
+
+    for name in [prefix.to_owned(), format!("{prefix}.txt")] {
+        match tokio::fs::read_to_string(dir.join(name)).await {
+            Ok(range) => {
+                return Ok(range.lines().any(|line| {
+                    line.split(':')
+                        .next()
+                        .is_some_and(|s| s.trim().eq_ignore_ascii_case(suffix))
+                }))
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(false)
+}
+
+/// The hash formats of other systems.
+enum Foreign {
+    Bcrypt,
+    /// scrypt or PBKDF2-SHA256 as PHC strings.
+    Phc,
+    Django,
+    Firebase,
+}
+
+fn foreign_kind(hash: &str) -> Option<Foreign> {
+    if ["$2a$", "$2b$", "$2x$", "$2y$"]
+        .iter()
+        .any(|p| hash.starts_with(p))
+    {
+        return Some(Foreign::Bcrypt);
+    }
+    if hash.starts_with("$scrypt$") || hash.starts_with("$pbkdf2-sha256$") {
+        return Some(Foreign::Phc);
+    }
+    if hash.starts_with(DJANGO_PBKDF2) {
+        return Some(Foreign::Django);
+    }
+    if hash.starts_with(FIREBASE_SCRYPT) {
+        return Some(Foreign::Firebase);
+    }
+
+    None
+}
+
+/// Checks a password against a hash of another system. Returns `None` if the hash is not one.
+fn verify_foreign(password: &str, hash: &str) -> Option<bool> {
+    let kind = foreign_kind(hash)?;
+    // Hashes may have been stored before the limits were lowered
+    if !is_supported(hash) {
+        return Some(false);
+    }
+
+    let valid = match kind {
+        Foreign::Bcrypt => bcrypt::verify(password, hash).unwrap_or(false),
+        Foreign::Phc => match PasswordHash::new(hash) {
+            Ok(parsed) if parsed.algorithm.as_str() == "scrypt" => scrypt::Scrypt
+                .verify_password(password.as_bytes(), &parsed)
+                .is_ok(),
+            Ok(parsed) => pbkdf2::Pbkdf2
+                .verify_password(password.as_bytes(), &parsed)
+                .is_ok(),
+            Err(_) => false,
+        },
+        Foreign::Django => match parse_django(hash) {
+            Some((iterations, salt, expected)) => {
+                let mut derived = vec![0u8; expected.len()];
+                pbkdf2::pbkdf2_hmac::<Sha256>(
+                    password.as_bytes(),
+                    salt.as_bytes(),
+                    iterations,
+                    &mut derived,
+                );
+                constant_time_eq(&derived, &expected)
+            }
+            None => false,
+        },
+        Foreign::Firebase => match parse_firebase(hash) {
+            Some(params) => verify_firebase(password, &params),
+            None => false,
+        },
+    };
+
+    Some(valid)
+}
+
+/// Checks whether a scrypt or PBKDF2-SHA256 PHC string is within the cost limits.
+fn is_phc_affordable(hash: &PasswordHash) -> bool {
+    if hash.algorithm.as_str() == "scrypt" {
+        return scrypt::Params::try_from(hash)
+            .is_ok_and(|params| is_scrypt_affordable(params.log_n(), params.r(), params.p()));
+    }
+
+    pbkdf2::Params::try_from(hash).is_ok_and(|params| {
+        params.rounds <= MAX_PBKDF2_ROUNDS && params.output_length <= MAX_PBKDF2_LENGTH
+    })
+}
+
+fn is_scrypt_affordable(log_n: u8, r: u32, p: u32) -> bool {
+    1u64.checked_shl(u32::from(log_n))
+        .and_then(|n| n.checked_mul(u64::from(r)))
+        .and_then(|nr| nr.checked_mul(u64::from(p)))
+        .is_some_and(|work| work <= MAX_SCRYPT_WORK)
+}
+
+/// Returns the iterations, salt and hash of a Django PBKDF2-SHA256 hash.
+fn parse_django(hash: &str) -> Option<(u32, String, Vec<u8>)> {
+    let mut parts = hash.strip_prefix(DJANGO_PBKDF2)?.split('$');
+    let iterations = parts.next()?.parse().ok().filter(|i| *i > 0)?;
+    let salt = parts.next()?.to_owned();
+    let expected = STANDARD.decode(parts.next()?).ok()?;
+
+    match (parts.next(), expected.is_empty()) {
+        (None, false) => Some((iterations, salt, expected)),
+        _ => None,
+    }
+}
+
+struct FirebaseParams {
+    mem_cost: u8,
+    rounds: u32,
+    salt: Vec<u8>,
+    salt_separator: Vec<u8>,
+    signer_key: Vec<u8>,
+    hash: Vec<u8>,
+}
+
+fn parse_firebase(hash: &str) -> Option<FirebaseParams> {
+    let mut parts = hash.strip_prefix(FIREBASE_SCRYPT)?.split('$');
+    let (mut mem_cost, mut rounds) = (None, None);
+    for param in parts.next()?.split(',') {
+        match param.split_once('=')? {
+            ("mem_cost", v) => mem_cost = v.parse::<u8>().ok().filter(|m| (1..=20).contains(m)),
+            ("rounds", v) => rounds = v.parse::<u32>().ok().filter(|r| (1..=16).contains(r)),
+            _ => return None,
+        }
+    }
+    let mut decode = || STANDARD.decode(parts.next()?).ok();
+    let params = FirebaseParams {
+        mem_cost: mem_cost?,
+        rounds: rounds?,
+        salt: decode()?,
+        salt_separator: decode()?,
+        signer_key: decode()?,
+        hash: decode()?,
+    };
+
+    match parts.next() {
+        None => Some(params),
+        Some(_) => None,
+    }
+}
+
+/// Checks a password the way Firebase Authentication does: the signer key is encrypted with
+/// AES-256-CTR under a key derived with scrypt from the password and the salted separator, and
+/// the result must equal the hash.
+fn verify_firebase(password: &str, params: &FirebaseParams) -> bool {
+    let scrypt_params = match scrypt::Params::new(params.mem_cost, params.rounds, 1, 32) {
+        Ok(scrypt_params) => scrypt_params,
+        Err(_) => return false,
+    };
+    let salt = [params.salt.as_slice(), params.salt_separator.as_slice()].concat();
+    let mut key = [0u8; 32];
+    if scrypt::scrypt(password.as_bytes(), &salt, &scrypt_params, &mut key).is_err() {
+        return false;
+    }
+
+    let mut signed = params.signer_key.clone();
+    Ctr128BE::<Aes256>::new(&key.into(), &[0u8; 16].into()).apply_keystream(&mut signed);
+
+    constant_time_eq(&signed, &params.hash)
+}
+
+/// Compares two byte strings in a time that depends only on their lengths, so that secrets
+/// compared with it cannot be guessed one byte at a time.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn hash_blocking(password: &str) -> Result<String, PasswordError> {
+    let salt = SaltString::generate(&mut OsRng);
+
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, PARAMS.clone())
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(PasswordError::Hash)
+}
+
+/// Checks whether a hash was made with another algorithm, version or parameters than the
+/// current ones.
+fn is_outdated(hash: &PasswordHash) -> bool {
+    if hash.algorithm != Algorithm::Argon2id.ident() || hash.version != Some(Version::V0x13.into())
+    {
+        return true;
+    }
+
+    match Params::try_from(hash) {
+        Ok(params) => {
+            params.m_cost() != PARAMS.m_cost()
+                || params.t_cost() != PARAMS.t_cost()
+                || params.p_cost() != PARAMS.p_cost()
+        }
+        Err(_) => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // PBKDF2-HMAC-SHA256 of "passwd" with the salt "salt" and one round, from RFC 7914
+    const PBKDF2_RFC7914: &str =
+        "VawEblbjCJ/sFpHCJUS2BflBhSFt3gRl5oudV8INrLxJypzM8Xm2RZkWZLOdd+8xfHG4RbHjC9UJESBB06GXgw==";
+
+    #[tokio::test]
+    async fn hashes_with_argon2id_and_a_random_salt() {
+        let first = hash("password").await.unwrap();
+        let second = hash("password").await.unwrap();
+
+        let parsed = PasswordHash::new(&first).unwrap();
+        assert_eq!(parsed.algorithm, Algorithm::Argon2id.ident());
+        assert_eq!(parsed.version, Some(Version::V0x13.into()));
+        assert_ne!(first, second);
+        assert!(verify("password", Some(&first)).await.unwrap().valid);
+        assert!(!verify("Password", Some(&first)).await.unwrap().valid);
+    }
+
+    #[tokio::test]
+    async fn refuses_missing_and_malformed_hashes() {
+        for stored in [None, Some(""), Some("not a hash"), Some("$argon2id$v=19$")] {
+            assert_eq!(
+                verify("haltion-dummy-password", stored).await.unwrap(),
+                Verification::default()
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn verifies_bcrypt() {
+        // From the jBCrypt test vectors
+        let hash = "$2a$06$DCq7YPn5Rq63x1Lad4cll.TV4S6ytwfsfvkgY8jIucDrjc8deX1s.";
+
+        assert_eq!(
+            verify("", Some(hash)).await.unwrap(),
+            Verification {
+                valid: true,
+                needs_rehash: true,
+            }
+        );
+        assert!(!verify("wrong", Some(hash)).await.unwrap().valid);
+    }
+
+    #[tokio::test]
+    async fn verifies_scrypt() {
+        // scrypt of "password" with the salt "NaCl", N = 1024, r = 8 and p = 16, from RFC 7914
+        let hash = "$scrypt$ln=10,r=8,p=16$TmFDbA$/bq+HJ00cgB4VucZDQHp/nxq18vII3gw53N2Y0s3MWIurzDZLiKjiG/xCSedmDDaxyevuUqD7m2DYMvfoswGQA";
+
+        assert!(verify("password", Some(hash)).await.unwrap().valid);
+        assert!(!verify("wrong", Some(hash)).await.unwrap().valid);
+    }
+
+    #[tokio::test]
+    async fn verifies_pbkdf2() {
+        let hash = format!(
+            "$pbkdf2-sha256$i=1,l=64$c2FsdA${}",
+            PBKDF2_RFC7914.trim_end_matches('=')
+        );
+
+        assert!(verify("passwd", Some(&hash)).await.unwrap().valid);
+        assert!(!verify("wrong", Some(&hash)).await.unwrap().valid);
+    }
+
+    #[tokio::test]
+    async fn verifies_django() {
+        let hash = format!("{DJANGO_PBKDF2}1$salt${PBKDF2_RFC7914}");
+
+        assert!(verify("passwd", Some(&hash)).await.unwrap().valid);
+        assert!(!verify("wrong", Some(&hash)).await.unwrap().valid);
+    }
+
+    #[tokio::test]
+    async fn verifies_firebase() {
+        // From the sample project of Firebase's scrypt reference implementation
+        let hash = firebase_hash(
+            "lSrfV15cpx95/sZS2W9c9Kp6i/LVgQNDNC/qzrCnh1SAyZvqmZqAjTdn3aoItz+VHjoZilo78198JAdRuid5lQ==",
+            "42xEC+ixf3L2lw==",
+            "Bw==",
+            "jxspr8Ki0RYycVU8zykbdLGjFQ3McFUH0uiiTvC8pVMXAn210wjLNmdZJzxUECKbm0QsEmYUSDzZvpjeJ9WmXA==",
+            8,
+            14,
+        );
+
+        assert!(verify("user1password", Some(&hash)).await.unwrap().valid);
+        assert!(!verify("wrong", Some(&hash)).await.unwrap().valid);
+    }
+
+    #[tokio::test]
+    async fn rehashes_outdated_argon2() {
+        let current = hash("password").await.unwrap();
+        let outdated = Argon2::new(
+            Algorithm::Argon2id,
+            Version::V0x13,
+            Params::new(PARAMS.m_cost() / 2, PARAMS.t_cost(), PARAMS.p_cost(), None).unwrap(),
+        )
+        .hash_password(b"password", &SaltString::generate(&mut OsRng))
+        .unwrap()
+        .to_string();
+
+        assert_eq!(
+            verify("password", Some(&current)).await.unwrap(),
+            Verification {
+                valid: true,
+                needs_rehash: false,
+            }
+        );
+        assert_eq!(
+            verify("password", Some(&outdated)).await.unwrap(),
+            Verification {
+                valid: true,
+                needs_rehash: true,
+            }
+        );
+        assert_eq!(
+            verify("password", None).await.unwrap(),
+            Verification::default()
+        );
+    }
+
+    #[test]
+    fn rejects_costs_above_the_limits() {
+        assert!(is_supported(&format!(
+            "{DJANGO_PBKDF2}1$salt${PBKDF2_RFC7914}"
+        )));
+        assert!(!is_supported(&format!(
+            "{DJANGO_PBKDF2}1000000000$salt${PBKDF2_RFC7914}"
+        )));
+        assert!(!is_supported(
+            "$2a$31$DCq7YPn5Rq63x1Lad4cll.TV4S6ytwfsfvkgY8jIucDrjc8deX1s."
+        ));
+        assert!(!is_supported("$scrypt$ln=20,r=8,p=1$TmFDbA$/bq+HJ00cgB4VucZDQHp/nxq18vII3gw53N2Y0s3MWIurzDZLiKjiG/xCSedmDDaxyevuUqD7m2DYMvfoswGQA"));
+        assert!(!is_supported(&format!(
+            "$pbkdf2-sha256$i=100000000,l=64$c2FsdA${}",
+            PBKDF2_RFC7914.trim_end_matches('=')
+        )));
+        assert!(!is_supported(&firebase_hash(
+            "AA==", "AA==", "AA==", "AA==", 16, 20
+        )));
+        assert_eq!(
+            verify_foreign(
+                "passwd",
+                &format!("{DJANGO_PBKDF2}1000000000$salt${PBKDF2_RFC7914}")
+            ),
+            Some(false)
+        );
+    }
+}
@@ -0,0 +1,18 @@
+use rand::{distributions::Alphanumeric, Rng};
+
+/// Generates a random alphanumeric string suitable for opaque identifiers and tokens.
+pub fn alphanumeric(len: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
+
+/// Generates a random string of decimal digits, for codes users type in.
+pub fn numeric(len: usize) -> String {
+    let mut rng = rand::thread_rng();
+    (0..len)
+        .map(|_| char::from(b'0' + rng.gen_range(0..10)))
+        .collect()
+}
@@ -1,9 +1,127 @@
 use redis::{
     aio::{AsyncStream, Connection},
-    AsyncCommands, Client,
+    AsyncCommands, Client, FromRedisValue, Script, ToRedisArgs,
 };
+use std::collections::HashMap;
 use tokio::macros::support::Pin;
 
+/// How many keys to ask for in each round of a `SCAN`.
+const SCAN_COUNT: usize = 500;
+
+/// Builds the keys of a tenant's part of the keyspace.
+///
+/// Every key starts with `tenant:{id}:`, so that one tenant's keys never collide with another's
+/// and all of a tenant's keys can be removed with a single prefix scan.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TenantKeys<'a>(&'a str);
+
+impl<'a> TenantKeys<'a> {
+    pub fn new(tenant: &'a str) -> Self {
+        Self(tenant)
+    }
+
+    /// The prefix shared by all of the tenant's keys.
+    pub fn prefix(&self) -> String {
+        format!("tenant:{}:", self.0)
+    }
+
+    /// A one-time code waiting to be verified, keyed by the challenge it was sent under.
+    pub fn otp(&self, challenge: &str) -> String {
+        self.key("otp", challenge)
+    }
+
+    /// The prefix shared by all of the tenant's one-time codes.
+    pub fn otp_prefix(&self) -> String {
+        self.key("otp", "")
+    }
+
+    /// A password login waiting to be confirmed with a second factor.
+    pub fn login_challenge(&self, challenge: &str) -> String {
+        self.key("login_challenge", challenge)
+    }
+
+    /// The prefix shared by all of the tenant's login challenges.
+    pub fn login_challenge_prefix(&self) -> String {
+        self.key("login_challenge", "")
+    }
+
+    /// The code waiting to confirm a user's username.
+    pub fn verification(&self, username: &str) -> String {
+        self.key("verification", username)
+    }
+
+    /// The code waiting to reset a user's password.
+    pub fn password_reset(&self, username: &str) -> String {
+        self.key("password_reset", username)
+    }
+
+    /// A new phone number or email address a user asked to change to, waiting to be verified.
+    pub fn identifier_change(&self, sub: &str) -> String {
+        self.key("identifier_change", sub)
+    }
+
+    /// The failed password logins of an account.
+    pub fn login_failures(&self, username: &str) -> String {
+        self.key("login_failures", username)
+    }
+
+    /// The IP addresses an account recently signed in from with its password.
+    pub fn known_login_ips(&self, username: &str) -> String {
+        self.key("known_login_ips", username)
+    }
+
+    /// The failed password logins from an IP address.
+    pub fn ip_login_failures(&self, ip: &str) -> String {
+        self.key("ip_login_failures", ip)
+    }
+
+    pub fn session(&self, sid: &str) -> String {
+        self.key("session", sid)
+    }
+
+    /// The set of a user's session ids.
+    pub fn user_sessions(&self, sub: &str) -> String {
+        self.key("user_sessions", sub)
+    }
+
+    /// The usage counters of a day or month.
+    pub fn usage(&self, period: &str, date: &str) -> String {
+        self.key("usage", &format!("{period}:{date}"))
+    }
+
+    /// The set of users active in a day or month.
+    pub fn active_users(&self, period: &str, date: &str) -> String {
+        self.key("active_users", &format!("{period}:{date}"))
+    }
+
+    /// The prefix shared by the sets of active users of every day and month.
+    pub fn active_users_prefix(&self) -> String {
+        self.key("active_users", "")
+    }
+
+    /// A DPoP proof that was already used, kept until it expires so that it cannot be replayed.
+    pub fn dpop_proof(&self, jkt: &str, jti: &str) -> String {
+        self.key("dpop", &format!("{jkt}:{jti}"))
+    }
+
+    /// The progress and report of a user import.
+    pub fn import_job(&self, job_id: &str) -> String {
+        self.key("import_job", job_id)
+    }
+
+    pub fn quotas(&self) -> String {
+        format!("{}quotas", self.prefix())
+    }
+
+    pub fn settings(&self) -> String {
+        format!("{}settings", self.prefix())
+    }
+
+    fn key(&self, kind: &str, id: &str) -> String {
+        format!("{}{kind}:{id}", self.prefix())
+    }
+}
+
 pub struct RedisClient {
     pub client: Client,
     pub con: Connection<Pin<Box<dyn AsyncStream + Send + Sync>>>,
@@ -24,19 +142,37 @@ impl RedisClient {
             .await
     }
 
-    pub async fn set_key_map(
+    /// Writes the given fields to the hash at `key` and sets its time to live in seconds.
+    pub async fn set_key_map<F: ToRedisArgs, V: ToRedisArgs>(
         &mut self,
         key: &str,
-        items: &[(String, String)],
-    ) -> Result<String, redis::RedisError> {
-        redis::cmd("HMSET")
-            .arg(key)
-            .arg(items)
-            .arg(&["EX", "300"])
-            .query_async::<_, String>(&mut self.con)
+        items: &[(F, V)],
+        ttl: i64,
+    ) -> Result<(), redis::RedisError> {
+        redis::pipe()
+            .atomic()
+            .hset_multiple(key, items)
+            .ignore()
+            .expire(key, ttl as usize)
+            .ignore()
+            .query_async(&mut self.con)
             .await
     }
 
+    /// Sets `key` only if it does not exist yet. Returns whether the key was set.
+    pub async fn set_key_nx(
+        &mut self,
+        key: &str,
+        value: &str,
+        ttl: i64,
+    ) -> Result<bool, redis::RedisError> {
+        redis::cmd("SET")
+            .arg(&[key, value, "NX", "EX", &ttl.to_string()])
+            .query_async::<_, Option<String>>(&mut self.con)
+            .await
+            .map(|reply| reply.is_some())
+    }
+
     pub async fn get_key(&mut self, key: &str) -> Result<String, redis::RedisError> {
         self.con.get(key).await
     }
@@ -44,4 +180,188 @@ impl RedisClient {
     pub async fn del_key(&mut self, key: &str) -> Result<(), redis::RedisError> {
         self.con.del(key).await
     }
+
+    /// Returns every key starting with `prefix`.
+    pub async fn scan_prefix(&mut self, prefix: &str) -> Result<Vec<String>, redis::RedisError> {
+        let pattern = format!("{}*", escape_pattern(prefix));
+        let mut cursor: u64 = 0;
+        let mut found = Vec::new();
+
+        loop {
+            let (next, keys): (u64, Vec<String>) = redis::cmd("SCAN")
+                .arg(cursor)
+                .arg("MATCH")
+                .arg(&pattern)
+                .arg("COUNT")
+                .arg(SCAN_COUNT)
+                .query_async(&mut self.con)
+                .await?;
+            found.extend(keys);
+            if next == 0 {
+                return Ok(found);
+            }
+            cursor = next;
+        }
+    }
+
+    /// Deletes every key starting with `prefix` and returns how many were deleted.
+    pub async fn delete_prefix(&mut self, prefix: &str) -> Result<usize, redis::RedisError> {
+        let pattern = format!("{}*", escape_pattern(prefix));
+        let mut cursor: u64 = 0;
+        let mut deleted = 0;
+
+        loop {
+            let (next, keys): (u64, Vec<String>) = redis::cmd("SCAN")
+                .arg(cursor)
+                .arg("MATCH")
+                .arg(&pattern)
+                .arg("COUNT")
+                .arg(SCAN_COUNT)
+                .query_async(&mut self.con)
+                .await?;
+            if !keys.is_empty() {
+                deleted += keys.len();
+                redis::cmd("UNLINK")
+                    .arg(&keys)
+                    .query_async::<_, ()>(&mut self.con)
+                    .await?;
+            }
+            if next == 0 {
+                return Ok(deleted);
+            }
+            cursor = next;
+        }
+    }
+
+    pub async fn set_hash_field(
+        &mut self,
+        key: &str,
+        field: &str,
+        value: &str,
+    ) -> Result<(), redis::RedisError> {
+        self.con.hset(key, field, value).await
+    }
+
+    /// Returns all fields of the hash at `key`, or an empty map if it does not exist.
+    pub async fn get_hash(
+        &mut self,
+        key: &str,
+    ) -> Result<HashMap<String, String>, redis::RedisError> {
+        self.con.hgetall(key).await
+    }
+
+    /// Increments a field of the hash at `key` and sets the hash's time to live in seconds.
+    pub async fn incr_hash_field(
+        &mut self,
+        key: &str,
+        field: &str,
+        by: i64,
+        ttl: i64,
+    ) -> Result<(), redis::RedisError> {
+        redis::pipe()
+            .atomic()
+            .hincr(key, field, by)
+            .ignore()
+            .expire(key, ttl as usize)
+            .ignore()
+            .query_async(&mut self.con)
+            .await
+    }
+
+    /// Replaces the hash at `key` with the given fields. The hash does not expire.
+    pub async fn replace_hash<F: ToRedisArgs, V: ToRedisArgs>(
+        &mut self,
+        key: &str,
+        items: &[(F, V)],
+    ) -> Result<(), redis::RedisError> {
+        let mut pipe = redis::pipe();
+        pipe.atomic().del(key).ignore();
+        if !items.is_empty() {
+            pipe.hset_multiple(key, items).ignore();
+        }
+        pipe.query_async(&mut self.con).await
+    }
+
+    /// Runs a Lua script on `keys` with `args`, so that it reads and writes them atomically.
+    pub async fn run_script<T: FromRedisValue>(
+        &mut self,
+        script: &Script,
+        keys: &[&str],
+        args: &[i64],
+    ) -> Result<T, redis::RedisError> {
+        let mut invocation = script.prepare_invoke();
+        for key in keys {
+            invocation.key(*key);
+        }
+        for arg in args {
+            invocation.arg(*arg);
+        }
+        invocation.invoke_async(&mut self.con).await
+    }
+
+    pub async fn exists(&mut self, key: &str) -> Result<bool, redis::RedisError> {
+        self.con.exists(key).await
+    }
+
+    /// Adds `member` to the set at `key`. When `ttl` is given, the set's time to live is reset to
+    /// that many seconds.
+    pub async fn add_member(
+        &mut self,
+        key: &str,
+        member: &str,
+        ttl: Option<i64>,
+    ) -> Result<(), redis::RedisError> {
+        let mut pipe = redis::pipe();
+        pipe.atomic().sadd(key, member).ignore();
+        if let Some(ttl) = ttl {
+            pipe.expire(key, ttl as usize).ignore();
+        }
+        pipe.query_async(&mut self.con).await
+    }
+
+    /// Replaces the set at `key` with the given members. The set does not expire.
+    pub async fn replace_members(
+        &mut self,
+        key: &str,
+        members: &[String],
+    ) -> Result<(), redis::RedisError> {
+        let mut pipe = redis::pipe();
+        pipe.atomic().del(key).ignore();
+        if !members.is_empty() {
+            pipe.sadd(key, members).ignore();
+        }
+        pipe.query_async(&mut self.con).await
+    }
+
+    pub async fn is_member(&mut self, key: &str, member: &str) -> Result<bool, redis::RedisError> {
+        self.con.sismember(key, member).await
+    }
+
+    pub async fn get_members(&mut self, key: &str) -> Result<Vec<String>, redis::RedisError> {
+        self.con.smembers(key).await
+    }
+
+    pub async fn count_members(&mut self, key: &str) -> Result<u64, redis::RedisError> {
+        self.con.scard(key).await
+    }
+
+    pub async fn remove_member(
+        &mut self,
+        key: &str,
+        member: &str,
+    ) -> Result<(), redis::RedisError> {
+        self.con.srem(key, member).await
+    }
+}
+
+/// Escapes the characters that have a special meaning in a `SCAN MATCH` pattern.
+fn escape_pattern(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
 }
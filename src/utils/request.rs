@@ -0,0 +1,122 @@
+use crate::config::env;
+use axum::http::HeaderMap;
+use lazy_static::lazy_static;
+use std::net::{IpAddr, SocketAddr};
+
+lazy_static! {
+    static ref TRUSTED_PROXIES: Vec<IpAddr> = parse_proxies(&env::TRUSTED_PROXIES);
+}
+
+/// Checks whether a request was forwarded by one of the reverse proxies set in `TRUSTED_PROXIES`,
+/// whose `X-Forwarded-*` headers can be believed.
+pub fn from_trusted_proxy(addr: &SocketAddr) -> bool {
+    TRUSTED_PROXIES.contains(&addr.ip())
+}
+
+/// Returns the address of the client. Requests forwarded by a trusted proxy are traced back
+/// through `X-Forwarded-For` to the right-most hop that no trusted proxy added, since every hop
+/// to its left could have been sent by the client itself.
+pub fn client_ip(headers: &HeaderMap, addr: &SocketAddr) -> String {
+    forwarded_for(headers, addr, &TRUSTED_PROXIES).to_string()
+}
+
+fn forwarded_for(headers: &HeaderMap, addr: &SocketAddr, trusted: &[IpAddr]) -> IpAddr {
+    let mut client = addr.ip();
+    if !trusted.contains(&client) {
+        return client;
+    }
+
+    let hops: Vec<&str> = headers
+        .get_all("X-Forwarded-For")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .collect();
+    for hop in hops.into_iter().rev() {
+        match hop.trim().parse::<IpAddr>() {
+            Ok(ip) if trusted.contains(&ip) => client = ip,
+            Ok(ip) => return ip,
+            Err(_) => break,
+        }
+    }
+
+    client
+}
+
+pub fn user_agent(headers: &HeaderMap) -> String {
+    headers
+        .get("User-Agent")
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("")
+        .to_owned()
+}
+
+/// Parses a comma-separated list of IP addresses. Entries that are not addresses are skipped.
+fn parse_proxies(value: &str) -> Vec<IpAddr> {
+    value
+        .split(',')
+        .filter_map(|ip| ip.trim().parse().ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_proxies_skips_invalid_entries() {
+        let proxies = parse_proxies(" 10.0.0.1, proxy.local,::1,");
+
+        assert_eq!(
+            proxies,
+            vec![
+                "10.0.0.1".parse::<IpAddr>().unwrap(),
+                "::1".parse().unwrap()
+            ]
+        );
+    }
+
+    fn forwarded(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Forwarded-For", value.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn forwarded_for_is_ignored_from_untrusted_peers() {
+        let headers = forwarded("198.51.100.1");
+        let addr = "203.0.113.7:50000".parse().unwrap();
+
+        let ip = forwarded_for(&headers, &addr, &[]);
+
+        assert_eq!(ip.to_string(), "203.0.113.7");
+    }
+
+    #[test]
+    fn forwarded_for_takes_the_right_most_untrusted_hop() {
+        let trusted = parse_proxies("10.0.0.1,10.0.0.2");
+        // The client made up the first hop
+        let headers = forwarded("192.0.2.66, 203.0.113.7, 10.0.0.2");
+        let addr = "10.0.0.1:50000".parse().unwrap();
+
+        let ip = forwarded_for(&headers, &addr, &trusted);
+
+        assert_eq!(ip.to_string(), "203.0.113.7");
+    }
+
+    #[test]
+    fn forwarded_for_stops_at_malformed_hops() {
+        let trusted = parse_proxies("10.0.0.1");
+        let headers = forwarded("unknown, 10.0.0.1");
+        let addr = "10.0.0.1:50000".parse().unwrap();
+
+        let ip = forwarded_for(&headers, &addr, &trusted);
+
+        assert_eq!(ip.to_string(), "10.0.0.1");
+    }
+
+    #[test]
+    fn no_proxy_is_trusted_by_default() {
+        assert!(!from_trusted_proxy(&"127.0.0.1:443".parse().unwrap()));
+    }
+}